@@ -34,6 +34,7 @@ pub enum T {
     Information(String),
     Notes(String),
     Origin(String),
+    Rhythm(String),
     Source(String),
     Title(String),
     Words(String),
@@ -44,6 +45,10 @@ pub enum T {
     Metre(music::Metre),
     KeySignature(music::PitchClass, music::Mode),
     DefaultNoteLength(music::FractionalDuration),
+    Tempo(music::Tempo),
+
+    /// Clef, from a "clef=" parameter in a "K:" or "V:" field.
+    Clef(music::Clef),
 
     SingleBar,
     DoubleBar,
@@ -52,7 +57,19 @@ pub enum T {
     EndBar,
     NTimeBar(u32),
 
+    /// Quoted guitar chord or annotation, e.g. "\"Am\"", as written.
+    GuitarChord(String),
+
+    /// Decoration on the following note, e.g. "~" or "!trill!".
+    Decoration(music::Decoration),
+
+    /// Grace notes before the following note, e.g. "{g}".
+    GraceNotes(music::GraceNotes),
+
     Note(music::Note),
+
+    /// Directive on a line of its own, e.g. "%%MIDI program 73", without the "%%".
+    Directive(String),
 }
 
 
@@ -132,7 +149,7 @@ impl<'a> Context<'a> {
     }
 
     /// Take the first n characters, if we have them.
-    #[test]
+    #[cfg(test)]
     fn take(&self, n: usize) -> Option<(Context<'a>, &'a [char])> {
         if !self.has(n) {
             None
@@ -408,6 +425,121 @@ fn lex_metre<'a>(ctx: Context<'a>, delimiter: char) -> LexResult {
     }
 }
 
+/// Read a double-quoted string, e.g. "\"Allegro\"", on the current line.
+fn read_quoted<'a>(ctx: Context<'a>) -> Option<(Context<'a>, String)> {
+    match ctx.first() {
+        Some((ctx, '"')) => {
+            match read_until(ctx, '"') {
+                Ok((ctx, chars)) => {
+                    // A quote that's never closed on this line isn't a quoted string.
+                    if chars.contains(&'\n') {
+                        None
+                    } else {
+                        Some((ctx, chars.iter().collect()))
+                    }
+                }
+                Err(_) => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Lex a tempo, e.g. "1/4=120", "120" or "\"Allegro\" 3/8=60".
+fn lex_tempo<'a>(ctx: Context<'a>, delimiter: char) -> LexResult {
+    // As with metre, the whole-line context is used to resume after errors.
+    match read_until(ctx, delimiter) {
+        Err(ctx) => LexResult::Error(ctx, ctx.i, LexError::PrematureEnd(During::Tempo)),
+
+        Ok((whole_line_ctx, _)) => {
+            // Optional label before the beat.
+            let (ctx, label) = match read_quoted(ctx) {
+                Some((ctx, label)) => (ctx.skip_whitespace(), Some(label)),
+                None => (ctx, None),
+            };
+
+            // A label on its own is fine.
+            match ctx.peek_first() {
+                Some((_, chr)) if chr == delimiter && label.is_some() => {
+                    return LexResult::t(
+                        whole_line_ctx,
+                        T::Tempo(music::Tempo {
+                            beat: None,
+                            bpm: None,
+                            label,
+                        }),
+                    )
+                }
+                _ => (),
+            }
+
+            let (ctx, beat, bpm) = match read_number(ctx, NumberRole::TempoBeat) {
+                Err((_, offset, err)) => return LexResult::Error(whole_line_ctx, offset, err),
+                Ok((ctx, numerator)) => {
+                    match ctx.first() {
+                        // e.g. "1/4=120"
+                        Some((ctx, '/')) => {
+                            match read_number(ctx, NumberRole::TempoBeat) {
+                                Err((_, offset, err)) => {
+                                    return LexResult::Error(whole_line_ctx, offset, err)
+                                }
+                                Ok((ctx, denomenator)) => {
+                                    match ctx.first() {
+                                        Some((ctx, '=')) => {
+                                            match read_number(ctx, NumberRole::TempoBpm) {
+                                                Err((_, offset, err)) => {
+                                                    return LexResult::Error(
+                                                        whole_line_ctx,
+                                                        offset,
+                                                        err,
+                                                    )
+                                                }
+                                                Ok((ctx, bpm)) => (
+                                                    ctx,
+                                                    Some(music::FractionalDuration(
+                                                        numerator,
+                                                        denomenator,
+                                                    )),
+                                                    bpm,
+                                                ),
+                                            }
+                                        }
+                                        _ => {
+                                            return LexResult::Error(
+                                                whole_line_ctx,
+                                                ctx.i,
+                                                LexError::ExpectedEqualsInTempo,
+                                            )
+                                        }
+                                    }
+                                }
+                            }
+                        }
+
+                        // Old-style, e.g. "120", which counts default note lengths.
+                        _ => (ctx, None, numerator),
+                    }
+                }
+            };
+
+            // Optional label after the beat, if there wasn't one before.
+            let label = match label {
+                Some(label) => Some(label),
+                None => read_quoted(ctx.skip_whitespace()).map(|(_, label)| label),
+            };
+
+            LexResult::t(
+                whole_line_ctx,
+                T::Tempo(music::Tempo {
+                    beat,
+                    bpm: Some(bpm),
+                    label,
+                }),
+            )
+        }
+    }
+}
+
 /// Lex a key note, e.g. "C", "Bf", "F Flat".
 fn read_key_note<'a>(ctx: Context<'a>) -> Option<(Context<'a>, music::PitchClass)> {
     let (ctx, diatonic) = match ctx.first() {
@@ -543,6 +675,14 @@ pub fn read_mode<'a>(ctx: Context<'a>) -> Option<(Context<'a>, music::Mode)> {
         Some((ctx, music::Mode::Phrygian))
     } else if let (ctx, true) = ctx.starts_with_insensitive_eager(&['l', 'o', 'c']) {
         Some((ctx, music::Mode::Locrian))
+
+    // Shortest form of minor, e.g. "Am". Must come after everything else beginning with 'm'.
+    // Only on its own, so that e.g. "middle=d" isn't read as minor.
+    } else if let (ctx, true) = ctx.starts_with_insensitive_eager(&['m']) {
+        match ctx.peek_first() {
+            Some((_, c)) if !(c.is_whitespace() || c == ']' || c == '%') => None,
+            _ => Some((ctx, music::Mode::Minor)),
+        }
     } else {
         None
     }
@@ -602,13 +742,39 @@ fn read_fractional_duration<'a>(ctx: Context<'a>) -> (Context, music::Fractional
 }
 
 
+/// Read a clef from the parameters of a "K:" or "V:" field, e.g. "clef=bass".
+/// The clef name may also be given on its own, e.g. "K:G bass".
+/// Unrecognised clefs are ignored.
+fn read_clef(chars: &[char]) -> Option<music::Clef> {
+    let value: String = chars.iter().collect();
+
+    for word in value.split_whitespace() {
+        let lower = word.to_lowercase();
+
+        if lower.starts_with("clef=") {
+            return music::Clef::from_name(&word[5..]);
+        }
+
+        match lower.as_str() {
+            "treble" | "treble-8" | "bass" | "alto" | "tenor" | "perc" => {
+                return music::Clef::from_name(&lower)
+            }
+            _ => (),
+        }
+    }
+
+    None
+}
+
 fn lex_key_signature<'a>(ctx: Context<'a>, delimiter: char) -> LexResult {
     match read_until(ctx, delimiter) {
         Err(ctx) => LexResult::Error(ctx, ctx.i, LexError::PrematureEnd(During::KeySignature)),
 
         // Although this context is discareded for parsing, it is used to return errors,
         // as it enables the lexer to continue at the next token.
-        Ok((whole_line_ctx, _)) => {
+        Ok((whole_line_ctx, chars)) => {
+            let clef = read_clef(chars);
+
             if let Some((ctx, key_note)) = read_key_note(ctx) {
 
                 // TODO: Assuming empty means 'major'. Is this correct for at the lexer?
@@ -618,7 +784,19 @@ fn lex_key_signature<'a>(ctx: Context<'a>, delimiter: char) -> LexResult {
                 // TODO extras like specific accidentals?
 
                 // Skip to end of delimited sequence (line or bracket).
-                LexResult::t(whole_line_ctx, T::KeySignature(key_note, mode))
+                match clef {
+                    Some(clef) => {
+                        LexResult::tt(
+                            whole_line_ctx,
+                            T::KeySignature(key_note, mode),
+                            T::Clef(clef),
+                        )
+                    }
+                    None => LexResult::t(whole_line_ctx, T::KeySignature(key_note, mode)),
+                }
+            } else if let Some(clef) = clef {
+                // A clef can be given without a key, e.g. "K:clef=bass".
+                LexResult::t(whole_line_ctx, T::Clef(clef))
             } else {
                 // TODO: There may be an alternative to a key-note. May need to amend this when
                 // fuzzing with real-world inputs.
@@ -628,18 +806,47 @@ fn lex_key_signature<'a>(ctx: Context<'a>, delimiter: char) -> LexResult {
     }
 }
 
+/// Lex a voice field, e.g. "V:1 clef=bass".
+/// Only the clef is used. Voices aren't otherwise supported yet, so the voice ID is ignored.
+fn lex_voice<'a>(ctx: Context<'a>, delimiter: char) -> LexResult {
+    match read_until(ctx, delimiter) {
+        Err(ctx) => LexResult::Error(ctx, ctx.i, LexError::PrematureEnd(During::Header)),
+
+        Ok((ctx, chars)) => LexResult::ts(ctx, read_clef(chars).into_iter().map(T::Clef).collect()),
+    }
+}
+
 
 /// Read an n-time-repeat, e.g. "[2" or "2" immediately following a barline.
 fn read_n_time<'a>(ctx: Context<'a>) -> (Context<'a>, Option<u32>) {
 
-    let ctx = ctx.skip_optional_prefix(&['[']);
-
-    match read_number(ctx, NumberRole::NTimeBar) {
+    match read_number(ctx.skip_optional_prefix(&['[']), NumberRole::NTimeBar) {
         Ok((ctx, number)) => (ctx, Some(number)),
+
+        // Leave a bracket that doesn't start a number, e.g. an inline field.
         _ => (ctx, None),
     }
 }
 
+/// Lex an inline field in the tune body, e.g. "[K:G clef=bass]".
+fn lex_inline_field<'a>(ctx: Context<'a>) -> LexResult {
+    let field = match (ctx.c.get(ctx.i + 1), ctx.c.get(ctx.i + 2)) {
+        (Some(&field), Some(&':')) => field,
+        _ => return LexResult::Error(ctx.skip(1), ctx.i, LexError::UnexpectedBodyChar('[')),
+    };
+
+    let ctx = ctx.skip(3).skip_whitespace();
+
+    match field {
+        'K' => lex_key_signature(ctx, ']'),
+        'L' => lex_note_length(ctx, ']'),
+        'M' => lex_metre(ctx, ']'),
+        'Q' => lex_tempo(ctx, ']'),
+        'V' => lex_voice(ctx, ']'),
+        _ => LexResult::Error(ctx, ctx.i, LexError::ExpectedFieldType(field)),
+    }
+}
+
 /// Lex a barline, when it is expected.
 /// TODO all tests for this!
 fn lex_barline<'a>(ctx: Context<'a>) -> LexResult {
@@ -731,6 +938,113 @@ fn lex_barline<'a>(ctx: Context<'a>) -> LexResult {
     }
 }
 
+/// Lex a guitar chord, e.g. "\"D/F#\"". The text isn't interpreted.
+fn lex_guitar_chord<'a>(ctx: Context<'a>) -> LexResult {
+    match read_quoted(ctx) {
+        Some((ctx, chord)) => LexResult::t(ctx, T::GuitarChord(chord)),
+        None => LexResult::Error(ctx, ctx.i, LexError::ExpectedDelimiter('"')),
+    }
+}
+
+/// Decoration abbreviated to a single character, e.g. "~".
+/// Some of these are also header field letters, which aren't allowed in the body.
+fn lex_decoration_shorthand<'a>(ctx: Context<'a>, shorthand: char) -> LexResult {
+    match (music::Decoration::from_shorthand(shorthand), ctx.skip(1).first()) {
+        (Some(_), Some((_, ':'))) | (None, _) => {
+            LexResult::Error(ctx, ctx.i, LexError::UnexpectedBodyChar(shorthand))
+        }
+        (Some(decoration), _) => LexResult::t(ctx.skip(1), T::Decoration(decoration)),
+    }
+}
+
+/// Decoration by name, e.g. "!trill!". Unknown decorations are ignored.
+fn lex_decoration<'a>(ctx: Context<'a>) -> LexResult {
+    let ctx = ctx.skip(1);
+    let rest = ctx.rest();
+
+    match rest.iter().position(|c| *c == '!' || *c == '\n') {
+        Some(length) if rest[length] == '!' => {
+            let name: String = rest[..length].iter().collect();
+            let ctx = ctx.skip(length + 1);
+
+            match music::Decoration::from_name(&name) {
+                Some(decoration) => LexResult::t(ctx, T::Decoration(decoration)),
+                None => LexResult::T(ctx, vec![]),
+            }
+        }
+        _ => LexResult::Error(ctx, ctx.i, LexError::ExpectedDelimiter('!')),
+    }
+}
+
+/// Grace notes, e.g. "{g}" or "{/ag}".
+fn lex_grace_notes<'a>(ctx: Context<'a>) -> LexResult {
+    let ctx = ctx.skip(1);
+
+    let (mut ctx, acciaccatura) = match ctx.first() {
+        Some((ctx, '/')) => (ctx, true),
+        _ => (ctx, false),
+    };
+
+    let mut notes = vec![];
+
+    loop {
+        match ctx.peek_first() {
+            Some((ctx, '}')) => {
+                return LexResult::t(
+                    ctx.skip(1),
+                    T::GraceNotes(music::GraceNotes {
+                        acciaccatura,
+                        notes,
+                    }),
+                )
+            }
+
+            Some((_, 'a'..='g')) |
+            Some((_, 'A'..='G')) |
+            Some((_, '^')) |
+            Some((_, '_')) |
+            Some((_, '=')) => {
+                match lex_note(ctx) {
+                    LexResult::T(next, tokens) => {
+                        for token in tokens {
+                            if let T::Note(note) = token {
+                                notes.push(note);
+                            }
+                        }
+                        ctx = next;
+                    }
+                    error => return error,
+                }
+            }
+
+            _ => return LexResult::Error(ctx, ctx.i, LexError::ExpectedDelimiter('}')),
+        }
+    }
+}
+
+/// Comment, from "%" to the end of the line.
+/// A line starting with "%%" is a directive, which is kept. Other comments are dropped.
+/// A comment taking up a whole line takes its newline with it.
+fn lex_comment<'a>(ctx: Context<'a>) -> LexResult {
+    let rest = ctx.rest();
+    let length = rest.iter().position(|c| *c == '\n').unwrap_or(rest.len());
+    let whole_line = ctx.i == 0 || ctx.c[ctx.i - 1] == '\n';
+
+    let next = if whole_line && length < rest.len() {
+        ctx.skip(length + 1)
+    } else {
+        ctx.skip(length)
+    };
+
+    match (whole_line, rest.get(1)) {
+        (true, Some(&'%')) => {
+            let value: String = rest[2..length].iter().collect();
+            LexResult::t(next, T::Directive(value.trim().to_string()))
+        }
+        _ => LexResult::T(next, vec![]),
+    }
+}
+
 fn lex_note<'a>(ctx: Context<'a>) -> LexResult {
     // Optional accidental.
     let (ctx, accidental) = if let (ctx, true) = ctx.starts_with_insensitive_eager(&['^', '^']) {
@@ -820,6 +1134,8 @@ pub enum During {
     KeySignature,
 
     DefaultNoteLenth,
+
+    Tempo,
 }
 
 #[derive(Debug, PartialEq, PartialOrd, Clone)]
@@ -831,6 +1147,8 @@ pub enum NumberRole {
     UpperDefaultNoteLength,
     LowerDefaultNoteLength,
     NTimeBar,
+    TempoBeat,
+    TempoBpm,
 }
 
 /// Types of errors. These should be as specific as possible to give the best help.
@@ -878,6 +1196,9 @@ pub enum LexError {
     UnrecognisedNote,
 
     ExpectedSlashInNoteLength,
+
+    /// During a tempo, expected to get an equals sign after the beat.
+    ExpectedEqualsInTempo,
 }
 
 /// Indent and print a line to a string buffer.
@@ -1010,6 +1331,20 @@ impl LexError {
                             &"I expected to find a n-time repeat bar.".to_string(),
                         )
                     }
+                    &NumberRole::TempoBeat => {
+                        indent_and_append_line(
+                            indent,
+                            buf,
+                            &"I expected to find the beat of a tempo, e.g. 1/4.".to_string(),
+                        )
+                    }
+                    &NumberRole::TempoBpm => {
+                        indent_and_append_line(
+                            indent,
+                            buf,
+                            &"I expected to find the beats per minute of a tempo.".to_string(),
+                        )
+                    }
 
                 }
             }
@@ -1050,6 +1385,13 @@ impl LexError {
                             &"I was in the middle of reading a default note length.".to_string(),
                         )
                     }
+                    &During::Tempo => {
+                        indent_and_append_line(
+                            indent,
+                            buf,
+                            &"I was in the middle of reading a tempo.".to_string(),
+                        )
+                    }
                 }
             }
             &LexError::UnexpectedBodyChar(chr) => {
@@ -1084,6 +1426,9 @@ impl LexError {
             &LexError::UnrecognisedNote => {
                 buf.push_str("I didn't understand how to read this note.");
             }
+            &LexError::ExpectedEqualsInTempo => {
+                buf.push_str("I expected to find an equals sign in the tempo, e.g. 1/4=120.");
+            }
 
         }
     }
@@ -1137,6 +1482,7 @@ impl<'a> LexResult<'a> {
 fn read(ctx: Context) -> LexResult {
     match ctx.peek_first() {
         None => LexResult::Terminal,
+        Some((ctx, '%')) => lex_comment(ctx),
         Some((ctx, first_char)) => {
             match ctx.tune_section {
                 TuneSection::Header => {
@@ -1171,6 +1517,7 @@ fn read(ctx: Context) -> LexResult {
                                                 'I' => LexResult::t(ctx, T::Information(value)),
                                                 'N' => LexResult::t(ctx, T::Notes(value)),
                                                 'O' => LexResult::t(ctx, T::Origin(value)),
+                                                'R' => LexResult::t(ctx, T::Rhythm(value)),
                                                 'S' => LexResult::t(ctx, T::Source(value)),
                                                 'T' => LexResult::t(ctx, T::Title(value)),
                                                 'W' => LexResult::t(ctx, T::Words(value)),
@@ -1216,7 +1563,7 @@ fn read(ctx: Context) -> LexResult {
 
                         // Non-text headers.
                         // Grouped for handling code.
-                        'K' | 'L' | 'M' | 'P' | 'Q' => {
+                        'K' | 'L' | 'M' | 'P' | 'Q' | 'V' => {
                             match ctx.first() {
                                 Some((ctx, ':')) => {
 
@@ -1250,13 +1597,10 @@ fn read(ctx: Context) -> LexResult {
                                         }
 
                                         // Tempo
-                                        'Q' => {
-                                            return LexResult::Error(
-                                                ctx,
-                                                ctx.i,
-                                                LexError::UnimplementedError(4),
-                                            )
-                                        }
+                                        'Q' => return lex_tempo(ctx, '\n'),
+
+                                        // Voice.
+                                        'V' => return lex_voice(ctx, '\n'),
 
                                         // This can only happen if the above cases get out of sync.
                                         _ => {
@@ -1297,6 +1641,18 @@ fn read(ctx: Context) -> LexResult {
 
                         '|' | ':' => lex_barline(ctx),
 
+                        '"' => lex_guitar_chord(ctx),
+
+                        '[' => lex_inline_field(ctx),
+
+                        '!' => lex_decoration(ctx),
+
+                        '~' | '.' | 'H' | 'L' | 'M' | 'P' | 'T' => {
+                            lex_decoration_shorthand(ctx, first_char)
+                        }
+
+                        '{' => lex_grace_notes(ctx),
+
                         'a' | 'b' | 'c' | 'd' | 'e' | 'f' | 'g' | 'A' | 'B' | 'C' | 'D' | 'E' |
                         'F' | 'G' | '^' | '_' | '=' => lex_note(ctx),
 
@@ -1309,6 +1665,16 @@ fn read(ctx: Context) -> LexResult {
     }
 }
 
+/// The range of input characters that a token was read from.
+/// Where several tokens are read together (e.g. a barline) they share a span.
+#[derive(Debug, PartialEq, PartialOrd, Clone, Copy)]
+pub struct Span {
+    /// Offset of the first character.
+    pub start: usize,
+    /// Offset after the last character.
+    pub end: usize,
+}
+
 /// A stateful lexer for an ABC string.
 /// Implements Iterator.
 pub struct Lexer<'a> {
@@ -1317,6 +1683,9 @@ pub struct Lexer<'a> {
     // Was the last result an error?
     // Used to attempt to skip over bad input.
     error: Option<LexError>,
+
+    // Offset where the most recent result started.
+    start: usize,
 }
 
 impl<'a> Lexer<'a> {
@@ -1326,6 +1695,7 @@ impl<'a> Lexer<'a> {
         Lexer {
             context,
             error: None,
+            start: 0,
         }
     }
 
@@ -1346,6 +1716,26 @@ impl<'a> Lexer<'a> {
             .collect::<Vec<T>>()
     }
 
+    /// Collect all tokens with their spans, ignoring errors.
+    pub fn collect_tokens_with_spans(mut self) -> Vec<(T, Span)> {
+        let mut result = vec![];
+
+        while let Some(lex_result) = self.next() {
+            if let LexResult::T(ctx, tokens) = lex_result {
+                let span = Span {
+                    start: self.start,
+                    end: ctx.i,
+                };
+
+                for token in tokens {
+                    result.push((token, span));
+                }
+            }
+        }
+
+        result
+    }
+
     pub fn collect_errors(self) -> Vec<(Context<'a>, usize, LexError)> {
         self.filter_map(|x| match x {
             LexResult::Error(ctx, offset, err) => Some((ctx, offset, err)),
@@ -1367,6 +1757,13 @@ impl<'a> Iterator for Lexer<'a> {
             Some(LexError::ExpectedNumber(NumberRole::LowerTimeSignature)) |
             Some(LexError::ExpectedNumber(NumberRole::UpperTimeSignature)) => 0,
 
+            // Likewise tempo errors resume at the next line.
+            Some(LexError::NumberTooLong(NumberRole::TempoBeat)) |
+            Some(LexError::NumberTooLong(NumberRole::TempoBpm)) |
+            Some(LexError::ExpectedNumber(NumberRole::TempoBeat)) |
+            Some(LexError::ExpectedNumber(NumberRole::TempoBpm)) |
+            Some(LexError::ExpectedEqualsInTempo) => 0,
+
             // If there was an error that we haven't deliberately discounted,
             // increment by one to try and recover.
             Some(_) => 1,
@@ -1377,6 +1774,7 @@ impl<'a> Iterator for Lexer<'a> {
 
         self.context = self.context.clone().skip(skip_amount);
         self.error = None;
+        self.start = self.context.i;
 
         // Take a temporary clone of self.context so it can be consumed.
         // TODO could read() work with a ref?
@@ -1724,6 +2122,56 @@ B2BB2AG2A|B3 BAB dBA|~B3 B2AG2A|B2dg2e dBA:|";
         );
     }
 
+    #[test]
+    fn collect_tokens_with_spans_test() {
+        let input = &(string_to_vec("T:Title\nK:G\nAB|".to_string()));
+
+        assert_eq!(
+            Lexer::new(input).collect_tokens_with_spans(),
+            vec![
+                (T::Title("Title".to_string()), Span { start: 0, end: 8 }),
+                (
+                    T::KeySignature(
+                        music::PitchClass {
+                            diatonic_pitch_class: music::DiatonicPitchClass::G,
+                            accidental: None,
+                        },
+                        music::Mode::Major,
+                    ),
+                    Span { start: 8, end: 12 },
+                ),
+                (
+                    T::Note(music::Note(
+                        music::Pitch {
+                            pitch_class: music::PitchClass {
+                                diatonic_pitch_class: music::DiatonicPitchClass::A,
+                                accidental: None,
+                            },
+                            octave: 0,
+                        },
+                        music::FractionalDuration(1, 1),
+                    )),
+                    Span { start: 12, end: 13 },
+                ),
+                (
+                    T::Note(music::Note(
+                        music::Pitch {
+                            pitch_class: music::PitchClass {
+                                diatonic_pitch_class: music::DiatonicPitchClass::B,
+                                accidental: None,
+                            },
+                            octave: 0,
+                        },
+                        music::FractionalDuration(1, 1),
+                    )),
+                    Span { start: 13, end: 14 },
+                ),
+                (T::BeamBreak, Span { start: 14, end: 15 }),
+                (T::SingleBar, Span { start: 14, end: 15 }),
+            ]
+        );
+    }
+
     // Test for every header to make sure everything hangs together.
     #[test]
     fn read_headers_test() {
@@ -1739,6 +2187,7 @@ H:HISTORY
 I:INFO
 N:NOTES
 O:ORIGIN
+R:RHYTHM
 S:SOURCE
 T:TITLE
 W:WORDS
@@ -1772,6 +2221,7 @@ K:    GFmaj
                 T::Information("INFO".to_string()),
                 T::Notes("NOTES".to_string()),
                 T::Origin("ORIGIN".to_string()),
+                T::Rhythm("RHYTHM".to_string()),
                 T::Source("SOURCE".to_string()),
                 T::Title("TITLE".to_string()),
                 T::Words("WORDS".to_string()),
@@ -1801,6 +2251,187 @@ K:    GFmaj
         );
     }
 
+    #[test]
+    fn read_clef_test() {
+        let lex = |abc: &str| Lexer::new(&string_to_vec(abc.to_string())).collect_tokens();
+
+        let g_major = T::KeySignature(
+            music::PitchClass {
+                diatonic_pitch_class: music::DiatonicPitchClass::G,
+                accidental: None,
+            },
+            music::Mode::Major,
+        );
+
+        assert_eq!(
+            lex("K:G clef=bass\n"),
+            vec![g_major.clone(), T::Clef(music::Clef::bass())]
+        );
+        assert_eq!(
+            lex("K:G treble-8\n"),
+            vec![g_major.clone(), T::Clef(music::Clef::treble_8vb())]
+        );
+        assert_eq!(lex("K:G clef=soprano\n"), vec![g_major.clone()], "Unknown clef ignored.");
+        assert_eq!(lex("K:clef=alto\n"), vec![T::Clef(music::Clef::alto())]);
+
+        let c_major = T::KeySignature(
+            music::PitchClass {
+                diatonic_pitch_class: music::DiatonicPitchClass::C,
+                accidental: None,
+            },
+            music::Mode::Major,
+        );
+        assert_eq!(lex("K:C middle=d\n"), vec![c_major], "Middle isn't minor.");
+        assert_eq!(lex("K:G m=e\n"), vec![g_major.clone()], "Nor is an 'm' before '='.");
+
+        assert_eq!(
+            lex("V:1 name=\"Cello\" clef=Tenor\nK:G\n"),
+            vec![T::Clef(music::Clef::tenor()), g_major.clone()]
+        );
+        assert_eq!(lex("V:T1\nK:G\n"), vec![g_major.clone()], "Voice without a clef.");
+    }
+
+    #[test]
+    fn inline_field_test() {
+        let lex = |abc: &str| {
+            let input = string_to_vec(abc.to_string());
+            let errors = Lexer::new(&input).in_body().collect_errors();
+            assert_eq!(errors.len(), 0, "Expected no errors but got: {:?}", errors);
+            Lexer::new(&input).in_body().collect_tokens()
+        };
+
+        assert_eq!(
+            lex("|[K:clef=bass]"),
+            vec![T::BeamBreak, T::SingleBar, T::Clef(music::Clef::bass())]
+        );
+        assert_eq!(
+            lex("[L:1/4][M:3/4]"),
+            vec![
+                T::DefaultNoteLength(music::FractionalDuration(1, 4)),
+                T::Metre(music::Metre(3, 4)),
+            ]
+        );
+
+        // An n-time bar still takes its bracket.
+        assert_eq!(lex("|[2"), vec![T::BeamBreak, T::SingleBar, T::NTimeBar(2)]);
+    }
+
+    #[test]
+    fn decoration_and_grace_notes_test() {
+        let lex = |abc: &str| {
+            let input = string_to_vec(abc.to_string());
+            let errors = Lexer::new(&input).in_body().collect_errors();
+            assert_eq!(errors.len(), 0, "Expected no errors but got: {:?}", errors);
+            Lexer::new(&input).in_body().collect_tokens()
+        };
+
+        let note = |diatonic_pitch_class, octave| {
+            music::Note(
+                music::Pitch {
+                    pitch_class: music::PitchClass {
+                        diatonic_pitch_class,
+                        accidental: None,
+                    },
+                    octave,
+                },
+                music::FractionalDuration(1, 1),
+            )
+        };
+
+        assert_eq!(
+            lex("~A!trill!TB"),
+            vec![
+                T::Decoration(music::Decoration::Roll),
+                T::Note(note(music::DiatonicPitchClass::A, 0)),
+                T::Decoration(music::Decoration::Trill),
+                T::Decoration(music::Decoration::Trill),
+                T::Note(note(music::DiatonicPitchClass::B, 0)),
+            ]
+        );
+
+        // Unknown decorations are ignored.
+        assert_eq!(lex("!wedge!A"), vec![T::Note(note(music::DiatonicPitchClass::A, 0))]);
+
+        assert_eq!(
+            lex("{/ga}A"),
+            vec![
+                T::GraceNotes(music::GraceNotes {
+                    acciaccatura: true,
+                    notes: vec![
+                        note(music::DiatonicPitchClass::G, 1),
+                        note(music::DiatonicPitchClass::A, 1),
+                    ],
+                }),
+                T::Note(note(music::DiatonicPitchClass::A, 0)),
+            ]
+        );
+
+        let input = string_to_vec("{g".to_string());
+        assert_eq!(Lexer::new(&input).in_body().collect_errors().len(), 1);
+
+        // A letter that's a decoration can't start a field in the body.
+        let input = string_to_vec("M:3/4".to_string());
+        assert!(Lexer::new(&input).in_body().collect_errors().len() > 0);
+    }
+
+    #[test]
+    fn comment_test() {
+        let input = string_to_vec(
+            "X:1\n% A comment\n%%MIDI program 73\nK:G\nAB % Trailing\n%%MIDI transpose -2\nc\n"
+                .to_string(),
+        );
+
+        assert_eq!(Lexer::new(&input).collect_errors().len(), 0);
+        assert_eq!(
+            Lexer::new(&input).collect_tokens(),
+            vec![
+                T::X("1".to_string()),
+                T::Directive("MIDI program 73".to_string()),
+                T::KeySignature(
+                    music::PitchClass {
+                        diatonic_pitch_class: music::DiatonicPitchClass::G,
+                        accidental: None,
+                    },
+                    music::Mode::Major,
+                ),
+                T::Note(music::Note(
+                    music::Pitch {
+                        pitch_class: music::PitchClass {
+                            diatonic_pitch_class: music::DiatonicPitchClass::A,
+                            accidental: None,
+                        },
+                        octave: 0,
+                    },
+                    music::FractionalDuration(1, 1),
+                )),
+                T::Note(music::Note(
+                    music::Pitch {
+                        pitch_class: music::PitchClass {
+                            diatonic_pitch_class: music::DiatonicPitchClass::B,
+                            accidental: None,
+                        },
+                        octave: 0,
+                    },
+                    music::FractionalDuration(1, 1),
+                )),
+                T::BeamBreak,
+                T::Newline,
+                T::Directive("MIDI transpose -2".to_string()),
+                T::Note(music::Note(
+                    music::Pitch {
+                        pitch_class: music::PitchClass {
+                            diatonic_pitch_class: music::DiatonicPitchClass::C,
+                            accidental: None,
+                        },
+                        octave: 1,
+                    },
+                    music::FractionalDuration(1, 1),
+                )),
+                T::Newline,
+            ]
+        );
+    }
+
     /// Errors for reading headers.
     #[test]
     fn header_errs() {
@@ -1896,8 +2527,49 @@ K:    GFmaj
                 .in_body()
                 .collect_tokens(),
             vec![T::Newline]
-        )
+        );
+
+        assert_eq!(
+            Lexer::new(&(string_to_vec("\"D/F#\"A \"G\"B".to_string())))
+                .in_body()
+                .collect_tokens(),
+            vec![
+                T::GuitarChord("D/F#".to_string()),
+                T::Note(music::Note(
+                    music::Pitch {
+                        pitch_class: music::PitchClass {
+                            diatonic_pitch_class: music::DiatonicPitchClass::A,
+                            accidental: None,
+                        },
+                        octave: 0,
+                    },
+                    music::FractionalDuration(1, 1),
+                )),
+                T::BeamBreak,
+                T::GuitarChord("G".to_string()),
+                T::Note(music::Note(
+                    music::Pitch {
+                        pitch_class: music::PitchClass {
+                            diatonic_pitch_class: music::DiatonicPitchClass::B,
+                            accidental: None,
+                        },
+                        octave: 0,
+                    },
+                    music::FractionalDuration(1, 1),
+                )),
+            ],
+            "Guitar chords should be read in the body."
+        );
 
+        // Unclosed quote is skipped, and the rest of the line can still be read.
+        assert_eq!(
+            Lexer::new(&(string_to_vec("\"Am A\n".to_string())))
+                .in_body()
+                .collect_tokens(),
+            Lexer::new(&(string_to_vec("Am A\n".to_string())))
+                .in_body()
+                .collect_tokens()
+        );
     }
 
     #[test]
@@ -2184,6 +2856,115 @@ K:    GFmaj
         }
     }
 
+    #[test]
+    fn lex_tempo_test() {
+        match lex_tempo(Context::new(&(string_to_vec(String::from("1/4=120\n")))), '\n') {
+            LexResult::T(_, tokens) => {
+                assert_eq!(
+                    tokens,
+                    &[
+                        T::Tempo(music::Tempo {
+                            beat: Some(music::FractionalDuration(1, 4)),
+                            bpm: Some(120),
+                            label: None,
+                        }),
+                    ],
+                    "Beat and BPM should be parsed"
+                )
+            }
+            _ => assert!(false),
+        }
+
+        match lex_tempo(Context::new(&(string_to_vec(String::from("120\n")))), '\n') {
+            LexResult::T(_, tokens) => {
+                assert_eq!(
+                    tokens,
+                    &[
+                        T::Tempo(music::Tempo {
+                            beat: None,
+                            bpm: Some(120),
+                            label: None,
+                        }),
+                    ],
+                    "Old-style BPM without a beat should be parsed"
+                )
+            }
+            _ => assert!(false),
+        }
+
+        match lex_tempo(
+            Context::new(&(string_to_vec(String::from("\"Allegro\" 3/8=60\n")))),
+            '\n',
+        ) {
+            LexResult::T(_, tokens) => {
+                assert_eq!(
+                    tokens,
+                    &[
+                        T::Tempo(music::Tempo {
+                            beat: Some(music::FractionalDuration(3, 8)),
+                            bpm: Some(60),
+                            label: Some("Allegro".to_string()),
+                        }),
+                    ],
+                    "Label should be parsed"
+                )
+            }
+            _ => assert!(false),
+        }
+
+        match lex_tempo(
+            Context::new(&(string_to_vec(String::from("\"Slow\"\n")))),
+            '\n',
+        ) {
+            LexResult::T(_, tokens) => {
+                assert_eq!(
+                    tokens,
+                    &[
+                        T::Tempo(music::Tempo {
+                            beat: None,
+                            bpm: None,
+                            label: Some("Slow".to_string()),
+                        }),
+                    ],
+                    "Label on its own should be parsed"
+                )
+            }
+            _ => assert!(false),
+        }
+
+        //
+        // Errors
+        //
+
+        match lex_tempo(Context::new(&(string_to_vec(String::from("1/4\n")))), '\n') {
+            LexResult::Error(_, _, LexError::ExpectedEqualsInTempo) => {
+                assert!(true, "Beat without BPM should fail")
+            }
+            _ => assert!(false),
+        }
+
+        match lex_tempo(Context::new(&(string_to_vec(String::from("1/4=\n")))), '\n') {
+            LexResult::Error(_, _, LexError::ExpectedNumber(NumberRole::TempoBpm)) => {
+                assert!(true, "Missing BPM should fail")
+            }
+            _ => assert!(false),
+        }
+
+        match lex_tempo(Context::new(&(string_to_vec(String::from("1/4=120")))), '\n') {
+            LexResult::Error(_, _, LexError::PrematureEnd(During::Tempo)) => {
+                assert!(true, "Unterminated tempo should fail")
+            }
+            _ => assert!(false),
+        }
+
+        // The lexer should recover at the next line after a bad tempo.
+        let input = &(string_to_vec("Q:1/4\nT:Title\n".to_string()));
+        assert_eq!(
+            Lexer::new(input).collect_tokens(),
+            vec![T::Title("Title".to_string())]
+        );
+    }
+
     #[test]
     fn read_until_no_delimiter() {
         let input = &(string_to_vec(String::from("This and that")));
@@ -2358,6 +3139,29 @@ K:    GFmaj
             x => assert!(false, "Expected mode got: {:?}", x),
         }
 
+        let input = &(string_to_vec("m".to_string()));
+        let ctx = Context::new(input);
+        match read_mode(ctx) {
+            Some((new_ctx, music::Mode::Minor)) => {
+                assert_eq!(new_ctx, ctx.skip(1), "Single 'm' is minor")
+            }
+            x => assert!(false, "Expected mode got: {:?}", x),
+        }
+
+        let input = &(string_to_vec("m]".to_string()));
+        let ctx = Context::new(input);
+        match read_mode(ctx) {
+            Some((new_ctx, music::Mode::Minor)) => {
+                assert_eq!(new_ctx, ctx.skip(1), "Single 'm' ending an inline field is minor")
+            }
+            x => assert!(false, "Expected mode got: {:?}", x),
+        }
+
+        for input in ["middle=d", "m=e", "mf"].iter() {
+            let input = &(string_to_vec(input.to_string()));
+            assert_eq!(read_mode(Context::new(input)), None, "'m' starting a word isn't minor");
+        }
+
     }
 
     #[test]
@@ -0,0 +1,389 @@
+//! ABC Writer
+//! Serialize a tune AST back into ABC text.
+//! Output is normalised, so it won't match the original input character for character, but it
+//! reads back as the same tokens.
+
+use std::fmt::Write;
+
+use abc_lexer as l;
+use music;
+use tune_ast_three;
+
+/// Format a pitch class as used in a key signature, e.g. "F#".
+pub fn pitch_class_to_string(pitch_class: music::PitchClass) -> String {
+    let mut buf = format!("{:?}", pitch_class.diatonic_pitch_class);
+
+    buf.push_str(match pitch_class.accidental {
+        Some(music::Accidental::Sharp) => "#",
+        Some(music::Accidental::Flat) => "b",
+        Some(music::Accidental::DoubleSharp) => "##",
+        Some(music::Accidental::DoubleFlat) => "bb",
+        Some(music::Accidental::Natural) | None => "",
+    });
+
+    buf
+}
+
+/// Format a key signature's value, e.g. "EDor".
+pub fn key_signature_to_string(tonic: music::PitchClass, mode: music::Mode) -> String {
+    let mut buf = pitch_class_to_string(tonic);
+
+    buf.push_str(match mode {
+        music::Mode::Natural | music::Mode::Major => "",
+        music::Mode::Minor => "m",
+        music::Mode::Ionian => "Ion",
+        music::Mode::Lydian => "Lyd",
+        music::Mode::Mixolydian => "Mix",
+        music::Mode::Dorian => "Dor",
+        music::Mode::Aeolian => "Aeo",
+        music::Mode::Phrygian => "Phr",
+        music::Mode::Locrian => "Loc",
+    });
+
+    buf
+}
+
+/// Format a note duration as a multiple of the default note length, e.g. "3/2".
+/// The default length itself is written as nothing.
+fn write_duration(duration: music::FractionalDuration, buf: &mut String) {
+    match duration {
+        music::FractionalDuration(1, 1) => (),
+        music::FractionalDuration(1, 2) => buf.push('/'),
+        music::FractionalDuration(n, 1) => write!(buf, "{}", n).unwrap(),
+        music::FractionalDuration(1, d) => write!(buf, "/{}", d).unwrap(),
+        music::FractionalDuration(n, d) => write!(buf, "{}/{}", n, d).unwrap(),
+    }
+}
+
+/// Format a note, given the default note length its duration is relative to.
+pub fn write_note(
+    note: &music::Note,
+    default_length: music::FractionalDuration,
+    buf: &mut String,
+) {
+    let &music::Note(pitch, duration) = note;
+
+    buf.push_str(match pitch.pitch_class.accidental {
+        Some(music::Accidental::Sharp) => "^",
+        Some(music::Accidental::Flat) => "_",
+        Some(music::Accidental::Natural) => "=",
+        Some(music::Accidental::DoubleSharp) => "^^",
+        Some(music::Accidental::DoubleFlat) => "__",
+        None => "",
+    });
+
+    let letter = format!("{:?}", pitch.pitch_class.diatonic_pitch_class);
+
+    // Lower case is the octave above middle C.
+    if pitch.octave >= 1 {
+        buf.push_str(&letter.to_lowercase());
+        for _ in 1..pitch.octave {
+            buf.push('\'');
+        }
+    } else {
+        buf.push_str(&letter);
+        for _ in pitch.octave..0 {
+            buf.push(',');
+        }
+    }
+
+    write_duration(duration.divide(default_length), buf);
+}
+
+/// Format the value of a header field, without the field name.
+/// None if this token isn't a header field.
+fn header_field(token: &l::T) -> Option<(char, String)> {
+    match token {
+        &l::T::Area(ref value) => Some(('A', value.clone())),
+        &l::T::Book(ref value) => Some(('B', value.clone())),
+        &l::T::Composer(ref value) => Some(('C', value.clone())),
+        &l::T::Discography(ref value) => Some(('D', value.clone())),
+        &l::T::Filename(ref value) => Some(('F', value.clone())),
+        &l::T::Group(ref value) => Some(('G', value.clone())),
+        &l::T::History(ref value) => Some(('H', value.clone())),
+        &l::T::Information(ref value) => Some(('I', value.clone())),
+        &l::T::Notes(ref value) => Some(('N', value.clone())),
+        &l::T::Origin(ref value) => Some(('O', value.clone())),
+        &l::T::Rhythm(ref value) => Some(('R', value.clone())),
+        &l::T::Source(ref value) => Some(('S', value.clone())),
+        &l::T::Title(ref value) => Some(('T', value.clone())),
+        &l::T::Words(ref value) => Some(('W', value.clone())),
+        &l::T::X(ref value) => Some(('X', value.clone())),
+        &l::T::Transcription(ref value) => Some(('Z', value.clone())),
+
+        &l::T::Metre(music::Metre(numerator, denomenator)) => {
+            Some(('M', format!("{}/{}", numerator, denomenator)))
+        }
+        &l::T::KeySignature(tonic, mode) => Some(('K', key_signature_to_string(tonic, mode))),
+        &l::T::Clef(clef) => Some(('K', format!("clef={}", clef.name()))),
+        &l::T::DefaultNoteLength(music::FractionalDuration(numerator, denomenator)) => {
+            Some(('L', format!("{}/{}", numerator, denomenator)))
+        }
+        &l::T::Tempo(ref tempo) => {
+            let mut value = String::new();
+
+            if let Some(ref label) = tempo.label {
+                write!(&mut value, "\"{}\"", label).unwrap();
+            }
+
+            match (tempo.beat, tempo.bpm) {
+                (Some(music::FractionalDuration(numerator, denomenator)), Some(bpm)) => {
+                    if !value.is_empty() {
+                        value.push(' ');
+                    }
+                    write!(&mut value, "{}/{}={}", numerator, denomenator, bpm).unwrap();
+                }
+                (None, Some(bpm)) => {
+                    if !value.is_empty() {
+                        value.push(' ');
+                    }
+                    write!(&mut value, "{}", bpm).unwrap();
+                }
+                _ => (),
+            }
+
+            Some(('Q', value))
+        }
+
+        _ => None,
+    }
+}
+
+fn is_barline(token: &l::T) -> bool {
+    match token {
+        &l::T::SingleBar |
+        &l::T::DoubleBar |
+        &l::T::OpenRepeat |
+        &l::T::CloseRepeat |
+        &l::T::EndBar => true,
+        _ => false,
+    }
+}
+
+/// Format a sequence of tune body tokens.
+/// Header fields within the body are written inline, e.g. "[K:G]".
+pub fn write_body(
+    tokens: &[l::T],
+    default_length: music::FractionalDuration,
+    buf: &mut String,
+) {
+    let mut default_length = default_length;
+
+    for (i, token) in tokens.iter().enumerate() {
+        // A clef following a key signature was read from the same field, and is written with it.
+        if let (&l::T::Clef(_), Some(&l::T::KeySignature(_, _))) =
+            (token, i.checked_sub(1).and_then(|i| tokens.get(i)))
+        {
+            continue;
+        }
+
+        if let Some((field, mut value)) = header_field(token) {
+            if let (&l::T::KeySignature(_, _), Some(&l::T::Clef(clef))) = (token, tokens.get(i + 1)) {
+                write!(&mut value, " clef={}", clef.name()).unwrap();
+            }
+
+            write!(buf, "[{}:{}]", field, value).unwrap();
+
+            if let &l::T::DefaultNoteLength(length) = token {
+                default_length = length;
+            }
+
+            continue;
+        }
+
+        match token {
+            // The lexer produces a beam break before every barline, which needn't be written.
+            &l::T::BeamBreak => {
+                if !tokens.get(i + 1).map(is_barline).unwrap_or(false) {
+                    buf.push(' ');
+                }
+            }
+            &l::T::Newline => buf.push('\n'),
+
+            &l::T::SingleBar => buf.push('|'),
+            &l::T::DoubleBar => buf.push_str("||"),
+            &l::T::OpenRepeat => buf.push_str("|:"),
+            &l::T::CloseRepeat => buf.push_str(":|"),
+            &l::T::EndBar => buf.push_str("|]"),
+            &l::T::NTimeBar(n) => write!(buf, "{}", n).unwrap(),
+
+            &l::T::GuitarChord(ref chord) => write!(buf, "\"{}\"", chord).unwrap(),
+
+            // Letter abbreviations can be redefined, so only the symbols are abbreviated.
+            &l::T::Decoration(decoration) => match decoration.shorthand() {
+                shorthand @ '~' | shorthand @ '.' => buf.push(shorthand),
+                _ => write!(buf, "!{}!", decoration.name()).unwrap(),
+            },
+
+            &l::T::GraceNotes(ref grace_notes) => {
+                buf.push('{');
+                if grace_notes.acciaccatura {
+                    buf.push('/');
+                }
+                for note in grace_notes.notes.iter() {
+                    write_note(note, default_length, buf);
+                }
+                buf.push('}');
+            }
+
+            &l::T::Note(ref note) => write_note(note, default_length, buf),
+
+            &l::T::Directive(ref directive) => writeln!(buf, "%%{}", directive).unwrap(),
+
+            // Header fields are handled above.
+            _ => (),
+        }
+    }
+}
+
+/// Serialize a tune as ABC.
+pub fn write_tune(tune: &tune_ast_three::Tune) -> String {
+    let mut buf = String::new();
+
+    // Durations in the AST are resolved, so they must be written relative to "L:".
+    let mut default_length = tune_ast_three::DEFAULT_NOTE_LENGTH;
+
+    // A clef in the header is written as part of the key, as "K:" must be the last field.
+    let clef = tune.prelude.iter().rev().filter_map(|token| match token {
+        &l::T::Clef(clef) => Some(clef),
+        _ => None,
+    }).next();
+
+    for token in tune.prelude.iter() {
+        match (token, clef) {
+            (&l::T::Clef(_), _) => continue,
+            (&l::T::KeySignature(tonic, mode), Some(clef)) => {
+                writeln!(
+                    &mut buf,
+                    "K:{} clef={}",
+                    key_signature_to_string(tonic, mode),
+                    clef.name()
+                ).unwrap();
+                continue;
+            }
+            _ => (),
+        }
+
+        if let Some((field, value)) = header_field(token) {
+            writeln!(&mut buf, "{}:{}", field, value).unwrap();
+        } else if let &l::T::Directive(ref directive) = token {
+            writeln!(&mut buf, "%%{}", directive).unwrap();
+        }
+
+        if let &l::T::DefaultNoteLength(length) = token {
+            default_length = length;
+        }
+    }
+
+    for voice in tune.voices.iter() {
+        write_body(voice, default_length, &mut buf);
+    }
+
+    buf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_support::read;
+
+    #[test]
+    fn write_note_test() {
+        let note = |diatonic_pitch_class, accidental, octave, duration| {
+            let mut buf = String::new();
+            write_note(
+                &music::Note(
+                    music::Pitch {
+                        pitch_class: music::PitchClass {
+                            diatonic_pitch_class,
+                            accidental,
+                        },
+                        octave,
+                    },
+                    duration,
+                ),
+                music::FractionalDuration(1, 8),
+                &mut buf,
+            );
+            buf
+        };
+
+        assert_eq!(
+            note(
+                music::DiatonicPitchClass::C,
+                None,
+                0,
+                music::FractionalDuration(1, 8),
+            ),
+            "C"
+        );
+        assert_eq!(
+            note(
+                music::DiatonicPitchClass::F,
+                Some(music::Accidental::Sharp),
+                1,
+                music::FractionalDuration(3, 8),
+            ),
+            "^f3"
+        );
+        assert_eq!(
+            note(
+                music::DiatonicPitchClass::B,
+                Some(music::Accidental::Flat),
+                -1,
+                music::FractionalDuration(1, 16),
+            ),
+            "_B,/"
+        );
+        assert_eq!(
+            note(
+                music::DiatonicPitchClass::E,
+                Some(music::Accidental::Natural),
+                3,
+                music::FractionalDuration(3, 32),
+            ),
+            "=e''3/4"
+        );
+    }
+
+    #[test]
+    fn round_trip_test() {
+        let abc = "X:1
+T:Kesh, The
+R:jig
+M:6/8
+L:1/8
+Q:\"Lively\" 3/8=120
+K:GMix
+|:\"G\"GAG GAB|\"D\"ABA ABd|e2d ^c2B|1A3 A2B:|2A3 A3||
+";
+        let tune = read(abc);
+        let written = write_tune(&tune);
+
+        assert_eq!(written, abc, "Simple normalised ABC should be written unchanged.");
+
+        let reread = read(&written);
+        assert_eq!(reread.prelude, tune.prelude);
+        assert_eq!(reread.voices, tune.voices);
+    }
+
+    #[test]
+    fn clef_round_trip_test() {
+        let abc = "X:1\nL:1/4\nK:G clef=bass\nG,A,B,C|\n";
+        let tune = read(abc);
+        assert_eq!(write_tune(&tune), abc);
+
+        // A clef from a voice field moves to the key.
+        let tune = read("X:1\nV:1 clef=alto\nK:D\nDEF|\n");
+        let written = write_tune(&tune);
+        assert_eq!(written, "X:1\nK:D clef=alto\nDEF|\n");
+        assert!(read(&written).prelude.contains(&l::T::Clef(music::Clef::alto())));
+    }
+
+    #[test]
+    fn decoration_round_trip_test() {
+        let abc = "X:1\n%%MIDI program 73\nL:1/8\nK:D\n~d2 {/g}A .F!trill!E|\n%%MIDI gchordon\n{ag}f4|\n";
+        let tune = read(abc);
+        assert_eq!(write_tune(&tune), abc);
+    }
+}
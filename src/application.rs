@@ -1,10 +1,18 @@
 //! FolkTuneFinder ABC Tools Application centre of gravity.
 
 use storage;
+use cluster;
+use fingerprint;
+use relations;
 use std::str;
 use tune_ast_three;
 use typeset;
+use ngram;
+use playback;
+use search;
+use synth;
 use abc_lexer;
+use text;
 use std::collections::HashMap;
 
 
@@ -115,4 +123,168 @@ impl Application {
             None
         }
     }
+
+    /// Render this tune as a WAV file, with expressive playback.
+    /// Only retrieves something if it's been loaded.
+    pub fn get_wav(&self, tune_id: u32) -> Option<Vec<u8>> {
+        if let Some(ref tune_store) = self.tune_store {
+            if let Some(abc_result) = tune_store.tune_cache.get_tune_string(&tune_id) {
+                let chars = abc_result.chars().collect::<Vec<char>>();
+                let ast = tune_ast_three::read_from_lexer(abc_lexer::Lexer::new(&chars));
+                Some(synth::tune_to_wav(&ast, &playback::Options::expressive()))
+            } else {
+                None
+            }
+        } else {
+            None
+        }
+    }
+
+    /// Search for tunes containing a melody, best first, using the index for the encoding.
+    /// An ABC fragment is read in the key, if given, otherwise in C.
+    /// Given a tune type's name, e.g. "reel", only finds tunes of that type.
+    /// Only searches something if it's been loaded.
+    pub fn search(
+        &self,
+        query: &str,
+        key: Option<&str>,
+        tune_type: Option<&str>,
+        encoding: ngram::Encoding,
+        limit: usize,
+    ) -> Result<Vec<search::SearchResult>, String> {
+        if let Some(ref tune_store) = self.tune_store {
+            let index = tune_store.ngram_index(encoding);
+
+            search::search(index, query, key, tune_type, limit, |tune_id| {
+                tune_store.tune_cache.get_tune_string(&tune_id).map(|abc| {
+                    let chars = abc.chars().collect::<Vec<char>>();
+                    tune_ast_three::read_from_lexer(abc_lexer::Lexer::new(&chars))
+                })
+            })
+        } else {
+            Err("Tunes aren't loaded.".to_string())
+        }
+    }
+
+    /// Find the tunes that are variants of each other, using the interval index.
+    /// The links found replace the automatic relations, and the tunes are regrouped.
+    /// Only clusters something if it's been loaded.
+    pub fn cluster(&mut self, options: &cluster::Options) -> Result<Vec<cluster::Link>, String> {
+        if let Some(ref mut tune_store) = self.tune_store {
+            let links = {
+                let index = tune_store.ngram_index(ngram::Encoding::Interval);
+
+                let mut tune_ids = tune_store.tune_cache.index.keys().cloned().collect::<Vec<u32>>();
+                tune_ids.sort();
+
+                cluster::cluster(index, &tune_ids, options, &tune_store.relations, |tune_id| {
+                    tune_store.tune_cache.get_tune_string(&tune_id).map(|abc| {
+                        let chars = abc.chars().collect::<Vec<char>>();
+                        tune_ast_three::read_from_lexer(abc_lexer::Lexer::new(&chars))
+                    })
+                })
+            };
+
+            tune_store.relations.replace_automatic(
+                cluster::PROVENANCE,
+                links.iter().flat_map(|link| link.relations()).collect(),
+            );
+            tune_store.update_relations();
+
+            Ok(links)
+        } else {
+            Err("Tunes aren't loaded.".to_string())
+        }
+    }
+
+    /// Find sets of tunes with the same music, in order of their first tune id, with whether each
+    /// tune's ABC is identical to the first's. These replace the duplicate relations found before,
+    /// and the tunes are regrouped.
+    /// Only finds something if it's been loaded.
+    pub fn find_duplicates(&mut self) -> Result<Vec<Vec<(u32, bool)>>, String> {
+        if let Some(ref mut tune_store) = self.tune_store {
+            let sets = {
+                let mut tunes = tune_store
+                    .tune_cache
+                    .index
+                    .keys()
+                    .filter_map(|tune_id| {
+                        tune_store.tune_cache.get_tune(tune_id).map(|data| (*tune_id, data))
+                    })
+                    .collect::<Vec<(u32, &[u8])>>();
+                tunes.sort_by_key(|&(tune_id, _)| tune_id);
+
+                fingerprint::duplicate_sets(&tunes)
+            };
+
+            tune_store.relations.replace_automatic(
+                fingerprint::PROVENANCE,
+                fingerprint::relations(&sets),
+            );
+            tune_store.update_relations();
+
+            Ok(sets)
+        } else {
+            Err("Tunes aren't loaded.".to_string())
+        }
+    }
+
+    /// Record an editor's link or never-link between two tunes, and regroup the tunes.
+    /// Only records something if it's been loaded.
+    pub fn assert_relation(
+        &mut self,
+        a: u32,
+        b: u32,
+        kind: relations::Kind,
+        provenance: &str,
+    ) -> Result<(), String> {
+        if let Some(ref mut tune_store) = self.tune_store {
+            if a == b {
+                return Err("A tune is always in its own group.".to_string());
+            }
+
+            for tune_id in [a, b].iter() {
+                if tune_store.tune_cache.get_tune(tune_id).is_none() {
+                    return Err(format!("Didn't recognise tune id {}.", tune_id));
+                }
+            }
+
+            tune_store.relations.assert(a, b, kind, provenance);
+            tune_store.update_relations();
+
+            Ok(())
+        } else {
+            Err("Tunes aren't loaded.".to_string())
+        }
+    }
+
+    /// The group this tune is in, with the relations that explain it.
+    /// Only retrieves something if it's been loaded.
+    pub fn get_group(&self, tune_id: u32) -> Option<relations::Group> {
+        if let Some(ref tune_store) = self.tune_store {
+            if tune_store.tune_cache.get_tune(&tune_id).is_some() {
+                Some(tune_store.relations.group(&tune_store.groups, tune_id))
+            } else {
+                None
+            }
+        } else {
+            None
+        }
+    }
+
+    /// Extract this tune's metadata from its header.
+    /// Only retrieves something if it's been loaded.
+    pub fn get_metadata(&self, tune_id: u32) -> Option<text::TuneMetadata> {
+        if let Some(ref tune_store) = self.tune_store {
+            if let Some(abc_result) = tune_store.tune_cache.get_tune_string(&tune_id) {
+                let chars = abc_result.chars().collect::<Vec<char>>();
+                let ast = tune_ast_three::read_from_lexer(abc_lexer::Lexer::new(&chars));
+                Some(text::TuneMetadata::from_tune(&ast))
+            } else {
+                None
+            }
+        } else {
+            None
+        }
+    }
 }
@@ -2,5 +2,349 @@
 //! Cluster tunes into groups that are similar, so that we can group very similar tunes
 //! (e.g. different transcriptions of the same tune) as one.
 //! Uses both geometric and ngram tune representations.
+//! Candidate pairs are tunes that share many ngrams, found with the ngram index. Each candidate is
+//! verified by comparing the tunes' geometry, and those that are close enough are linked.
+//! Links are recorded as relations, which explain why tunes are grouped.
 
-// Placeholder.
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+use geometry;
+use index;
+use ngram;
+use relations;
+use text;
+use tune_ast_three;
+
+/// Provenance of the relations found.
+pub const PROVENANCE: &str = "cluster";
+
+/// Name of the groups file in the tune store.
+pub const GROUPS_FILENAME: &str = "groups";
+
+pub struct Options {
+    /// Proportion of the shorter tune's distinct ngrams that must be shared for a candidate.
+    pub min_overlap: f32,
+
+    /// Ngrams in more tunes than this, such as scales, say little and don't suggest candidates.
+    pub max_ngram_tunes: usize,
+
+    /// Greatest distance between the curves of linked tunes, in semitones.
+    pub max_distance: f32,
+}
+
+impl Options {
+    pub fn new() -> Options {
+        Options {
+            min_overlap: 0.5,
+            max_ngram_tunes: 500,
+            max_distance: 1.0,
+        }
+    }
+}
+
+/// Two tunes found to be variants of each other.
+#[derive(Debug, PartialEq, PartialOrd, Clone)]
+pub struct Link {
+    /// The lower tune id.
+    pub a: u32,
+    pub b: u32,
+
+    /// Proportion of the shorter tune's distinct ngrams found in the other.
+    pub overlap: f32,
+
+    /// Distance between their curves.
+    pub distance: f32,
+
+    pub same_title: bool,
+}
+
+impl Link {
+    /// The relations that explain the link.
+    pub fn relations(&self) -> Vec<relations::Relation> {
+        let mut result = vec![
+            relations::Relation::new(
+                self.a,
+                self.b,
+                relations::Kind::NgramOverlap,
+                self.overlap,
+                PROVENANCE
+            ),
+            relations::Relation::new(
+                self.a,
+                self.b,
+                relations::Kind::GeometricDistance,
+                self.distance,
+                PROVENANCE
+            ),
+        ];
+
+        if self.same_title {
+            result.push(relations::Relation::new(
+                self.a,
+                self.b,
+                relations::Kind::SameTitle,
+                1.0,
+                PROVENANCE,
+            ));
+        }
+
+        result
+    }
+}
+
+/// What's compared of each tune.
+struct Features {
+    /// Distinct ngrams, sorted.
+    ngrams: Vec<ngram::NgramId>,
+
+    curve: geometry::Curve,
+
+    /// Main title, lower case, without punctuation.
+    title: Option<String>,
+}
+
+impl Features {
+    fn new(tune: &tune_ast_three::Tune, options: &ngram::Options) -> Features {
+        let mut ngrams = ngram::sequence(tune, options);
+        ngrams.sort();
+        ngrams.dedup();
+
+        let title = text::TuneMetadata::from_tune(tune).title().map(|title| {
+            title
+                .chars()
+                .filter(|c| c.is_alphanumeric() || c.is_whitespace())
+                .collect::<String>()
+                .to_lowercase()
+                .split_whitespace()
+                .collect::<Vec<&str>>()
+                .join(" ")
+        });
+
+        Features {
+            ngrams,
+            curve: geometry::Curve::from_tune(tune),
+            title,
+        }
+    }
+}
+
+/// Distance between two curves. The closer of the area between them, and the distance over bars,
+/// which tolerates bars added or repeated.
+pub fn distance(a: &geometry::Curve, b: &geometry::Curve) -> f32 {
+    f32::min(geometry::area_distance(a, b), geometry::dtw_distance(a, b))
+}
+
+/// Pairs of tunes that share enough ngrams to be variants, with the proportion shared.
+/// Each pair is given once, lower tune id first.
+fn candidates(
+    index: &index::NgramIndex,
+    features: &HashMap<u32, Features>,
+    options: &Options,
+) -> Vec<(u32, u32, f32)> {
+    let mut tune_ids = features.keys().cloned().collect::<Vec<u32>>();
+    tune_ids.sort();
+
+    let mut result = vec![];
+
+    for a in tune_ids.iter() {
+        let ngrams = &features[a].ngrams;
+
+        // Tune id -> number of this tune's ngrams it shares.
+        let mut shared: HashMap<u32, usize> = HashMap::new();
+
+        for ngram in ngrams.iter() {
+            let postings = index.get(*ngram);
+
+            // Postings for a tune are together.
+            let mut others = postings.iter().map(|posting| posting.tune_id).collect::<Vec<u32>>();
+            others.dedup();
+
+            if others.len() > options.max_ngram_tunes {
+                continue;
+            }
+
+            for b in others.iter().filter(|b| *b > a) {
+                *shared.entry(*b).or_insert(0) += 1;
+            }
+        }
+
+        let mut found = shared
+            .iter()
+            .filter_map(|(b, count)| {
+                let other = features.get(b)?;
+                let shorter = usize::min(ngrams.len(), other.ngrams.len());
+                let overlap = *count as f32 / usize::max(shorter, 1) as f32;
+
+                if overlap >= options.min_overlap {
+                    Some((*a, *b, overlap))
+                } else {
+                    None
+                }
+            })
+            .collect::<Vec<(u32, u32, f32)>>();
+
+        found.sort_by_key(|x| x.1);
+        result.extend(found);
+    }
+
+    result
+}
+
+/// Find the tunes that are variants of each other, in order of tune ids.
+/// Tunes are retrieved with `get_tune`, and candidates are found with the index.
+/// Pairs that an editor has never-linked aren't considered.
+pub fn cluster<F>(
+    index: &index::NgramIndex,
+    tune_ids: &[u32],
+    options: &Options,
+    relations: &relations::Relations,
+    get_tune: F,
+) -> Vec<Link>
+where
+    F: Fn(u32) -> Option<tune_ast_three::Tune>,
+{
+    let ngram_options = index.options();
+
+    let mut features = HashMap::with_capacity(tune_ids.len());
+    for (i, tune_id) in tune_ids.iter().enumerate() {
+        if let Some(tune) = get_tune(*tune_id) {
+            features.insert(*tune_id, Features::new(&tune, &ngram_options));
+        }
+
+        if (i + 1) % 10000 == 0 {
+            eprintln!("Read {} tunes", i + 1);
+        }
+    }
+
+    let candidates = candidates(index, &features, options);
+    eprintln!("Found {} candidate pairs", candidates.len());
+
+    let mut links = vec![];
+
+    for &(a, b, overlap) in candidates.iter() {
+        if relations.is_never_linked(a, b) {
+            continue;
+        }
+
+        let (features_a, features_b) = (&features[&a], &features[&b]);
+        let distance = distance(&features_a.curve, &features_b.curve);
+
+        if distance <= options.max_distance {
+            links.push(Link {
+                a,
+                b,
+                overlap,
+                distance,
+                same_title: features_a.title.is_some() && features_a.title == features_b.title,
+            });
+        }
+    }
+
+    links
+}
+
+/// Write each group as a line of its members' tune ids, separated by spaces, lowest first.
+pub fn write_groups(grouper: &relations::Grouper, path: &PathBuf) -> io::Result<()> {
+    let mut file = File::create(path)?;
+
+    for members in grouper.groups() {
+        let members = members
+            .iter()
+            .map(|member| member.to_string())
+            .collect::<Vec<String>>();
+
+        writeln!(file, "{}", members.join(" "))?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use test_support::{self, temp_path};
+
+    const TUNES: &[(u32, &str)] = &[
+        (1, "X:1\nT:Tune\nL:1/8\nK:G\nD2 GF GABc|dedB dBGB|cBAG FGAF|G2 GF G4|]\n"),
+        // Another transcription, in A, with a note changed.
+        (2, "X:2\nT:Tune\nL:1/8\nK:A\nE2 AG ABcd|efec eBAc|dcBA GABG|A2 AG A4|]\n"),
+        // Another transcription, in 4/4 with the same notes.
+        (5, "X:5\nT:Same Tune\nM:4/4\nL:1/8\nK:G\nD2GF GABc|dedB dBGB|\ncBAG FGAF|G2GF G4|]\n"),
+        // Unrelated.
+        (3, "X:3\nT:Other\nL:1/8\nK:D\nFAdA FAdA|GBdB GBdB|AFDF AFDF|E2 D2 D4|]\n"),
+        // Shares the opening, but then goes elsewhere.
+        (4, "X:4\nT:Opening\nL:1/8\nK:G\nD2 GF GABc|d2 g2 e2 c2|A2 F2 D4|D8|]\n"),
+    ];
+
+    fn get_tune(tune_id: u32) -> Option<tune_ast_three::Tune> {
+        test_support::get_tune(TUNES, tune_id)
+    }
+
+    #[test]
+    fn cluster_test() {
+        let index = test_support::index(TUNES, ngram::Encoding::Interval);
+        let tune_ids = [1, 2, 3, 4, 5];
+        let mut relations = relations::Relations::new(temp_path("cluster_relations"));
+
+        let links = cluster(&index, &tune_ids, &Options::new(), &relations, get_tune);
+
+        assert_eq!(
+            links.iter().map(|link| (link.a, link.b)).collect::<Vec<_>>(),
+            vec![(1, 2), (1, 5), (2, 5)]
+        );
+
+        // The same notes are no distance apart.
+        assert_eq!(links[1].overlap, 1.0);
+        assert!(links[1].distance < 1e-4);
+        assert!(links[0].distance > 0.0);
+
+        assert!(links[0].same_title);
+        assert!(!links[1].same_title);
+        assert_eq!(links[0].relations().len(), 3);
+        assert_eq!(links[1].relations().len(), 2);
+
+        let found = links.iter().flat_map(|link| link.relations()).collect();
+        relations.replace_automatic(PROVENANCE, found);
+        let grouper = relations.grouper();
+        assert_eq!(grouper.group_ids(), vec![1]);
+        assert_eq!(grouper.get_members(1), vec![1, 2, 5]);
+        assert_eq!(grouper.get(3), None);
+        assert_eq!(grouper.get(4), None);
+
+        // The fourth tune shares enough to be a candidate, but its shape is too different.
+        let options = Options {
+            min_overlap: 0.0,
+            ..Options::new()
+        };
+        let links = cluster(&index, &tune_ids, &options, &relations, get_tune);
+        assert!(links.iter().all(|link| link.a != 4 && link.b != 4));
+
+        // Only the same notes are linked when the distance is strict enough.
+        let options = Options {
+            max_distance: 0.0,
+            ..Options::new()
+        };
+        let links = cluster(&index, &tune_ids, &options, &relations, get_tune);
+        assert_eq!(links.iter().map(|link| (link.a, link.b)).collect::<Vec<_>>(), vec![(1, 5)]);
+
+        // Written out.
+        let found = links.iter().flat_map(|link| link.relations()).collect();
+        relations.replace_automatic(PROVENANCE, found);
+        let path = temp_path("cluster_groups");
+        write_groups(&relations.grouper(), &path).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "1 5\n");
+        fs::remove_file(&path).unwrap();
+
+        // Pairs that are never linked aren't considered.
+        relations.assert(5, 1, relations::Kind::NeverLink, "editor");
+        let links = cluster(&index, &tune_ids, &Options::new(), &relations, get_tune);
+        assert_eq!(
+            links.iter().map(|link| (link.a, link.b)).collect::<Vec<_>>(),
+            vec![(1, 2), (2, 5)]
+        );
+    }
+}
@@ -0,0 +1,307 @@
+//! Fingerprint
+//! A canonical fingerprint of a tune's music, so that copies of the same transcription are found
+//! however they're written. Pitches and durations are resolved, so it doesn't matter how
+//! accidentals, keys or note lengths are written. Header fields, decorations, guitar chords and
+//! formatting are ignored, but changes of metre, barlines and repeats are kept.
+
+use std::collections::HashMap;
+
+use abc_lexer as l;
+use music;
+use relations;
+use tune_ast_three;
+use visitor;
+
+pub type Fingerprint = u64;
+
+/// Provenance of the relations found.
+pub const PROVENANCE: &str = "dupes";
+
+/// 64-bit FNV-1a.
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+/// What's hashed, each written as a tag and its values.
+const TAG_VOICE: u8 = 1;
+const TAG_METRE: u8 = 2;
+const TAG_BAR: u8 = 3;
+const TAG_OPEN_REPEAT: u8 = 4;
+const TAG_CLOSE_REPEAT: u8 = 5;
+const TAG_N_TIME_BAR: u8 = 6;
+const TAG_NOTE: u8 = 7;
+
+struct Hasher {
+    hash: u64,
+
+    /// A plain barline is due before the next symbol. Those at the end of a voice are dropped,
+    /// as are several in a row.
+    bar: bool,
+
+    /// The last symbol was a repeat or n-time bar, which stands in for a plain barline next to it.
+    repeat: bool,
+
+    /// Something has been written in this voice.
+    started: bool,
+}
+
+impl Hasher {
+    fn new() -> Hasher {
+        Hasher {
+            hash: FNV_OFFSET_BASIS,
+            bar: false,
+            repeat: false,
+            started: false,
+        }
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        for byte in bytes.iter() {
+            self.hash = (self.hash ^ *byte as u64).wrapping_mul(FNV_PRIME);
+        }
+    }
+
+    fn symbol(&mut self, tag: u8, values: &[u32]) {
+        if self.bar && self.started {
+            self.write_bytes(&[TAG_BAR]);
+        }
+
+        self.bar = false;
+        self.repeat = false;
+        self.started = true;
+
+        self.write_bytes(&[tag]);
+        for value in values.iter() {
+            self.write_bytes(&value.to_le_bytes());
+        }
+    }
+
+    fn barline(&mut self) {
+        if !self.repeat {
+            self.bar = true;
+        }
+    }
+
+    /// A repeat or n-time bar. A plain barline just before or after it is dropped, so that e.g.
+    /// `|\n|:` is the same as `|:`.
+    fn repeat(&mut self, tag: u8, values: &[u32]) {
+        self.bar = false;
+        self.symbol(tag, values);
+        self.repeat = true;
+    }
+
+    fn voice(&mut self) {
+        self.bar = false;
+        self.repeat = false;
+        self.started = false;
+        self.write_bytes(&[TAG_VOICE]);
+    }
+}
+
+/// Fingerprint of a tune's music. None if it has no notes.
+pub fn fingerprint(tune: &tune_ast_three::Tune) -> Option<Fingerprint> {
+    let mut state = visitor::RunningState::new();
+    for token in tune.prelude.iter() {
+        state.update(token);
+    }
+
+    let mut hasher = Hasher::new();
+    let mut num_notes = 0;
+
+    let music::Metre(numerator, denominator) = state.metre;
+    hasher.symbol(TAG_METRE, &[numerator, denominator]);
+
+    for voice in tune.voices.iter() {
+        let mut state = state;
+        let mut accidentals = music::Accidentals::new(state.key);
+
+        hasher.voice();
+
+        for token in voice.iter() {
+            let metre = state.metre;
+            state.update(token);
+
+            match token {
+                &l::T::KeySignature(_, _) => accidentals.set_key(state.key),
+
+                // Only a change, as restating the metre changes nothing.
+                &l::T::Metre(music::Metre(numerator, denominator)) if state.metre != metre => {
+                    hasher.symbol(TAG_METRE, &[numerator, denominator])
+                }
+
+                &l::T::SingleBar | &l::T::DoubleBar | &l::T::EndBar => {
+                    accidentals.end_bar();
+                    hasher.barline();
+                }
+
+                &l::T::OpenRepeat => {
+                    accidentals.end_bar();
+                    hasher.repeat(TAG_OPEN_REPEAT, &[]);
+                }
+
+                &l::T::CloseRepeat => {
+                    accidentals.end_bar();
+                    hasher.repeat(TAG_CLOSE_REPEAT, &[]);
+                }
+
+                &l::T::NTimeBar(n) => {
+                    accidentals.end_bar();
+                    hasher.repeat(TAG_N_TIME_BAR, &[n]);
+                }
+
+                &l::T::Note(music::Note(pitch, duration)) => {
+                    let semitones = accidentals.resolve(pitch).semitones();
+                    let music::FractionalDuration(numerator, denominator) = duration.reduce();
+
+                    hasher.symbol(TAG_NOTE, &[semitones as u32, numerator, denominator]);
+                    num_notes += 1;
+                }
+
+                _ => (),
+            }
+        }
+    }
+
+    if num_notes > 0 {
+        Some(hasher.hash)
+    } else {
+        None
+    }
+}
+
+/// Fingerprint of a tune from its ABC. None if it isn't UTF-8 or has no notes.
+pub fn fingerprint_abc(data: &[u8]) -> Option<Fingerprint> {
+    let abc = String::from_utf8(data.to_vec()).ok()?;
+    let chars = abc.chars().collect::<Vec<char>>();
+
+    fingerprint(&tune_ast_three::read_from_lexer(l::Lexer::new(&chars)))
+}
+
+/// Sets of tunes with the same music, each in order of tune id, with whether the tune's ABC is
+/// identical to the first's. Sets are in order of their first tune id.
+pub fn duplicate_sets(tunes: &[(u32, &[u8])]) -> Vec<Vec<(u32, bool)>> {
+    let mut by_fingerprint: HashMap<Fingerprint, Vec<(u32, &[u8])>> = HashMap::new();
+
+    for &(tune_id, data) in tunes.iter() {
+        if let Some(fingerprint) = fingerprint_abc(data) {
+            by_fingerprint.entry(fingerprint).or_default().push((tune_id, data));
+        }
+    }
+
+    let mut result = by_fingerprint
+        .values_mut()
+        .filter(|copies| copies.len() > 1)
+        .map(|copies| {
+            copies.sort_by_key(|&(tune_id, _)| tune_id);
+            let first = copies[0].1;
+
+            copies.iter().map(|&(tune_id, data)| (tune_id, data == first)).collect()
+        })
+        .collect::<Vec<Vec<(u32, bool)>>>();
+
+    result.sort();
+    result
+}
+
+/// Relations from the first tune of each set to each of the others.
+pub fn relations(sets: &[Vec<(u32, bool)>]) -> Vec<relations::Relation> {
+    let mut result = vec![];
+
+    for set in sets.iter() {
+        for &(tune_id, exact) in set.iter().skip(1) {
+            result.push(relations::Relation::new(
+                set[0].0,
+                tune_id,
+                relations::Kind::Duplicate,
+                if exact { 1.0 } else { 0.0 },
+                PROVENANCE,
+            ));
+        }
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fingerprint_of(abc: &str) -> Option<Fingerprint> {
+        fingerprint_abc(abc.as_bytes())
+    }
+
+    #[test]
+    fn fingerprint_test() {
+        let tune = fingerprint_of("X:1\nT:Tune\nM:4/4\nL:1/8\nK:G\n|:GABc dedB|d2 g2 f2 e2:|\n");
+        assert!(tune.is_some());
+
+        // Another number, header order, note lengths, spacing, line breaks, a restated metre and a
+        // final barline.
+        assert_eq!(
+            tune,
+            fingerprint_of(
+                "X:27\nL:1/16\nM:4/4\nT:Tune\nK:G\n|:G2A2B2c2 d2e2d2B2|\nM:4/4\nd4g4 f4e4:|\n|]\n"
+            )
+        );
+
+        // Accidentals written out, and in another key signature with the same notes.
+        assert_eq!(tune, fingerprint_of("X:1\nM:4/4\nL:1/8\nK:C\n|:GABc dedB|d2 g2 ^f2 e2:|\n"));
+        assert_eq!(tune, fingerprint_of("X:1\nM:4/4\nL:1/8\nK:Em\n|:GABc dedB|d2 g2 f2 e2:|\n"));
+
+        // Plain barlines next to repeats, and the way a repeat is written.
+        let repeats = fingerprint_of("X:1\nM:4/4\nL:1/8\nK:G\nGABc|:dedB::d2 g2|[1f2 e2:|\n");
+        assert!(repeats.is_some());
+        assert!(repeats != tune);
+        assert_eq!(
+            repeats,
+            fingerprint_of("X:1\nM:4/4\nL:1/8\nK:G\nGABc|\n|:dedB:||:d2 g2|\n|[1f2 e2:||\n")
+        );
+        assert_eq!(
+            repeats,
+            fingerprint_of("X:1\nM:4/4\nL:1/8\nK:G\nGABc||:dedB:|:d2 g2|1f2 e2:|]\n")
+        );
+
+        // Decorations and chords are ignored.
+        assert_eq!(
+            tune,
+            fingerprint_of("X:1\nM:4/4\nL:1/8\nK:G\n|:\"G\"~GABc dedB|\"D\"d2 g2 f2 e2:|\n")
+        );
+
+        // Pitches, durations, barlines, repeats and the metre matter.
+        assert!(tune != fingerprint_of("X:1\nM:4/4\nL:1/8\nK:D\n|:GABc dedB|d2 g2 f2 e2:|\n"));
+        assert!(tune != fingerprint_of("X:1\nM:4/4\nL:1/8\nK:G\n|:GABc dedB|d2 g2 f2 e4:|\n"));
+        assert!(tune != fingerprint_of("X:1\nM:4/4\nL:1/8\nK:G\n|:GABc dedB d2 g2 f2 e2:|\n"));
+        assert!(tune != fingerprint_of("X:1\nM:4/4\nL:1/8\nK:G\nGABc dedB|d2 g2 f2 e2|\n"));
+        assert!(tune != fingerprint_of("X:1\nM:2/2\nL:1/8\nK:G\n|:GABc dedB|d2 g2 f2 e2:|\n"));
+
+        assert_eq!(fingerprint_of("X:1\nT:Empty\nK:G\n"), None);
+    }
+
+    #[test]
+    fn duplicate_sets_test() {
+        let tune = "X:1\nT:Tune\nL:1/8\nK:D\nd2 AF DFA|B3 A3|]\n";
+        let reformatted = "X:5\nL:1/4\nT:Tune\nK:D\nd A/2F/2 D/2F/2A/2|B3/2 A3/2|]\n";
+        let other = "X:3\nT:Other\nL:1/8\nK:D\nFAd FAd|e3 d3|]\n";
+        let empty = "X:6\nT:Empty\nK:D\n";
+
+        let tunes: Vec<(u32, &[u8])> = vec![
+            (5, reformatted.as_bytes()),
+            (2, tune.as_bytes()),
+            (3, other.as_bytes()),
+            (4, tune.as_bytes()),
+            (6, empty.as_bytes()),
+            (7, empty.as_bytes()),
+        ];
+
+        let sets = duplicate_sets(&tunes);
+        assert_eq!(sets, vec![vec![(2, true), (4, true), (5, false)]]);
+
+        let relations = relations(&sets);
+        assert_eq!(
+            relations.iter().map(|r| (r.a, r.b, r.kind, r.score)).collect::<Vec<_>>(),
+            vec![
+                (2, 4, relations::Kind::Duplicate, 1.0),
+                (2, 5, relations::Kind::Duplicate, 0.0),
+            ]
+        );
+    }
+}
@@ -1,4 +1,357 @@
 //! Tune Geometry
 //! Represent a tune in two-dimensional space as a geometric object.
+//! The melody is a step function of pitch against time, played straight with repeats expanded.
+//! Time is in bars from the first note, pitch in semitones. Each note lasts until the next starts.
 
-// Placeholder.
+use playback;
+use timeline;
+use tune_ast_three;
+
+/// Resolution of each bar when comparing bars.
+const SAMPLES_PER_BAR: usize = 24;
+
+/// A note, as a horizontal line.
+#[derive(Debug, PartialEq, PartialOrd, Clone, Copy)]
+pub struct Segment {
+    /// Onset in bars.
+    pub start: f32,
+    pub end: f32,
+
+    /// Semitones from middle C, or from the mean once normalised.
+    pub pitch: f32,
+}
+
+#[derive(Debug, PartialEq, PartialOrd, Clone)]
+pub struct Curve {
+    /// Segments in time order, each starting where the previous one ends.
+    pub segments: Vec<Segment>,
+}
+
+/// Converts ticks to bars, following changes of metre.
+struct BarMap {
+    /// Time in ticks and bars of each change, and the new bar length in ticks.
+    changes: Vec<(u32, f32, u32)>,
+}
+
+impl BarMap {
+    fn bars(&self, ticks: u32) -> f32 {
+        let &(start, start_bars, bar_ticks) = self
+            .changes
+            .iter()
+            .rev()
+            .find(|&&(time, _, _)| time <= ticks)
+            .unwrap_or(&self.changes[0]);
+
+        start_bars + (ticks - start) as f32 / bar_ticks as f32
+    }
+}
+
+impl Curve {
+    /// The curve of a tune's first voice. Where notes start together, the highest is taken.
+    pub fn from_tune(tune: &tune_ast_three::Tune) -> Curve {
+        let timeline = timeline::timeline(tune, &playback::Options::straight());
+
+        let mut bar_map = BarMap { changes: vec![] };
+        let mut notes: Vec<(u32, u32, u8)> = vec![];
+
+        for event in timeline.events.iter() {
+            match event.event {
+                timeline::Event::Metre(metre) => {
+                    let bar_ticks = u32::max(timeline::ticks(metre.bar()), 1);
+
+                    let bars = if bar_map.changes.is_empty() {
+                        0.0
+                    } else {
+                        bar_map.bars(event.time)
+                    };
+
+                    bar_map.changes.push((event.time, bars, bar_ticks));
+                }
+
+                timeline::Event::Note {
+                    track: 0,
+                    midi_number,
+                    duration,
+                    ..
+                } => notes.push((event.time, event.time + duration, midi_number)),
+
+                _ => (),
+            }
+        }
+
+        // Highest first within each onset, so that dedup keeps it.
+        notes.sort_by(|a, b| a.0.cmp(&b.0).then(b.2.cmp(&a.2)));
+        notes.dedup_by_key(|note| note.0);
+
+        let first = notes.first().map_or(0.0, |&(start, _, _)| bar_map.bars(start));
+
+        let segments = notes
+            .iter()
+            .enumerate()
+            .map(|(i, &(start, end, midi_number))| {
+                let end = match notes.get(i + 1) {
+                    Some(&(next, _, _)) => next,
+                    None => end,
+                };
+
+                Segment {
+                    start: bar_map.bars(start) - first,
+                    end: bar_map.bars(end) - first,
+                    pitch: midi_number as f32 - 60.0,
+                }
+            })
+            .collect();
+
+        Curve { segments }
+    }
+
+    /// Length in bars.
+    pub fn length(&self) -> f32 {
+        self.segments.last().map_or(0.0, |segment| segment.end)
+    }
+
+    /// Mean pitch, weighted by duration.
+    pub fn mean_pitch(&self) -> f32 {
+        let length = self.length();
+
+        if length <= 0.0 {
+            return 0.0;
+        }
+
+        self.segments
+            .iter()
+            .map(|segment| segment.pitch * (segment.end - segment.start))
+            .sum::<f32>() / length
+    }
+
+    /// Pitch at a time, or None outside the curve.
+    pub fn pitch_at(&self, time: f32) -> Option<f32> {
+        // The segment starting latest at or before the time.
+        let i = self.segments.iter().position(|segment| segment.end > time)?;
+        let segment = &self.segments[i];
+
+        if segment.start <= time {
+            Some(segment.pitch)
+        } else {
+            None
+        }
+    }
+
+    /// The same shape with a mean pitch of zero and a length of one.
+    pub fn normalised(&self) -> Curve {
+        let length = self.length();
+        let mean = self.mean_pitch();
+
+        if length <= 0.0 {
+            return Curve { segments: vec![] };
+        }
+
+        Curve {
+            segments: self.segments
+                .iter()
+                .map(|segment| {
+                    Segment {
+                        start: segment.start / length,
+                        end: segment.end / length,
+                        pitch: segment.pitch - mean,
+                    }
+                })
+                .collect(),
+        }
+    }
+
+    /// Pitch sampled evenly through each bar, relative to the mean pitch.
+    fn bars(&self) -> Vec<[f32; SAMPLES_PER_BAR]> {
+        let mean = self.mean_pitch();
+        let num_bars = self.length().ceil() as usize;
+
+        (0..num_bars)
+            .map(|bar| {
+                let mut samples = [0.0; SAMPLES_PER_BAR];
+                let mut previous = None;
+
+                for (i, sample) in samples.iter_mut().enumerate() {
+                    let time = bar as f32 + (i as f32 + 0.5) / SAMPLES_PER_BAR as f32;
+
+                    // A short last bar holds its last note.
+                    let pitch = self.pitch_at(time).or(previous).unwrap_or(mean);
+                    *sample = pitch - mean;
+                    previous = Some(pitch);
+                }
+
+                samples
+            })
+            .collect()
+    }
+}
+
+/// Area between two curves, each scaled to a length of one, at the transposition that minimises
+/// it. This is the mean distance between them in semitones.
+pub fn area_distance(a: &Curve, b: &Curve) -> f32 {
+    let a = a.normalised();
+    let b = b.normalised();
+
+    if a.segments.is_empty() || b.segments.is_empty() {
+        return 0.0;
+    }
+
+    // Where either curve changes pitch.
+    let mut times = a.segments
+        .iter()
+        .chain(b.segments.iter())
+        .flat_map(|segment| vec![segment.start, segment.end])
+        .collect::<Vec<f32>>();
+    times.sort_by(|x, y| x.partial_cmp(y).unwrap());
+    times.dedup();
+
+    // Difference and how long it lasts.
+    let mut differences = times
+        .windows(2)
+        .filter(|pair| pair[1] > pair[0])
+        .filter_map(|pair| {
+            let middle = (pair[0] + pair[1]) / 2.0;
+
+            match (a.pitch_at(middle), b.pitch_at(middle)) {
+                (Some(x), Some(y)) => Some((x - y, pair[1] - pair[0])),
+                _ => None,
+            }
+        })
+        .collect::<Vec<(f32, f32)>>();
+
+    // The area is least when transposed by the median difference.
+    differences.sort_by(|x, y| x.0.partial_cmp(&y.0).unwrap());
+    let total: f32 = differences.iter().map(|&(_, width)| width).sum();
+
+    let mut covered = 0.0;
+    let mut median = 0.0;
+    for &(difference, width) in differences.iter() {
+        covered += width;
+        if covered >= total / 2.0 {
+            median = difference;
+            break;
+        }
+    }
+
+    differences
+        .iter()
+        .map(|&(difference, width)| (difference - median).abs() * width)
+        .sum()
+}
+
+/// Mean distance in semitones between two bars.
+fn bar_distance(a: &[f32; SAMPLES_PER_BAR], b: &[f32; SAMPLES_PER_BAR]) -> f32 {
+    a.iter().zip(b.iter()).map(|(x, y)| (x - y).abs()).sum::<f32>() / SAMPLES_PER_BAR as f32
+}
+
+/// Dynamic time warping over bars, with each curve relative to its mean pitch. Tolerates bars
+/// added, dropped or repeated. This is the mean distance in semitones between matched bars.
+pub fn dtw_distance(a: &Curve, b: &Curve) -> f32 {
+    let a = a.bars();
+    let b = b.bars();
+
+    if a.is_empty() || b.is_empty() {
+        return 0.0;
+    }
+
+    // Cost and length of the best path to each pair of bars, row by row.
+    let mut previous: Vec<(f32, usize)> = vec![];
+
+    for (i, bar_a) in a.iter().enumerate() {
+        let mut row: Vec<(f32, usize)> = Vec::with_capacity(b.len());
+
+        for (j, bar_b) in b.iter().enumerate() {
+            let cost = bar_distance(bar_a, bar_b);
+
+            let best = match (i, j) {
+                (0, 0) => (0.0, 0),
+                (0, _) => row[j - 1],
+                (_, 0) => previous[j],
+                _ => {
+                    let options = [previous[j - 1], previous[j], row[j - 1]];
+                    *options
+                        .iter()
+                        .min_by(|x, y| x.0.partial_cmp(&y.0).unwrap().then(x.1.cmp(&y.1)))
+                        .unwrap()
+                }
+            };
+
+            row.push((best.0 + cost, best.1 + 1));
+        }
+
+        previous = row;
+    }
+
+    let (cost, steps) = previous[b.len() - 1];
+    cost / steps as f32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_support;
+
+    fn read(input: &str) -> Curve {
+        Curve::from_tune(&test_support::read(input))
+    }
+
+    fn close(a: f32, b: f32) -> bool {
+        (a - b).abs() < 1e-4
+    }
+
+    #[test]
+    fn curve_test() {
+        let curve = read("X:1\nM:2/4\nL:1/8\nK:G\n|:G2 Bd:|e4|]\n");
+
+        assert_eq!(
+            curve.segments.iter().map(|s| (s.start, s.end, s.pitch)).collect::<Vec<_>>(),
+            vec![
+                (0.0, 0.5, 7.0),
+                (0.5, 0.75, 11.0),
+                (0.75, 1.0, 14.0),
+                (1.0, 1.5, 7.0),
+                (1.5, 1.75, 11.0),
+                (1.75, 2.0, 14.0),
+                (2.0, 3.0, 16.0),
+            ]
+        );
+
+        assert!(close(curve.length(), 3.0));
+        assert!(close(curve.mean_pitch(), (2.0 * (3.5 + 2.75 + 3.5) + 16.0) / 3.0));
+        assert_eq!(curve.pitch_at(0.6), Some(11.0));
+        assert_eq!(curve.pitch_at(3.0), None);
+
+        let normalised = curve.normalised();
+        assert!(close(normalised.length(), 1.0));
+        assert!(close(normalised.mean_pitch(), 0.0));
+    }
+
+    #[test]
+    fn area_distance_test() {
+        let tune = read("X:1\nM:2/4\nL:1/8\nK:G\nG2 Bd|e4|]\n");
+
+        // Transposition makes no difference.
+        assert!(close(area_distance(&tune, &read("X:1\nM:2/4\nL:1/8\nK:D\nd2 fa|b4|]\n")), 0.0));
+
+        // Nor does the metre, as lengths are scaled.
+        assert!(close(area_distance(&tune, &read("X:1\nM:4/4\nL:1/8\nK:G\nG2 Bd e4|]\n")), 0.0));
+
+        // One quaver of eight a tone out.
+        let changed = read("X:1\nM:2/4\nL:1/8\nK:G\nG2 Bc|e4|]\n");
+        assert!(close(area_distance(&tune, &changed), 2.0 / 8.0));
+        assert!(close(area_distance(&changed, &tune), 2.0 / 8.0));
+    }
+
+    #[test]
+    fn dtw_distance_test() {
+        let tune = read("X:1\nM:2/4\nL:1/8\nK:G\nGABc|dcBA|G4|]\n");
+
+        assert!(close(dtw_distance(&tune, &tune), 0.0));
+
+        // A bar played twice is matched with itself. Only the mean pitch moves, slightly.
+        let repeated = read("X:1\nM:2/4\nL:1/8\nK:G\nGABc|GABc|dcBA|G4|]\n");
+        assert!(dtw_distance(&tune, &repeated) < 0.25);
+
+        let different = read("X:1\nM:2/4\nL:1/8\nK:G\ng2 fe|d4|GBdg|]\n");
+        assert!(dtw_distance(&tune, &different) > 2.0);
+    }
+}
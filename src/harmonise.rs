@@ -0,0 +1,440 @@
+//! Harmonise
+//! Suggest accompaniment chords for a tune that has none.
+//! Notes are grouped by half bar or bar, and each group is given the diatonic chord of the tune's
+//! key that best fits its notes, preferring to keep the same chord through a bar and to end on the
+//! tonic.
+
+use abc_lexer as l;
+use key_detection;
+use music;
+use text;
+use tune_ast_three;
+use visitor;
+
+/// Preference for the chord on each degree of the scale, before looking at the notes.
+const DEGREE_PRIORS: [f32; 7] = [1.0, 0.3, 0.2, 0.7, 0.7, 0.4, 0.5];
+
+/// How much the prior counts, relative to the fit of the notes.
+const PRIOR_WEIGHT: f32 = 0.2;
+
+/// Bonus for keeping the previous chord within a bar.
+const CONTINUITY_BONUS: f32 = 0.15;
+
+/// Bonus for the tonic chord at the end of the tune.
+const FINAL_TONIC_BONUS: f32 = 0.5;
+
+/// Weight of a note by its place in the chord: root, third, fifth.
+const CHORD_TONE_WEIGHTS: [f32; 3] = [1.0, 0.9, 0.8];
+
+/// Weight of a note that isn't in the chord.
+const NON_CHORD_TONE_WEIGHT: f32 = -0.5;
+
+/// Extra emphasis on the note at the start of a group.
+const DOWNBEAT_EMPHASIS: f32 = 1.5;
+
+/// A candidate chord with its scale degree.
+struct Candidate {
+    chord: music::ChordSymbol,
+    degree: usize,
+
+    /// Semitones above C of root, third and fifth.
+    tones: [i16; 3],
+}
+
+/// The diatonic triads of a key, leaving out diminished ones, which are rare in accompaniment.
+fn vocabulary(key: music::KeySignature) -> Vec<Candidate> {
+    let tonic_degree = key.tonic.diatonic_pitch_class.to_degree();
+
+    let scale_note = |degree: usize| {
+        let diatonic_pitch_class = music::DiatonicPitchClass::from_degree(
+            tonic_degree + degree as i16,
+        );
+        music::PitchClass {
+            diatonic_pitch_class,
+            accidental: key.accidental(diatonic_pitch_class),
+        }
+    };
+
+    let mut result = vec![];
+
+    for degree in 0..7 {
+        let root = scale_note(degree);
+        let third = scale_note(degree + 2);
+        let fifth = scale_note(degree + 4);
+
+        let third_semitones = (third.semitones() - root.semitones()).rem_euclid(12);
+        let fifth_semitones = (fifth.semitones() - root.semitones()).rem_euclid(12);
+
+        let quality = match (third_semitones, fifth_semitones) {
+            (4, 7) => music::ChordQuality::Major,
+            (3, 7) => music::ChordQuality::Minor,
+            _ => continue,
+        };
+
+        result.push(Candidate {
+            chord: music::ChordSymbol {
+                root,
+                quality,
+                extensions: vec![],
+                alterations: vec![],
+                bass: None,
+            },
+            degree,
+            tones: [
+                root.semitones().rem_euclid(12),
+                third.semitones().rem_euclid(12),
+                fifth.semitones().rem_euclid(12),
+            ],
+        });
+    }
+
+    result
+}
+
+/// The notes that a chord is chosen for, and where to put it.
+struct Group {
+    /// Index of the voice token to insert the chord before.
+    token_index: usize,
+
+    /// Pitch class and duration of each note. The first note is on the downbeat.
+    notes: Vec<(i16, f32)>,
+
+    /// Does this start a bar?
+    starts_bar: bool,
+}
+
+/// Duration of a group of notes that share a chord: half a bar if it has an even number of beats,
+/// otherwise the whole bar.
+fn group_length(metre: music::Metre) -> music::FractionalDuration {
+    let bar = metre.bar();
+    let beat = metre.beat();
+    let beats = bar.divide(beat);
+
+    match beats {
+        music::FractionalDuration(n, 1) if n % 2 == 0 => bar.multiply(music::FractionalDuration(1, 2)),
+        _ => bar,
+    }
+}
+
+/// Divide a voice into groups of notes.
+/// Where the group length is zero, e.g. with a metre of `0/4`, each bar is one group.
+fn groups(voice: &[l::T], state: &visitor::RunningState) -> Vec<Group> {
+    let mut state = *state;
+    let mut accidentals = music::Accidentals::new(state.key);
+
+    let mut result: Vec<Group> = vec![];
+
+    // Position within the bar, and the end of the current group.
+    let mut position = music::FractionalDuration(0, 1);
+    let mut group_end = music::FractionalDuration(0, 1);
+    let mut new_bar = true;
+
+    for (i, token) in voice.iter().enumerate() {
+        state.update(token);
+
+        match token {
+            &l::T::KeySignature(_, _) => accidentals.set_key(state.key),
+
+            &l::T::SingleBar |
+            &l::T::DoubleBar |
+            &l::T::OpenRepeat |
+            &l::T::CloseRepeat |
+            &l::T::EndBar |
+            &l::T::NTimeBar(_) => {
+                accidentals.end_bar();
+                position = music::FractionalDuration(0, 1);
+                group_end = music::FractionalDuration(0, 1);
+                new_bar = true;
+            }
+
+            &l::T::Note(music::Note(pitch, duration)) => {
+                let pitch = accidentals.resolve(pitch);
+
+                // A malformed duration, e.g. `A/0`, takes no time.
+                let duration = match duration {
+                    music::FractionalDuration(_, 0) => music::FractionalDuration(0, 1),
+                    _ => duration,
+                };
+
+                let length = group_length(state.metre);
+                let bar_only = length.0 == 0 || length.1 == 0;
+
+                // Start a new group if this note is past the end of the current one.
+                if new_bar || (!bar_only && position.gte(&group_end)) {
+                    while !bar_only && position.gte(&group_end) {
+                        group_end = group_end.add(length);
+                    }
+
+                    result.push(Group {
+                        token_index: i,
+                        notes: vec![],
+                        starts_bar: new_bar,
+                    });
+                    new_bar = false;
+                }
+
+                if let Some(group) = result.last_mut() {
+                    group.notes.push((
+                        pitch.pitch_class.semitones().rem_euclid(12),
+                        duration.0 as f32 / duration.1 as f32,
+                    ));
+                }
+
+                position = position.add(duration);
+            }
+
+            _ => (),
+        }
+    }
+
+    result
+}
+
+/// How well a chord fits a group of notes.
+fn score(
+    candidate: &Candidate,
+    group: &Group,
+    previous: Option<usize>,
+    this: usize,
+    is_final: bool,
+) -> f32 {
+    let mut fit = 0.0;
+    let mut total = 0.0;
+
+    for (i, &(pitch_class, duration)) in group.notes.iter().enumerate() {
+        let weight = match candidate.tones.iter().position(|tone| *tone == pitch_class) {
+            Some(tone) => CHORD_TONE_WEIGHTS[tone],
+            None => NON_CHORD_TONE_WEIGHT,
+        };
+
+        let emphasis = if i == 0 { DOWNBEAT_EMPHASIS } else { 1.0 };
+
+        fit += weight * duration * emphasis;
+        total += duration * emphasis;
+    }
+
+    let mut result = fit / total + DEGREE_PRIORS[candidate.degree] * PRIOR_WEIGHT;
+
+    if !group.starts_bar && previous == Some(this) {
+        result += CONTINUITY_BONUS;
+    }
+
+    if is_final && candidate.degree == 0 {
+        result += FINAL_TONIC_BONUS;
+    }
+
+    result
+}
+
+/// The key to harmonise in: the declared key, unless the notes suggest otherwise.
+pub fn harmony_key(tune: &tune_ast_three::Tune) -> Option<music::KeySignature> {
+    let declared = text::TuneMetadata::from_tune(tune).key;
+
+    match (declared, key_detection::check_declared_key(tune)) {
+        (Some(key), None) => Some(key),
+        _ => key_detection::estimate_key(tune).map(|estimate| estimate.key).or(declared),
+    }
+}
+
+/// Is this guitar chord text a chord symbol, rather than an annotation?
+fn is_chord_symbol(token: &l::T) -> bool {
+    match token {
+        &l::T::GuitarChord(ref chord) => music::ChordSymbol::parse(chord).is_some(),
+        _ => false,
+    }
+}
+
+/// Suggest a chord for each group of notes in a voice.
+/// Returns the index of the token to insert each chord before.
+fn suggest(
+    voice: &[l::T],
+    state: &visitor::RunningState,
+    key: music::KeySignature,
+) -> Vec<(usize, music::ChordSymbol)> {
+    let candidates = vocabulary(key);
+    let groups = groups(voice, state);
+
+    let mut result = vec![];
+    let mut previous = None;
+
+    for (i, group) in groups.iter().enumerate() {
+        let is_final = i + 1 == groups.len();
+
+        let best = (0..candidates.len())
+            .map(|c| (c, score(&candidates[c], group, previous, c, is_final)))
+            .fold(None, |best: Option<(usize, f32)>, (c, score)| match best {
+                Some((_, best_score)) if best_score >= score => best,
+                _ => Some((c, score)),
+            });
+
+        if let Some((c, _)) = best {
+            // Always give the chord at the start of a bar, otherwise only when it changes.
+            if group.starts_bar || previous != Some(c) {
+                result.push((group.token_index, candidates[c].chord.clone()));
+            }
+
+            previous = Some(c);
+        }
+    }
+
+    result
+}
+
+/// Add suggested chords to a tune. Existing chord symbols are replaced, annotations are kept.
+pub fn harmonise(tune: &tune_ast_three::Tune) -> tune_ast_three::Tune {
+    let key = match harmony_key(tune) {
+        Some(key) => key,
+        None => return tune.clone(),
+    };
+
+    let mut state = visitor::RunningState::new();
+    for token in tune.prelude.iter() {
+        state.update(token);
+    }
+
+    let mut voices = vec![];
+    let mut voice_spans = vec![];
+
+    for (v, voice) in tune.voices.iter().enumerate() {
+        let spans = tune.voice_spans.get(v).map_or(&[][..], |spans| &spans[..]);
+        let mut chords = suggest(voice, &state, key).into_iter().peekable();
+
+        let mut new_voice = vec![];
+        let mut new_spans = vec![];
+
+        for (i, token) in voice.iter().enumerate() {
+            // Tunes built in code may lack spans.
+            let span = spans.get(i).cloned().unwrap_or(l::Span { start: 0, end: 0 });
+
+            while let Some(&(_, ref chord)) = chords.peek().filter(|&&(index, _)| index == i) {
+                new_voice.push(l::T::GuitarChord(chord.to_string()));
+
+                // The chord wasn't in the input, so give it an empty span where it was inserted.
+                new_spans.push(l::Span {
+                    start: span.start,
+                    end: span.start,
+                });
+
+                chords.next();
+            }
+
+            if !is_chord_symbol(token) {
+                new_voice.push(token.clone());
+                new_spans.push(span);
+            }
+        }
+
+        voices.push(new_voice);
+        voice_spans.push(new_spans);
+    }
+
+    tune_ast_three::Tune {
+        prelude: tune.prelude.clone(),
+        voices,
+        prelude_spans: tune.prelude_spans.clone(),
+        voice_spans,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use abc_writer;
+    use test_support::read;
+
+    fn harmonise_abc(abc: &str) -> String {
+        abc_writer::write_tune(&harmonise(&read(abc)))
+    }
+
+    fn chords(abc: &str) -> Vec<String> {
+        read(abc).voices[0]
+            .iter()
+            .filter_map(|token| match token {
+                &l::T::GuitarChord(ref chord) => Some(chord.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn vocabulary_test() {
+        let names = |key| {
+            vocabulary(key)
+                .iter()
+                .map(|candidate| candidate.chord.to_string())
+                .collect::<Vec<String>>()
+        };
+
+        let key = |diatonic_pitch_class, mode| {
+            music::KeySignature {
+                tonic: music::PitchClass {
+                    diatonic_pitch_class,
+                    accidental: None,
+                },
+                mode,
+            }
+        };
+
+        assert_eq!(
+            names(key(music::DiatonicPitchClass::G, music::Mode::Major)),
+            vec!["G", "Am", "Bm", "C", "D", "Em"]
+        );
+        assert_eq!(
+            names(key(music::DiatonicPitchClass::A, music::Mode::Minor)),
+            vec!["Am", "C", "Dm", "Em", "F", "G"]
+        );
+        assert_eq!(
+            names(key(music::DiatonicPitchClass::E, music::Mode::Dorian)),
+            vec!["Em", "F#m", "G", "A", "Bm", "D"]
+        );
+    }
+
+    #[test]
+    fn harmonise_test() {
+        // One chord a bar where the harmony is clear, starting and ending on the tonic.
+        let harmonised = harmonise_abc("M:4/4\nL:1/8\nK:G\nGABG dBGB|ADFA dAFD|GABc dBGB|d2B2 G4|]\n");
+
+        assert_eq!(chords(&harmonised), vec!["G", "D", "G", "G"]);
+        assert!(harmonised.starts_with("M:4/4\nL:1/8\nK:G\n\"G\"GABG dBGB|\"D\"ADFA"));
+    }
+
+    #[test]
+    fn harmonise_half_bars_test() {
+        // Two chords in a bar when the harmony changes half way.
+        let harmonised = harmonise_abc("M:4/4\nL:1/8\nK:D\nD2FA d2AF|G2Bd A2ce|d8|]\n");
+
+        assert_eq!(chords(&harmonised), vec!["D", "G", "A", "D"]);
+    }
+
+    #[test]
+    fn harmonise_replaces_chords_test() {
+        let harmonised = harmonise_abc("M:3/4\nL:1/4\nK:G\n\"^Slowly\"\"Em\"GBd|\"C\"G3|]\n");
+
+        assert_eq!(chords(&harmonised), vec!["^Slowly", "G", "G"]);
+    }
+
+    #[test]
+    fn no_notes_test() {
+        assert_eq!(harmonise_abc("K:G\n"), "K:G\n");
+    }
+
+    #[test]
+    fn zero_length_test() {
+        // A zero-length group is the whole bar.
+        assert_eq!(chords(&harmonise_abc("M:0/4\nL:1/8\nK:G\nGABc|dBGB|\n")).len(), 2);
+
+        // A zero-length note takes no time.
+        assert_eq!(chords(&harmonise_abc("M:4/4\nL:1/8\nK:G\nA/0GABc|\n")).len(), 1);
+    }
+
+    #[test]
+    fn missing_spans_test() {
+        let mut tune = read("M:4/4\nL:1/8\nK:G\nGABc dBGB|\n");
+        tune.voice_spans.clear();
+
+        let harmonised = harmonise(&tune);
+        assert_eq!(harmonised.voices.len(), 1);
+        assert_eq!(harmonised.voices[0].len(), tune.voices[0].len() + 1);
+        assert_eq!(harmonised.voice_spans[0].len(), harmonised.voices[0].len());
+    }
+}
@@ -0,0 +1,366 @@
+//! Index
+//! An inverted index from ngram to the tunes that contain it, and where, for finding tunes that
+//! contain a phrase. There's an index for each encoding of the melody, each persisted next to the
+//! tune cache and updated as tunes are added.
+
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+
+use abc_lexer;
+use ngram;
+use tune_ast_three;
+
+/// Identifies the file type.
+const MAGIC: &[u8; 4] = b"FTNI";
+
+/// Increment when the format, or the way ngrams are extracted, changes.
+/// An index with a different version is discarded and rebuilt.
+pub const VERSION: u32 = 2;
+
+/// Number of steps in an indexed ngram. Long enough to be distinctive, short enough to be found
+/// in variant settings of a tune. Coarser encodings need longer ngrams.
+pub fn ngram_length(encoding: ngram::Encoding) -> usize {
+    match encoding {
+        ngram::Encoding::Interval => 4,
+        ngram::Encoding::Contour => 5,
+        ngram::Encoding::Rhythm => 6,
+        ngram::Encoding::Parsons => 7,
+    }
+}
+
+/// Name of the index file for an encoding.
+pub fn filename(encoding: ngram::Encoding) -> String {
+    match encoding {
+        ngram::Encoding::Interval => "ngramindex".to_string(),
+        _ => format!("ngramindex-{}", encoding.name()),
+    }
+}
+
+/// An occurrence of an ngram.
+#[derive(Debug, PartialEq, PartialOrd, Clone, Copy)]
+pub struct Posting {
+    pub tune_id: u32,
+
+    /// Position of the ngram in the tune's sequence of ngrams, i.e. of its first note.
+    pub position: u32,
+}
+
+/// Ngram id -> occurrences, in the order the tunes were added.
+type Postings = HashMap<ngram::NgramId, Vec<Posting>>;
+
+#[derive(Debug)]
+pub struct NgramIndex {
+    filename: PathBuf,
+
+    encoding: ngram::Encoding,
+
+    postings: Postings,
+
+    /// Tunes that have been indexed.
+    tune_ids: HashSet<u32>,
+}
+
+/// Reads little-endian values from a buffer.
+struct Reader<'a> {
+    buffer: &'a [u8],
+    i: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn u32(&mut self) -> Option<u32> {
+        if self.i + 4 > self.buffer.len() {
+            return None;
+        }
+
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(&self.buffer[self.i..self.i + 4]);
+        self.i += 4;
+
+        Some(u32::from_le_bytes(bytes))
+    }
+
+    /// A count read from the file, capped at how many items of this size are left, so that a
+    /// damaged count can't make a huge allocation.
+    fn capacity(&self, count: u32, item_size: usize) -> usize {
+        usize::min(count as usize, (self.buffer.len() - self.i) / item_size)
+    }
+}
+
+impl NgramIndex {
+    /// Construct a new index at the given filename, loading it if it exists.
+    pub fn new(filename: PathBuf, encoding: ngram::Encoding) -> NgramIndex {
+        let mut result = NgramIndex {
+            filename,
+            encoding,
+            postings: HashMap::new(),
+            tune_ids: HashSet::new(),
+        };
+
+        result.load();
+
+        result
+    }
+
+    /// Read the index into memory, if there is one. An old or damaged index is discarded.
+    fn load(&mut self) {
+        let mut buffer = vec![];
+
+        match File::open(&self.filename) {
+            Err(_) => return,
+            Ok(mut file) => {
+                file.read_to_end(&mut buffer).expect("Can't read ngram index file.");
+            }
+        }
+
+        if let Some((postings, tune_ids)) = self.parse(&buffer) {
+            self.postings = postings;
+            self.tune_ids = tune_ids;
+        } else {
+            eprintln!("Ngram index is out of date, rebuilding.");
+        }
+    }
+
+    // Format, all u32 little-endian:
+    // magic, version, encoding, ngram length
+    // number of tunes, then each tune id
+    // number of ngrams, then for each ngram:
+    //   ngram id, number of postings, then each posting's tune id and position
+    fn parse(&self, buffer: &[u8]) -> Option<(Postings, HashSet<u32>)> {
+        if buffer.len() < 4 || &buffer[0..4] != MAGIC {
+            return None;
+        }
+
+        let mut reader = Reader { buffer, i: 4 };
+
+        if reader.u32()? != VERSION ||
+            reader.u32()? != self.encoding_number() ||
+            reader.u32()? != self.options().n as u32
+        {
+            return None;
+        }
+
+        let num_tunes = reader.u32()?;
+        let mut tune_ids = HashSet::with_capacity(reader.capacity(num_tunes, 4));
+        for _ in 0..num_tunes {
+            tune_ids.insert(reader.u32()?);
+        }
+
+        let num_ngrams = reader.u32()?;
+        let mut postings = HashMap::with_capacity(reader.capacity(num_ngrams, 8));
+        for _ in 0..num_ngrams {
+            let ngram = reader.u32()?;
+            let num_postings = reader.u32()?;
+
+            let mut list = Vec::with_capacity(reader.capacity(num_postings, 8));
+            for _ in 0..num_postings {
+                list.push(Posting {
+                    tune_id: reader.u32()?,
+                    position: reader.u32()?,
+                });
+            }
+
+            postings.insert(ngram, list);
+        }
+
+        if reader.i == buffer.len() {
+            Some((postings, tune_ids))
+        } else {
+            None
+        }
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&VERSION.to_le_bytes());
+        bytes.extend_from_slice(&self.encoding_number().to_le_bytes());
+        bytes.extend_from_slice(&(self.options().n as u32).to_le_bytes());
+
+        bytes.extend_from_slice(&(self.tune_ids.len() as u32).to_le_bytes());
+        for tune_id in self.tune_ids.iter() {
+            bytes.extend_from_slice(&tune_id.to_le_bytes());
+        }
+
+        bytes.extend_from_slice(&(self.postings.len() as u32).to_le_bytes());
+        for (ngram, list) in self.postings.iter() {
+            bytes.extend_from_slice(&ngram.to_le_bytes());
+            bytes.extend_from_slice(&(list.len() as u32).to_le_bytes());
+
+            for posting in list.iter() {
+                bytes.extend_from_slice(&posting.tune_id.to_le_bytes());
+                bytes.extend_from_slice(&posting.position.to_le_bytes());
+            }
+        }
+
+        bytes
+    }
+
+    pub fn save(&self) {
+        eprintln!("Write ngram index to {:?}", &self.filename);
+
+        let mut file = File::create(&self.filename).expect("Can't create ngram index file.");
+        file.write_all(&self.to_bytes()).expect("Can't write ngram index file.");
+    }
+
+    fn encoding_number(&self) -> u32 {
+        ngram::Encoding::all()
+            .iter()
+            .position(|encoding| *encoding == self.encoding)
+            .unwrap() as u32
+    }
+
+    /// How ngrams are extracted for this index. They're as written, so that positions correspond
+    /// to the tune.
+    pub fn options(&self) -> ngram::Options {
+        ngram::Options {
+            encoding: self.encoding,
+            ..ngram::Options::new(ngram_length(self.encoding))
+        }
+    }
+
+    pub fn encoding(&self) -> ngram::Encoding {
+        self.encoding
+    }
+
+    pub fn has_tune(&self, tune_id: u32) -> bool {
+        self.tune_ids.contains(&tune_id)
+    }
+
+    pub fn num_tunes(&self) -> usize {
+        self.tune_ids.len()
+    }
+
+    /// Index a tune, if it isn't already.
+    pub fn add_tune(&mut self, tune_id: u32, tune: &tune_ast_three::Tune) {
+        if !self.tune_ids.insert(tune_id) {
+            return;
+        }
+
+        for (position, ngram) in ngram::sequence(tune, &self.options()).iter().enumerate() {
+            self.postings.entry(*ngram).or_default().push(Posting {
+                tune_id,
+                position: position as u32,
+            });
+        }
+    }
+
+    /// Index a tune from its ABC, if it isn't already.
+    #[cfg(test)]
+    pub fn add_abc(&mut self, tune_id: u32, data: &[u8]) {
+        add_abc_to_all(::std::slice::from_mut(self), tune_id, data);
+    }
+
+    /// Occurrences of an ngram.
+    pub fn get(&self, ngram: ngram::NgramId) -> &[Posting] {
+        match self.postings.get(&ngram) {
+            Some(list) => list,
+            None => &[],
+        }
+    }
+}
+
+/// Index a tune from its ABC in each of the indexes that don't have it, reading it only once.
+/// Files that aren't UTF-8 are recorded as indexed, with no ngrams.
+pub fn add_abc_to_all(indexes: &mut [NgramIndex], tune_id: u32, data: &[u8]) {
+    if indexes.iter().all(|index| index.has_tune(tune_id)) {
+        return;
+    }
+
+    match String::from_utf8(data.to_vec()) {
+        Ok(abc) => {
+            let chars = abc.chars().collect::<Vec<char>>();
+            let ast = tune_ast_three::read_from_lexer(abc_lexer::Lexer::new(&chars));
+
+            for index in indexes.iter_mut() {
+                index.add_tune(tune_id, &ast);
+            }
+        }
+        Err(_) => {
+            for index in indexes.iter_mut() {
+                index.tune_ids.insert(tune_id);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use test_support::temp_path;
+
+    #[test]
+    fn index_test() {
+        let path = temp_path("ngram_index");
+        let _ = fs::remove_file(&path);
+
+        let mut index = NgramIndex::new(path.clone(), ngram::Encoding::Interval);
+        assert_eq!(index.num_tunes(), 0);
+
+        index.add_abc(1, b"X:1\nL:1/8\nK:D\nd2 AF DFA|B3 A3|]\n");
+        index.add_abc(2, b"X:2\nL:1/8\nK:G\nB2 GB dBG|g3 d3|]\n");
+
+        // The same phrase, transposed.
+        index.add_abc(3, b"X:3\nL:1/8\nK:G\ng2 dB GBd|e3 d3|]\n");
+
+        // Adding again does nothing.
+        index.add_abc(1, b"X:1\nL:1/8\nK:D\nd2 AF DFA|B3 A3|]\n");
+
+        assert_eq!(index.num_tunes(), 3);
+
+        // d A F D F, i.e. down a fourth, a minor third and a major third, then back up.
+        let descent = ngram::hash(&[-5, -3, -4, 4]);
+        assert_eq!(
+            index.get(descent),
+            &[
+                Posting {
+                    tune_id: 1,
+                    position: 0,
+                },
+                Posting {
+                    tune_id: 3,
+                    position: 0,
+                },
+            ]
+        );
+
+        // Saved and loaded.
+        index.save();
+        let loaded = NgramIndex::new(path.clone(), ngram::Encoding::Interval);
+        assert!(loaded.has_tune(2));
+        assert_eq!(loaded.num_tunes(), 3);
+        assert_eq!(loaded.get(descent), index.get(descent));
+        assert_eq!(loaded.postings, index.postings);
+
+        // An index with a different version is discarded.
+        let mut bytes = index.to_bytes();
+        bytes[4] = (VERSION + 1) as u8;
+        fs::write(&path, &bytes).unwrap();
+        assert_eq!(NgramIndex::new(path.clone(), ngram::Encoding::Interval).num_tunes(), 0);
+
+        // As is one for another encoding.
+        fs::write(&path, index.to_bytes()).unwrap();
+        assert_eq!(NgramIndex::new(path.clone(), ngram::Encoding::Parsons).num_tunes(), 0);
+
+        // And a truncated one.
+        fs::write(&path, &index.to_bytes()[..20]).unwrap();
+        assert_eq!(NgramIndex::new(path.clone(), ngram::Encoding::Interval).num_tunes(), 0);
+
+        // And ones with counts far larger than what follows.
+        let mut bytes = index.to_bytes()[..16].to_vec();
+        bytes.extend_from_slice(&u32::MAX.to_le_bytes());
+        fs::write(&path, &bytes).unwrap();
+        assert_eq!(NgramIndex::new(path.clone(), ngram::Encoding::Interval).num_tunes(), 0);
+
+        let mut bytes = index.to_bytes()[..16].to_vec();
+        for count in [0, 1, 0, u32::MAX].iter() {
+            bytes.extend_from_slice(&count.to_le_bytes());
+        }
+        fs::write(&path, &bytes).unwrap();
+        assert_eq!(NgramIndex::new(path.clone(), ngram::Encoding::Interval).num_tunes(), 0);
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,562 @@
+//! JSON
+//! Minimal JSON serialization of tokens, metadata and the tune AST, without external dependencies.
+
+use std::fmt::Write;
+
+use abc_lexer as l;
+use music;
+use relations;
+use search;
+use text;
+use tune_ast_three;
+
+/// A JSON value.
+#[derive(Debug, PartialEq, PartialOrd, Clone)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    /// Key order is preserved.
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    /// Convenience for building a string value.
+    pub fn string(value: &str) -> Json {
+        Json::String(value.to_string())
+    }
+
+    /// Convenience for building an object from static keys.
+    pub fn object(fields: Vec<(&str, Json)>) -> Json {
+        Json::Object(
+            fields
+                .into_iter()
+                .map(|(key, value)| (key.to_string(), value))
+                .collect(),
+        )
+    }
+
+    /// Render as compact JSON text.
+    pub fn render(&self) -> String {
+        let mut buf = String::new();
+        self.write(&mut buf);
+        buf
+    }
+
+    fn write(&self, buf: &mut String) {
+        match self {
+            &Json::Null => buf.push_str("null"),
+            &Json::Bool(value) => buf.push_str(if value { "true" } else { "false" }),
+
+            // JSON has no representation for NaN or infinity.
+            &Json::Number(value) if !value.is_finite() => buf.push_str("null"),
+            &Json::Number(value) => write!(buf, "{}", value).unwrap(),
+
+            &Json::String(ref value) => escape(value, buf),
+            &Json::Array(ref values) => {
+                buf.push('[');
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 {
+                        buf.push(',');
+                    }
+                    value.write(buf);
+                }
+                buf.push(']');
+            }
+            &Json::Object(ref fields) => {
+                buf.push('{');
+                for (i, &(ref key, ref value)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        buf.push(',');
+                    }
+                    escape(key, buf);
+                    buf.push(':');
+                    value.write(buf);
+                }
+                buf.push('}');
+            }
+        }
+    }
+}
+
+/// Write a string as a quoted, escaped JSON string.
+fn escape(value: &str, buf: &mut String) {
+    buf.push('"');
+
+    for c in value.chars() {
+        match c {
+            '"' => buf.push_str("\\\""),
+            '\\' => buf.push_str("\\\\"),
+            '\n' => buf.push_str("\\n"),
+            '\r' => buf.push_str("\\r"),
+            '\t' => buf.push_str("\\t"),
+            c if (c as u32) < 0x20 => write!(buf, "\\u{:04x}", c as u32).unwrap(),
+            c => buf.push(c),
+        }
+    }
+
+    buf.push('"');
+}
+
+fn optional_string(value: &Option<String>) -> Json {
+    match value {
+        &Some(ref value) => Json::string(value),
+        &None => Json::Null,
+    }
+}
+
+fn duration_to_json(duration: music::FractionalDuration) -> Json {
+    let music::FractionalDuration(numerator, denominator) = duration;
+    Json::object(vec![
+        ("numerator", Json::Number(numerator as f64)),
+        ("denominator", Json::Number(denominator as f64)),
+    ])
+}
+
+fn metre_to_json(metre: music::Metre) -> Json {
+    let music::Metre(numerator, denominator) = metre;
+    Json::object(vec![
+        ("numerator", Json::Number(numerator as f64)),
+        ("denominator", Json::Number(denominator as f64)),
+    ])
+}
+
+fn pitch_class_to_json(pitch_class: music::PitchClass) -> Json {
+    Json::object(vec![
+        (
+            "diatonic",
+            Json::String(format!("{:?}", pitch_class.diatonic_pitch_class)),
+        ),
+        (
+            "accidental",
+            match pitch_class.accidental {
+                Some(accidental) => Json::String(format!("{:?}", accidental)),
+                None => Json::Null,
+            },
+        ),
+    ])
+}
+
+fn key_signature_to_json(tonic: music::PitchClass, mode: music::Mode) -> Json {
+    Json::object(vec![
+        ("tonic", pitch_class_to_json(tonic)),
+        ("mode", Json::String(format!("{:?}", mode))),
+    ])
+}
+
+fn tempo_to_json(tempo: &music::Tempo) -> Json {
+    Json::object(vec![
+        (
+            "beat",
+            match tempo.beat {
+                Some(beat) => duration_to_json(beat),
+                None => Json::Null,
+            },
+        ),
+        (
+            "bpm",
+            match tempo.bpm {
+                Some(bpm) => Json::Number(bpm as f64),
+                None => Json::Null,
+            },
+        ),
+        ("label", optional_string(&tempo.label)),
+    ])
+}
+
+fn note_to_json(note: &music::Note) -> Json {
+    let &music::Note(pitch, duration) = note;
+    Json::object(vec![
+        ("pitch", pitch_class_to_json(pitch.pitch_class)),
+        ("octave", Json::Number(pitch.octave as f64)),
+        ("duration", duration_to_json(duration)),
+    ])
+}
+
+fn span_to_json(span: l::Span) -> Json {
+    Json::Array(vec![
+        Json::Number(span.start as f64),
+        Json::Number(span.end as f64),
+    ])
+}
+
+/// Serialize a token as an object with a "type" and its fields.
+pub fn token_to_json(token: &l::T) -> Json {
+    let text = |name: &str, value: &String| {
+        Json::object(vec![("type", Json::string(name)), ("value", Json::string(value))])
+    };
+
+    let simple = |name: &str| Json::object(vec![("type", Json::string(name))]);
+
+    match token {
+        &l::T::Newline => simple("newline"),
+        &l::T::BeamBreak => simple("beam_break"),
+
+        &l::T::Area(ref value) => text("area", value),
+        &l::T::Book(ref value) => text("book", value),
+        &l::T::Composer(ref value) => text("composer", value),
+        &l::T::Discography(ref value) => text("discography", value),
+        &l::T::Filename(ref value) => text("filename", value),
+        &l::T::Group(ref value) => text("group", value),
+        &l::T::History(ref value) => text("history", value),
+        &l::T::Information(ref value) => text("information", value),
+        &l::T::Notes(ref value) => text("notes", value),
+        &l::T::Origin(ref value) => text("origin", value),
+        &l::T::Rhythm(ref value) => text("rhythm", value),
+        &l::T::Source(ref value) => text("source", value),
+        &l::T::Title(ref value) => text("title", value),
+        &l::T::Words(ref value) => text("words", value),
+        &l::T::X(ref value) => text("x", value),
+        &l::T::Transcription(ref value) => text("transcription", value),
+
+        &l::T::Metre(metre) => {
+            Json::object(vec![
+                ("type", Json::string("metre")),
+                ("value", metre_to_json(metre)),
+            ])
+        }
+        &l::T::KeySignature(tonic, mode) => {
+            Json::object(vec![
+                ("type", Json::string("key_signature")),
+                ("value", key_signature_to_json(tonic, mode)),
+            ])
+        }
+        &l::T::DefaultNoteLength(length) => {
+            Json::object(vec![
+                ("type", Json::string("default_note_length")),
+                ("value", duration_to_json(length)),
+            ])
+        }
+        &l::T::Tempo(ref tempo) => {
+            Json::object(vec![
+                ("type", Json::string("tempo")),
+                ("value", tempo_to_json(tempo)),
+            ])
+        }
+
+        &l::T::Clef(clef) => text("clef", &clef.name().to_string()),
+
+        &l::T::SingleBar => simple("single_bar"),
+        &l::T::DoubleBar => simple("double_bar"),
+        &l::T::OpenRepeat => simple("open_repeat"),
+        &l::T::CloseRepeat => simple("close_repeat"),
+        &l::T::EndBar => simple("end_bar"),
+        &l::T::NTimeBar(n) => {
+            Json::object(vec![
+                ("type", Json::string("n_time_bar")),
+                ("value", Json::Number(n as f64)),
+            ])
+        }
+
+        &l::T::GuitarChord(ref value) => text("guitar_chord", value),
+        &l::T::Decoration(decoration) => {
+            text("decoration", &decoration.name().to_string())
+        }
+        &l::T::GraceNotes(ref grace_notes) => {
+            Json::object(vec![
+                ("type", Json::string("grace_notes")),
+                ("acciaccatura", Json::Bool(grace_notes.acciaccatura)),
+                (
+                    "value",
+                    Json::Array(grace_notes.notes.iter().map(note_to_json).collect()),
+                ),
+            ])
+        }
+
+        &l::T::Note(ref note) => {
+            Json::object(vec![
+                ("type", Json::string("note")),
+                ("value", note_to_json(note)),
+            ])
+        }
+
+        &l::T::Directive(ref value) => text("directive", value),
+    }
+}
+
+/// Serialize tokens, with their spans if available.
+fn tokens_to_json(tokens: &[l::T], spans: &[l::Span]) -> Json {
+    Json::Array(
+        tokens
+            .iter()
+            .enumerate()
+            .map(|(i, token)| match (token_to_json(token), spans.get(i)) {
+                (Json::Object(mut fields), Some(span)) => {
+                    fields.push(("span".to_string(), span_to_json(*span)));
+                    Json::Object(fields)
+                }
+                (json, _) => json,
+            })
+            .collect(),
+    )
+}
+
+pub fn metadata_to_json(metadata: &text::TuneMetadata) -> Json {
+    Json::object(vec![
+        (
+            "titles",
+            Json::Array(metadata.titles.iter().map(|t| Json::string(t)).collect()),
+        ),
+        (
+            "x",
+            match metadata.x {
+                Some(x) => Json::Number(x as f64),
+                None => Json::Null,
+            },
+        ),
+        ("composer", optional_string(&metadata.composer)),
+        ("rhythm", optional_string(&metadata.rhythm)),
+        ("origin", optional_string(&metadata.origin)),
+        ("source", optional_string(&metadata.source)),
+        ("book", optional_string(&metadata.book)),
+        ("discography", optional_string(&metadata.discography)),
+        (
+            "notes",
+            Json::Array(metadata.notes.iter().map(|n| Json::string(n)).collect()),
+        ),
+        ("transcription", optional_string(&metadata.transcription)),
+        (
+            "key",
+            match metadata.key {
+                Some(key) => key_signature_to_json(key.tonic, key.mode),
+                None => Json::Null,
+            },
+        ),
+        (
+            "metre",
+            match metadata.metre {
+                Some(metre) => metre_to_json(metre),
+                None => Json::Null,
+            },
+        ),
+        (
+            "default_length",
+            match metadata.default_length {
+                Some(length) => duration_to_json(length),
+                None => Json::Null,
+            },
+        ),
+        (
+            "tempo",
+            match metadata.tempo {
+                Some(ref tempo) => tempo_to_json(tempo),
+                None => Json::Null,
+            },
+        ),
+    ])
+}
+
+pub fn search_results_to_json(results: &[search::SearchResult]) -> Json {
+    Json::Array(
+        results
+            .iter()
+            .map(|result| {
+                Json::object(vec![
+                    ("tune_id", Json::Number(result.tune_id as f64)),
+                    ("score", Json::Number(result.score as f64)),
+                    ("matched", Json::Number(result.matched as f64)),
+                    (
+                        "bars",
+                        Json::Array(
+                            result
+                                .bars
+                                .iter()
+                                .map(|&(first, last)| {
+                                    Json::Array(vec![
+                                        Json::Number(first as f64),
+                                        Json::Number(last as f64),
+                                    ])
+                                })
+                                .collect(),
+                        ),
+                    ),
+                ])
+            })
+            .collect(),
+    )
+}
+
+fn relation_to_json(relation: &relations::Relation) -> Json {
+    Json::object(vec![
+        ("a", Json::Number(relation.a as f64)),
+        ("b", Json::Number(relation.b as f64)),
+        ("kind", Json::string(relation.kind.name())),
+        ("score", Json::Number(relation.score as f64)),
+        ("provenance", Json::string(&relation.provenance)),
+    ])
+}
+
+/// A group's members, with their titles, and the relations that explain it.
+pub fn group_to_json(group: &relations::Group, titles: &[Option<String>]) -> Json {
+    Json::object(vec![
+        ("group_id", Json::Number(group.id as f64)),
+        (
+            "members",
+            Json::Array(
+                group
+                    .members
+                    .iter()
+                    .zip(titles.iter())
+                    .map(|(tune_id, title)| {
+                        Json::object(vec![
+                            ("tune_id", Json::Number(*tune_id as f64)),
+                            ("title", optional_string(title)),
+                        ])
+                    })
+                    .collect(),
+            ),
+        ),
+        (
+            "relations",
+            Json::Array(group.relations.iter().map(relation_to_json).collect()),
+        ),
+    ])
+}
+
+fn bars_to_json(bars: &[tune_ast_three::Bar], spans: &[l::Span]) -> Json {
+    Json::Array(
+        bars.iter()
+            .map(|bar| {
+                Json::object(vec![
+                    (
+                        "tokens",
+                        Json::Array(vec![
+                            Json::Number(bar.start as f64),
+                            Json::Number(bar.end as f64),
+                        ]),
+                    ),
+                    (
+                        "span",
+                        match bar.span(spans) {
+                            Some(span) => span_to_json(span),
+                            None => Json::Null,
+                        },
+                    ),
+                ])
+            })
+            .collect(),
+    )
+}
+
+fn sections_to_json(voice: &[l::T], spans: &[l::Span]) -> Json {
+    Json::Array(
+        tune_ast_three::sections(voice)
+            .iter()
+            .map(|section| {
+                Json::object(vec![
+                    ("repeat", Json::Bool(section.repeat)),
+                    ("bars", bars_to_json(&section.main, spans)),
+                    (
+                        "endings",
+                        Json::Array(
+                            section
+                                .endings
+                                .iter()
+                                .map(|&(n, ref bars)| {
+                                    Json::object(vec![
+                                        ("n", Json::Number(n as f64)),
+                                        ("bars", bars_to_json(bars, spans)),
+                                    ])
+                                })
+                                .collect(),
+                        ),
+                    ),
+                ])
+            })
+            .collect(),
+    )
+}
+
+/// Serialize a whole tune: metadata, prelude tokens and, per voice, tokens and sections.
+/// Spans are character offsets into the ABC input.
+pub fn tune_to_json(tune: &tune_ast_three::Tune) -> Json {
+    let no_spans = vec![];
+
+    let voices = tune.voices
+        .iter()
+        .enumerate()
+        .map(|(i, voice)| {
+            let spans = tune.voice_spans.get(i).unwrap_or(&no_spans);
+            Json::object(vec![
+                ("tokens", tokens_to_json(voice, spans)),
+                ("sections", sections_to_json(voice, spans)),
+            ])
+        })
+        .collect();
+
+    Json::object(vec![
+        (
+            "metadata",
+            metadata_to_json(&text::TuneMetadata::from_tune(tune)),
+        ),
+        ("prelude", tokens_to_json(&tune.prelude, &tune.prelude_spans)),
+        ("voices", Json::Array(voices)),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escape_test() {
+        assert_eq!(Json::string("plain").render(), "\"plain\"");
+        assert_eq!(
+            Json::string("Say \"hi\"").render(),
+            "\"Say \\\"hi\\\"\"",
+            "Quotes should be escaped."
+        );
+        assert_eq!(Json::string("a\\b").render(), "\"a\\\\b\"");
+        assert_eq!(Json::string("a\nb\r\tc").render(), "\"a\\nb\\r\\tc\"");
+        assert_eq!(
+            Json::string("bell\u{7}").render(),
+            "\"bell\\u0007\"",
+            "Other control characters should be escaped as unicode."
+        );
+        assert_eq!(
+            Json::string("Caoineadh Uí Néill").render(),
+            "\"Caoineadh Uí Néill\"",
+            "Non-ASCII should pass through."
+        );
+    }
+
+    #[test]
+    fn render_test() {
+        let json = Json::object(vec![
+            ("a", Json::Array(vec![Json::Number(1.0), Json::Number(0.5)])),
+            ("b", Json::Null),
+            ("c", Json::Bool(true)),
+        ]);
+
+        assert_eq!(json.render(), "{\"a\":[1,0.5],\"b\":null,\"c\":true}");
+    }
+
+    #[test]
+    fn text_field_escaping_test() {
+        let json = token_to_json(&l::T::Title("The \"Tricky\" \\ Tune".to_string()));
+
+        assert_eq!(
+            json.render(),
+            "{\"type\":\"title\",\"value\":\"The \\\"Tricky\\\" \\\\ Tune\"}"
+        );
+    }
+
+    #[test]
+    fn tune_to_json_test() {
+        let chars = "T:Quote \"Me\"\nM:2/4\nK:G\nAB|cd|]\n"
+            .chars()
+            .collect::<Vec<char>>();
+        let tune = tune_ast_three::read_from_lexer(l::Lexer::new(&chars));
+        let json = tune_to_json(&tune).render();
+
+        assert!(json.contains("\"titles\":[\"Quote \\\"Me\\\"\"]"));
+        assert!(json.contains(
+            "{\"type\":\"title\",\"value\":\"Quote \\\"Me\\\"\",\"span\":[0,13]}"
+        ));
+        assert!(json.contains(
+            "\"sections\":[{\"repeat\":false,\"bars\":[{\"tokens\":[0,2],\"span\":[23,25]},{\"tokens\":[4,6],\"span\":[26,28]}],\"endings\":[]}]"
+        ));
+    }
+}
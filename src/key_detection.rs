@@ -0,0 +1,317 @@
+//! Key Detection
+//! Estimate a tune's key and mode from its notes, for tunes with a wrong or missing "K:" field.
+//! A histogram of sounding pitch classes, weighted by duration and with extra weight for phrase
+//! endings, is correlated against a profile for every tonic and mode.
+
+use music;
+use text;
+use tune_ast_three;
+use visitor;
+use abc_lexer as l;
+
+/// Modes that are considered, in order of preference when they score the same.
+const MODES: &[music::Mode] = &[
+    music::Mode::Major,
+    music::Mode::Minor,
+    music::Mode::Dorian,
+    music::Mode::Mixolydian,
+    music::Mode::Lydian,
+    music::Mode::Phrygian,
+];
+
+/// Semitones above the tonic of each degree of a mode.
+fn scale(mode: music::Mode) -> [i16; 7] {
+    match mode {
+        music::Mode::Lydian => [0, 2, 4, 6, 7, 9, 11],
+        music::Mode::Mixolydian => [0, 2, 4, 5, 7, 9, 10],
+        music::Mode::Dorian => [0, 2, 3, 5, 7, 9, 10],
+        music::Mode::Aeolian | music::Mode::Minor => [0, 2, 3, 5, 7, 8, 10],
+        music::Mode::Phrygian => [0, 1, 3, 5, 7, 8, 10],
+        music::Mode::Locrian => [0, 1, 3, 5, 6, 8, 10],
+        music::Mode::Natural | music::Mode::Ionian | music::Mode::Major => {
+            [0, 2, 4, 5, 7, 9, 11]
+        }
+    }
+}
+
+/// Weight of each degree of the scale in a profile. The tonic, fifth and third stand out.
+const DEGREE_WEIGHTS: [f32; 7] = [2.5, 1.0, 1.5, 1.0, 1.75, 1.0, 1.0];
+
+/// Extra weight for the note that ends a phrase, as a proportion of the tune's total duration.
+const PHRASE_ENDING_WEIGHT: f32 = 0.05;
+
+/// Extra weight for the final note, as a proportion of the tune's total duration.
+const FINAL_NOTE_WEIGHT: f32 = 0.1;
+
+/// Don't warn about the declared key unless the estimate is at least this confident.
+const WARNING_CONFIDENCE: f32 = 0.6;
+
+/// Don't warn about the declared key unless the estimate fits this much better.
+const WARNING_MARGIN: f32 = 0.1;
+
+/// Histogram of the time spent on each pitch class, indexed by semitones above C.
+pub type Histogram = [f32; 12];
+
+/// Collects the sounding pitch class of every note, with phrase endings.
+struct PitchCollector {
+    accidentals: music::Accidentals,
+
+    /// Pitch class and duration of each note.
+    notes: Vec<(i16, f32)>,
+
+    /// Indexes of notes that end phrases.
+    phrase_endings: Vec<usize>,
+}
+
+impl visitor::Visitor for PitchCollector {
+    fn start_voice(&mut self, _voice: usize, state: &visitor::RunningState) {
+        self.accidentals = music::Accidentals::new(state.key);
+    }
+
+    fn visit_key_signature(&mut self, key: music::KeySignature, _state: &visitor::RunningState) {
+        self.accidentals.set_key(key);
+    }
+
+    fn visit_note(&mut self, note: &music::Note, _state: &visitor::RunningState) {
+        let &music::Note(pitch, music::FractionalDuration(numerator, denominator)) = note;
+        let pitch = self.accidentals.resolve(pitch);
+
+        self.notes.push((
+            pitch.pitch_class.semitones().rem_euclid(12),
+            numerator as f32 / denominator as f32,
+        ));
+    }
+
+    fn visit_barline(&mut self, barline: &l::T, _state: &visitor::RunningState) {
+        self.accidentals.end_bar();
+
+        match barline {
+            &l::T::DoubleBar | &l::T::EndBar | &l::T::CloseRepeat => {
+                if !self.notes.is_empty() {
+                    self.phrase_endings.push(self.notes.len() - 1);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn visit_n_time_bar(&mut self, _n: u32, _state: &visitor::RunningState) {
+        self.accidentals.end_bar();
+    }
+}
+
+/// Build a weighted pitch class histogram of a tune's notes.
+pub fn histogram(tune: &tune_ast_three::Tune) -> Histogram {
+    let mut collector = PitchCollector {
+        accidentals: music::Accidentals::new(visitor::RunningState::new().key),
+        notes: vec![],
+        phrase_endings: vec![],
+    };
+    visitor::walk(tune, &mut collector);
+
+    weighted_histogram(&collector.notes, &collector.phrase_endings)
+}
+
+/// Build a weighted pitch class histogram from the pitch class and duration of each note, and the
+/// indexes of notes that end phrases.
+pub fn weighted_histogram(notes: &[(i16, f32)], phrase_endings: &[usize]) -> Histogram {
+    let mut histogram = [0.0; 12];
+
+    for &(pitch_class, duration) in notes.iter() {
+        histogram[pitch_class as usize] += duration;
+    }
+
+    let total: f32 = histogram.iter().sum();
+
+    for index in phrase_endings.iter() {
+        let (pitch_class, _) = notes[*index];
+        histogram[pitch_class as usize] += total * PHRASE_ENDING_WEIGHT;
+    }
+
+    if let Some(&(pitch_class, _)) = notes.last() {
+        histogram[pitch_class as usize] += total * FINAL_NOTE_WEIGHT;
+    }
+
+    histogram
+}
+
+/// Profile of the expected weight of each pitch class in a key.
+fn profile(tonic: i16, mode: music::Mode) -> Histogram {
+    let mut profile = [0.0; 12];
+
+    for (degree, semitones) in scale(mode).iter().enumerate() {
+        profile[(tonic + semitones).rem_euclid(12) as usize] = DEGREE_WEIGHTS[degree];
+    }
+
+    profile
+}
+
+/// Pearson correlation of two histograms, from -1 to 1.
+fn correlation(a: &Histogram, b: &Histogram) -> f32 {
+    let mean_a = a.iter().sum::<f32>() / 12.0;
+    let mean_b = b.iter().sum::<f32>() / 12.0;
+
+    let mut covariance = 0.0;
+    let mut variance_a = 0.0;
+    let mut variance_b = 0.0;
+
+    for i in 0..12 {
+        covariance += (a[i] - mean_a) * (b[i] - mean_b);
+        variance_a += (a[i] - mean_a) * (a[i] - mean_a);
+        variance_b += (b[i] - mean_b) * (b[i] - mean_b);
+    }
+
+    if variance_a == 0.0 || variance_b == 0.0 {
+        0.0
+    } else {
+        covariance / (variance_a * variance_b).sqrt()
+    }
+}
+
+/// How well a histogram fits a key, from -1 to 1.
+pub fn score_key(histogram: &Histogram, key: music::KeySignature) -> f32 {
+    correlation(histogram, &profile(key.tonic.semitones(), key.mode))
+}
+
+/// An estimated key.
+#[derive(Debug, PartialEq, PartialOrd, Clone, Copy)]
+pub struct KeyEstimate {
+    pub key: music::KeySignature,
+
+    /// How well the notes fit the key, from 0 to 1.
+    pub confidence: f32,
+}
+
+/// All candidate keys, best first.
+pub fn rank_keys(histogram: &Histogram) -> Vec<KeyEstimate> {
+    let mut result = vec![];
+
+    for tonic in 0..12 {
+        for mode in MODES.iter() {
+            let key = music::KeySignature::from_semitones(tonic, *mode);
+
+            result.push(KeyEstimate {
+                key,
+                confidence: score_key(histogram, key).max(0.0),
+            });
+        }
+    }
+
+    // Sort is stable, so ties keep the order of preference.
+    result.sort_by(|a, b| {
+        b.confidence.partial_cmp(&a.confidence).unwrap_or(
+            ::std::cmp::Ordering::Equal,
+        )
+    });
+
+    result
+}
+
+/// Estimate the key of a tune. None if it has no notes.
+pub fn estimate_key(tune: &tune_ast_three::Tune) -> Option<KeyEstimate> {
+    let histogram = histogram(tune);
+
+    if histogram.iter().all(|x| *x == 0.0) {
+        return None;
+    }
+
+    rank_keys(&histogram).first().cloned()
+}
+
+/// A warning message if the declared key looks wrong.
+pub fn check_declared_key(tune: &tune_ast_three::Tune) -> Option<String> {
+    let declared = match text::TuneMetadata::from_tune(tune).key {
+        Some(key) => key,
+        None => return None,
+    };
+
+    let estimate = match estimate_key(tune) {
+        Some(estimate) => estimate,
+        None => return None,
+    };
+
+    let declared_score = score_key(&histogram(tune), declared);
+
+    if !estimate.key.equivalent(&declared) && estimate.confidence >= WARNING_CONFIDENCE &&
+        estimate.confidence - declared_score >= WARNING_MARGIN
+    {
+        Some(format!(
+            "The key is given as {} but the notes suggest {} (confidence {:.2}).",
+            text::format_key(declared),
+            text::format_key(estimate.key),
+            estimate.confidence
+        ))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_support::read;
+
+    fn key(
+        diatonic_pitch_class: music::DiatonicPitchClass,
+        mode: music::Mode,
+    ) -> music::KeySignature {
+        music::KeySignature {
+            tonic: music::PitchClass {
+                diatonic_pitch_class,
+                accidental: None,
+            },
+            mode,
+        }
+    }
+
+    #[test]
+    fn estimate_key_test() {
+        let tune = read("L:1/8\nK:G\nGABc dBGB|cAFA GBdB|GABc dBGB|cAFD G4|]\n");
+        assert!(
+            estimate_key(&tune)
+                .unwrap()
+                .key
+                .equivalent(&key(music::DiatonicPitchClass::G, music::Mode::Major))
+        );
+        assert_eq!(check_declared_key(&tune), None);
+
+        let tune = read("L:1/8\nK:Am\nA2cA eAcA|G2BG dGBG|A2cA eAcA|GBdB A4|]\n");
+        assert!(
+            estimate_key(&tune)
+                .unwrap()
+                .key
+                .equivalent(&key(music::DiatonicPitchClass::A, music::Mode::Minor))
+        );
+    }
+
+    #[test]
+    fn wrong_key_test() {
+        // E Dorian, with the key given as D major.
+        let tune = read("L:1/8\nK:D\n|:E2BE dEBE|E2BE AFDF|E2BE dEBc|1AFDF E4:|2AFDF E4||\n");
+
+        let estimate = estimate_key(&tune).unwrap();
+        assert!(
+            estimate.key.equivalent(&key(music::DiatonicPitchClass::E, music::Mode::Dorian)),
+            "{:?}",
+            estimate
+        );
+
+        assert!(check_declared_key(&tune).unwrap().contains("E Dorian"));
+    }
+
+    #[test]
+    fn accidentals_resolved_test() {
+        // F is sharp by key signature, and natural by accidental for the rest of the bar.
+        let tune = read("L:1/4\nK:G\nF=FF|F|\n");
+        let histogram = histogram(&tune);
+
+        // One note each, and the final note on F# gets extra weight.
+        assert_eq!(histogram[5], 2.0 * 0.25);
+        assert!(histogram[6] > 2.0 * 0.25);
+    }
+
+    #[test]
+    fn no_notes_test() {
+        assert_eq!(estimate_key(&read("K:G\n")), None);
+    }
+}
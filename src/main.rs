@@ -1,26 +1,43 @@
-use std::io::{self, Read};
+use std::fs;
+use std::io::{self, Read, Write};
 use std::env;
 extern crate tiny_http;
 extern crate regex;
 
 mod abc_lexer;
+mod abc_writer;
 mod archive;
 mod cluster;
+mod fingerprint;
 mod geometry;
+mod harmonise;
+mod index;
+mod json;
+mod key_detection;
 mod midi;
+mod midi_import;
 mod ngram;
 mod text;
+mod timeline;
+mod transpose;
+mod tune_type;
+mod visitor;
 // mod tune_ast;
 mod tune_ast_three;
 mod viz;
 mod music;
+mod playback;
 mod typeset;
 // mod typeset2;
 mod svg;
+mod synth;
 mod storage;
 mod server;
 mod application;
 mod relations;
+mod search;
+#[cfg(test)]
+mod test_support;
 
 /// Get STDIN as a string.
 fn get_stdin() -> String {
@@ -34,11 +51,10 @@ fn get_stdin() -> String {
     buffer
 }
 
-
-/// Check an ABC file, from STDIN to STDOUT.
-fn main_check(_application: &application::Application) {
-    let chars = get_stdin().chars().collect::<Vec<char>>();
-    let (num_errors, num_unshown, message) = abc_lexer::format_error_message_from_abc(&chars);
+/// Print any errors in the ABC to STDERR.
+/// Return true if there were errors.
+fn report_errors(chars: &[char]) -> bool {
+    let (num_errors, num_unshown, message) = abc_lexer::format_error_message_from_abc(chars);
 
     if num_errors > 0 {
         if num_errors == 1 {
@@ -53,32 +69,32 @@ fn main_check(_application: &application::Application) {
         if num_unshown > 0 {
             eprintln!("{} errors weren't shown", num_unshown);
         }
+    }
+
+    num_errors > 0
+}
+
+/// Check an ABC file, from STDIN to STDOUT.
+fn main_check(_application: &application::Application) {
+    let chars = get_stdin().chars().collect::<Vec<char>>();
+
+    if report_errors(&chars) {
         return;
     }
 
-    // let ast = tune_ast_two::read_from_lexer(abc_lexer::Lexer::new(&chars));
-    // println!("Tune: {:#?}", ast);
+    let ast = tune_ast_three::read_from_lexer(abc_lexer::Lexer::new(&chars));
+
+    if let Some(warning) = key_detection::check_declared_key(&ast) {
+        eprintln!("Warning: {}", warning);
+    }
 }
 
 
 /// Check an ABC file, from STDIN to STDOUT.
 fn main_typeset(_application: &application::Application) {
     let chars = get_stdin().chars().collect::<Vec<char>>();
-    let (num_errors, num_unshown, message) = abc_lexer::format_error_message_from_abc(&chars);
 
-    if num_errors > 0 {
-        if num_errors == 1 {
-            eprintln!("There was {} error!", num_errors);
-        } else {
-            eprintln!("There were {} errors!", num_errors);
-        }
-
-        eprintln!("{}", message);
-
-        // Don't expect this to happen but explain if it does.
-        if num_unshown > 0 {
-            eprintln!("{} errors weren't shown", num_unshown);
-        }
+    if report_errors(&chars) {
         return;
     }
 
@@ -94,29 +110,319 @@ fn main_typeset(_application: &application::Application) {
 /// Visualise an ABC file. Whatever that means.
 fn main_viz(_application: &application::Application) {
     let chars = get_stdin().chars().collect::<Vec<char>>();
-    let (num_errors, num_unshown, message) = abc_lexer::format_error_message_from_abc(&chars);
 
-    if num_errors > 0 {
-        if num_errors == 1 {
-            eprintln!("There was {} error!", num_errors);
+    if report_errors(&chars) {
+        return;
+    }
+
+    // let ast = tune_ast_two::read_from_lexer(abc_lexer::Lexer::new(&chars));
+
+    // let viz = viz::viz_from_ast(ast);
+
+    // println!("{}", viz);
+}
+
+/// Export an ABC file as JSON, from STDIN to STDOUT.
+fn main_json(_application: &application::Application) {
+    let chars = get_stdin().chars().collect::<Vec<char>>();
+
+    if report_errors(&chars) {
+        return;
+    }
+
+    let ast = tune_ast_three::read_from_lexer(abc_lexer::Lexer::new(&chars));
+
+    println!("{}", json::tune_to_json(&ast).render());
+}
+
+/// Add suggested accompaniment chords to an ABC file, from STDIN to STDOUT.
+fn main_harmonise(_application: &application::Application) {
+    let chars = get_stdin().chars().collect::<Vec<char>>();
+
+    if report_errors(&chars) {
+        return;
+    }
+
+    let ast = tune_ast_three::read_from_lexer(abc_lexer::Lexer::new(&chars));
+
+    print!("{}", abc_writer::write_tune(&harmonise::harmonise(&ast)));
+}
+
+/// Export an ABC file as a MIDI file, from STDIN to the given path, or STDOUT if there isn't one.
+/// Expressive playback rules are applied unless "--straight" is given.
+fn main_midi(_application: &application::Application, args: Vec<String>) {
+    let options = if args.iter().any(|arg| arg == "--straight") {
+        playback::Options::straight()
+    } else {
+        playback::Options::expressive()
+    };
+    let path = args.into_iter().find(|arg| !arg.starts_with("--"));
+
+    let chars = get_stdin().chars().collect::<Vec<char>>();
+
+    if report_errors(&chars) {
+        return;
+    }
+
+    let ast = tune_ast_three::read_from_lexer(abc_lexer::Lexer::new(&chars));
+    let bytes = midi::tune_to_bytes(&ast, &options);
+
+    let result = match path {
+        Some(path) => fs::write(&path, &bytes),
+        None => io::stdout().write_all(&bytes),
+    };
+
+    if let Err(error) = result {
+        eprintln!("Can't write MIDI file: {}", error);
+    }
+}
+
+/// Search the tune store for a melody, given as an ABC fragment, e.g. "GABc dedB".
+/// The fragment is read in C, unless a key is given with "--key", e.g. "--key D", or in a "K:"
+/// field. Results are restricted to a tune type with "--type", e.g. "--type reel".
+/// An option such as "--parsons" searches by another encoding, where a Parsons code is also
+/// accepted, e.g. "*UUDRD".
+fn main_search(application: &mut application::Application, args: Vec<String>) {
+    let mut encoding = ngram::Encoding::Interval;
+    let mut key = None;
+    let mut tune_type = None;
+    let mut words = vec![];
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "--key" {
+            match args.next() {
+                Some(value) => key = Some(value.as_str()),
+                None => {
+                    eprintln!("Search in what key? Give one after --key, e.g. --key D");
+                    return;
+                }
+            }
+        } else if arg == "--type" {
+            match args.next() {
+                Some(value) => tune_type = Some(value.as_str()),
+                None => {
+                    eprintln!(
+                        "Search for what type of tune? Give one after --type, e.g. --type reel"
+                    );
+                    return;
+                }
+            }
+        } else if let Some(name) = arg.strip_prefix("--") {
+            match ngram::Encoding::from_name(name) {
+                Some(value) => encoding = value,
+                None => {
+                    eprintln!("Unrecognised search option: {}", arg);
+                    return;
+                }
+            }
         } else {
-            eprintln!("There were {} errors!", num_errors);
+            words.push(arg.as_str());
         }
+    }
 
-        eprintln!("{}", message);
+    let query = words.join(" ");
 
-        // Don't expect this to happen but explain if it does.
-        if num_unshown > 0 {
-            eprintln!("{} errors weren't shown", num_unshown);
+    application.ensure_load_tunes();
+
+    match application.search(&query, key, tune_type, encoding, search::DEFAULT_LIMIT) {
+        Ok(results) => {
+            for result in results.iter() {
+                let bars = result
+                    .bars
+                    .iter()
+                    .map(|&(first, last)| if first == last {
+                        format!("{}", first)
+                    } else {
+                        format!("{}-{}", first, last)
+                    })
+                    .collect::<Vec<String>>()
+                    .join(", ");
+
+                let title = application
+                    .get_metadata(result.tune_id)
+                    .and_then(|metadata| metadata.titles.first().cloned())
+                    .unwrap_or_default();
+
+                println!("{}\t{:.3}\t{}\t{}", result.tune_id, result.score, bars, title);
+            }
         }
+        Err(error) => eprintln!("Can't search: {}", error),
+    }
+}
+
+/// Render an ABC file as a WAV file, from STDIN to the given path, or STDOUT if there isn't one.
+/// Expressive playback rules are applied unless "--straight" is given.
+fn main_wav(_application: &application::Application, args: Vec<String>) {
+    let options = if args.iter().any(|arg| arg == "--straight") {
+        playback::Options::straight()
+    } else {
+        playback::Options::expressive()
+    };
+    let path = args.into_iter().find(|arg| !arg.starts_with("--"));
+
+    let chars = get_stdin().chars().collect::<Vec<char>>();
+
+    if report_errors(&chars) {
         return;
     }
 
-    // let ast = tune_ast_two::read_from_lexer(abc_lexer::Lexer::new(&chars));
+    let ast = tune_ast_three::read_from_lexer(abc_lexer::Lexer::new(&chars));
+    let bytes = synth::tune_to_wav(&ast, &options);
 
-    // let viz = viz::viz_from_ast(ast);
+    let result = match path {
+        Some(path) => fs::write(&path, &bytes),
+        None => io::stdout().write_all(&bytes),
+    };
 
-    // println!("{}", viz);
+    if let Err(error) = result {
+        eprintln!("Can't write WAV file: {}", error);
+    }
+}
+
+/// Transcribe a MIDI file, from the given path or STDIN, to ABC on STDOUT.
+fn main_midi_import(_application: &application::Application, path: Option<String>) {
+    let mut bytes = vec![];
+
+    let result = match path {
+        Some(path) => fs::File::open(&path).and_then(|mut file| file.read_to_end(&mut bytes)),
+        None => io::stdin().read_to_end(&mut bytes),
+    };
+
+    if let Err(error) = result {
+        eprintln!("Can't read MIDI file: {}", error);
+        return;
+    }
+
+    match midi_import::import(&bytes) {
+        Ok(tune) => print!("{}", abc_writer::write_tune(&tune)),
+        Err(error) => eprintln!("Can't transcribe MIDI file: {}", error),
+    }
+}
+
+/// Transpose an ABC file, from STDIN to STDOUT.
+/// The target is a number of semitones, an interval or a key, e.g. "-2", "-P5" or "Bb".
+fn main_transpose(_application: &application::Application, target: Option<String>) {
+    let target = match target.as_ref().map(|target| transpose::Target::parse(target)) {
+        Some(Ok(target)) => target,
+        Some(Err(error)) => {
+            eprintln!("{}", error);
+            return;
+        }
+        None => {
+            eprintln!("Transpose to what? Give a number of semitones (e.g. -2), an interval (e.g. -P5) or a key (e.g. Bb).");
+            return;
+        }
+    };
+
+    let chars = get_stdin().chars().collect::<Vec<char>>();
+
+    if report_errors(&chars) {
+        return;
+    }
+
+    let ast = tune_ast_three::read_from_lexer(abc_lexer::Lexer::new(&chars));
+
+    print!("{}", abc_writer::write_tune(&transpose::transpose_to(&ast, target)));
+}
+
+/// Group the tunes in the tune store that are variants of each other, and write the groups to
+/// the given path, or to the tune store if there isn't one.
+/// Editors' links and never-links are honoured.
+fn main_cluster(application: &mut application::Application, path: Option<String>) {
+    application.ensure_load_tunes();
+
+    let links = match application.cluster(&cluster::Options::new()) {
+        Ok(links) => links,
+        Err(error) => {
+            eprintln!("Can't cluster: {}", error);
+            return;
+        }
+    };
+
+    if let Some(ref tune_store) = application.tune_store {
+        let path = match path {
+            Some(path) => std::path::PathBuf::from(path),
+            None => tune_store.path(cluster::GROUPS_FILENAME),
+        };
+
+        match cluster::write_groups(&tune_store.groups, &path) {
+            Ok(_) => eprintln!(
+                "Linked {} pairs into {} groups, written to {}",
+                links.len(),
+                tune_store.groups.num_groups(),
+                path.display()
+            ),
+            Err(error) => eprintln!("Can't write groups: {}", error),
+        }
+    }
+}
+
+/// List sets of tunes in the tune store with the same music, one per line, with the tune ids
+/// and the title of the first. Tunes whose ABC isn't identical to the first's are marked with "~".
+/// They're grouped together.
+fn main_dupes(application: &mut application::Application) {
+    application.ensure_load_tunes();
+
+    let sets = match application.find_duplicates() {
+        Ok(sets) => sets,
+        Err(error) => {
+            eprintln!("Can't find duplicates: {}", error);
+            return;
+        }
+    };
+
+    for set in sets.iter() {
+        let ids = set
+            .iter()
+            .map(|&(tune_id, exact)| if exact {
+                format!("{}", tune_id)
+            } else {
+                format!("~{}", tune_id)
+            })
+            .collect::<Vec<String>>()
+            .join(" ");
+
+        let title = application
+            .get_metadata(set[0].0)
+            .and_then(|metadata| metadata.titles.first().cloned())
+            .unwrap_or_default();
+
+        println!("{}\t{}", ids, title);
+    }
+
+    eprintln!(
+        "Found {} sets of duplicates, with {} tunes",
+        sets.len(),
+        sets.iter().map(|set| set.len()).sum::<usize>()
+    );
+}
+
+/// Record that two tunes are, or aren't, the same tune, with an optional note of who or why.
+/// This is kept when the tunes are clustered again.
+fn main_relate(
+    application: &mut application::Application,
+    kind: relations::Kind,
+    args: Vec<String>,
+) {
+    let ids = args.iter().take(2).filter_map(|arg| arg.parse::<u32>().ok()).collect::<Vec<u32>>();
+
+    if ids.len() != 2 {
+        eprintln!("Which tunes? Give two tune ids, e.g. {} 123 456", kind.name());
+        return;
+    }
+
+    let provenance = if args.len() > 2 {
+        args[2..].join(" ")
+    } else {
+        "manual".to_string()
+    };
+
+    application.ensure_load_tunes();
+
+    if let Err(error) = application.assert_relation(ids[0], ids[1], kind, &provenance) {
+        eprintln!("Can't record {}: {}", kind.name(), error);
+    }
 }
 
 fn main_scan(application: &mut application::Application) {
@@ -143,7 +449,20 @@ fn main_unrecognised(_application: &application::Application) {
  - db_server
  - check
  - typeset
- - viz"
+ - viz
+ - json
+ - transpose <semitones|interval|key>
+ - harmonise
+ - midi [--straight] [file.mid]
+ - midi_import [file.mid]
+ - wav [--straight] [file.wav]
+ - search [--interval|--parsons|--contour|--rhythm] [--key <key>] [--type <tune type>]
+   <abc fragment|parsons code>
+   (a fragment is read in C unless a key is given)
+ - cluster [groups file]
+ - link <tune id> <tune id> [note]
+ - never_link <tune id> <tune id> [note]
+ - dupes"
     );
 }
 
@@ -160,6 +479,19 @@ fn main() {
                 "check" => main_check(&application),
                 "typeset" => main_typeset(&application),
                 "viz" => main_viz(&application),
+                "json" => main_json(&application),
+                "transpose" => main_transpose(&application, args.next()),
+                "harmonise" => main_harmonise(&application),
+                "midi" => main_midi(&application, args.collect()),
+                "midi_import" => main_midi_import(&application, args.next()),
+                "wav" => main_wav(&application, args.collect()),
+                "search" => main_search(&mut application, args.collect()),
+                "cluster" => main_cluster(&mut application, args.next()),
+                "dupes" => main_dupes(&mut application),
+                "link" => main_relate(&mut application, relations::Kind::Link, args.collect()),
+                "never_link" => {
+                    main_relate(&mut application, relations::Kind::NeverLink, args.collect())
+                }
                 _ => main_unrecognised(&application),
             }
         }
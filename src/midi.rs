@@ -1,4 +1,720 @@
 //! Midi
-//! Write Tune ASTs into MIDI files.
+//! Write Tune ASTs into Standard MIDI Files, and read them back.
 
-// Placeholder.
+use abc_lexer as l;
+use music;
+use playback;
+use timeline;
+use tune_ast_three;
+
+/// MIDI clocks per crotchet, fixed by the standard.
+const CLOCKS_PER_CROTCHET: u32 = 24;
+
+/// Largest key signature MIDI can express.
+const MAX_SHARPS: i16 = 7;
+
+#[derive(Debug, PartialEq, PartialOrd, Clone, Copy)]
+pub enum Format {
+    /// Type 0, everything in one track.
+    Single,
+
+    /// Type 1, a conductor track followed by one track per voice.
+    Multiple,
+}
+
+#[derive(Debug, PartialEq, PartialOrd, Clone)]
+pub enum MidiEvent {
+    NoteOff { channel: u8, key: u8, velocity: u8 },
+    NoteOn { channel: u8, key: u8, velocity: u8 },
+    ProgramChange { channel: u8, program: u8 },
+
+    /// Microseconds per crotchet.
+    Tempo(u32),
+
+    TimeSignature {
+        numerator: u8,
+        /// Denominator as a power of two.
+        denominator_power: u8,
+        /// MIDI clocks per metronome click.
+        clocks_per_click: u8,
+    },
+
+    /// Sharps, or negative for flats, and whether the key is minor.
+    KeySignature { sharps: i8, minor: bool },
+
+    TrackName(String),
+    EndOfTrack,
+
+    /// Any other channel message, as the status byte and data.
+    OtherChannel(u8, Vec<u8>),
+
+    /// Any other meta event, as the type and data.
+    OtherMeta(u8, Vec<u8>),
+
+    SysEx(Vec<u8>),
+}
+
+impl MidiEvent {
+    /// Order of simultaneous events, so that notes end before the next ones start.
+    fn rank(&self) -> u8 {
+        match self {
+            &MidiEvent::EndOfTrack => 3,
+            &MidiEvent::NoteOn { .. } => 2,
+            &MidiEvent::NoteOff { .. } => 1,
+            _ => 0,
+        }
+    }
+
+    fn write(&self, bytes: &mut Vec<u8>) {
+        match self {
+            &MidiEvent::NoteOff { channel, key, velocity } => {
+                bytes.extend_from_slice(&[0x80 | channel, key, velocity])
+            }
+            &MidiEvent::NoteOn { channel, key, velocity } => {
+                bytes.extend_from_slice(&[0x90 | channel, key, velocity])
+            }
+            &MidiEvent::ProgramChange { channel, program } => {
+                bytes.extend_from_slice(&[0xC0 | channel, program])
+            }
+            &MidiEvent::Tempo(microseconds) => {
+                write_meta(bytes, 0x51, &(microseconds.to_be_bytes()[1..]))
+            }
+            &MidiEvent::TimeSignature {
+                numerator,
+                denominator_power,
+                clocks_per_click,
+            } => write_meta(
+                bytes,
+                0x58,
+                &[numerator, denominator_power, clocks_per_click, 8],
+            ),
+            &MidiEvent::KeySignature { sharps, minor } => {
+                write_meta(bytes, 0x59, &[sharps as u8, minor as u8])
+            }
+            &MidiEvent::TrackName(ref name) => write_meta(bytes, 0x03, name.as_bytes()),
+            &MidiEvent::EndOfTrack => write_meta(bytes, 0x2F, &[]),
+            &MidiEvent::OtherChannel(status, ref data) => {
+                bytes.push(status);
+                bytes.extend_from_slice(data);
+            }
+            &MidiEvent::OtherMeta(meta_type, ref data) => write_meta(bytes, meta_type, data),
+            &MidiEvent::SysEx(ref data) => {
+                bytes.push(0xF0);
+                write_variable_length(bytes, data.len() as u32);
+                bytes.extend_from_slice(data);
+            }
+        }
+    }
+}
+
+/// A track, as events at absolute times in ticks.
+#[derive(Debug, PartialEq, PartialOrd, Clone)]
+pub struct Track {
+    pub events: Vec<(u32, MidiEvent)>,
+}
+
+impl Track {
+    pub fn new() -> Track {
+        Track { events: vec![] }
+    }
+
+    /// Sort events into playing order and finish with an end of track.
+    fn finish(&mut self, length: u32) {
+        let end = self.events.iter().map(|&(time, _)| time).max().unwrap_or(0);
+        self.events.push((u32::max(end, length), MidiEvent::EndOfTrack));
+
+        self.events.sort_by_key(|&(time, ref event)| (time, event.rank()));
+    }
+
+    fn write(&self, bytes: &mut Vec<u8>) {
+        let mut data = vec![];
+        let mut previous = 0;
+
+        for &(time, ref event) in self.events.iter() {
+            write_variable_length(&mut data, time - previous);
+            event.write(&mut data);
+            previous = time;
+        }
+
+        bytes.extend_from_slice(b"MTrk");
+        bytes.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&data);
+    }
+}
+
+/// A Standard MIDI File.
+#[derive(Debug, PartialEq, PartialOrd, Clone)]
+pub struct Smf {
+    pub format: Format,
+
+    /// Ticks per crotchet.
+    pub division: u16,
+
+    pub tracks: Vec<Track>,
+}
+
+impl Smf {
+    /// Build a MIDI file from a tune, with repeats expanded.
+    /// Each voice plays on its own channel, as does any accompaniment.
+    #[cfg(test)]
+    pub fn from_tune(
+        tune: &tune_ast_three::Tune,
+        format: Format,
+        options: &playback::Options,
+    ) -> Smf {
+        Smf::from_timeline(&timeline::timeline(tune, options), title(tune), format)
+    }
+
+    fn from_timeline(timeline: &timeline::Timeline, title: Option<String>, format: Format) -> Smf {
+        let mut conductor = Track::new();
+        let mut voices = vec![Track::new(); timeline.tracks];
+
+        if let Some(title) = title {
+            conductor.events.push((0, MidiEvent::TrackName(title)));
+        }
+
+        for event in timeline.events.iter() {
+            match event.event {
+                timeline::Event::Note {
+                    track,
+                    channel,
+                    midi_number,
+                    velocity,
+                    duration,
+                } => {
+                    voices[track].events.push((
+                        event.time,
+                        MidiEvent::NoteOn {
+                            channel,
+                            key: midi_number,
+                            velocity,
+                        },
+                    ));
+                    voices[track].events.push((
+                        event.time + duration,
+                        MidiEvent::NoteOff {
+                            channel,
+                            key: midi_number,
+                            velocity: 0,
+                        },
+                    ));
+                }
+
+                timeline::Event::Program {
+                    track,
+                    channel,
+                    program,
+                } => voices[track].events.push((
+                    event.time,
+                    MidiEvent::ProgramChange { channel, program },
+                )),
+
+                timeline::Event::Tempo { microseconds_per_crotchet } => {
+                    conductor
+                        .events
+                        .push((event.time, MidiEvent::Tempo(microseconds_per_crotchet)))
+                }
+
+                timeline::Event::Metre(metre) => {
+                    conductor.events.push((event.time, time_signature(metre)))
+                }
+
+                timeline::Event::Key(key) => {
+                    conductor.events.push((event.time, key_signature(key)))
+                }
+            }
+        }
+
+        let tracks = match format {
+            Format::Single => {
+                for mut voice in voices {
+                    conductor.events.append(&mut voice.events);
+                }
+                conductor.finish(timeline.length);
+                vec![conductor]
+            }
+            Format::Multiple => {
+                let mut tracks = vec![conductor];
+                tracks.append(&mut voices);
+                for track in tracks.iter_mut() {
+                    track.finish(timeline.length);
+                }
+                tracks
+            }
+        };
+
+        Smf {
+            format,
+            division: timeline::TICKS_PER_CROTCHET as u16,
+            tracks,
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+
+        let format: u16 = match self.format {
+            Format::Single => 0,
+            Format::Multiple => 1,
+        };
+
+        bytes.extend_from_slice(b"MThd");
+        bytes.extend_from_slice(&6u32.to_be_bytes());
+        bytes.extend_from_slice(&format.to_be_bytes());
+        bytes.extend_from_slice(&(self.tracks.len() as u16).to_be_bytes());
+        bytes.extend_from_slice(&self.division.to_be_bytes());
+
+        for track in self.tracks.iter() {
+            track.write(&mut bytes);
+        }
+
+        bytes
+    }
+
+    /// Read a MIDI file. Note ons with zero velocity are read as note offs.
+    /// Only metrical divisions are supported, not SMPTE.
+    pub fn parse(bytes: &[u8]) -> Result<Smf, String> {
+        let mut reader = Reader { bytes, i: 0 };
+
+        if reader.take(4)? != b"MThd" {
+            return Err("Not a MIDI file.".to_string());
+        }
+
+        let header_length = reader.u32()? as usize;
+        let header_start = reader.i;
+
+        let format = match reader.u16()? {
+            0 => Format::Single,
+            1 => Format::Multiple,
+            other => return Err(format!("Unsupported MIDI format {}.", other)),
+        };
+
+        let num_tracks = reader.u16()?;
+        let division = reader.u16()?;
+
+        if division & 0x8000 != 0 {
+            return Err("SMPTE time division isn't supported.".to_string());
+        }
+
+        if division == 0 {
+            return Err("Time division of zero ticks.".to_string());
+        }
+
+        reader.i = header_start + header_length;
+
+        let mut tracks = vec![];
+
+        while tracks.len() < num_tracks as usize {
+            let chunk_type = reader.take(4)?;
+            let length = reader.u32()? as usize;
+            let data = reader.take(length)?;
+
+            // Unknown chunks must be skipped.
+            if chunk_type == b"MTrk" {
+                tracks.push(parse_track(data)?);
+            }
+        }
+
+        Ok(Smf {
+            format,
+            division,
+            tracks,
+        })
+    }
+}
+
+fn title(tune: &tune_ast_three::Tune) -> Option<String> {
+    tune.prelude.iter().find_map(|token| match token {
+        &l::T::Title(ref title) => Some(title.clone()),
+        _ => None,
+    })
+}
+
+fn time_signature(metre: music::Metre) -> MidiEvent {
+    let music::Metre(numerator, denominator) = metre;
+    let beat = metre.beat();
+
+    MidiEvent::TimeSignature {
+        numerator: numerator as u8,
+        denominator_power: (31 - u32::max(denominator, 1).leading_zeros()) as u8,
+        clocks_per_click: (CLOCKS_PER_CROTCHET * 4 * beat.0 / u32::max(beat.1, 1)) as u8,
+    }
+}
+
+/// MIDI only has major and minor keys, so other modes are given as the major key with the same
+/// signature.
+fn key_signature(key: music::KeySignature) -> MidiEvent {
+    let sharps = key.fifths();
+
+    MidiEvent::KeySignature {
+        sharps: i16::max(-MAX_SHARPS, i16::min(MAX_SHARPS, sharps)) as i8,
+        minor: match key.mode {
+            music::Mode::Minor | music::Mode::Aeolian => true,
+            _ => false,
+        },
+    }
+}
+
+fn write_meta(bytes: &mut Vec<u8>, meta_type: u8, data: &[u8]) {
+    bytes.push(0xFF);
+    bytes.push(meta_type);
+    write_variable_length(bytes, data.len() as u32);
+    bytes.extend_from_slice(data);
+}
+
+/// Write a number as a variable length quantity, 7 bits per byte, most significant first.
+fn write_variable_length(bytes: &mut Vec<u8>, value: u32) {
+    let mut groups = vec![(value & 0x7F) as u8];
+    let mut rest = value >> 7;
+
+    while rest > 0 {
+        groups.push((rest & 0x7F) as u8 | 0x80);
+        rest >>= 7;
+    }
+
+    bytes.extend(groups.iter().rev());
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    i: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn is_finished(&self) -> bool {
+        self.i >= self.bytes.len()
+    }
+
+    fn take(&mut self, length: usize) -> Result<&'a [u8], String> {
+        if self.i + length > self.bytes.len() {
+            return Err("Unexpected end of MIDI data.".to_string());
+        }
+
+        let result = &self.bytes[self.i..self.i + length];
+        self.i += length;
+        Ok(result)
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, String> {
+        let bytes = self.take(2)?;
+        Ok((bytes[0] as u16) << 8 | bytes[1] as u16)
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        let bytes = self.take(4)?;
+        Ok(bytes.iter().fold(0, |acc, &byte| acc << 8 | byte as u32))
+    }
+
+    fn variable_length(&mut self) -> Result<u32, String> {
+        let mut value: u32 = 0;
+
+        // At most four bytes.
+        for _ in 0..4 {
+            let byte = self.u8()?;
+            value = value << 7 | (byte & 0x7F) as u32;
+
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+
+        Err("Variable length quantity too long.".to_string())
+    }
+}
+
+fn parse_track(data: &[u8]) -> Result<Track, String> {
+    let mut reader = Reader { bytes: data, i: 0 };
+    let mut track = Track::new();
+    let mut time = 0;
+
+    // Channel messages can omit a repeated status byte.
+    let mut running_status: Option<u8> = None;
+
+    while !reader.is_finished() {
+        time += reader.variable_length()?;
+
+        let status = match reader.bytes.get(reader.i) {
+            None => return Err("Unexpected end of MIDI data.".to_string()),
+            Some(&byte) if byte & 0x80 != 0 => {
+                reader.i += 1;
+                byte
+            }
+            Some(_) => match running_status {
+                Some(status) => status,
+                None => return Err("Data byte without a status.".to_string()),
+            },
+        };
+
+        let event = match status {
+            0xFF => {
+                running_status = None;
+                let meta_type = reader.u8()?;
+                let length = reader.variable_length()? as usize;
+                let data = reader.take(length)?;
+
+                match (meta_type, data.len()) {
+                    (0x03, _) => MidiEvent::TrackName(String::from_utf8_lossy(data).to_string()),
+                    (0x2F, _) => MidiEvent::EndOfTrack,
+                    (0x51, 3) => MidiEvent::Tempo(
+                        (data[0] as u32) << 16 | (data[1] as u32) << 8 | data[2] as u32,
+                    ),
+                    (0x58, 4) => MidiEvent::TimeSignature {
+                        numerator: data[0],
+                        denominator_power: data[1],
+                        clocks_per_click: data[2],
+                    },
+                    (0x59, 2) => MidiEvent::KeySignature {
+                        sharps: data[0] as i8,
+                        minor: data[1] != 0,
+                    },
+                    _ => MidiEvent::OtherMeta(meta_type, data.to_vec()),
+                }
+            }
+
+            0xF0 | 0xF7 => {
+                running_status = None;
+                let length = reader.variable_length()? as usize;
+                MidiEvent::SysEx(reader.take(length)?.to_vec())
+            }
+
+            status if status >= 0xF0 => {
+                return Err(format!("Unexpected status byte {:X} in track.", status))
+            }
+
+            status => {
+                running_status = Some(status);
+                let channel = status & 0x0F;
+
+                match status & 0xF0 {
+                    0x80 => MidiEvent::NoteOff {
+                        channel,
+                        key: reader.u8()?,
+                        velocity: reader.u8()?,
+                    },
+                    0x90 => {
+                        let key = reader.u8()?;
+                        let velocity = reader.u8()?;
+
+                        if velocity == 0 {
+                            MidiEvent::NoteOff {
+                                channel,
+                                key,
+                                velocity,
+                            }
+                        } else {
+                            MidiEvent::NoteOn {
+                                channel,
+                                key,
+                                velocity,
+                            }
+                        }
+                    }
+                    0xC0 => MidiEvent::ProgramChange {
+                        channel,
+                        program: reader.u8()?,
+                    },
+                    // Channel pressure has one data byte.
+                    0xD0 => MidiEvent::OtherChannel(status, reader.take(1)?.to_vec()),
+                    _ => MidiEvent::OtherChannel(status, reader.take(2)?.to_vec()),
+                }
+            }
+        };
+
+        let finished = event == MidiEvent::EndOfTrack;
+        track.events.push((time, event));
+
+        if finished {
+            break;
+        }
+    }
+
+    Ok(track)
+}
+
+/// Export a tune as a MIDI file.
+/// Tunes with more than one voice or with accompaniment get a track for each.
+pub fn tune_to_bytes(tune: &tune_ast_three::Tune, options: &playback::Options) -> Vec<u8> {
+    let timeline = timeline::timeline(tune, options);
+
+    let format = if timeline.tracks > 1 {
+        Format::Multiple
+    } else {
+        Format::Single
+    };
+
+    Smf::from_timeline(&timeline, title(tune), format).to_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_support::read;
+
+    #[test]
+    fn variable_length_test() {
+        for &(value, ref expected) in [
+            (0, vec![0x00]),
+            (0x7F, vec![0x7F]),
+            (0x80, vec![0x81, 0x00]),
+            (0x3FFF, vec![0xFF, 0x7F]),
+            (0x0FFFFFFF, vec![0xFF, 0xFF, 0xFF, 0x7F]),
+        ].iter()
+        {
+            let mut bytes = vec![];
+            write_variable_length(&mut bytes, value);
+            assert_eq!(&bytes, expected);
+
+            let mut reader = Reader { bytes: &bytes, i: 0 };
+            assert_eq!(reader.variable_length(), Ok(value));
+        }
+    }
+
+    #[test]
+    fn midi_round_trip_test() {
+        let tune = read("T:Test\nM:6/8\nL:1/8\nQ:3/8=100\nK:Bb\n|:Bcd:|e3|]\n");
+        let bytes = tune_to_bytes(&tune, &playback::Options::straight());
+
+        assert_eq!(&bytes[0..4], b"MThd");
+
+        let smf = Smf::parse(&bytes).unwrap();
+        assert_eq!(
+            smf,
+            Smf::from_tune(&tune, Format::Single, &playback::Options::straight())
+        );
+        assert_eq!(smf.division, 480);
+        assert_eq!(smf.tracks.len(), 1);
+
+        let events = &smf.tracks[0].events;
+
+        assert!(events.contains(&(0, MidiEvent::TrackName("Test".to_string()))));
+        assert!(events.contains(&(0, MidiEvent::Tempo(400_000))));
+        assert!(events.contains(&(
+            0,
+            MidiEvent::TimeSignature {
+                numerator: 6,
+                denominator_power: 3,
+                clocks_per_click: 36,
+            },
+        )));
+        assert!(events.contains(&(
+            0,
+            MidiEvent::KeySignature {
+                sharps: -2,
+                minor: false,
+            },
+        )));
+
+        // The repeat is expanded and the key's flats are applied.
+        let note_ons = events
+            .iter()
+            .filter_map(|&(time, ref event)| match event {
+                &MidiEvent::NoteOn { key, .. } => Some((time, key)),
+                _ => None,
+            })
+            .collect::<Vec<(u32, u8)>>();
+
+        assert_eq!(
+            note_ons,
+            vec![
+                (0, 70),
+                (240, 72),
+                (480, 74),
+                (720, 70),
+                (960, 72),
+                (1200, 74),
+                (1440, 75),
+            ]
+        );
+
+        assert_eq!(events.last(), Some(&(2160, MidiEvent::EndOfTrack)));
+    }
+
+    #[test]
+    fn midi_multiple_tracks_test() {
+        let mut tune = read("X:1\nK:Am\nA2|\n");
+
+        // The reader only builds one voice, so add a second.
+        let voice = tune.voices[0].clone();
+        tune.voices.push(voice);
+
+        let smf = Smf::parse(&tune_to_bytes(&tune, &playback::Options::straight())).unwrap();
+
+        assert_eq!(smf.format, Format::Multiple);
+        assert_eq!(smf.tracks.len(), tune.voices.len() + 1);
+
+        assert!(smf.tracks[0].events.contains(&(
+            0,
+            MidiEvent::KeySignature {
+                sharps: 0,
+                minor: true,
+            },
+        )));
+
+        // Each voice has its own track and channel.
+        for (voice, track) in smf.tracks[1..].iter().enumerate() {
+            assert!(track.events.iter().any(|&(_, ref event)| match event {
+                &MidiEvent::NoteOn { channel: c, .. } => c == timeline::voice_channel(voice),
+                _ => false,
+            }));
+        }
+    }
+
+    #[test]
+    fn midi_running_status_test() {
+        // Note on, then a note off as a zero velocity note on with running status.
+        let mut bytes = vec![];
+        bytes.extend_from_slice(b"MThd\x00\x00\x00\x06\x00\x00\x00\x01\x00\x60");
+        bytes.extend_from_slice(b"MTrk\x00\x00\x00\x0C");
+        bytes.extend_from_slice(&[0x00, 0x90, 0x3C, 0x40, 0x60, 0x3C, 0x00, 0x00, 0xFF, 0x2F, 0x00]);
+
+        // Chunk length counts the bytes above.
+        let length = bytes.len() - 22;
+        bytes[21] = length as u8;
+
+        let smf = Smf::parse(&bytes).unwrap();
+
+        assert_eq!(
+            smf.tracks[0].events,
+            vec![
+                (
+                    0,
+                    MidiEvent::NoteOn {
+                        channel: 0,
+                        key: 60,
+                        velocity: 0x40,
+                    },
+                ),
+                (
+                    0x60,
+                    MidiEvent::NoteOff {
+                        channel: 0,
+                        key: 60,
+                        velocity: 0,
+                    },
+                ),
+                (0x60, MidiEvent::EndOfTrack),
+            ]
+        );
+
+        assert!(Smf::parse(b"RIFF").is_err());
+    }
+
+    #[test]
+    fn midi_malformed_test() {
+        // A track that ends after a delta time.
+        let truncated = b"MThd\x00\x00\x00\x06\x00\x00\x00\x01\x00\x60MTrk\x00\x00\x00\x01\x00";
+        assert!(Smf::parse(truncated).is_err());
+
+        // No ticks per crotchet.
+        let no_division = b"MThd\x00\x00\x00\x06\x00\x00\x00\x00\x00\x00";
+        assert!(Smf::parse(no_division).is_err());
+
+        // A metre without a denominator still makes a file.
+        let tune = read("M:3/0\nL:1/8\nK:G\nA/0B|c\n");
+        assert!(Smf::parse(&tune_to_bytes(&tune, &playback::Options::expressive())).is_ok());
+    }
+}
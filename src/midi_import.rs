@@ -0,0 +1,529 @@
+//! MIDI Import
+//! Transcribe a Standard MIDI File, e.g. one recorded on a keyboard, into a tune.
+//! Only the melody is kept: where notes start together, the highest is taken, and ornaments are
+//! dropped. The AST has no rests or ties yet, so silences lengthen the previous note and notes
+//! across a barline are split.
+
+use std::collections::HashMap;
+
+use abc_lexer as l;
+use key_detection;
+use midi;
+use music;
+use tune_ast_three;
+
+/// Notes shorter than this aren't played deliberately, so the grid is never finer.
+const MIN_GRID_SECONDS: f64 = 0.09;
+
+/// Nor is the grid ever finer than a semiquaver.
+const FINEST_GRID: music::FractionalDuration = music::FractionalDuration(1, 16);
+
+const BARS_PER_LINE: u32 = 4;
+
+/// Percussion doesn't belong in the melody.
+const PERCUSSION_CHANNEL: u8 = 9;
+
+/// A note as recorded, in ticks.
+#[derive(Debug, PartialEq, PartialOrd, Clone, Copy)]
+struct RecordedNote {
+    start: u32,
+    end: u32,
+    key: u8,
+}
+
+/// A note quantised to the grid, in grid units.
+#[derive(Debug, PartialEq, PartialOrd, Clone, Copy)]
+struct GridNote {
+    onset: u32,
+    length: u32,
+    key: u8,
+}
+
+/// The parts of a MIDI file that matter for transcription.
+/// Where there are several tempos, metres or keys, the first is used.
+struct Recording {
+    title: Option<String>,
+    metre: Option<music::Metre>,
+    microseconds_per_crotchet: Option<u32>,
+    key: Option<music::KeySignature>,
+    notes: Vec<RecordedNote>,
+}
+
+fn read_recording(smf: &midi::Smf) -> Recording {
+    let mut recording = Recording {
+        title: None,
+        metre: None,
+        microseconds_per_crotchet: None,
+        key: None,
+        notes: vec![],
+    };
+
+    for (track_number, track) in smf.tracks.iter().enumerate() {
+        // Start of each sounding note, by channel and key.
+        let mut sounding: HashMap<(u8, u8), u32> = HashMap::new();
+
+        for &(time, ref event) in track.events.iter() {
+            match event {
+                &midi::MidiEvent::NoteOn { channel, key, .. } if channel != PERCUSSION_CHANNEL => {
+                    // A repeated note on ends the previous one.
+                    if let Some(start) = sounding.insert((channel, key), time) {
+                        recording.notes.push(RecordedNote {
+                            start,
+                            end: time,
+                            key,
+                        });
+                    }
+                }
+
+                &midi::MidiEvent::NoteOff { channel, key, .. } => {
+                    if let Some(start) = sounding.remove(&(channel, key)) {
+                        recording.notes.push(RecordedNote {
+                            start,
+                            end: time,
+                            key,
+                        });
+                    }
+                }
+
+                &midi::MidiEvent::Tempo(microseconds) => {
+                    recording.microseconds_per_crotchet =
+                        recording.microseconds_per_crotchet.or(Some(microseconds));
+                }
+
+                &midi::MidiEvent::TimeSignature {
+                    numerator,
+                    denominator_power,
+                    ..
+                } if recording.metre.is_none() && numerator > 0 && denominator_power < 8 => {
+                    recording.metre = Some(music::Metre(
+                        numerator as u32,
+                        1 << denominator_power as u32,
+                    ));
+                }
+
+                &midi::MidiEvent::KeySignature { sharps, minor } if recording.key.is_none() => {
+                    recording.key = Some(key_from_sharps(sharps, minor));
+                }
+
+                // Other tracks' names are usually instrument names.
+                &midi::MidiEvent::TrackName(ref name)
+                    if track_number == 0 && recording.title.is_none() && !name.trim().is_empty() =>
+                {
+                    recording.title = Some(name.trim().to_string());
+                }
+
+                _ => (),
+            }
+        }
+    }
+
+    recording
+}
+
+/// Key from a MIDI key signature.
+fn key_from_sharps(sharps: i8, minor: bool) -> music::KeySignature {
+    // Each sharp moves the major tonic up a fifth. The relative minor is a minor third below.
+    let major_tonic = (sharps as i16 * 7).rem_euclid(12);
+
+    if minor {
+        music::KeySignature::from_semitones(major_tonic - 3, music::Mode::Minor)
+    } else {
+        music::KeySignature::from_semitones(major_tonic, music::Mode::Major)
+    }
+}
+
+/// Choose the grid: the beat, subdivided while the subdivisions are long enough at the tempo.
+fn choose_grid(metre: music::Metre, microseconds_per_crotchet: u32) -> music::FractionalDuration {
+    let seconds = |duration: music::FractionalDuration| {
+        duration.0 as f64 / duration.1 as f64 * 4.0 * microseconds_per_crotchet as f64 / 1_000_000.0
+    };
+
+    let mut grid = metre.beat();
+
+    // Compound beats divide into three, then everything into two.
+    let mut divisor = if grid.0 == 3 { 3 } else { 2 };
+
+    loop {
+        let next = grid.divide(music::FractionalDuration(divisor, 1));
+
+        if !next.gte(&FINEST_GRID) || seconds(next) < MIN_GRID_SECONDS {
+            return grid;
+        }
+
+        grid = next;
+        divisor = 2;
+    }
+}
+
+/// How many grid units make up a duration, to the nearest.
+fn units(duration: music::FractionalDuration, grid: music::FractionalDuration) -> u32 {
+    let music::FractionalDuration(numerator, denominator) = duration.divide(grid);
+    (numerator + denominator / 2) / denominator
+}
+
+/// Drop ornaments, such as grace notes: notes shorter than the grid that run straight into the
+/// next note. That note takes their start, as it would have started there as written.
+fn drop_ornaments(notes: &[RecordedNote], grid_ticks: f64) -> Vec<RecordedNote> {
+    let mut notes = notes.to_vec();
+    notes.sort_by_key(|note| note.start);
+
+    // Measure before any starts move.
+    let short: Vec<bool> = notes
+        .iter()
+        .map(|note| (note.end.saturating_sub(note.start) as f64) < grid_ticks)
+        .collect();
+
+    let mut ornament = vec![false; notes.len()];
+
+    for i in 0..notes.len() {
+        let note = notes[i];
+        let next = (i + 1..notes.len()).find(|&j| notes[j].start > note.start);
+
+        if let Some(j) = next {
+            if short[i] && notes[j].start as f64 <= note.end as f64 + grid_ticks / 2.0 {
+                notes[j].start = note.start;
+                ornament[i] = true;
+            }
+        }
+    }
+
+    notes
+        .iter()
+        .zip(ornament.iter())
+        .filter(|&(_, &ornament)| !ornament)
+        .map(|(note, _)| *note)
+        .collect()
+}
+
+/// Quantise notes to the grid, keeping the highest of notes that start together.
+/// Each note lasts until the next one starts. Leading empty bars are dropped.
+fn quantise(notes: &[RecordedNote], grid_ticks: f64, bar_units: u32) -> Vec<GridNote> {
+    let to_units = |ticks: u32| (ticks as f64 / grid_ticks).round() as u32;
+
+    let mut onsets: Vec<(u32, u8, u32)> = notes
+        .iter()
+        .map(|note| (to_units(note.start), note.key, to_units(note.end)))
+        .collect();
+
+    // Highest first within each onset, so that dedup keeps it.
+    onsets.sort_by(|a, b| a.0.cmp(&b.0).then(b.1.cmp(&a.1)));
+    onsets.dedup_by_key(|note| note.0);
+
+    let first_bar = match onsets.first() {
+        Some(&(onset, _, _)) => onset / bar_units,
+        None => return vec![],
+    };
+
+    let mut result = vec![];
+
+    for (i, &(onset, key, end)) in onsets.iter().enumerate() {
+        let length = match onsets.get(i + 1) {
+            Some(&(next, _, _)) => next - onset,
+            None => u32::max(end.saturating_sub(onset), 1),
+        };
+
+        result.push(GridNote {
+            onset: onset - first_bar * bar_units,
+            length,
+            key,
+        });
+    }
+
+    result
+}
+
+/// The default note length: the most common note length, as a crotchet, quaver or semiquaver.
+fn choose_default_length(
+    notes: &[GridNote],
+    grid: music::FractionalDuration,
+) -> music::FractionalDuration {
+    let mut counts: HashMap<u32, usize> = HashMap::new();
+    for note in notes.iter() {
+        *counts.entry(note.length).or_insert(0) += 1;
+    }
+
+    // Prefer the shorter of equally common lengths, for a stable choice.
+    let most_common = counts
+        .iter()
+        .max_by(|a, b| a.1.cmp(b.1).then(b.0.cmp(a.0)))
+        .map(|(length, _)| *length)
+        .unwrap_or(1);
+
+    let duration = grid.multiply(music::FractionalDuration(most_common, 1));
+
+    if duration.gte(&music::FractionalDuration(1, 4)) {
+        music::FractionalDuration(1, 4)
+    } else if duration.gte(&music::FractionalDuration(1, 8)) {
+        music::FractionalDuration(1, 8)
+    } else {
+        music::FractionalDuration(1, 16)
+    }
+}
+
+/// Estimate the key from the time spent on each pitch class.
+fn estimate_key(notes: &[GridNote]) -> music::KeySignature {
+    let pitch_classes = notes
+        .iter()
+        .map(|note| ((note.key % 12) as i16, note.length as f32))
+        .collect::<Vec<(i16, f32)>>();
+
+    let histogram = key_detection::weighted_histogram(&pitch_classes, &[]);
+
+    match key_detection::rank_keys(&histogram).first() {
+        Some(estimate) => estimate.key,
+        None => music::KeySignature::from_semitones(0, music::Mode::Major),
+    }
+}
+
+/// Spell a note in the key. In minor keys, the note below the tonic is the raised seventh,
+/// e.g. C# rather than Db in D minor, as melodies lead up to the tonic.
+fn spell(number: u8, key: music::KeySignature) -> music::Pitch {
+    let above_tonic = (number as i16 - key.tonic.semitones()).rem_euclid(12);
+
+    if key.mode == music::Mode::Minor && above_tonic == 11 && number > 0 {
+        // The natural seventh is a tone below the tonic, so is in the key.
+        let mut seventh = music::Pitch::from_midi_number(number - 1, key);
+
+        let raised = seventh.pitch_class.accidental.map_or(0, |accidental| accidental.semitones()) + 1;
+
+        match music::Accidental::from_semitones(raised) {
+            Some(music::Accidental::Natural) => seventh.pitch_class.accidental = None,
+            Some(accidental) => seventh.pitch_class.accidental = Some(accidental),
+            None => return music::Pitch::from_midi_number(number, key),
+        }
+
+        return seventh;
+    }
+
+    music::Pitch::from_midi_number(number, key)
+}
+
+/// Write out the notes with barlines, beam breaks at each beat and line breaks every few bars.
+fn body(
+    notes: &[GridNote],
+    grid: music::FractionalDuration,
+    bar_units: u32,
+    beat_units: u32,
+    key: music::KeySignature,
+) -> Vec<l::T> {
+    let mut tokens = vec![];
+    let mut accidentals = music::Accidentals::new(key);
+    let mut bars = 0;
+
+    for note in notes.iter() {
+        let mut onset = note.onset;
+        let mut remaining = note.length;
+
+        while remaining > 0 {
+            let position = onset % bar_units;
+
+            if position == 0 && !tokens.is_empty() {
+                tokens.push(l::T::BeamBreak);
+                tokens.push(l::T::SingleBar);
+                accidentals.end_bar();
+
+                bars += 1;
+                if bars % BARS_PER_LINE == 0 {
+                    tokens.push(l::T::Newline);
+                }
+            } else if position % beat_units == 0 && !tokens.is_empty() {
+                tokens.push(l::T::BeamBreak);
+            }
+
+            let length = u32::min(remaining, bar_units - position);
+            let pitch = spell(note.key, key);
+
+            tokens.push(l::T::Note(music::Note(
+                accidentals.notate(pitch),
+                grid.multiply(music::FractionalDuration(length, 1)),
+            )));
+
+            onset += length;
+            remaining -= length;
+        }
+    }
+
+    tokens.push(l::T::BeamBreak);
+    tokens.push(l::T::EndBar);
+    tokens.push(l::T::Newline);
+
+    tokens
+}
+
+/// Transcribe a MIDI file into a tune.
+pub fn smf_to_tune(smf: &midi::Smf) -> Result<tune_ast_three::Tune, String> {
+    let recording = read_recording(smf);
+
+    if recording.notes.is_empty() {
+        return Err("There are no notes in the MIDI file.".to_string());
+    }
+
+    let metre = recording.metre.unwrap_or(music::Metre(4, 4));
+    let microseconds_per_crotchet = recording.microseconds_per_crotchet.unwrap_or(500_000);
+
+    let grid = choose_grid(metre, microseconds_per_crotchet);
+    let grid_ticks = smf.division as f64 * 4.0 * grid.0 as f64 / grid.1 as f64;
+    let bar_units = u32::max(units(metre.bar(), grid), 1);
+    let beat_units = u32::max(units(metre.beat(), grid), 1);
+
+    let notes = drop_ornaments(&recording.notes, grid_ticks);
+    let notes = quantise(&notes, grid_ticks, bar_units);
+    let key = recording.key.unwrap_or_else(|| estimate_key(&notes));
+    let default_length = choose_default_length(&notes, grid);
+
+    let mut tune = tune_ast_three::Tune::new();
+
+    tune.prelude.push(l::T::X("1".to_string()));
+    if let Some(title) = recording.title {
+        tune.prelude.push(l::T::Title(title));
+    }
+    tune.prelude.push(l::T::Metre(metre));
+    tune.prelude.push(l::T::DefaultNoteLength(default_length));
+    if let Some(microseconds) = recording.microseconds_per_crotchet {
+        tune.prelude.push(l::T::Tempo(music::Tempo {
+            beat: Some(music::FractionalDuration(1, 4)),
+            bpm: Some(((60_000_000.0 / microseconds as f64).round()) as u32),
+            label: None,
+        }));
+    }
+    tune.prelude.push(l::T::KeySignature(key.tonic, key.mode));
+
+    tune.voices.push(body(&notes, grid, bar_units, beat_units, key));
+    tune.voice_spans.push(vec![]);
+
+    Ok(tune)
+}
+
+/// Read a MIDI file and transcribe it.
+pub fn import(bytes: &[u8]) -> Result<tune_ast_three::Tune, String> {
+    smf_to_tune(&midi::Smf::parse(bytes)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_support::read;
+    use abc_writer;
+    use playback;
+
+    #[test]
+    fn round_trip_test() {
+        let abc = "X:1\nT:Round Trip\nM:6/8\nL:1/8\nQ:1/4=150\nK:G\ndBG AFD|G3 g2=f|fga g3|]\n";
+
+        let bytes = midi::tune_to_bytes(&read(abc), &playback::Options::straight());
+        let tune = import(&bytes).unwrap();
+
+        assert_eq!(abc_writer::write_tune(&tune), abc);
+    }
+
+    #[test]
+    fn minor_round_trip_test() {
+        let abc = "X:1\nM:3/4\nL:1/8\nQ:1/4=100\nK:Cm\nc2 =Bc de|e4 d2|=B6|]\n";
+
+        let bytes = midi::tune_to_bytes(&read(abc), &playback::Options::straight());
+        let tune = import(&bytes).unwrap();
+
+        assert_eq!(abc_writer::write_tune(&tune), abc);
+    }
+
+    #[test]
+    fn grace_note_round_trip_test() {
+        let abc = "X:1\nM:6/8\nL:1/8\nQ:1/4=150\nK:G\n\"G\"BAG {g}FED:|\n";
+
+        let options = playback::Options {
+            ornaments: true,
+            ..playback::Options::straight()
+        };
+        let bytes = midi::tune_to_bytes(&read(abc), &options);
+        let tune = import(&bytes).unwrap();
+
+        // The grace note is dropped rather than taking the melody note's place.
+        assert_eq!(
+            abc_writer::write_tune(&tune),
+            "X:1\nM:6/8\nL:1/8\nQ:1/4=150\nK:G\nBAG FED|BAG FED|]\n"
+        );
+    }
+
+    #[test]
+    fn quantise_test() {
+        let note = |start: u32, end: u32, key: u8| {
+            vec![
+                (
+                    start,
+                    midi::MidiEvent::NoteOn {
+                        channel: 0,
+                        key,
+                        velocity: 80,
+                    },
+                ),
+                (
+                    end,
+                    midi::MidiEvent::NoteOff {
+                        channel: 0,
+                        key,
+                        velocity: 0,
+                    },
+                ),
+            ]
+        };
+
+        // A bar's silence, then quavers played unevenly and detached, then two minims.
+        // The first note has a lower note played with it.
+        let mut events = vec![];
+        events.extend(note(1930, 2050, 52));
+        for (i, &key) in [60, 62, 64, 65, 67, 69, 71, 72].iter().enumerate() {
+            let start = 1920 + i as u32 * 240;
+            let jitter: i32 = if i % 2 == 0 { 15 } else { -20 };
+            events.extend(note((start as i32 + jitter) as u32, start + 150, key));
+        }
+        events.extend(note(3830, 4600, 72));
+        events.extend(note(4810, 5700, 67));
+        events.sort_by_key(|event| event.0);
+
+        let smf = midi::Smf {
+            format: midi::Format::Single,
+            division: 480,
+            tracks: vec![midi::Track { events }],
+        };
+
+        let tune = smf_to_tune(&smf).unwrap();
+
+        assert_eq!(
+            abc_writer::write_tune(&tune),
+            "X:1\nM:4/4\nL:1/8\nK:C\nCD EF GA Bc|c4 G4|]\n"
+        );
+    }
+
+    #[test]
+    fn grid_test() {
+        // Semiquavers at a normal speed.
+        assert_eq!(
+            choose_grid(music::Metre(4, 4), 500_000),
+            music::FractionalDuration(1, 16)
+        );
+
+        // A fast reel can't have semiquavers to speak of.
+        assert_eq!(
+            choose_grid(music::Metre(2, 2), 250_000),
+            music::FractionalDuration(1, 8)
+        );
+
+        // Compound beats are divided in three first.
+        assert_eq!(
+            choose_grid(music::Metre(6, 8), 300_000),
+            music::FractionalDuration(1, 8)
+        );
+    }
+
+    #[test]
+    fn empty_test() {
+        let smf = midi::Smf {
+            format: midi::Format::Single,
+            division: 480,
+            tracks: vec![midi::Track { events: vec![(0, midi::MidiEvent::EndOfTrack)] }],
+        };
+
+        assert!(smf_to_tune(&smf).is_err());
+        assert!(import(b"not midi").is_err());
+    }
+}
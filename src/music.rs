@@ -1,5 +1,13 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::ops;
+
 pub const NOTES_IN_SCALE: i16 = 7;
 
+/// Widest interval, in degrees. Ten octaves is more than any instrument, and keeps the arithmetic
+/// on semitones well within range.
+pub const MAX_INTERVAL_DEGREES: i32 = 70;
+
 #[derive(Debug, PartialEq, PartialOrd, Clone, Copy)]
 pub enum DiatonicPitchClass {
     A,
@@ -23,6 +31,51 @@ impl DiatonicPitchClass {
             &DiatonicPitchClass::B => 6,
         }
     }
+
+    /// Diatonic pitch class from a degree counted from C. Wraps around the octave.
+    pub fn from_degree(degree: i16) -> DiatonicPitchClass {
+        match degree.rem_euclid(NOTES_IN_SCALE) {
+            0 => DiatonicPitchClass::C,
+            1 => DiatonicPitchClass::D,
+            2 => DiatonicPitchClass::E,
+            3 => DiatonicPitchClass::F,
+            4 => DiatonicPitchClass::G,
+            5 => DiatonicPitchClass::A,
+            _ => DiatonicPitchClass::B,
+        }
+    }
+
+    /// Semitones above C of the natural note.
+    pub fn semitones(&self) -> i16 {
+        match self {
+            &DiatonicPitchClass::C => 0,
+            &DiatonicPitchClass::D => 2,
+            &DiatonicPitchClass::E => 4,
+            &DiatonicPitchClass::F => 5,
+            &DiatonicPitchClass::G => 7,
+            &DiatonicPitchClass::A => 9,
+            &DiatonicPitchClass::B => 11,
+        }
+    }
+
+    /// Position of the natural note on the circle of fifths, relative to C.
+    fn fifths(&self) -> i16 {
+        match self {
+            &DiatonicPitchClass::F => -1,
+            &DiatonicPitchClass::C => 0,
+            &DiatonicPitchClass::G => 1,
+            &DiatonicPitchClass::D => 2,
+            &DiatonicPitchClass::A => 3,
+            &DiatonicPitchClass::E => 4,
+            &DiatonicPitchClass::B => 5,
+        }
+    }
+}
+
+/// Semitones above C of the natural note at any number of degrees from C, including octaves.
+fn degree_semitones(degree: i16) -> i16 {
+    12 * degree.div_euclid(NOTES_IN_SCALE) +
+        DiatonicPitchClass::from_degree(degree).semitones()
 }
 
 #[derive(Debug, PartialEq, PartialOrd, Clone, Copy)]
@@ -44,6 +97,27 @@ impl Accidental {
             &Accidental::DoubleFlat => -2,
         }
     }
+
+    /// Accidental that alters a note by this many semitones, if there is one.
+    /// Zero gives a natural.
+    pub fn from_semitones(semitones: i16) -> Option<Accidental> {
+        match semitones {
+            -2 => Some(Accidental::DoubleFlat),
+            -1 => Some(Accidental::Flat),
+            0 => Some(Accidental::Natural),
+            1 => Some(Accidental::Sharp),
+            2 => Some(Accidental::DoubleSharp),
+            _ => None,
+        }
+    }
+}
+
+/// Semitones of an optional accidental, where none means natural.
+fn accidental_semitones(accidental: Option<Accidental>) -> i16 {
+    match accidental {
+        Some(accidental) => accidental.semitones(),
+        None => 0,
+    }
 }
 
 /// Musical Mode
@@ -64,19 +138,57 @@ pub enum Mode {
     Minor,
 }
 
+impl Mode {
+    /// Number of fifths to add to the tonic's position to get the key signature.
+    /// e.g. D Dorian has the same signature as C Major, two fifths below D.
+    fn fifths(&self) -> i16 {
+        match self {
+            &Mode::Lydian => 1,
+            &Mode::Natural | &Mode::Ionian | &Mode::Major => 0,
+            &Mode::Mixolydian => -1,
+            &Mode::Dorian => -2,
+            &Mode::Aeolian | &Mode::Minor => -3,
+            &Mode::Phrygian => -4,
+            &Mode::Locrian => -5,
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, PartialOrd, Clone, Copy)]
 pub enum ClefShape {
+    /// G clef.
     Treble,
+
+    /// F clef.
+    Bass,
+
+    /// C clef, used for alto and tenor.
+    C,
+
+    /// Unpitched percussion. Notes are placed as if on a treble clef.
+    Percussion,
 }
 
 impl ClefShape {
     /// What pitch does this shape represent?
     pub fn pitch(&self) -> PitchClass {
+        let diatonic_pitch_class = match self {
+            &ClefShape::Treble | &ClefShape::Percussion => DiatonicPitchClass::G,
+            &ClefShape::Bass => DiatonicPitchClass::F,
+            &ClefShape::C => DiatonicPitchClass::C,
+        };
+
+        PitchClass {
+            diatonic_pitch_class,
+            accidental: None,
+        }
+    }
+
+    /// Octave of the pitch that this shape usually represents, relative to middle C.
+    fn octave(&self) -> i16 {
         match self {
-            Treble => PitchClass {
-                diatonic_pitch_class: DiatonicPitchClass::G,
-                accidental: None,
-            },
+            &ClefShape::Bass => -1,
+            &ClefShape::Treble | &ClefShape::C | &ClefShape::Percussion => 0,
         }
     }
 }
@@ -84,26 +196,85 @@ impl ClefShape {
 #[derive(Debug, PartialEq, PartialOrd, Clone, Copy)]
 pub struct Clef {
     pub shape: ClefShape,
-    // Position on stave relative to middle line.
+    // Position on stave of the clef's line, counting lines and spaces up from the bottom line.
     pub centre: i32,
     pub pitch: Pitch,
 }
 
 impl Clef {
-    /// Construct a treble clef.
-    pub fn treble() -> Clef {
+    fn new(shape: ClefShape, centre: i32, octave: i16) -> Clef {
         Clef {
-            shape: ClefShape::Treble,
-            centre: 2,
+            shape,
+            centre,
             pitch: Pitch {
-                pitch_class: PitchClass {
-                    diatonic_pitch_class: DiatonicPitchClass::G,
-                    accidental: None,
-                },
-                octave: 0,
+                pitch_class: shape.pitch(),
+                octave,
             },
         }
     }
+
+    /// Construct a treble clef.
+    pub fn treble() -> Clef {
+        Clef::new(ClefShape::Treble, 2, 0)
+    }
+
+    /// Treble clef sounding an octave lower, as used for tenor voice and guitar.
+    pub fn treble_8vb() -> Clef {
+        Clef::new(ClefShape::Treble, 2, -1)
+    }
+
+    pub fn bass() -> Clef {
+        Clef::new(ClefShape::Bass, 6, -1)
+    }
+
+    /// C clef on the middle line.
+    pub fn alto() -> Clef {
+        Clef::new(ClefShape::C, 4, 0)
+    }
+
+    /// C clef on the fourth line.
+    pub fn tenor() -> Clef {
+        Clef::new(ClefShape::C, 6, 0)
+    }
+
+    pub fn percussion() -> Clef {
+        Clef::new(ClefShape::Percussion, 2, 0)
+    }
+
+    /// Octaves the clef is transposed by, e.g. -1 for treble-8.
+    pub fn octave_shift(&self) -> i16 {
+        self.pitch.octave - self.shape.octave()
+    }
+
+    /// Clef from its name as used in ABC "clef=" parameters, e.g. "bass" or "treble-8".
+    pub fn from_name(name: &str) -> Option<Clef> {
+        match name.to_lowercase().as_str() {
+            "treble" | "g2" => Some(Clef::treble()),
+            "treble-8" | "g2-8" => Some(Clef::treble_8vb()),
+            "bass" | "f4" => Some(Clef::bass()),
+            "alto" | "c3" => Some(Clef::alto()),
+            "tenor" | "c4" => Some(Clef::tenor()),
+            "perc" | "percussion" => Some(Clef::percussion()),
+            _ => None,
+        }
+    }
+
+    /// Name as used in ABC "clef=" parameters. The inverse of `from_name`.
+    pub fn name(&self) -> &'static str {
+        match (self.shape, self.centre, self.octave_shift()) {
+            (ClefShape::Treble, _, -1) => "treble-8",
+            (ClefShape::Treble, _, _) => "treble",
+            (ClefShape::Bass, _, _) => "bass",
+            (ClefShape::C, 6, _) => "tenor",
+            (ClefShape::C, _, _) => "alto",
+            (ClefShape::Percussion, _, _) => "perc",
+        }
+    }
+
+    /// Position on the stave of a pitch, counting lines and spaces up from the bottom line.
+    pub fn position(&self, pitch: Pitch) -> i32 {
+        self.pitch.interval_to(pitch).pitch_classes + self.centre
+    }
 }
 
 #[derive(Debug, PartialEq, PartialOrd, Clone, Copy)]
@@ -112,220 +283,1465 @@ pub struct PitchClass {
     pub accidental: Option<Accidental>,
 }
 
+impl PitchClass {
+    /// Semitones above C, including the accidental. May fall outside 0 to 11, e.g. for Cb.
+    pub fn semitones(&self) -> i16 {
+        self.diatonic_pitch_class.semitones() + accidental_semitones(self.accidental)
+    }
+
+    /// Transpose by an interval, keeping the spelling implied by the interval.
+    pub fn transpose(&self, interval: Interval) -> PitchClass {
+        Pitch {
+            pitch_class: *self,
+            octave: 0,
+        }.transpose(interval)
+            .pitch_class
+    }
+}
+
+
+/// Quality of an interval.
+#[derive(Debug, PartialEq, PartialOrd, Clone, Copy)]
+pub enum IntervalQuality {
+    DoublyDiminished,
+    Diminished,
+    Minor,
+    Perfect,
+    Major,
+    Augmented,
+    DoublyAugmented,
+}
 
 /// Interval as number of tones and an accidental.
 /// Note that "unison" is expressed as "1" but here as 0.
+/// Negative intervals are descending.
 #[derive(Debug, PartialEq, PartialOrd, Clone, Copy)]
 pub struct Interval {
     /// Interval
     pub pitch_classes: i32,
-    /// Accidental
+    /// Accidental, as the alteration in semitones of the major or perfect interval.
+    /// Positive widens the interval in its direction, e.g. 1 with 3 pitch classes is an augmented
+    /// fourth, and with -3 is a descending augmented fourth.
+    /// This was the difference between the accidentals of the two notes, and changed meaning when
+    /// intervals got qualities. E to G is now -1, a minor third, though neither note has one.
     pub accidental_semitones: i16,
 }
 
-#[derive(Debug, PartialEq, PartialOrd, Clone, Copy)]
-pub struct Pitch {
-    pub pitch_class: PitchClass,
-    /// Octave
-    pub octave: i16,
+/// Is this simple interval, as a number of degrees from 0 to 6, perfect rather than major?
+fn is_perfect_degree(degrees: i32) -> bool {
+    match degrees % NOTES_IN_SCALE as i32 {
+        0 | 3 | 4 => true,
+        _ => false,
+    }
 }
 
-impl Pitch {
-    // How many diatonic degrees between this note and another.
-    // Note that if this occurs in a key signature, the key signature must be applied first!
-    pub fn interval_to(&self, other: Pitch) -> Interval {
-        let degrees = (other.pitch_class.diatonic_pitch_class.to_degree() +
-                           NOTES_IN_SCALE * other.octave) -
-            (self.pitch_class.diatonic_pitch_class.to_degree() + NOTES_IN_SCALE * self.octave);
+impl Interval {
+    /// Interval from a quality and a number, e.g. (Major, 3) for a major third.
+    /// Negative numbers are descending. None if the quality doesn't go with the number, e.g.
+    /// a perfect third, for zero, or beyond `MAX_INTERVAL_DEGREES`.
+    pub fn new(quality: IntervalQuality, number: i32) -> Option<Interval> {
+        if number == 0 {
+            return None;
+        }
 
-        let accidental = match other.pitch_class.accidental {
-            None => 0,
-            Some(ref accidental) => accidental.semitones(),
-        } -
-            match self.pitch_class.accidental {
-                None => 0,
-                Some(ref accidental) => accidental.semitones(),
-            };
+        let degrees = number.checked_abs()? - 1;
+        if degrees > MAX_INTERVAL_DEGREES {
+            return None;
+        }
+
+        let accidental = match (is_perfect_degree(degrees), quality) {
+            (true, IntervalQuality::DoublyDiminished) => -2,
+            (true, IntervalQuality::Diminished) => -1,
+            (true, IntervalQuality::Perfect) => 0,
+            (false, IntervalQuality::DoublyDiminished) => -3,
+            (false, IntervalQuality::Diminished) => -2,
+            (false, IntervalQuality::Minor) => -1,
+            (false, IntervalQuality::Major) => 0,
+            (_, IntervalQuality::Augmented) => 1,
+            (_, IntervalQuality::DoublyAugmented) => 2,
+            _ => return None,
+        };
+
+        Some(Interval {
+            pitch_classes: number.signum() * degrees,
+            accidental_semitones: accidental,
+        })
+    }
+
+    /// Interval spanning a number of degrees and semitones, e.g. 2 and 3 for a minor third.
+    pub fn from_degrees(degrees: i32, semitones: i16) -> Interval {
+        let natural = degree_semitones(degrees.abs() as i16);
+
+        let accidental = if degrees < 0 {
+            -semitones - natural
+        } else {
+            semitones - natural
+        };
 
         Interval {
-            pitch_classes: degrees as i32,
+            pitch_classes: degrees,
             accidental_semitones: accidental,
         }
     }
-}
 
-/// Time signature
-#[derive(Debug, PartialEq, PartialOrd, Clone, Copy)]
-pub struct Metre(pub u32, pub u32);
+    /// Size of the interval in semitones.
+    /// With no accidental, `pitch_classes` gives major and perfect intervals, e.g. 2 is a major
+    /// third and 4 a perfect fifth.
+    pub fn semitones(&self) -> i16 {
+        let size = degree_semitones(self.pitch_classes.abs() as i16) + self.accidental_semitones;
 
-/// The duration class of a notehead, i.e. its shape.
-#[derive(Debug, Copy, Clone, PartialEq, PartialOrd)]
-pub enum DurationClass {
-    Semibreve,
-    Minim,
-    Crotchet,
-    Quaver,
-    Semiquaver,
-    Demisemiquaver,
-}
+        if self.pitch_classes < 0 { -size } else { size }
+    }
 
-// All duration classes, in order of duration.
-const DURATION_CLASSES: &[DurationClass] = &[
-    DurationClass::Semibreve,
-    DurationClass::Minim,
-    DurationClass::Crotchet,
-    DurationClass::Quaver,
-    DurationClass::Semiquaver,
-    DurationClass::Demisemiquaver,
-];
+    /// Does the interval go down?
+    pub fn is_descending(&self) -> bool {
+        self.pitch_classes < 0 || self.pitch_classes == 0 && self.semitones() < 0
+    }
 
-impl DurationClass {
-    fn duration(&self) -> FractionalDuration {
-        match self {
-            &DurationClass::Semibreve => FractionalDuration(1, 1),
-            &DurationClass::Minim => FractionalDuration(1, 2),
-            &DurationClass::Crotchet => FractionalDuration(1, 4),
-            &DurationClass::Quaver => FractionalDuration(1, 8),
-            &DurationClass::Semiquaver => FractionalDuration(1, 16),
-            &DurationClass::Demisemiquaver => FractionalDuration(1, 32),
+    /// Interval number, counting unison as 1. Negative if descending.
+    pub fn number(&self) -> i32 {
+        if self.is_descending() {
+            -(self.pitch_classes.abs() + 1)
+        } else {
+            self.pitch_classes + 1
         }
     }
 
-    // How many beams / tails does this duration render as?
-    pub fn beams(&self) -> u32 {
-        match self {
-            &DurationClass::Semibreve => 0,
-            &DurationClass::Minim => 0,
-            &DurationClass::Crotchet => 0,
-            &DurationClass::Quaver => 1,
-            &DurationClass::Semiquaver => 2,
-            &DurationClass::Demisemiquaver => 3,
+    /// Alteration from the major or perfect interval, measured in the interval's direction.
+    fn alteration(&self) -> i16 {
+        // Descending unisons are measured upwards, like diminished unisons.
+        if self.pitch_classes == 0 {
+            self.semitones()
+        } else {
+            self.accidental_semitones
         }
     }
-}
 
-/// Represent a duration per notation.
-#[derive(Debug, PartialEq, Copy, Clone, PartialOrd)]
-pub struct DurationGlyph {
-    pub shape: DurationClass,
-    pub dots: u32,
-}
+    /// The quality, e.g. Major. None for intervals altered by more than two semitones.
+    pub fn quality(&self) -> Option<IntervalQuality> {
+        let perfect = is_perfect_degree(self.pitch_classes.abs());
+        let alteration = self.alteration();
+
+        match (perfect, alteration) {
+            (true, -2) | (false, -3) => Some(IntervalQuality::DoublyDiminished),
+            (true, -1) | (false, -2) => Some(IntervalQuality::Diminished),
+            (false, -1) => Some(IntervalQuality::Minor),
+            (true, 0) => Some(IntervalQuality::Perfect),
+            (false, 0) => Some(IntervalQuality::Major),
+            (_, 1) => Some(IntervalQuality::Augmented),
+            (_, 2) => Some(IntervalQuality::DoublyAugmented),
+            _ => None,
+        }
+    }
 
-/// A duration as a fraction of the default duration.
-#[derive(Debug, PartialEq, PartialOrd, Clone, Copy)]
-pub struct FractionalDuration(pub u32, pub u32);
+    /// Invert the interval within the octave, e.g. a major third becomes a minor sixth.
+    /// Compound intervals are reduced first. The direction is kept.
+    #[cfg(test)]
+    pub fn invert(&self) -> Interval {
+        let degrees = self.pitch_classes.abs();
+        let semitones = self.semitones().abs();
+
+        // Reduce to between a unison and an octave inclusive.
+        let octaves = if degrees > 0 && degrees % NOTES_IN_SCALE as i32 == 0 {
+            degrees / NOTES_IN_SCALE as i32 - 1
+        } else {
+            degrees / NOTES_IN_SCALE as i32
+        };
+
+        let simple_degrees = degrees - NOTES_IN_SCALE as i32 * octaves;
+        let simple_semitones = semitones - 12 * octaves as i16;
+
+        let direction = if self.is_descending() { -1 } else { 1 };
+
+        Interval::from_degrees(
+            direction * (NOTES_IN_SCALE as i32 - simple_degrees),
+            direction as i16 * (12 - simple_semitones),
+        )
+    }
 
-impl FractionalDuration {
-    /// Multiply this fractional duration by another.
-    /// Used to resolve a duration against a standard duration.
-    pub fn multiply(self, other: FractionalDuration) -> FractionalDuration {
+    /// Parse an interval such as "M3", "-P5", "A4", "m10" or "dd7".
+    pub fn parse(input: &str) -> Option<Interval> {
+        let (direction, input) = if input.starts_with('-') {
+            (-1, &input[1..])
+        } else if input.starts_with('+') {
+            (1, &input[1..])
+        } else {
+            (1, input)
+        };
+
+        let split = input.find(|c: char| c.is_ascii_digit()).unwrap_or(input.len());
+        let (quality, number) = input.split_at(split);
+
+        let number = match number.parse::<i32>() {
+            Ok(number) if number > 0 => number,
+            _ => return None,
+        };
+
+        let quality = match quality {
+            "dd" => IntervalQuality::DoublyDiminished,
+            "d" => IntervalQuality::Diminished,
+            "m" => IntervalQuality::Minor,
+            "P" => IntervalQuality::Perfect,
+            "M" => IntervalQuality::Major,
+            "A" => IntervalQuality::Augmented,
+            "AA" => IntervalQuality::DoublyAugmented,
+            _ => return None,
+        };
+
+        Interval::new(quality, direction * number)
+    }
 
-        let vulgar = FractionalDuration(self.0 * other.0, self.1 * other.1);
+    /// The interval that spells `from` as `to` and spans this many semitones.
+    /// The semitones decide the direction and octave, e.g. C to G is 7 or -5.
+    pub fn between(from: PitchClass, to: PitchClass, semitones: i16) -> Interval {
+        let degrees = (to.diatonic_pitch_class.to_degree() -
+                           from.diatonic_pitch_class.to_degree())
+            .rem_euclid(NOTES_IN_SCALE);
 
-        return vulgar.reduce();
+        // Smallest alteration from the natural interval that reaches the right pitch class.
+        let natural = degree_semitones(degrees);
+        let accidental = (to.semitones() - from.semitones() - natural + 6).rem_euclid(12) - 6;
+
+        // Whatever's left is whole octaves.
+        let octaves = (semitones - natural - accidental).div_euclid(12);
+
+        Interval::from_degrees((degrees + NOTES_IN_SCALE * octaves) as i32, semitones)
     }
+}
 
-    pub fn subtract(self, other: FractionalDuration) -> FractionalDuration {
-        let self_numerator = self.0 * other.1;
-        let other_numerator = other.0 * self.1;
-        let denomenator = self.1 * other.1;
+impl ops::Add for Interval {
+    type Output = Interval;
 
-        FractionalDuration(self_numerator - other_numerator, denomenator).reduce()
+    /// Stack two intervals, e.g. a major third and a minor third make a perfect fifth.
+    fn add(self, other: Interval) -> Interval {
+        Interval::from_degrees(
+            self.pitch_classes + other.pitch_classes,
+            self.semitones() + other.semitones(),
+        )
     }
+}
 
-    /// Reduce this fraction to its simplest form.
-    pub fn reduce(self) -> FractionalDuration {
-        let max = u32::max(self.0, self.1);
-        for i in (1..max + 1).rev() {
+impl ops::Neg for Interval {
+    type Output = Interval;
 
-            if (self.0 % i == 0) && (self.1 % i) == 0 {
-                return FractionalDuration(self.0 / i, self.1 / i);
-            }
+    /// The same interval in the other direction.
+    fn neg(self) -> Interval {
+        Interval::from_degrees(-self.pitch_classes, -self.semitones())
+    }
+}
+
+impl fmt::Display for Interval {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_descending() {
+            write!(f, "-")?;
         }
 
-        self
+        match self.quality() {
+            Some(IntervalQuality::DoublyDiminished) => write!(f, "dd")?,
+            Some(IntervalQuality::Diminished) => write!(f, "d")?,
+            Some(IntervalQuality::Minor) => write!(f, "m")?,
+            Some(IntervalQuality::Perfect) => write!(f, "P")?,
+            Some(IntervalQuality::Major) => write!(f, "M")?,
+            Some(IntervalQuality::Augmented) => write!(f, "A")?,
+            Some(IntervalQuality::DoublyAugmented) => write!(f, "AA")?,
+
+            // Can't be named, so give the alteration.
+            None => write!(f, "({:+})", self.alteration())?,
+        }
+
+        write!(f, "{}", self.number().abs())
     }
+}
 
-    /// Is this duration greater than the other one?
-    /// TODO Implement PartialOrd properly!
-    pub fn gte(&self, other: &FractionalDuration) -> bool {
-        let self_numerator = self.0 * other.1;
-        let other_numerator = other.0 * self.1;
-        self_numerator >= other_numerator
+#[derive(Debug, PartialEq, PartialOrd, Clone, Copy)]
+pub struct Pitch {
+    pub pitch_class: PitchClass,
+    /// Octave
+    pub octave: i16,
+}
+
+impl Pitch {
+    // How many diatonic degrees between this note and another.
+    // Note that if this occurs in a key signature, the key signature must be applied first!
+    pub fn interval_to(&self, other: Pitch) -> Interval {
+        let degrees = (other.pitch_class.diatonic_pitch_class.to_degree() +
+                           NOTES_IN_SCALE * other.octave) -
+            (self.pitch_class.diatonic_pitch_class.to_degree() + NOTES_IN_SCALE * self.octave);
+
+        Interval::from_degrees(degrees as i32, self.semitones_to(other))
     }
 
-    /// Transform this duration into a notehead glyph.
-    /// i.e. "3/2" becomes "dotted crotchet".
-    /// TODO in future this may be represented as a sequence of tied glyphs
-    /// for complicted durations.
-    pub fn to_glyph(&self) -> Option<DurationGlyph> {
-        const MAX_DOTS: u32 = 4;
+    /// Semitones above middle C.
+    pub fn semitones(&self) -> i16 {
+        12 * self.octave + self.pitch_class.semitones()
+    }
+
+    /// Number of semitones from this pitch up to another. Negative if the other is lower.
+    pub fn semitones_to(&self, other: Pitch) -> i16 {
+        other.semitones() - self.semitones()
+    }
 
-        // Start with self's duration, keep chipping away until there's nothing left to represent.
-        let mut this = *self;
+    /// MIDI note number, where middle C is 60. None if out of MIDI's range.
+    pub fn midi_number(&self) -> Option<u8> {
+        let number = MIDDLE_C_MIDI_NUMBER + self.semitones();
 
-        let mut result = None;
+        if number >= 0 && number <= 127 {
+            Some(number as u8)
+        } else {
+            None
+        }
+    }
 
-        // Try each top level duration class first.
-        for duration_class in DURATION_CLASSES.iter() {
+    /// Spell a MIDI note number in the context of a key.
+    /// Notes in the key are spelled as the key does, others with sharps in sharp keys and flats in
+    /// flat keys. Accidentals implied by the key are included, so the pitch is absolute.
+    pub fn from_midi_number(number: u8, key: KeySignature) -> Pitch {
+        let semitones = number as i16 - MIDDLE_C_MIDI_NUMBER;
+
+        // Every pitch can be spelled as the natural, sharp or flat of a neighbouring letter.
+        let degree = (semitones * NOTES_IN_SCALE).div_euclid(12);
+        let spellings = [degree - 1, degree, degree + 1]
+            .iter()
+            .filter(|degree| (semitones - degree_semitones(**degree)).abs() <= 1)
+            .filter_map(|degree| Pitch::spell(*degree, semitones - degree_semitones(*degree)))
+            .collect::<Vec<Pitch>>();
+
+        let in_key = |pitch: &&Pitch| {
+            let implied = key.accidental(pitch.pitch_class.diatonic_pitch_class);
+            accidental_semitones(pitch.pitch_class.accidental) == accidental_semitones(implied)
+        };
+
+        let wanted_accidental = if key.fifths() < 0 {
+            Some(Accidental::Flat)
+        } else {
+            Some(Accidental::Sharp)
+        };
+
+        let preferred = spellings
+            .iter()
+            .find(in_key)
+            .or_else(|| spellings.iter().find(|pitch| pitch.pitch_class.accidental.is_none()))
+            .or_else(|| {
+                spellings.iter().find(|pitch| {
+                    pitch.pitch_class.accidental == wanted_accidental
+                })
+            });
+
+        match preferred {
+            Some(pitch) => *pitch,
+            None => Pitch::from_semitones(semitones, degree),
+        }
+    }
 
-            // When there's nothing left to represent, stop there.
-            if this.reduce() == FractionalDuration(0, 1) {
-                break;
-            }
+    /// Transpose by an interval. The new note is spelled by moving the letter name by the
+    /// interval's degrees and choosing the accidental that gives the right pitch.
+    /// Notes that need no accidental have none.
+    pub fn transpose(&self, interval: Interval) -> Pitch {
+        let degree = self.pitch_class.diatonic_pitch_class.to_degree() +
+            NOTES_IN_SCALE * self.octave + interval.pitch_classes as i16;
 
-            let mut duration = duration_class.duration();
-            let mut num_dots = 0;
+        let semitones = self.semitones() + interval.semitones();
 
-            // It is possible to represent self duration using this duration class.
-            if this.gte(&duration) {
-                for _ in 0..MAX_DOTS + 1 {
-                    this = this.subtract(duration);
+        // Intervals with large accidentals can produce notes that can't be spelled.
+        match Pitch::spell(degree, semitones - degree_semitones(degree)) {
+            Some(pitch) => pitch,
+            None => Pitch::from_semitones(semitones, degree),
+        }
+    }
 
-                    if this.reduce() == FractionalDuration(0, 1) {
+    /// Pitch at a number of degrees from middle C with an accidental, if it can be notated.
+    fn spell(degree: i16, accidental: i16) -> Option<Pitch> {
+        let accidental = match Accidental::from_semitones(accidental) {
+            Some(Accidental::Natural) => None,
+            Some(accidental) => Some(accidental),
+            None => return None,
+        };
+
+        Some(Pitch {
+            pitch_class: PitchClass {
+                diatonic_pitch_class: DiatonicPitchClass::from_degree(degree),
+                accidental,
+            },
+            octave: degree.div_euclid(NOTES_IN_SCALE),
+        })
+    }
 
-                        break;
-                    }
+    /// Respell double sharps and flats as the neighbouring note, e.g. F## as G.
+    pub fn simplify(&self) -> Pitch {
+        match self.pitch_class.accidental {
+            Some(Accidental::DoubleSharp) |
+            Some(Accidental::DoubleFlat) => {
+                let degree = self.pitch_class.diatonic_pitch_class.to_degree() +
+                    NOTES_IN_SCALE * self.octave;
 
-                    // Half the duration to correspond to another dot.
-                    duration = duration.multiply(FractionalDuration(1, 2));
-                    num_dots += 1;
-                }
+                let direction = accidental_semitones(self.pitch_class.accidental).signum();
 
-                result = Some(DurationGlyph {
-                    shape: *duration_class,
-                    dots: num_dots,
-                });
+                Pitch::from_semitones(self.semitones(), degree + direction)
             }
+            _ => *self,
         }
+    }
 
-        result
+    /// Spell a pitch, given as semitones above middle C, near the given degree.
+    /// The nearest degree that can be spelled with a single accidental or none is chosen.
+    fn from_semitones(semitones: i16, degree: i16) -> Pitch {
+        // Every pitch is within a semitone of a natural within a degree either side.
+        let candidates = [degree, degree - 1, degree + 1];
+
+        candidates
+            .iter()
+            .filter(|candidate| (semitones - degree_semitones(**candidate)).abs() <= 1)
+            .filter_map(|candidate| {
+                Pitch::spell(*candidate, semitones - degree_semitones(*candidate))
+            })
+            .next()
+            .unwrap_or(Pitch {
+                pitch_class: PitchClass {
+                    diatonic_pitch_class: DiatonicPitchClass::from_degree(degree),
+                    accidental: None,
+                },
+                octave: degree.div_euclid(NOTES_IN_SCALE),
+            })
     }
 }
 
-#[derive(Debug, PartialEq, PartialOrd, Clone)]
-pub struct Note(pub Pitch, pub FractionalDuration);
+/// Tracks the accidentals in force: those in the key signature and those written earlier in the
+/// bar. Accidentals in a bar apply to later notes of the same letter in the same octave.
+#[derive(Debug, Clone)]
+pub struct Accidentals {
+    key: KeySignature,
 
-impl Note {
-    /// Adjust this note's duration by mutiplying by a base.
-    pub fn resolve_duration(&self, base_duration: FractionalDuration) -> Note {
-        Note(self.0, self.1.multiply(base_duration))
-    }
+    /// Semitones of accidentals written in the current bar, by degree and octave.
+    bar: HashMap<(i16, i16), i16>,
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+impl Accidentals {
+    pub fn new(key: KeySignature) -> Accidentals {
+        Accidentals {
+            key,
+            bar: HashMap::new(),
+        }
+    }
 
-    #[test]
-    fn fractional_duration_multiply() {
-        assert_eq!(
-            FractionalDuration(1, 1).multiply(FractionalDuration(1, 4)),
-            FractionalDuration(1, 4),
-            "Resolving duration of 1 in 1/4 gives 1/4"
-        );
+    pub fn key(&self) -> KeySignature {
+        self.key
+    }
 
-        assert_eq!(
+    /// Change key. This also ends the bar.
+    pub fn set_key(&mut self, key: KeySignature) {
+        self.key = key;
+        self.bar.clear();
+    }
+
+    /// Forget accidentals written in the bar.
+    pub fn end_bar(&mut self) {
+        self.bar.clear();
+    }
+
+    fn implied(&self, diatonic_pitch_class: DiatonicPitchClass, octave: i16) -> i16 {
+        match self.bar.get(&(diatonic_pitch_class.to_degree(), octave)) {
+            Some(semitones) => *semitones,
+            None => accidental_semitones(self.key.accidental(diatonic_pitch_class)),
+        }
+    }
+
+    /// Resolve a note as written to the pitch that sounds, remembering any accidental for the
+    /// rest of the bar. The result always has an explicit accidental, which may be natural.
+    pub fn resolve(&mut self, pitch: Pitch) -> Pitch {
+        let diatonic_pitch_class = pitch.pitch_class.diatonic_pitch_class;
+
+        let semitones = match pitch.pitch_class.accidental {
+            Some(accidental) => {
+                self.bar.insert(
+                    (diatonic_pitch_class.to_degree(), pitch.octave),
+                    accidental.semitones(),
+                );
+                accidental.semitones()
+            }
+            None => self.implied(diatonic_pitch_class, pitch.octave),
+        };
+
+        Pitch {
+            pitch_class: PitchClass {
+                diatonic_pitch_class,
+                accidental: Accidental::from_semitones(semitones),
+            },
+            octave: pitch.octave,
+        }
+    }
+
+    /// The reverse of `resolve`. Given a sounding pitch, return it as it should be written, with
+    /// an accidental only where the key or earlier accidentals wouldn't give the right pitch.
+    pub fn notate(&mut self, pitch: Pitch) -> Pitch {
+        let diatonic_pitch_class = pitch.pitch_class.diatonic_pitch_class;
+        let wanted = accidental_semitones(pitch.pitch_class.accidental);
+
+        let accidental = if wanted == self.implied(diatonic_pitch_class, pitch.octave) {
+            None
+        } else {
+            self.bar.insert(
+                (diatonic_pitch_class.to_degree(), pitch.octave),
+                wanted,
+            );
+            Accidental::from_semitones(wanted)
+        };
+
+        Pitch {
+            pitch_class: PitchClass {
+                diatonic_pitch_class,
+                accidental,
+            },
+            octave: pitch.octave,
+        }
+    }
+}
+
+/// MIDI note number of middle C.
+const MIDDLE_C_MIDI_NUMBER: i16 = 60;
+
+/// MIDI note number of the A above middle C, the usual tuning reference.
+const REFERENCE_A_MIDI_NUMBER: i16 = 69;
+
+/// A tuning system, for converting pitches to frequencies.
+#[derive(Debug, PartialEq, PartialOrd, Clone, Copy)]
+pub enum Temperament {
+    /// Twelve equal semitones per octave.
+    Equal,
+}
+
+/// Tuning, for converting pitches to frequencies.
+#[derive(Debug, PartialEq, PartialOrd, Clone, Copy)]
+pub struct Tuning {
+    pub temperament: Temperament,
+
+    /// Frequency of the A above middle C in Hz.
+    pub reference_a: f64,
+}
+
+impl Tuning {
+    /// Equal temperament with A at 440Hz.
+    pub fn standard() -> Tuning {
+        Tuning {
+            temperament: Temperament::Equal,
+            reference_a: 440.0,
+        }
+    }
+
+    /// Frequency of a pitch in Hz.
+    #[cfg(test)]
+    pub fn frequency(&self, pitch: Pitch) -> f64 {
+        self.frequency_of_midi_number((MIDDLE_C_MIDI_NUMBER + pitch.semitones()) as f64)
+    }
+
+    /// Frequency of a MIDI note number in Hz. Fractional numbers are allowed, e.g. for bends.
+    pub fn frequency_of_midi_number(&self, number: f64) -> f64 {
+        match self.temperament {
+            Temperament::Equal => {
+                self.reference_a * 2.0_f64.powf((number - REFERENCE_A_MIDI_NUMBER as f64) / 12.0)
+            }
+        }
+    }
+}
+
+impl ops::Add<Interval> for Pitch {
+    type Output = Pitch;
+
+    /// Transpose by an interval. See `transpose`.
+    fn add(self, interval: Interval) -> Pitch {
+        self.transpose(interval)
+    }
+}
+
+/// Quality of a chord's triad.
+#[derive(Debug, PartialEq, PartialOrd, Clone, Copy)]
+pub enum ChordQuality {
+    Major,
+    Minor,
+    Diminished,
+    Augmented,
+    Suspended2,
+    Suspended4,
+
+    /// Root and fifth only, e.g. "A5".
+    Power,
+}
+
+impl ChordQuality {
+    /// Intervals above the root.
+    fn intervals(&self) -> Vec<Interval> {
+        let interval = |quality, number| Interval::new(quality, number).unwrap();
+        let root = interval(IntervalQuality::Perfect, 1);
+        let fifth = interval(IntervalQuality::Perfect, 5);
+
+        match self {
+            &ChordQuality::Major => vec![root, interval(IntervalQuality::Major, 3), fifth],
+            &ChordQuality::Minor => vec![root, interval(IntervalQuality::Minor, 3), fifth],
+            &ChordQuality::Diminished => {
+                vec![
+                    root,
+                    interval(IntervalQuality::Minor, 3),
+                    interval(IntervalQuality::Diminished, 5),
+                ]
+            }
+            &ChordQuality::Augmented => {
+                vec![
+                    root,
+                    interval(IntervalQuality::Major, 3),
+                    interval(IntervalQuality::Augmented, 5),
+                ]
+            }
+            &ChordQuality::Suspended2 => vec![root, interval(IntervalQuality::Major, 2), fifth],
+            &ChordQuality::Suspended4 => vec![root, interval(IntervalQuality::Perfect, 4), fifth],
+            &ChordQuality::Power => vec![root, fifth],
+        }
+    }
+}
+
+/// A chord symbol, as found in ABC guitar chords, e.g. "Am7" or "D/F#".
+#[derive(Debug, PartialEq, PartialOrd, Clone)]
+pub struct ChordSymbol {
+    pub root: PitchClass,
+    pub quality: ChordQuality,
+
+    /// Tones above the triad implied by the chord's number, e.g. a minor 7th and major 9th for "9".
+    pub extensions: Vec<Interval>,
+
+    /// Altered or added tones, e.g. "b5", "#9", "add9". These replace any tone of the same number.
+    pub alterations: Vec<Interval>,
+
+    /// Bass note, when it isn't the root, e.g. F# in "D/F#".
+    pub bass: Option<PitchClass>,
+}
+
+/// Read a pitch class from the start of a chord symbol, e.g. "Bb" from "Bbm7".
+/// Chord roots are always upper case.
+fn read_chord_pitch_class(input: &str) -> Option<(PitchClass, &str)> {
+    let diatonic_pitch_class = match input.chars().next() {
+        Some('A') => DiatonicPitchClass::A,
+        Some('B') => DiatonicPitchClass::B,
+        Some('C') => DiatonicPitchClass::C,
+        Some('D') => DiatonicPitchClass::D,
+        Some('E') => DiatonicPitchClass::E,
+        Some('F') => DiatonicPitchClass::F,
+        Some('G') => DiatonicPitchClass::G,
+        _ => return None,
+    };
+
+    let rest = &input[1..];
+
+    let (accidental, rest) = if let Some(rest) = rest.strip_prefix('#') {
+        (Some(Accidental::Sharp), rest)
+    } else if let Some(rest) = rest.strip_prefix('b') {
+        (Some(Accidental::Flat), rest)
+    } else {
+        (None, rest)
+    };
+
+    Some((
+        PitchClass {
+            diatonic_pitch_class,
+            accidental,
+        },
+        rest,
+    ))
+}
+
+/// Read a number from the start of a chord symbol, e.g. 13 from "13b9".
+fn read_chord_number(input: &str) -> Option<(i32, &str)> {
+    let length = input.chars().take_while(|c| c.is_ascii_digit()).count();
+
+    match input[..length].parse::<i32>() {
+        Ok(number) => Some((number, &input[length..])),
+        Err(_) => None,
+    }
+}
+
+/// If the input starts with any of the prefixes, the rest after the longest one that matches.
+fn strip_chord_prefix<'a>(input: &'a str, prefixes: &[&str]) -> Option<&'a str> {
+    prefixes
+        .iter()
+        .filter(|prefix| input.starts_with(*prefix))
+        .map(|prefix| &input[prefix.len()..])
+        .min_by_key(|rest| rest.len())
+}
+
+/// Format a pitch class as in a chord symbol, e.g. "F#".
+fn chord_pitch_class_to_string(pitch_class: PitchClass) -> String {
+    let mut result = format!("{:?}", pitch_class.diatonic_pitch_class);
+
+    result.push_str(match pitch_class.accidental {
+        Some(Accidental::Sharp) => "#",
+        Some(Accidental::Flat) => "b",
+        Some(Accidental::DoubleSharp) => "##",
+        Some(Accidental::DoubleFlat) => "bb",
+        Some(Accidental::Natural) | None => "",
+    });
+
+    result
+}
+
+impl ChordSymbol {
+    /// Parse a chord symbol, e.g. "Am7", "D/F#", "Gsus4", "Bbmaj7", "Edim" or "C7(#9)".
+    /// None if it isn't a chord, e.g. an annotation.
+    pub fn parse(input: &str) -> Option<ChordSymbol> {
+        let interval = |quality, number| Interval::new(quality, number);
+
+        let (root, rest) = read_chord_pitch_class(input.trim())?;
+
+        // Quality. Check "maj" before the "m" of minor.
+        let mut major_seventh = false;
+
+        let (quality, rest) = if let Some(rest) = strip_chord_prefix(rest, &["maj", "M", "Δ"]) {
+            major_seventh = true;
+            (ChordQuality::Major, rest)
+        } else if let Some(rest) = strip_chord_prefix(rest, &["m", "min", "-"]) {
+            // Minor-major seventh, e.g. "Cm(maj7)".
+            if let Some(rest) = strip_chord_prefix(rest, &["maj", "M", "(maj"]) {
+                major_seventh = true;
+                (ChordQuality::Minor, rest)
+            } else {
+                (ChordQuality::Minor, rest)
+            }
+        } else if let Some(rest) = strip_chord_prefix(rest, &["dim", "o", "°"]) {
+            (ChordQuality::Diminished, rest)
+        } else if let Some(rest) = strip_chord_prefix(rest, &["aug", "+"]) {
+            (ChordQuality::Augmented, rest)
+        } else {
+            (ChordQuality::Major, rest)
+        };
+
+        let mut quality = quality;
+        let mut extensions = vec![];
+        let mut alterations = vec![];
+
+        // Half diminished is a minor seventh with a flat fifth.
+        let rest = if let Some(rest) = strip_chord_prefix(rest, &["ø7", "ø"]) {
+            quality = ChordQuality::Minor;
+            extensions.push(interval(IntervalQuality::Minor, 7)?);
+            alterations.push(interval(IntervalQuality::Diminished, 5)?);
+            rest
+        } else {
+            rest
+        };
+
+        // The number gives the highest extension, which implies those below it.
+        let rest = match read_chord_number(rest) {
+            Some((5, rest)) if quality == ChordQuality::Major && !major_seventh => {
+                quality = ChordQuality::Power;
+                rest
+            }
+            Some((6, rest)) => {
+                extensions.push(interval(IntervalQuality::Major, 6)?);
+                rest
+            }
+            Some((number, rest)) if number == 7 || number == 9 || number == 11 || number == 13 => {
+                extensions.push(if major_seventh {
+                    interval(IntervalQuality::Major, 7)?
+                } else if quality == ChordQuality::Diminished {
+                    interval(IntervalQuality::Diminished, 7)?
+                } else {
+                    interval(IntervalQuality::Minor, 7)?
+                });
+
+                if number >= 9 {
+                    extensions.push(interval(IntervalQuality::Major, 9)?);
+                }
+                if number >= 11 {
+                    extensions.push(interval(IntervalQuality::Perfect, 11)?);
+                }
+                if number >= 13 {
+                    extensions.push(interval(IntervalQuality::Major, 13)?);
+                }
+
+                rest
+            }
+            Some(_) => return None,
+
+            // A triangle on its own is a major seventh, but "maj" on its own is just major.
+            None if major_seventh && input.contains('Δ') => {
+                extensions.push(interval(IntervalQuality::Major, 7)?);
+                rest
+            }
+            None => rest,
+        };
+
+        // Close the bracket of "m(maj7)".
+        let rest = rest.trim_start_matches(')');
+
+        let mut rest = if let Some(rest) = strip_chord_prefix(rest, &["sus2"]) {
+            quality = ChordQuality::Suspended2;
+            rest
+        } else if let Some(rest) = strip_chord_prefix(rest, &["sus4", "sus"]) {
+            quality = ChordQuality::Suspended4;
+            rest
+        } else {
+            rest
+        };
+
+        // Alterations, optionally in brackets, e.g. "b9", "(#11)" or "add9".
+        loop {
+            rest = rest.trim_start_matches(&['(', ')', ','][..]);
+
+            let (sharpen, after) = if let Some(after) = strip_chord_prefix(rest, &["b", "-"]) {
+                (Some(false), after)
+            } else if let Some(after) = strip_chord_prefix(rest, &["#", "+"]) {
+                (Some(true), after)
+            } else if let Some(after) = strip_chord_prefix(rest, &["add"]) {
+                (None, after)
+            } else {
+                break;
+            };
+
+            let (number, after) = read_chord_number(after)?;
+
+            let perfect = is_perfect_degree(number - 1);
+            let alteration_quality = match (sharpen, perfect) {
+                (Some(true), _) => IntervalQuality::Augmented,
+                (Some(false), true) => IntervalQuality::Diminished,
+                (Some(false), false) => IntervalQuality::Minor,
+                (None, true) => IntervalQuality::Perfect,
+                (None, false) => IntervalQuality::Major,
+            };
+
+            alterations.push(interval(alteration_quality, number)?);
+            rest = after;
+        }
+
+        let (bass, rest) = if let Some(rest) = rest.strip_prefix('/') {
+            let (bass, rest) = read_chord_pitch_class(rest)?;
+            (Some(bass), rest)
+        } else {
+            (None, rest)
+        };
+
+        if !rest.is_empty() {
+            return None;
+        }
+
+        Some(ChordSymbol {
+            root,
+            quality,
+            extensions,
+            alterations,
+            bass,
+        })
+    }
+
+    /// Intervals of the chord tones above the root, lowest first.
+    pub fn intervals(&self) -> Vec<Interval> {
+        let mut result = self.quality.intervals();
+        result.extend(self.extensions.iter().cloned());
+
+        for alteration in self.alterations.iter() {
+            result.retain(|interval| interval.number() != alteration.number());
+            result.push(*alteration);
+        }
+
+        result.sort_by_key(|interval| interval.semitones());
+        result
+    }
+
+    /// The pitch classes in the chord. The bass note comes first, then the rest from the root up.
+    pub fn pitch_classes(&self) -> Vec<PitchClass> {
+        let mut result = vec![];
+
+        if let Some(bass) = self.bass {
+            result.push(bass);
+        }
+
+        for interval in self.intervals() {
+            let pitch_class = self.root.transpose(interval);
+
+            if !result.contains(&pitch_class) {
+                result.push(pitch_class);
+            }
+        }
+
+        result
+    }
+
+    /// Transpose by an interval. Double sharps and flats are respelled.
+    pub fn transpose(&self, interval: Interval) -> ChordSymbol {
+        let transpose = |pitch_class: PitchClass| {
+            Pitch {
+                pitch_class,
+                octave: 0,
+            }.transpose(interval)
+                .simplify()
+                .pitch_class
+        };
+
+        ChordSymbol {
+            root: transpose(self.root),
+            bass: self.bass.map(transpose),
+            ..self.clone()
+        }
+    }
+}
+
+impl fmt::Display for ChordSymbol {
+    /// Canonical form, e.g. "Bbmaj7", "Am7b5", "D7sus4/A".
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", chord_pitch_class_to_string(self.root))?;
+
+        let seventh = self.extensions.iter().find(|interval| interval.number() == 7).and_then(
+            |interval| interval.quality(),
+        );
+
+        write!(
+            f,
+            "{}",
+            match (self.quality, seventh) {
+                (ChordQuality::Minor, Some(IntervalQuality::Major)) => "mmaj",
+                (ChordQuality::Minor, _) => "m",
+                (ChordQuality::Diminished, _) => "dim",
+                (ChordQuality::Augmented, _) => "aug",
+                (ChordQuality::Power, _) => "5",
+                (_, Some(IntervalQuality::Major)) => "maj",
+                _ => "",
+            }
+        )?;
+
+        if let Some(highest) = self.extensions.iter().map(|interval| interval.number()).max() {
+            write!(f, "{}", highest)?;
+        }
+
+        match self.quality {
+            ChordQuality::Suspended2 => write!(f, "sus2")?,
+            ChordQuality::Suspended4 => write!(f, "sus4")?,
+            _ => (),
+        }
+
+        for alteration in self.alterations.iter() {
+            let prefix = match alteration.quality() {
+                Some(IntervalQuality::Diminished) |
+                Some(IntervalQuality::Minor) => "b",
+                Some(IntervalQuality::Augmented) => "#",
+                _ => "add",
+            };
+
+            write!(f, "{}{}", prefix, alteration.number())?;
+        }
+
+        if let Some(bass) = self.bass {
+            write!(f, "/{}", chord_pitch_class_to_string(bass))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Time signature
+#[derive(Debug, PartialEq, PartialOrd, Clone, Copy)]
+pub struct Metre(pub u32, pub u32);
+
+impl Metre {
+    /// Duration of a whole bar.
+    pub fn bar(&self) -> FractionalDuration {
+        FractionalDuration(self.0, self.1).reduce()
+    }
+
+    /// Duration of a beat. Compound metres, e.g. 6/8 or 9/8, have dotted beats.
+    pub fn beat(&self) -> FractionalDuration {
+        let &Metre(numerator, denomenator) = self;
+
+        if numerator > 3 && numerator % 3 == 0 && denomenator >= 8 {
+            FractionalDuration(3, denomenator).reduce()
+        } else {
+            FractionalDuration(1, denomenator)
+        }
+    }
+}
+
+/// Key signature, i.e. a tonic and a mode.
+#[derive(Debug, PartialEq, PartialOrd, Clone, Copy)]
+pub struct KeySignature {
+    pub tonic: PitchClass,
+    pub mode: Mode,
+}
+
+/// Order in which sharps are added to a key signature. Flats are the reverse.
+const ORDER_OF_SHARPS: &[DiatonicPitchClass] = &[
+    DiatonicPitchClass::F,
+    DiatonicPitchClass::C,
+    DiatonicPitchClass::G,
+    DiatonicPitchClass::D,
+    DiatonicPitchClass::A,
+    DiatonicPitchClass::E,
+    DiatonicPitchClass::B,
+];
+
+/// Keys with more than this many sharps or flats are respelled enharmonically when transposing.
+const MAX_KEY_FIFTHS: i16 = 6;
+
+impl KeySignature {
+    /// The key with this tonic, given as semitones above C, spelled with the fewest sharps or flats,
+    /// e.g. 8 in major is Ab rather than G#.
+    pub fn from_semitones(tonic_semitones: i16, mode: Mode) -> KeySignature {
+        let mut best: Option<KeySignature> = None;
+
+        for degree in 0..NOTES_IN_SCALE {
+            for accidental in [None, Some(Accidental::Sharp), Some(Accidental::Flat)].iter() {
+                let key = KeySignature {
+                    tonic: PitchClass {
+                        diatonic_pitch_class: DiatonicPitchClass::from_degree(degree),
+                        accidental: *accidental,
+                    },
+                    mode,
+                };
+
+                if (key.tonic.semitones() - tonic_semitones).rem_euclid(12) != 0 {
+                    continue;
+                }
+
+                match best {
+                    Some(best) if best.fifths().abs() <= key.fifths().abs() => (),
+                    _ => best = Some(key),
+                }
+            }
+        }
+
+        // Every semitone is a natural or a sharp, so there is always a match.
+        best.unwrap_or(KeySignature {
+            tonic: PitchClass {
+                diatonic_pitch_class: DiatonicPitchClass::C,
+                accidental: None,
+            },
+            mode,
+        })
+    }
+
+    /// Do the two keys have the same tonic pitch and mode, allowing for synonyms and enharmonics?
+    /// e.g. A Minor and A Aeolian, or F# Major and Gb Major.
+    pub fn equivalent(&self, other: &KeySignature) -> bool {
+        let normalise = |mode: Mode| match mode {
+            Mode::Natural | Mode::Ionian => Mode::Major,
+            Mode::Aeolian => Mode::Minor,
+            mode => mode,
+        };
+
+        (self.tonic.semitones() - other.tonic.semitones()).rem_euclid(12) == 0 &&
+            normalise(self.mode) == normalise(other.mode)
+    }
+
+    /// Number of sharps in the key signature, or negative for flats.
+    /// Theoretical keys can have more than 7, e.g. D# major has 9.
+    pub fn fifths(&self) -> i16 {
+        self.tonic.diatonic_pitch_class.fifths() +
+            7 * accidental_semitones(self.tonic.accidental) + self.mode.fifths()
+    }
+
+    /// The accidental that the key signature applies to this note, if any.
+    pub fn accidental(&self, diatonic_pitch_class: DiatonicPitchClass) -> Option<Accidental> {
+        let fifths = self.fifths();
+
+        let position = ORDER_OF_SHARPS
+            .iter()
+            .position(|x| *x == diatonic_pitch_class)
+            .unwrap_or(0) as i16;
+
+        // Count from the other end for flats.
+        let (position, direction) = if fifths >= 0 {
+            (position, 1)
+        } else {
+            (NOTES_IN_SCALE - 1 - position, -1)
+        };
+
+        // Number of times this note is altered. More than once for theoretical keys.
+        let times = if fifths.abs() > position {
+            (fifths.abs() - position - 1) / NOTES_IN_SCALE + 1
+        } else {
+            0
+        };
+
+        match times {
+            0 => None,
+            times => Accidental::from_semitones(times * direction),
+        }
+    }
+
+    /// Transpose the key by an interval. Keys with an impractical number of sharps or flats are
+    /// respelled, e.g. C# major becomes Db major.
+    pub fn transpose(&self, interval: Interval) -> KeySignature {
+        let key = KeySignature {
+            tonic: self.tonic.transpose(interval),
+            mode: self.mode,
+        };
+
+        let fifths = key.fifths();
+        if fifths.abs() <= MAX_KEY_FIFTHS {
+            return key;
+        }
+
+        // Twelve fifths round the circle is the same pitch, spelled with a different letter.
+        let degrees = if fifths > 0 { 1 } else { -1 };
+        let tonic = Pitch {
+            pitch_class: key.tonic,
+            octave: 0,
+        };
+        let respelled = Pitch::from_semitones(
+            tonic.semitones(),
+            key.tonic.diatonic_pitch_class.to_degree() + degrees,
+        );
+
+        KeySignature {
+            tonic: respelled.pitch_class,
+            mode: self.mode,
+        }
+    }
+}
+
+/// Tempo, e.g. "1/4=120" or "\"Allegro\"".
+/// Any of the parts may be missing.
+#[derive(Debug, PartialEq, PartialOrd, Clone)]
+pub struct Tempo {
+    /// The duration that counts as one beat.
+    /// When absent, the beat is the default note length.
+    pub beat: Option<FractionalDuration>,
+
+    /// Beats per minute.
+    pub bpm: Option<u32>,
+
+    /// Textual description, e.g. "Allegro".
+    pub label: Option<String>,
+}
+
+/// The duration class of a notehead, i.e. its shape.
+#[derive(Debug, Copy, Clone, PartialEq, PartialOrd)]
+pub enum DurationClass {
+    Breve,
+    Semibreve,
+    Minim,
+    Crotchet,
+    Quaver,
+    Semiquaver,
+    Demisemiquaver,
+    Hemidemisemiquaver,
+}
+
+// All duration classes, in order of duration.
+const DURATION_CLASSES: &[DurationClass] = &[
+    DurationClass::Breve,
+    DurationClass::Semibreve,
+    DurationClass::Minim,
+    DurationClass::Crotchet,
+    DurationClass::Quaver,
+    DurationClass::Semiquaver,
+    DurationClass::Demisemiquaver,
+    DurationClass::Hemidemisemiquaver,
+];
+
+/// Durations are decomposed into glyphs in whole numbers of the shortest glyph, a
+/// hemidemisemiquaver.
+const DURATION_UNITS_PER_SEMIBREVE: u32 = 64;
+
+/// Most dots used when decomposing a duration into tied glyphs.
+const MAX_TIED_DOTS: u32 = 2;
+
+impl DurationClass {
+    fn duration(&self) -> FractionalDuration {
+        match self {
+            &DurationClass::Breve => FractionalDuration(2, 1),
+            &DurationClass::Semibreve => FractionalDuration(1, 1),
+            &DurationClass::Minim => FractionalDuration(1, 2),
+            &DurationClass::Crotchet => FractionalDuration(1, 4),
+            &DurationClass::Quaver => FractionalDuration(1, 8),
+            &DurationClass::Semiquaver => FractionalDuration(1, 16),
+            &DurationClass::Demisemiquaver => FractionalDuration(1, 32),
+            &DurationClass::Hemidemisemiquaver => FractionalDuration(1, 64),
+        }
+    }
+
+    // How many beams / tails does this duration render as?
+    pub fn beams(&self) -> u32 {
+        match self {
+            &DurationClass::Breve => 0,
+            &DurationClass::Semibreve => 0,
+            &DurationClass::Minim => 0,
+            &DurationClass::Crotchet => 0,
+            &DurationClass::Quaver => 1,
+            &DurationClass::Semiquaver => 2,
+            &DurationClass::Demisemiquaver => 3,
+            &DurationClass::Hemidemisemiquaver => 4,
+        }
+    }
+}
+
+/// Represent a duration per notation.
+#[derive(Debug, PartialEq, Copy, Clone, PartialOrd)]
+pub struct DurationGlyph {
+    pub shape: DurationClass,
+    pub dots: u32,
+}
+
+impl DurationGlyph {
+    /// Duration represented, e.g. 3/8 for a dotted crotchet.
+    pub fn duration(&self) -> FractionalDuration {
+        // Each dot adds half of the previous value.
+        let dots = 2u32.pow(self.dots);
+        self.shape.duration().multiply(
+            FractionalDuration(dots * 2 - 1, dots),
+        )
+    }
+
+    /// All glyphs up to a number of dots, longest first.
+    fn all(max_dots: u32) -> Vec<DurationGlyph> {
+        let mut result = vec![];
+
+        for shape in DURATION_CLASSES.iter() {
+            for dots in 0..max_dots + 1 {
+                result.push(DurationGlyph {
+                    shape: *shape,
+                    dots,
+                });
+            }
+        }
+
+        result.sort_by(|a, b| b.duration().units_exact().cmp(&a.duration().units_exact()));
+
+        result
+    }
+}
+
+/// A duration as a fraction of the default duration.
+#[derive(Debug, PartialEq, PartialOrd, Clone, Copy)]
+pub struct FractionalDuration(pub u32, pub u32);
+
+impl FractionalDuration {
+    /// Multiply this fractional duration by another.
+    /// Used to resolve a duration against a standard duration.
+    pub fn multiply(self, other: FractionalDuration) -> FractionalDuration {
+
+        let vulgar = FractionalDuration(self.0 * other.0, self.1 * other.1);
+
+        return vulgar.reduce();
+    }
+
+    /// Divide this fractional duration by another.
+    /// Used to express a duration in multiples of a standard duration.
+    pub fn divide(self, other: FractionalDuration) -> FractionalDuration {
+        FractionalDuration(self.0 * other.1, self.1 * other.0).reduce()
+    }
+
+    pub fn add(self, other: FractionalDuration) -> FractionalDuration {
+        FractionalDuration(self.0 * other.1 + other.0 * self.1, self.1 * other.1).reduce()
+    }
+
+    #[cfg(test)]
+    pub fn subtract(self, other: FractionalDuration) -> FractionalDuration {
+        let self_numerator = self.0 * other.1;
+        let other_numerator = other.0 * self.1;
+        let denomenator = self.1 * other.1;
+
+        FractionalDuration(self_numerator - other_numerator, denomenator).reduce()
+    }
+
+    /// Reduce this fraction to its simplest form.
+    pub fn reduce(self) -> FractionalDuration {
+        let max = u32::max(self.0, self.1);
+        for i in (1..max + 1).rev() {
+
+            if (self.0 % i == 0) && (self.1 % i) == 0 {
+                return FractionalDuration(self.0 / i, self.1 / i);
+            }
+        }
+
+        self
+    }
+
+    /// Is this duration greater than the other one?
+    /// TODO Implement PartialOrd properly!
+    pub fn gte(&self, other: &FractionalDuration) -> bool {
+        let self_numerator = self.0 * other.1;
+        let other_numerator = other.0 * self.1;
+        self_numerator >= other_numerator
+    }
+
+    /// Number of hemidemisemiquavers, rounded to the nearest.
+    /// Durations that can't be notated without tuplets are approximated. A malformed duration
+    /// with no denominator, e.g. `A/0`, has none.
+    fn units(&self) -> u32 {
+        if self.1 == 0 {
+            return 0;
+        }
+
+        (self.0 * DURATION_UNITS_PER_SEMIBREVE + self.1 / 2) / self.1
+    }
+
+    /// Number of hemidemisemiquavers, if a whole number.
+    fn units_exact(&self) -> Option<u32> {
+        if (self.0 * DURATION_UNITS_PER_SEMIBREVE) % self.1 == 0 {
+            Some(self.0 * DURATION_UNITS_PER_SEMIBREVE / self.1)
+        } else {
+            None
+        }
+    }
+
+    /// Transform this duration into a single notehead glyph.
+    /// i.e. "3/8" becomes "dotted crotchet".
+    /// None if it needs more than one glyph. See `to_glyphs`, which typesetting uses instead.
+    #[cfg(test)]
+    pub fn to_glyph(&self) -> Option<DurationGlyph> {
+        const MAX_DOTS: u32 = 4;
+
+        let this = self.reduce();
+
+        for shape in DURATION_CLASSES.iter() {
+            for dots in 0..MAX_DOTS + 1 {
+                let glyph = DurationGlyph {
+                    shape: *shape,
+                    dots,
+                };
+
+                if glyph.duration() == this {
+                    return Some(glyph);
+                }
+            }
+        }
+
+        None
+    }
+
+    /// Transform this duration into a sequence of tied notehead glyphs, for a note starting at
+    /// `start` within a bar.
+    /// A note starting on a beat may fill whole beats, up to the end of the bar. Otherwise it is
+    /// split at the next beat. Durations are rounded to the nearest hemidemisemiquaver.
+    pub fn to_glyphs(&self, start: FractionalDuration, metre: Metre) -> Vec<DurationGlyph> {
+        let glyphs = DurationGlyph::all(MAX_TIED_DOTS)
+            .into_iter()
+            .filter_map(|glyph| glyph.duration().units_exact().map(|units| (glyph, units)))
+            .collect::<Vec<(DurationGlyph, u32)>>();
+
+        let beat = metre.beat().units().max(1);
+        let bar = metre.bar().units().max(1);
+
+        let mut position = start.units() % bar;
+
+        // Even the shortest note needs a glyph.
+        let mut remaining = self.units().max(1);
+
+        let mut result = vec![];
+
+        while remaining > 0 {
+            let to_beat = beat - position % beat;
+            let to_bar = bar - position;
+            let whole_beats = u32::min(remaining, to_bar) / beat * beat;
+
+            let limit = if position % beat == 0 && whole_beats > 0 {
+                whole_beats
+            } else {
+                u32::min(remaining, u32::min(to_beat, to_bar))
+            };
+
+            // There is always a glyph of one unit, so this always finds one.
+            let &(glyph, units) = glyphs.iter().find(|&&(_, units)| units <= limit).unwrap();
+
+            result.push(glyph);
+            remaining -= units;
+            position = (position + units) % bar;
+        }
+
+        result
+    }
+}
+
+#[derive(Debug, PartialEq, PartialOrd, Clone)]
+pub struct Note(pub Pitch, pub FractionalDuration);
+
+impl Note {
+    /// Adjust this note's duration by mutiplying by a base.
+    pub fn resolve_duration(&self, base_duration: FractionalDuration) -> Note {
+        Note(self.0, self.1.multiply(base_duration))
+    }
+}
+
+/// Grace notes, e.g. "{g}" or "{/ag}", attached to the following note.
+#[derive(Debug, PartialEq, PartialOrd, Clone)]
+pub struct GraceNotes {
+    /// Slashed, i.e. played as quickly as possible.
+    pub acciaccatura: bool,
+    pub notes: Vec<Note>,
+}
+
+/// A decoration on the following note, e.g. "~" or "!trill!".
+/// Only those that affect playback are recognised.
+#[derive(Debug, PartialEq, PartialOrd, Clone, Copy)]
+pub enum Decoration {
+    Roll,
+    Trill,
+    LowerMordent,
+    UpperMordent,
+    Staccato,
+    Accent,
+    Fermata,
+}
+
+// All decorations, for lookup by name.
+const DECORATIONS: &[Decoration] = &[
+    Decoration::Roll,
+    Decoration::Trill,
+    Decoration::LowerMordent,
+    Decoration::UpperMordent,
+    Decoration::Staccato,
+    Decoration::Accent,
+    Decoration::Fermata,
+];
+
+impl Decoration {
+    /// Name as written between exclamation marks.
+    pub fn name(&self) -> &'static str {
+        match self {
+            &Decoration::Roll => "roll",
+            &Decoration::Trill => "trill",
+            &Decoration::LowerMordent => "lowermordent",
+            &Decoration::UpperMordent => "uppermordent",
+            &Decoration::Staccato => "staccato",
+            &Decoration::Accent => "accent",
+            &Decoration::Fermata => "fermata",
+        }
+    }
+
+    /// Single character abbreviation, as defined by the ABC standard.
+    pub fn shorthand(&self) -> char {
+        match self {
+            &Decoration::Roll => '~',
+            &Decoration::Trill => 'T',
+            &Decoration::LowerMordent => 'M',
+            &Decoration::UpperMordent => 'P',
+            &Decoration::Staccato => '.',
+            &Decoration::Accent => 'L',
+            &Decoration::Fermata => 'H',
+        }
+    }
+
+    /// Look up a decoration by name, including synonyms, e.g. "mordent" or ">".
+    pub fn from_name(name: &str) -> Option<Decoration> {
+        match name {
+            "mordent" => Some(Decoration::LowerMordent),
+            "pralltriller" => Some(Decoration::UpperMordent),
+            ">" | "emphasis" => Some(Decoration::Accent),
+            _ => DECORATIONS.iter().cloned().find(|d| d.name() == name),
+        }
+    }
+
+    pub fn from_shorthand(shorthand: char) -> Option<Decoration> {
+        DECORATIONS.iter().cloned().find(|d| d.shorthand() == shorthand)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fractional_duration_multiply() {
+        assert_eq!(
+            FractionalDuration(1, 1).multiply(FractionalDuration(1, 4)),
+            FractionalDuration(1, 4),
+            "Resolving duration of 1 in 1/4 gives 1/4"
+        );
+
+        assert_eq!(
             FractionalDuration(2, 1).multiply(FractionalDuration(1, 4)),
             FractionalDuration(1, 2),
             "Resolving duration of 1 in 1/4 gives simplified 1/2"
@@ -441,61 +1857,168 @@ mod tests {
             })
         );
         assert_eq!(
-            FractionalDuration(3, 64).to_glyph(),
-            Some(DurationGlyph {
-                shape: DurationClass::Demisemiquaver,
-                dots: 1,
-            })
+            FractionalDuration(3, 64).to_glyph(),
+            Some(DurationGlyph {
+                shape: DurationClass::Demisemiquaver,
+                dots: 1,
+            })
+        );
+
+        // Two dots
+        assert_eq!(
+            FractionalDuration(7, 4).to_glyph(),
+            Some(DurationGlyph {
+                shape: DurationClass::Semibreve,
+                dots: 2,
+            })
+        );
+        assert_eq!(
+            FractionalDuration(7, 8).to_glyph(),
+            Some(DurationGlyph {
+                shape: DurationClass::Minim,
+                dots: 2,
+            })
+        );
+        assert_eq!(
+            FractionalDuration(7, 16).to_glyph(),
+            Some(DurationGlyph {
+                shape: DurationClass::Crotchet,
+                dots: 2,
+            })
+        );
+        assert_eq!(
+            FractionalDuration(7, 32).to_glyph(),
+            Some(DurationGlyph {
+                shape: DurationClass::Quaver,
+                dots: 2,
+            })
+        );
+
+        assert_eq!(
+            FractionalDuration(7, 64).to_glyph(),
+            Some(DurationGlyph {
+                shape: DurationClass::Semiquaver,
+                dots: 2,
+            })
+        );
+
+        // A double-dotted semiquaver should be enough for anyone.
+        assert_eq!(
+            FractionalDuration(7, 128).to_glyph(),
+            Some(DurationGlyph {
+                shape: DurationClass::Demisemiquaver,
+                dots: 2,
+            })
+        );
+    }
+
+    #[test]
+    fn duration_to_glyph_complex_test() {
+        assert_eq!(
+            FractionalDuration(2, 1).to_glyph(),
+            Some(DurationGlyph {
+                shape: DurationClass::Breve,
+                dots: 0,
+            })
+        );
+        assert_eq!(
+            FractionalDuration(1, 64).to_glyph(),
+            Some(DurationGlyph {
+                shape: DurationClass::Hemidemisemiquaver,
+                dots: 0,
+            })
+        );
+
+        // Needs a tie.
+        assert_eq!(FractionalDuration(5, 8).to_glyph(), None);
+        assert_eq!(FractionalDuration(1, 3).to_glyph(), None);
+    }
+
+    #[test]
+    fn duration_to_glyphs_test() {
+        let glyph = |shape, dots| DurationGlyph { shape, dots };
+        let start = |n, d| FractionalDuration(n, d);
+
+        // A single glyph where one will do.
+        assert_eq!(
+            FractionalDuration(3, 8).to_glyphs(start(0, 1), Metre(6, 8)),
+            vec![glyph(DurationClass::Crotchet, 1)]
+        );
+        assert_eq!(
+            FractionalDuration(3, 4).to_glyphs(start(0, 1), Metre(4, 4)),
+            vec![glyph(DurationClass::Minim, 1)]
+        );
+
+        // Whole beats, then the rest.
+        assert_eq!(
+            FractionalDuration(5, 8).to_glyphs(start(0, 1), Metre(6, 8)),
+            vec![glyph(DurationClass::Crotchet, 1), glyph(DurationClass::Crotchet, 0)]
+        );
+
+        // Off the beat, split at the next beat.
+        assert_eq!(
+            FractionalDuration(1, 4).to_glyphs(start(1, 8), Metre(2, 4)),
+            vec![glyph(DurationClass::Quaver, 0), glyph(DurationClass::Quaver, 0)]
+        );
+        assert_eq!(
+            FractionalDuration(1, 2).to_glyphs(start(1, 8), Metre(4, 4)),
+            vec![
+                glyph(DurationClass::Quaver, 0),
+                glyph(DurationClass::Crotchet, 0),
+                glyph(DurationClass::Quaver, 0),
+            ]
         );
 
-        // Two dots
+        // Across the barline.
         assert_eq!(
-            FractionalDuration(7, 4).to_glyph(),
-            Some(DurationGlyph {
-                shape: DurationClass::Semibreve,
-                dots: 2,
-            })
+            FractionalDuration(2, 1).to_glyphs(start(1, 2), Metre(4, 4)),
+            vec![
+                glyph(DurationClass::Minim, 0),
+                glyph(DurationClass::Semibreve, 0),
+                glyph(DurationClass::Minim, 0),
+            ]
         );
+
+        // A whole bar in long metres.
         assert_eq!(
-            FractionalDuration(7, 8).to_glyph(),
-            Some(DurationGlyph {
-                shape: DurationClass::Minim,
-                dots: 2,
-            })
+            FractionalDuration(2, 1).to_glyphs(start(0, 1), Metre(4, 2)),
+            vec![glyph(DurationClass::Breve, 0)]
         );
+
+        // Tuplets can't be notated yet, so are approximated, here to 5/64.
         assert_eq!(
-            FractionalDuration(7, 16).to_glyph(),
-            Some(DurationGlyph {
-                shape: DurationClass::Crotchet,
-                dots: 2,
-            })
+            FractionalDuration(1, 12).to_glyphs(start(0, 1), Metre(4, 4)),
+            vec![
+                glyph(DurationClass::Semiquaver, 0),
+                glyph(DurationClass::Hemidemisemiquaver, 0),
+            ]
         );
         assert_eq!(
-            FractionalDuration(7, 32).to_glyph(),
-            Some(DurationGlyph {
-                shape: DurationClass::Quaver,
-                dots: 2,
-            })
+            FractionalDuration(1, 1000).to_glyphs(start(0, 1), Metre(4, 4)),
+            vec![glyph(DurationClass::Hemidemisemiquaver, 0)]
         );
 
+        // A malformed duration gets the shortest glyph. In a malformed metre, every unit is a bar.
         assert_eq!(
-            FractionalDuration(7, 64).to_glyph(),
-            Some(DurationGlyph {
-                shape: DurationClass::Semiquaver,
-                dots: 2,
-            })
+            FractionalDuration(1, 0).to_glyphs(start(0, 1), Metre(4, 4)),
+            vec![glyph(DurationClass::Hemidemisemiquaver, 0)]
         );
-
-        // A double-dotted semiquaver should be enough for anyone.
         assert_eq!(
-            FractionalDuration(7, 128).to_glyph(),
-            Some(DurationGlyph {
-                shape: DurationClass::Demisemiquaver,
-                dots: 2,
-            })
+            FractionalDuration(1, 4).to_glyphs(start(1, 0), Metre(3, 0)),
+            vec![glyph(DurationClass::Hemidemisemiquaver, 0); 16]
         );
     }
 
+    #[test]
+    fn metre_beat_test() {
+        assert_eq!(Metre(4, 4).beat(), FractionalDuration(1, 4));
+        assert_eq!(Metre(3, 4).beat(), FractionalDuration(1, 4));
+        assert_eq!(Metre(6, 8).beat(), FractionalDuration(3, 8));
+        assert_eq!(Metre(9, 8).beat(), FractionalDuration(3, 8));
+        assert_eq!(Metre(3, 8).beat(), FractionalDuration(1, 8));
+        assert_eq!(Metre(6, 8).bar(), FractionalDuration(3, 4));
+    }
+
     #[test]
     fn pitch_minus_as_degrees_test() {
         assert_eq!(
@@ -585,4 +2108,597 @@ mod tests {
 
     }
 
+
+    #[test]
+    fn key_signature_accidental_test() {
+        let key = |diatonic_pitch_class, accidental, mode| {
+            KeySignature {
+                tonic: PitchClass {
+                    diatonic_pitch_class,
+                    accidental,
+                },
+                mode,
+            }
+        };
+
+        let d_major = key(DiatonicPitchClass::D, None, Mode::Major);
+        assert_eq!(d_major.fifths(), 2);
+        assert_eq!(d_major.accidental(DiatonicPitchClass::F), Some(Accidental::Sharp));
+        assert_eq!(d_major.accidental(DiatonicPitchClass::C), Some(Accidental::Sharp));
+        assert_eq!(d_major.accidental(DiatonicPitchClass::G), None);
+
+        let e_dorian = key(DiatonicPitchClass::E, None, Mode::Dorian);
+        assert_eq!(e_dorian.fifths(), 2, "E Dorian has the same signature as D major.");
+
+        let b_flat_minor = key(DiatonicPitchClass::B, Some(Accidental::Flat), Mode::Minor);
+        assert_eq!(b_flat_minor.fifths(), -5);
+        assert_eq!(b_flat_minor.accidental(DiatonicPitchClass::G), Some(Accidental::Flat));
+        assert_eq!(b_flat_minor.accidental(DiatonicPitchClass::C), None);
+
+        let d_sharp_major = key(DiatonicPitchClass::D, Some(Accidental::Sharp), Mode::Major);
+        assert_eq!(d_sharp_major.fifths(), 9);
+        assert_eq!(
+            d_sharp_major.accidental(DiatonicPitchClass::F),
+            Some(Accidental::DoubleSharp)
+        );
+        assert_eq!(
+            d_sharp_major.transpose(Interval {
+                pitch_classes: 0,
+                accidental_semitones: 0,
+            }).tonic,
+            PitchClass {
+                diatonic_pitch_class: DiatonicPitchClass::E,
+                accidental: Some(Accidental::Flat),
+            },
+            "Theoretical keys are respelled."
+        );
+    }
+
+    #[test]
+    fn pitch_transpose_test() {
+        let pitch = |diatonic_pitch_class, accidental, octave| {
+            Pitch {
+                pitch_class: PitchClass {
+                    diatonic_pitch_class,
+                    accidental,
+                },
+                octave,
+            }
+        };
+
+        let minor_third = Interval {
+            pitch_classes: 2,
+            accidental_semitones: -1,
+        };
+
+        assert_eq!(minor_third.semitones(), 3);
+        assert_eq!(
+            pitch(DiatonicPitchClass::A, None, 0).transpose(minor_third),
+            pitch(DiatonicPitchClass::C, None, 1),
+            "Transposition crosses into the next octave at C."
+        );
+        assert_eq!(
+            pitch(DiatonicPitchClass::E, None, 0).transpose(minor_third),
+            pitch(DiatonicPitchClass::G, None, 0)
+        );
+        assert_eq!(
+            pitch(DiatonicPitchClass::B, None, 0).transpose(minor_third),
+            pitch(DiatonicPitchClass::D, None, 1)
+        );
+        assert_eq!(
+            pitch(DiatonicPitchClass::F, Some(Accidental::Sharp), 0).transpose(minor_third),
+            pitch(DiatonicPitchClass::A, None, 0)
+        );
+
+        let down_fifth = Interval {
+            pitch_classes: -4,
+            accidental_semitones: 0,
+        };
+        assert_eq!(down_fifth.semitones(), -7);
+        assert_eq!(
+            pitch(DiatonicPitchClass::F, None, 0).transpose(down_fifth),
+            pitch(DiatonicPitchClass::B, Some(Accidental::Flat), -1)
+        );
+
+        assert_eq!(
+            pitch(DiatonicPitchClass::B, Some(Accidental::DoubleSharp), 0).simplify(),
+            pitch(DiatonicPitchClass::C, Some(Accidental::Sharp), 1)
+        );
+
+        assert_eq!(
+            Interval::between(
+                PitchClass {
+                    diatonic_pitch_class: DiatonicPitchClass::B,
+                    accidental: None,
+                },
+                PitchClass {
+                    diatonic_pitch_class: DiatonicPitchClass::D,
+                    accidental: Some(Accidental::Flat),
+                },
+                2,
+            ),
+            Interval {
+                pitch_classes: 2,
+                accidental_semitones: -2,
+            },
+            "B to Db is a diminished third."
+        );
+    }
+
+    #[test]
+    fn midi_number_test() {
+        let pitch = |diatonic_pitch_class, accidental, octave| {
+            Pitch {
+                pitch_class: PitchClass {
+                    diatonic_pitch_class,
+                    accidental,
+                },
+                octave,
+            }
+        };
+
+        assert_eq!(pitch(DiatonicPitchClass::C, None, 0).midi_number(), Some(60));
+        assert_eq!(pitch(DiatonicPitchClass::A, None, 0).midi_number(), Some(69));
+        assert_eq!(
+            pitch(DiatonicPitchClass::B, Some(Accidental::Sharp), 0).midi_number(),
+            Some(72),
+            "B# is enharmonic with the C above."
+        );
+        assert_eq!(pitch(DiatonicPitchClass::C, None, -5).midi_number(), Some(0));
+        assert_eq!(pitch(DiatonicPitchClass::C, None, -6).midi_number(), None);
+        assert_eq!(pitch(DiatonicPitchClass::A, None, 6).midi_number(), None);
+
+        assert_eq!(
+            pitch(DiatonicPitchClass::E, None, 0)
+                .semitones_to(pitch(DiatonicPitchClass::C, None, 0)),
+            -4
+        );
+    }
+
+    #[test]
+    fn from_midi_number_test() {
+        let key = |diatonic_pitch_class, accidental, mode| {
+            KeySignature {
+                tonic: PitchClass {
+                    diatonic_pitch_class,
+                    accidental,
+                },
+                mode,
+            }
+        };
+
+        let pitch = |diatonic_pitch_class, accidental, octave| {
+            Pitch {
+                pitch_class: PitchClass {
+                    diatonic_pitch_class,
+                    accidental,
+                },
+                octave,
+            }
+        };
+
+        let d_major = key(DiatonicPitchClass::D, None, Mode::Major);
+        let f_major = key(DiatonicPitchClass::F, None, Mode::Major);
+        let c_major = key(DiatonicPitchClass::C, None, Mode::Major);
+        let a_flat_major = key(DiatonicPitchClass::A, Some(Accidental::Flat), Mode::Major);
+
+        assert_eq!(
+            Pitch::from_midi_number(66, d_major),
+            pitch(DiatonicPitchClass::F, Some(Accidental::Sharp), 0)
+        );
+        assert_eq!(
+            Pitch::from_midi_number(70, f_major),
+            pitch(DiatonicPitchClass::B, Some(Accidental::Flat), 0)
+        );
+        assert_eq!(
+            Pitch::from_midi_number(70, c_major),
+            pitch(DiatonicPitchClass::A, Some(Accidental::Sharp), 0),
+            "Black notes are sharp outside of flat keys."
+        );
+        assert_eq!(
+            Pitch::from_midi_number(66, a_flat_major),
+            pitch(DiatonicPitchClass::G, Some(Accidental::Flat), 0),
+            "Black notes are flat in flat keys."
+        );
+        assert_eq!(
+            Pitch::from_midi_number(48, c_major),
+            pitch(DiatonicPitchClass::C, None, -1)
+        );
+
+        // Round trip.
+        for number in 0..128 {
+            assert_eq!(Pitch::from_midi_number(number, d_major).midi_number(), Some(number));
+        }
+    }
+
+    #[test]
+    fn frequency_test() {
+        let a = Pitch {
+            pitch_class: PitchClass {
+                diatonic_pitch_class: DiatonicPitchClass::A,
+                accidental: None,
+            },
+            octave: 0,
+        };
+
+        let close = |a: f64, b: f64| (a - b).abs() < 0.01;
+
+        assert!(close(Tuning::standard().frequency(a), 440.0));
+        assert!(close(
+            Tuning::standard().frequency(Pitch {
+                octave: 1,
+                ..a
+            }),
+            880.0
+        ));
+        assert!(close(Tuning::standard().frequency_of_midi_number(60.0), 261.63));
+
+        let baroque = Tuning {
+            temperament: Temperament::Equal,
+            reference_a: 415.0,
+        };
+        assert!(close(baroque.frequency(a), 415.0));
+    }
+
+    #[test]
+    fn accidentals_test() {
+        let pitch = |diatonic_pitch_class, accidental| {
+            Pitch {
+                pitch_class: PitchClass {
+                    diatonic_pitch_class,
+                    accidental,
+                },
+                octave: 0,
+            }
+        };
+
+        let mut accidentals = Accidentals::new(KeySignature {
+            tonic: PitchClass {
+                diatonic_pitch_class: DiatonicPitchClass::G,
+                accidental: None,
+            },
+            mode: Mode::Major,
+        });
+
+        assert_eq!(
+            accidentals.resolve(pitch(DiatonicPitchClass::F, None)),
+            pitch(DiatonicPitchClass::F, Some(Accidental::Sharp)),
+            "Key signature applies."
+        );
+        assert_eq!(
+            accidentals.resolve(pitch(DiatonicPitchClass::F, Some(Accidental::Natural))),
+            pitch(DiatonicPitchClass::F, Some(Accidental::Natural))
+        );
+        assert_eq!(
+            accidentals.resolve(pitch(DiatonicPitchClass::F, None)),
+            pitch(DiatonicPitchClass::F, Some(Accidental::Natural)),
+            "Accidental lasts for the bar."
+        );
+
+        accidentals.end_bar();
+        assert_eq!(
+            accidentals.resolve(pitch(DiatonicPitchClass::F, None)),
+            pitch(DiatonicPitchClass::F, Some(Accidental::Sharp)),
+            "Accidental forgotten in the next bar."
+        );
+
+        assert_eq!(
+            accidentals.notate(pitch(DiatonicPitchClass::F, Some(Accidental::Sharp))),
+            pitch(DiatonicPitchClass::F, None)
+        );
+        assert_eq!(
+            accidentals.notate(pitch(DiatonicPitchClass::C, Some(Accidental::Sharp))),
+            pitch(DiatonicPitchClass::C, Some(Accidental::Sharp))
+        );
+        assert_eq!(
+            accidentals.notate(pitch(DiatonicPitchClass::C, Some(Accidental::Sharp))),
+            pitch(DiatonicPitchClass::C, None),
+            "Accidental needn't be repeated."
+        );
+    }
+
+    #[test]
+    fn key_signature_from_semitones_test() {
+        assert_eq!(
+            KeySignature::from_semitones(8, Mode::Major).tonic,
+            PitchClass {
+                diatonic_pitch_class: DiatonicPitchClass::A,
+                accidental: Some(Accidental::Flat),
+            }
+        );
+        assert_eq!(
+            KeySignature::from_semitones(8, Mode::Minor).tonic,
+            PitchClass {
+                diatonic_pitch_class: DiatonicPitchClass::G,
+                accidental: Some(Accidental::Sharp),
+            }
+        );
+        assert!(KeySignature::from_semitones(9, Mode::Minor).equivalent(&KeySignature {
+            tonic: PitchClass {
+                diatonic_pitch_class: DiatonicPitchClass::A,
+                accidental: None,
+            },
+            mode: Mode::Aeolian,
+        }));
+    }
+
+    #[test]
+    fn interval_quality_test() {
+        let major_third = Interval::new(IntervalQuality::Major, 3).unwrap();
+        assert_eq!(major_third.semitones(), 4);
+        assert_eq!(major_third.quality(), Some(IntervalQuality::Major));
+        assert_eq!(major_third.number(), 3);
+
+        let descending_fifth = Interval::new(IntervalQuality::Perfect, -5).unwrap();
+        assert_eq!(descending_fifth.semitones(), -7);
+        assert_eq!(descending_fifth.quality(), Some(IntervalQuality::Perfect));
+        assert_eq!(descending_fifth.number(), -5);
+
+        let augmented_fourth = Interval::new(IntervalQuality::Augmented, 4).unwrap();
+        assert_eq!(augmented_fourth.semitones(), 6);
+
+        let diminished_fifth = Interval::new(IntervalQuality::Diminished, 5).unwrap();
+        assert_eq!(diminished_fifth.semitones(), 6);
+        assert!(augmented_fourth != diminished_fifth, "Enharmonic but not equal.");
+
+        let major_tenth = Interval::new(IntervalQuality::Major, 10).unwrap();
+        assert_eq!(major_tenth.semitones(), 16);
+        assert_eq!(major_tenth.quality(), Some(IntervalQuality::Major));
+
+        assert_eq!(Interval::new(IntervalQuality::Perfect, 3), None);
+        assert_eq!(Interval::new(IntervalQuality::Minor, 5), None);
+        assert_eq!(Interval::new(IntervalQuality::Major, 0), None);
+
+        let descending_diminished_unison = Interval::from_degrees(0, -1);
+        assert_eq!(
+            descending_diminished_unison.quality(),
+            Some(IntervalQuality::Diminished)
+        );
+        assert!(descending_diminished_unison.is_descending());
+    }
+
+    #[test]
+    fn interval_arithmetic_test() {
+        let interval = |input| Interval::parse(input).unwrap();
+
+        assert_eq!(interval("M3") + interval("m3"), interval("P5"));
+        assert_eq!(interval("P5") + interval("P4"), interval("P8"));
+        assert_eq!(interval("M3") + interval("-M3"), interval("P1"));
+        assert_eq!(interval("P5") + interval("-M2"), interval("P4"));
+        assert_eq!(-interval("m6"), interval("-m6"));
+
+        assert_eq!(interval("M3").invert(), interval("m6"));
+        assert_eq!(interval("A4").invert(), interval("d5"));
+        assert_eq!(interval("P1").invert(), interval("P8"));
+        assert_eq!(interval("P8").invert(), interval("P1"));
+        assert_eq!(interval("M10").invert(), interval("m6"), "Compound reduced first.");
+        assert_eq!(interval("-m2").invert(), interval("-M7"), "Direction is kept.");
+
+        let pitch = |diatonic_pitch_class, accidental, octave| {
+            Pitch {
+                pitch_class: PitchClass {
+                    diatonic_pitch_class,
+                    accidental,
+                },
+                octave,
+            }
+        };
+
+        assert_eq!(
+            pitch(DiatonicPitchClass::F, None, 0) + interval("A4"),
+            pitch(DiatonicPitchClass::B, None, 0)
+        );
+        assert_eq!(
+            pitch(DiatonicPitchClass::F, None, 0) + interval("d5"),
+            pitch(DiatonicPitchClass::C, Some(Accidental::Flat), 1)
+        );
+        assert_eq!(
+            pitch(DiatonicPitchClass::E, None, 0) + interval("-m3"),
+            pitch(DiatonicPitchClass::C, Some(Accidental::Sharp), 0)
+        );
+
+        assert_eq!(
+            pitch(DiatonicPitchClass::F, None, 0).interval_to(pitch(DiatonicPitchClass::B, None, 0)),
+            interval("A4")
+        );
+        assert_eq!(
+            pitch(DiatonicPitchClass::E, None, 1).interval_to(pitch(DiatonicPitchClass::C, None, 0)),
+            interval("-M10")
+        );
+    }
+
+    #[test]
+    fn interval_display_test() {
+        for input in ["P1", "M3", "-P5", "A4", "d5", "m10", "-M7", "dd7", "AA6"].iter() {
+            assert_eq!(Interval::parse(input).unwrap().to_string(), *input);
+        }
+
+        assert_eq!(Interval::parse("+M2"), Interval::parse("M2"));
+        assert_eq!(Interval::parse("M"), None);
+        assert_eq!(Interval::parse("X3"), None);
+        assert_eq!(Interval::parse("M0"), None);
+        assert_eq!(Interval::parse("P2"), None);
+
+        // Too wide to transpose by.
+        assert_eq!(Interval::parse("P71").unwrap().semitones(), 120);
+        assert_eq!(Interval::parse("M72"), None);
+        assert_eq!(Interval::parse("A20000"), None);
+        assert_eq!(Interval::parse("-P99999999"), None);
+
+        assert_eq!(Interval::from_degrees(2, 7).to_string(), "(+3)3");
+    }
+
+    #[test]
+    fn clef_test() {
+        let pitch = |diatonic_pitch_class, octave| {
+            Pitch {
+                pitch_class: PitchClass {
+                    diatonic_pitch_class,
+                    accidental: None,
+                },
+                octave,
+            }
+        };
+
+        let middle_c = pitch(DiatonicPitchClass::C, 0);
+
+        // Middle C is the first ledger line below treble, above bass, and the middle line of alto.
+        assert_eq!(Clef::treble().position(middle_c), -2);
+        assert_eq!(Clef::bass().position(middle_c), 10);
+        assert_eq!(Clef::alto().position(middle_c), 4);
+        assert_eq!(Clef::tenor().position(middle_c), 6);
+        assert_eq!(Clef::treble_8vb().position(middle_c), 5);
+
+        // Bottom line of each stave.
+        assert_eq!(Clef::treble().position(pitch(DiatonicPitchClass::E, 0)), 0);
+        assert_eq!(Clef::bass().position(pitch(DiatonicPitchClass::G, -2)), 0);
+        assert_eq!(Clef::alto().position(pitch(DiatonicPitchClass::F, -1)), 0);
+        assert_eq!(Clef::tenor().position(pitch(DiatonicPitchClass::D, -1)), 0);
+
+        for name in ["treble", "treble-8", "bass", "alto", "tenor", "perc"].iter() {
+            assert_eq!(Clef::from_name(name).unwrap().name(), *name);
+        }
+
+        assert_eq!(Clef::from_name("Bass"), Some(Clef::bass()));
+        assert_eq!(Clef::from_name("F4"), Some(Clef::bass()));
+        assert_eq!(Clef::from_name("soprano"), None);
+    }
+
+    #[test]
+    fn chord_symbol_parse_test() {
+        let pitch_class = |diatonic_pitch_class, accidental| {
+            PitchClass {
+                diatonic_pitch_class,
+                accidental,
+            }
+        };
+        let interval = |input| Interval::parse(input).unwrap();
+
+        assert_eq!(
+            ChordSymbol::parse("Am7"),
+            Some(ChordSymbol {
+                root: pitch_class(DiatonicPitchClass::A, None),
+                quality: ChordQuality::Minor,
+                extensions: vec![interval("m7")],
+                alterations: vec![],
+                bass: None,
+            })
+        );
+        assert_eq!(
+            ChordSymbol::parse("D/F#"),
+            Some(ChordSymbol {
+                root: pitch_class(DiatonicPitchClass::D, None),
+                quality: ChordQuality::Major,
+                extensions: vec![],
+                alterations: vec![],
+                bass: Some(pitch_class(DiatonicPitchClass::F, Some(Accidental::Sharp))),
+            })
+        );
+        assert_eq!(
+            ChordSymbol::parse("Bbmaj7").unwrap().extensions,
+            vec![interval("M7")]
+        );
+        assert_eq!(ChordSymbol::parse("Gsus4").unwrap().quality, ChordQuality::Suspended4);
+        assert_eq!(ChordSymbol::parse("Edim").unwrap().quality, ChordQuality::Diminished);
+        assert_eq!(ChordSymbol::parse("A5").unwrap().quality, ChordQuality::Power);
+        assert_eq!(
+            ChordSymbol::parse("C7(#9)").unwrap().alterations,
+            vec![interval("A9")]
+        );
+
+        // Annotations aren't chords.
+        assert_eq!(ChordSymbol::parse("Fine"), None);
+        assert_eq!(ChordSymbol::parse("^D.C."), None);
+        assert_eq!(ChordSymbol::parse("fine"), None);
+        assert_eq!(ChordSymbol::parse(""), None);
+    }
+
+    #[test]
+    fn chord_symbol_to_string_test() {
+        let canonical = |input| ChordSymbol::parse(input).unwrap().to_string();
+
+        // Canonical forms are unchanged.
+        for input in [
+            "C",
+            "Am",
+            "Am7",
+            "D/F#",
+            "Gsus4",
+            "G7sus4",
+            "Bbmaj7",
+            "Edim",
+            "Edim7",
+            "Faug",
+            "Bm7b5",
+            "C6",
+            "Cm6",
+            "D9",
+            "Cmaj9",
+            "G13",
+            "Cadd9",
+            "E7#9",
+            "Cmmaj7",
+            "A5",
+        ].iter()
+        {
+            assert_eq!(canonical(input), *input);
+        }
+
+        // Alternative spellings.
+        assert_eq!(canonical("Amin7"), "Am7");
+        assert_eq!(canonical("A-7"), "Am7");
+        assert_eq!(canonical("CM7"), "Cmaj7");
+        assert_eq!(canonical("CΔ"), "Cmaj7");
+        assert_eq!(canonical("Cmaj"), "C");
+        assert_eq!(canonical("Bø"), "Bm7b5");
+        assert_eq!(canonical("Co7"), "Cdim7");
+        assert_eq!(canonical("C+"), "Caug");
+        assert_eq!(canonical("Csus"), "Csus4");
+        assert_eq!(canonical("Cm(maj7)"), "Cmmaj7");
+        assert_eq!(canonical("C7(b9,#11)"), "C7b9#11");
+    }
+
+    #[test]
+    fn chord_symbol_pitch_classes_test() {
+        let names = |input| {
+            ChordSymbol::parse(input)
+                .unwrap()
+                .pitch_classes()
+                .iter()
+                .map(|pitch_class| chord_pitch_class_to_string(*pitch_class))
+                .collect::<Vec<String>>()
+                .join(" ")
+        };
+
+        assert_eq!(names("C"), "C E G");
+        assert_eq!(names("Am7"), "A C E G");
+        assert_eq!(names("D/F#"), "F# D A");
+        assert_eq!(names("G7/F"), "F G B D");
+        assert_eq!(names("Gsus4"), "G C D");
+        assert_eq!(names("Bbmaj7"), "Bb D F A");
+        assert_eq!(names("Edim"), "E G Bb");
+        assert_eq!(names("Bdim7"), "B D F Ab");
+        assert_eq!(names("Bm7b5"), "B D F A");
+        assert_eq!(names("Caug"), "C E G#");
+        assert_eq!(names("C9"), "C E G Bb D");
+        assert_eq!(names("C7b9"), "C E G Bb Db");
+        assert_eq!(names("C13b9"), "C E G Bb Db F A");
+        assert_eq!(names("Cadd9"), "C E G D");
+        assert_eq!(names("A5"), "A E");
+    }
+
+    #[test]
+    fn chord_symbol_transpose_test() {
+        let transpose = |input, interval| {
+            ChordSymbol::parse(input)
+                .unwrap()
+                .transpose(Interval::parse(interval).unwrap())
+                .to_string()
+        };
+
+        assert_eq!(transpose("D/F#", "M2"), "E/G#");
+        assert_eq!(transpose("Am7", "-P5"), "Dm7");
+        assert_eq!(transpose("Bbmaj7", "m3"), "Dbmaj7");
+        assert_eq!(transpose("E#m", "M2"), "Gm", "Double sharps respelled.");
+    }
 }
@@ -1,5 +1,387 @@
 //! Ngrams
 //! Represent a tune as a set of ngrams of intervals.
+//! Intervals are in semitones, so a tune has the same ngrams in any key. Coarser encodings of each
+//! step from note to note are also available, for when only the shape or rhythm is remembered.
+//! Each ngram is hashed into a compact id, so that the ngrams of a whole corpus fit in memory.
 
+use abc_lexer as l;
+use music;
+use tune_ast_three;
+use visitor;
 
-// Placeholder.
+/// Hashed ngram. Collisions are possible but rare enough not to matter for search.
+pub type NgramId = u32;
+
+/// Intervals wider than two octaves are clamped, as they're usually between phrases.
+const MAX_INTERVAL: i16 = 24;
+
+/// 32-bit FNV-1a.
+const FNV_OFFSET_BASIS: u32 = 0x811c_9dc5;
+const FNV_PRIME: u32 = 0x0100_0193;
+
+/// How each step from one note to the next is represented.
+#[derive(Debug, PartialEq, Eq, Hash, PartialOrd, Clone, Copy)]
+pub enum Encoding {
+    /// Interval in semitones.
+    Interval,
+
+    /// Parsons code: up, down or repeat.
+    Parsons,
+
+    /// Direction, and whether it's a step (up to a tone), skip (a third) or leap.
+    Contour,
+
+    /// Ratio of the note's duration to the previous one's, ignoring pitch.
+    Rhythm,
+}
+
+impl Encoding {
+    pub fn all() -> [Encoding; 4] {
+        [
+            Encoding::Interval,
+            Encoding::Parsons,
+            Encoding::Contour,
+            Encoding::Rhythm,
+        ]
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            &Encoding::Interval => "interval",
+            &Encoding::Parsons => "parsons",
+            &Encoding::Contour => "contour",
+            &Encoding::Rhythm => "rhythm",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Encoding> {
+        Encoding::all().iter().find(|encoding| encoding.name() == name).cloned()
+    }
+
+    /// Symbol for the step between two notes.
+    fn symbol(&self, from: &Sounded, to: &Sounded) -> i16 {
+        let interval = to.0 - from.0;
+
+        match self {
+            &Encoding::Interval => interval,
+            &Encoding::Parsons => interval.signum(),
+            &Encoding::Contour => {
+                let size = match interval.abs() {
+                    0 => 0,
+                    1..=2 => 1,
+                    3..=4 => 2,
+                    _ => 3,
+                };
+
+                size * interval.signum()
+            }
+            // In half-octaves of ratio, so that 2:1, 3:2 (dotted) and 3:1 are told apart.
+            &Encoding::Rhythm => {
+                if from.1 > 0.0 && to.1 > 0.0 {
+                    ((to.1 / from.1).log2() * 2.0).round() as i16
+                } else {
+                    0
+                }
+            }
+        }
+    }
+}
+
+/// Read a Parsons code, e.g. "*UDDRU". Letters are case-insensitive, and the "*" for the first
+/// note is optional. None if it isn't a Parsons code.
+pub fn parse_parsons(code: &str) -> Option<Vec<i16>> {
+    let code = code.trim();
+    let code = code.strip_prefix('*').unwrap_or(code);
+
+    if code.is_empty() {
+        return None;
+    }
+
+    code.chars()
+        .filter(|c| !c.is_whitespace())
+        .map(|c| match c.to_ascii_uppercase() {
+            'U' => Some(1),
+            'D' => Some(-1),
+            'R' => Some(0),
+            _ => None,
+        })
+        .collect()
+}
+
+#[derive(Debug, PartialEq, PartialOrd, Clone, Copy)]
+pub struct Options {
+    /// Number of steps in each ngram.
+    pub n: usize,
+
+    pub encoding: Encoding,
+
+    /// Weight each ngram by the duration of its notes, in crotchets, rather than counting it.
+    pub rhythm_weighted: bool,
+
+    /// Play repeats and endings through, rather than reading the notes as written.
+    pub expand_repeats: bool,
+}
+
+impl Options {
+    /// Ngrams of n intervals, counted each time they're written.
+    pub fn new(n: usize) -> Options {
+        Options {
+            n,
+            encoding: Encoding::Interval,
+            rhythm_weighted: false,
+            expand_repeats: false,
+        }
+    }
+}
+
+/// A note's pitch in semitones from middle C, its duration in crotchets, and its bar's index in
+/// the order the bars are read.
+type Sounded = (i16, f32, usize);
+
+/// The notes of a voice, with accidentals resolved.
+fn notes(voice: &[l::T], state: visitor::RunningState, expand_repeats: bool) -> Vec<Sounded> {
+    // State before each token, so that each bar can start from the right key.
+    let mut states = Vec::with_capacity(voice.len());
+    let mut running = state;
+    for token in voice.iter() {
+        states.push(running);
+        running.update(token);
+    }
+
+    let sections = tune_ast_three::sections(voice);
+
+    let bars = if expand_repeats {
+        tune_ast_three::playing_order(&sections)
+    } else {
+        sections
+            .iter()
+            .flat_map(|section| {
+                section.main.iter().chain(
+                    section.endings.iter().flat_map(|&(_, ref ending)| ending.iter()),
+                )
+            })
+            .cloned()
+            .collect()
+    };
+
+    let mut result = vec![];
+
+    for (bar_index, bar) in bars.iter().enumerate() {
+        let mut bar_state = states[bar.start];
+        let mut accidentals = music::Accidentals::new(bar_state.key);
+
+        for token in voice[bar.start..bar.end].iter() {
+            bar_state.update(token);
+
+            match token {
+                &l::T::KeySignature(_, _) => accidentals.set_key(bar_state.key),
+
+                &l::T::Note(music::Note(pitch, music::FractionalDuration(numerator, denominator))) => {
+                    result.push((
+                        accidentals.resolve(pitch).semitones(),
+                        numerator as f32 * 4.0 / denominator as f32,
+                        bar_index,
+                    ));
+                }
+
+                _ => (),
+            }
+        }
+    }
+
+    result
+}
+
+/// Hash a sequence of steps.
+pub fn hash(steps: &[i16]) -> NgramId {
+    let mut hash = FNV_OFFSET_BASIS;
+
+    for step in steps.iter() {
+        let byte = i16::max(-MAX_INTERVAL, i16::min(MAX_INTERVAL, *step)) as i8 as u8;
+        hash = (hash ^ byte as u32).wrapping_mul(FNV_PRIME);
+    }
+
+    hash
+}
+
+/// An ngram where it occurs: its id, weight, and the indexes of its first and last bars.
+type Occurrence = (NgramId, f32, (usize, usize));
+
+/// Ngrams of a sequence of notes in order.
+fn ngrams_of_notes(notes: &[Sounded], options: &Options, result: &mut Vec<Occurrence>) {
+    if options.n == 0 || notes.len() <= options.n {
+        return;
+    }
+
+    let steps = notes
+        .windows(2)
+        .map(|pair| options.encoding.symbol(&pair[0], &pair[1]))
+        .collect::<Vec<i16>>();
+
+    for (i, window) in steps.windows(options.n).enumerate() {
+        let notes = &notes[i..i + options.n + 1];
+
+        let weight = if options.rhythm_weighted {
+            notes.iter().map(|&(_, duration, _)| duration).sum()
+        } else {
+            1.0
+        };
+
+        result.push((hash(window), weight, (notes[0].2, notes[options.n].2)));
+    }
+}
+
+/// Every ngram of a tune's voices in order.
+fn occurrences(tune: &tune_ast_three::Tune, options: &Options) -> Vec<Occurrence> {
+    let mut state = visitor::RunningState::new();
+    for token in tune.prelude.iter() {
+        state.update(token);
+    }
+
+    let mut result = vec![];
+    for voice in tune.voices.iter() {
+        ngrams_of_notes(&notes(voice, state, options.expand_repeats), options, &mut result);
+    }
+
+    result
+}
+
+/// Every ngram of a tune's voices in order, so that an ngram's index is its position.
+pub fn sequence(tune: &tune_ast_three::Tune, options: &Options) -> Vec<NgramId> {
+    occurrences(tune, options).iter().map(|&(id, _, _)| id).collect()
+}
+
+/// Ngrams of a sequence of steps in order, e.g. from a Parsons code.
+pub fn sequence_of_steps(steps: &[i16], n: usize) -> Vec<NgramId> {
+    if n == 0 {
+        return vec![];
+    }
+
+    steps.windows(n).map(hash).collect()
+}
+
+/// The first and last bar of each ngram in `sequence`, as indexes in the order bars are read.
+pub fn sequence_bars(tune: &tune_ast_three::Tune, options: &Options) -> Vec<(usize, usize)> {
+    occurrences(tune, options).iter().map(|&(_, _, bars)| bars).collect()
+}
+
+/// The ngrams of a tune's voices, with their total weights, ordered by id.
+/// The index only needs them in order, so this is for checking the weights.
+#[cfg(test)]
+pub fn ngrams(tune: &tune_ast_three::Tune, options: &Options) -> Vec<(NgramId, f32)> {
+    let mut all = occurrences(tune, options);
+    all.sort_by_key(|&(id, _, _)| id);
+
+    let mut result: Vec<(NgramId, f32)> = Vec::with_capacity(all.len());
+    for (id, weight, _) in all {
+        match result.last_mut() {
+            Some(last) if last.0 == id => last.1 += weight,
+            _ => result.push((id, weight)),
+        }
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_support::read;
+
+    #[test]
+    fn ngram_test() {
+        let tune = read("X:1\nL:1/8\nK:G\nGAB GAB|c2 B2|]\n");
+        let result = ngrams(&tune, &Options::new(2));
+
+        // G A B, A B G, B G A, G A B, A B c and B c B.
+        assert_eq!(result.len(), 5);
+        assert!(result.contains(&(hash(&[2, 2]), 2.0)));
+        assert!(result.contains(&(hash(&[2, -4]), 1.0)));
+        assert!(result.contains(&(hash(&[1, -1]), 1.0)));
+
+        // Accidentals carry through the bar, and F is sharp in G.
+        let tune = read("X:1\nL:1/8\nK:G\nF^GG|F=F|]\n");
+        assert_eq!(
+            ngrams(&tune, &Options::new(1)),
+            ngrams(&read("X:1\nL:1/8\nK:C\n^F^G^G|^F=F|]\n"), &Options::new(1))
+        );
+
+        assert_eq!(
+            sequence(&read("X:1\nL:1/8\nK:G\nGAB GAB|c2 B2|]\n"), &Options::new(2))[..3],
+            [hash(&[2, 2]), hash(&[2, -4]), hash(&[-4, 2])]
+        );
+        assert_eq!(
+            sequence_bars(&read("X:1\nL:1/8\nK:G\nGAB GAB|c2 B2|]\n"), &Options::new(2)),
+            vec![(0, 0), (0, 0), (0, 0), (0, 0), (0, 1), (0, 1)]
+        );
+
+        // Too short.
+        assert!(ngrams(&read("X:1\nK:C\nCD|]\n"), &Options::new(2)).is_empty());
+    }
+
+    #[test]
+    fn encoding_test() {
+        // Up a tone, a minor third, a fourth, a semitone, then repeated and down an octave.
+        let tune = read("X:1\nL:1/8\nK:C\nCDFB, C2C C,3|]\n");
+        let steps = |encoding| {
+            sequence(
+                &tune,
+                &Options {
+                    encoding,
+                    ..Options::new(1)
+                },
+            )
+        };
+
+        assert_eq!(steps(Encoding::Interval), sequence_of_steps(&[2, 3, -6, 1, 0, -12], 1));
+        assert_eq!(steps(Encoding::Parsons), sequence_of_steps(&[1, 1, -1, 1, 0, -1], 1));
+        assert_eq!(steps(Encoding::Contour), sequence_of_steps(&[1, 2, -3, 1, 0, -3], 1));
+        assert_eq!(steps(Encoding::Rhythm), sequence_of_steps(&[0, 0, 0, 2, -2, 3], 1));
+
+        assert_eq!(parse_parsons("*UdR D"), Some(vec![1, -1, 0, -1]));
+        assert_eq!(parse_parsons("GABc"), None);
+        assert_eq!(parse_parsons("*"), None);
+
+        assert_eq!(Encoding::from_name("contour"), Some(Encoding::Contour));
+        assert_eq!(Encoding::from_name("pitch"), None);
+    }
+
+    #[test]
+    fn transposition_test() {
+        let options = Options::new(3);
+
+        assert_eq!(
+            sequence(&read("X:1\nL:1/8\nK:D\nd2 AF DFA|B3 A3|]\n"), &options),
+            sequence(&read("X:1\nL:1/8\nK:G\ng2 dB GBd|e3 d3|]\n"), &options)
+        );
+    }
+
+    #[test]
+    fn options_test() {
+        let tune = read("X:1\nL:1/8\nK:C\n|:CD|1E:|2F|]\n");
+
+        // Written: C D E F.
+        let result = ngrams(&tune, &Options::new(1));
+        assert_eq!(result.len(), 2);
+        assert!(result.contains(&(hash(&[2]), 2.0)));
+        assert!(result.contains(&(hash(&[1]), 1.0)));
+
+        // Played: C D E C D F.
+        let expanded = Options {
+            expand_repeats: true,
+            ..Options::new(1)
+        };
+        let result = ngrams(&tune, &expanded);
+        assert!(result.contains(&(hash(&[2]), 3.0)));
+        assert!(result.contains(&(hash(&[-4]), 1.0)));
+        assert!(result.contains(&(hash(&[3]), 1.0)));
+
+        // Weighted by the crotchets in each ngram: C2 D, then D E.
+        let weighted = Options {
+            rhythm_weighted: true,
+            ..Options::new(1)
+        };
+        let result = ngrams(&read("X:1\nL:1/8\nK:C\nC2DE|]\n"), &weighted);
+        assert_eq!(result, vec![(hash(&[2]), 2.5)]);
+    }
+}
@@ -0,0 +1,578 @@
+//! Playback
+//! Rules for expressive playback: lilt, accents, ornaments and "%%MIDI" directives.
+//! Written music for dancing is an approximation of how it's played, so playing it exactly as
+//! written sounds wrong. These rules are rough, but better than nothing.
+
+use music;
+use timeline;
+use tune_type;
+
+/// Which rules to apply.
+#[derive(Debug, PartialEq, PartialOrd, Clone, Copy)]
+pub struct Options {
+    /// Uneven subdivisions of the beat, according to the tune type.
+    pub lilt: bool,
+
+    /// Louder notes on stronger beats.
+    pub accents: bool,
+
+    /// Play decorations and grace notes.
+    pub ornaments: bool,
+
+    /// Obey "%%MIDI" directives in the tune.
+    pub directives: bool,
+}
+
+impl Options {
+    /// Play exactly as written.
+    pub fn straight() -> Options {
+        Options {
+            lilt: false,
+            accents: false,
+            ornaments: false,
+            directives: false,
+        }
+    }
+
+    /// Apply all the rules.
+    pub fn expressive() -> Options {
+        Options {
+            lilt: true,
+            accents: true,
+            ornaments: true,
+            directives: true,
+        }
+    }
+}
+
+/// An uneven division of a group of equal notes, e.g. a jig's long-short-medium.
+#[derive(Debug, PartialEq, PartialOrd, Clone, Copy)]
+pub struct Lilt {
+    /// Written length of each note in the group.
+    pub unit: music::FractionalDuration,
+
+    /// Relative played length of each note in the group.
+    pub weights: &'static [f32],
+}
+
+/// The lilt for a tune type, if it has one.
+pub fn lilt(tune_type: tune_type::TuneType) -> Option<Lilt> {
+    let quavers = |weights| {
+        Some(Lilt {
+            unit: music::FractionalDuration(1, 8),
+            weights,
+        })
+    };
+
+    match tune_type {
+        tune_type::TuneType::Reel => quavers(&[0.54, 0.46]),
+        tune_type::TuneType::Polka => quavers(&[0.56, 0.44]),
+        tune_type::TuneType::Hornpipe |
+        tune_type::TuneType::Barndance |
+        tune_type::TuneType::Schottische => quavers(&[0.62, 0.38]),
+        tune_type::TuneType::Jig |
+        tune_type::TuneType::SingleJig |
+        tune_type::TuneType::SlipJig |
+        tune_type::TuneType::Slide => quavers(&[0.4, 0.27, 0.33]),
+        tune_type::TuneType::Mazurka => {
+            Some(Lilt {
+                unit: music::FractionalDuration(1, 4),
+                weights: &[0.3, 0.37, 0.33],
+            })
+        }
+
+        // Strathspeys are written as they're played, and the rest are played fairly straight.
+        _ => None,
+    }
+}
+
+impl Lilt {
+    /// Played position of a written position, both in ticks from the start of the bar.
+    /// Groups start at the start of the bar, and the start of each group is left where it is.
+    pub fn warp(&self, position: u32) -> u32 {
+        let unit = timeline::ticks(self.unit);
+        let group = unit * self.weights.len() as u32;
+        let total: f32 = self.weights.iter().sum();
+
+        if group == 0 {
+            return position;
+        }
+
+        let mut offset = position % group;
+        let mut played = 0.0;
+
+        for weight in self.weights.iter() {
+            let length = weight / total * group as f32;
+
+            if offset < unit {
+                played += offset as f32 * length / unit as f32;
+                break;
+            }
+
+            played += length;
+            offset -= unit;
+        }
+
+        position - position % group + played.round() as u32
+    }
+}
+
+const DOWNBEAT_VELOCITY: u8 = 100;
+const HALF_BAR_VELOCITY: u8 = 90;
+const BEAT_VELOCITY: u8 = 84;
+const OFF_BEAT_VELOCITY: u8 = 70;
+
+/// Added to the velocity of accented notes.
+const ACCENT_VELOCITY: u8 = 20;
+
+/// Velocity of a note starting at this position in the bar, in ticks.
+/// The first beat is strongest, then the middle of the bar, then the other beats.
+pub fn accent(position: u32, metre: music::Metre) -> u8 {
+    let bar = timeline::ticks(metre.bar());
+    let beat = timeline::ticks(metre.beat());
+
+    if bar == 0 || beat == 0 {
+        return timeline::DEFAULT_VELOCITY;
+    }
+
+    let beats = bar / beat;
+
+    if position % bar == 0 {
+        DOWNBEAT_VELOCITY
+    } else if position % beat != 0 {
+        OFF_BEAT_VELOCITY
+    } else if beats >= 4 && beats % 2 == 0 && position % (bar / 2) == 0 {
+        HALF_BAR_VELOCITY
+    } else {
+        BEAT_VELOCITY
+    }
+}
+
+/// Length of an ornamental note, e.g. a cut, in ticks.
+pub const ORNAMENT_TICKS: u32 = 40;
+
+/// Length of each note of a trill, in ticks.
+const TRILL_TICKS: u32 = 60;
+
+/// One of the sounds that a written note is played as.
+#[derive(Debug, PartialEq, PartialOrd, Clone, Copy)]
+pub struct Sound {
+    pub pitch: music::Pitch,
+
+    /// Ticks from the start of the written note.
+    pub start: u32,
+
+    pub duration: u32,
+    pub velocity: u8,
+}
+
+/// The next note up or down the scale, in the key.
+fn neighbour(pitch: music::Pitch, steps: i16, key: music::KeySignature) -> music::Pitch {
+    let degree = pitch.pitch_class.diatonic_pitch_class.to_degree() + steps;
+    let diatonic_pitch_class = music::DiatonicPitchClass::from_degree(degree);
+
+    music::Pitch {
+        pitch_class: music::PitchClass {
+            diatonic_pitch_class,
+            accidental: key.accidental(diatonic_pitch_class),
+        },
+        octave: pitch.octave + degree.div_euclid(music::NOTES_IN_SCALE),
+    }
+}
+
+/// Realise a note with its decorations and grace notes as sounds.
+/// Pitches should be resolved. Grace notes take their time from the start of the note, as in
+/// traditional playing. Fermatas are ignored, as they would put voices out of step.
+pub fn realise(
+    pitch: music::Pitch,
+    duration: u32,
+    velocity: u8,
+    decorations: &[music::Decoration],
+    grace_notes: &[music::Pitch],
+    acciaccatura: bool,
+    key: music::KeySignature,
+) -> Vec<Sound> {
+    let mut sounds = vec![];
+
+    let velocity = if decorations.contains(&music::Decoration::Accent) {
+        u8::min(velocity.saturating_add(ACCENT_VELOCITY), 127)
+    } else {
+        velocity
+    };
+
+    // Grace notes never take more than half of the note.
+    let mut start = 0;
+    if !grace_notes.is_empty() {
+        let length = if acciaccatura {
+            ORNAMENT_TICKS
+        } else {
+            ORNAMENT_TICKS * 2
+        };
+        let length = u32::min(length, duration / 2 / grace_notes.len() as u32);
+
+        if length > 0 {
+            for grace_note in grace_notes.iter() {
+                sounds.push(Sound {
+                    pitch: *grace_note,
+                    start,
+                    duration: length,
+                    velocity,
+                });
+                start += length;
+            }
+        }
+    }
+
+    let remaining = duration - start;
+    let upper = neighbour(pitch, 1, key);
+    let lower = neighbour(pitch, -1, key);
+
+    // Pitches of the ornament in order, each as a length in ticks. The last note takes what's left.
+    let mut ornament: Vec<(music::Pitch, u32)> = vec![];
+
+    for decoration in decorations.iter() {
+        match decoration {
+            // Note, cut, note, tap, note, in three equal parts.
+            &music::Decoration::Roll if remaining / 3 > ORNAMENT_TICKS => {
+                let third = remaining / 3;
+                ornament = vec![
+                    (pitch, third),
+                    (upper, ORNAMENT_TICKS),
+                    (pitch, third - ORNAMENT_TICKS),
+                    (lower, ORNAMENT_TICKS),
+                ];
+            }
+
+            &music::Decoration::Trill if remaining >= TRILL_TICKS * 3 => {
+                ornament = (0..remaining / TRILL_TICKS - 1)
+                    .map(|i| (if i % 2 == 0 { pitch } else { upper }, TRILL_TICKS))
+                    .collect();
+
+                // Alternate in pairs, so the note finishes on the main note.
+                if ornament.len() % 2 == 1 {
+                    ornament.pop();
+                }
+            }
+
+            &music::Decoration::UpperMordent if remaining > ORNAMENT_TICKS * 3 => {
+                ornament = vec![(pitch, ORNAMENT_TICKS), (upper, ORNAMENT_TICKS)];
+            }
+
+            &music::Decoration::LowerMordent if remaining > ORNAMENT_TICKS * 3 => {
+                ornament = vec![(pitch, ORNAMENT_TICKS), (lower, ORNAMENT_TICKS)];
+            }
+
+            _ => (),
+        }
+    }
+
+    for (ornament_pitch, length) in ornament {
+        sounds.push(Sound {
+            pitch: ornament_pitch,
+            start,
+            duration: length,
+            velocity,
+        });
+        start += length;
+    }
+
+    let last = duration - start;
+    let last = if decorations.contains(&music::Decoration::Staccato) {
+        u32::max(last / 2, 1)
+    } else {
+        last
+    };
+
+    sounds.push(Sound {
+        pitch,
+        start,
+        duration: last,
+        velocity,
+    });
+
+    sounds
+}
+
+/// Program used for the drone unless a directive says otherwise, i.e. bagpipes.
+const DEFAULT_DRONE_PROGRAM: u8 = 109;
+
+/// Notes of the drone unless a directive says otherwise, i.e. A and the A below.
+const DEFAULT_DRONE_NOTES: (u8, u8) = (45, 33);
+
+const DEFAULT_DRONE_VELOCITY: u8 = 60;
+
+/// A "%%MIDI" directive, following abc2midi. Others are ignored.
+#[derive(Debug, PartialEq, PartialOrd, Clone, Copy)]
+pub enum Directive {
+    /// General MIDI program for the voice, from 0.
+    Program(u8),
+
+    /// Play the voice this many semitones higher.
+    Transpose(i16),
+
+    /// Program, notes and velocity of the drone.
+    Drone {
+        program: u8,
+        notes: (u8, u8),
+        velocity: u8,
+    },
+
+    DroneOn,
+    DroneOff,
+
+    /// Program for guitar chords. Also turns them on.
+    ChordProgram(u8),
+
+    /// Play guitar chords. They are off by default.
+    ChordsOn,
+    ChordsOff,
+}
+
+impl Directive {
+    /// Parse a directive, without the "%%".
+    pub fn parse(directive: &str) -> Option<Directive> {
+        let mut words = directive.split_whitespace();
+
+        if words.next() != Some("MIDI") {
+            return None;
+        }
+
+        let command = words.next()?;
+        let numbers = words
+            .map(|word| word.parse::<i16>().ok())
+            .collect::<Option<Vec<i16>>>()?;
+
+        let midi_byte = |i: usize| match numbers.get(i) {
+            Some(&number) if number >= 0 && number <= 127 => Some(number as u8),
+            _ => None,
+        };
+
+        match command {
+            // The channel may come first, but each voice has its own channel here.
+            "program" => midi_byte(numbers.len().saturating_sub(1)).map(Directive::Program),
+            "transpose" => numbers.first().map(|&semitones| Directive::Transpose(semitones)),
+            "drone" => Some(Directive::Drone {
+                program: midi_byte(0).unwrap_or(DEFAULT_DRONE_PROGRAM),
+                notes: (
+                    midi_byte(1).unwrap_or(DEFAULT_DRONE_NOTES.0),
+                    midi_byte(2).unwrap_or(DEFAULT_DRONE_NOTES.1),
+                ),
+                velocity: midi_byte(3).unwrap_or(DEFAULT_DRONE_VELOCITY),
+            }),
+            "droneon" => Some(Directive::DroneOn),
+            "droneoff" => Some(Directive::DroneOff),
+            "chordprog" => midi_byte(0).map(Directive::ChordProgram),
+            "gchordon" => Some(Directive::ChordsOn),
+            "gchordoff" => Some(Directive::ChordsOff),
+            _ => None,
+        }
+    }
+}
+
+/// Default program for guitar chords, i.e. acoustic piano.
+pub const DEFAULT_CHORD_PROGRAM: u8 = 0;
+
+pub const CHORD_VELOCITY: u8 = 60;
+
+/// MIDI note numbers for a chord: the bass in the second octave below middle C, then the chord
+/// tones in the octave below middle C.
+pub fn chord_notes(chord: &music::ChordSymbol) -> Vec<u8> {
+    chord
+        .pitch_classes()
+        .iter()
+        .enumerate()
+        .map(|(i, pitch_class)| {
+            let semitones = pitch_class.semitones().rem_euclid(12) as u8;
+            if i == 0 { 36 + semitones } else { 48 + semitones }
+        })
+        .collect()
+}
+
+/// Settings changed by directives as the tune is played.
+#[derive(Debug, PartialEq, PartialOrd, Clone, Copy)]
+pub struct Settings {
+    pub transpose: i16,
+    pub chords: bool,
+    pub chord_program: u8,
+    pub drone: bool,
+    pub drone_program: u8,
+    pub drone_notes: (u8, u8),
+    pub drone_velocity: u8,
+}
+
+impl Settings {
+    pub fn new() -> Settings {
+        Settings {
+            transpose: 0,
+            chords: false,
+            chord_program: DEFAULT_CHORD_PROGRAM,
+            drone: false,
+            drone_program: DEFAULT_DRONE_PROGRAM,
+            drone_notes: DEFAULT_DRONE_NOTES,
+            drone_velocity: DEFAULT_DRONE_VELOCITY,
+        }
+    }
+
+    /// Apply a directive. Programs for voices aren't settings, as they take effect immediately.
+    pub fn update(&mut self, directive: Directive) {
+        match directive {
+            Directive::Transpose(semitones) => self.transpose = semitones,
+            Directive::Drone {
+                program,
+                notes,
+                velocity,
+            } => {
+                self.drone_program = program;
+                self.drone_notes = notes;
+                self.drone_velocity = velocity;
+            }
+            Directive::DroneOn => self.drone = true,
+            Directive::DroneOff => self.drone = false,
+            Directive::ChordProgram(program) => {
+                self.chord_program = program;
+                self.chords = true;
+            }
+            Directive::ChordsOn => self.chords = true,
+            Directive::ChordsOff => self.chords = false,
+            Directive::Program(_) => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pitch(diatonic_pitch_class: music::DiatonicPitchClass, octave: i16) -> music::Pitch {
+        music::Pitch {
+            pitch_class: music::PitchClass {
+                diatonic_pitch_class,
+                accidental: None,
+            },
+            octave,
+        }
+    }
+
+    #[test]
+    fn lilt_test() {
+        let jig = lilt(tune_type::TuneType::Jig).unwrap();
+
+        // Quavers in a jig are long, short, medium. Group boundaries stay put.
+        assert_eq!(jig.warp(0), 0);
+        assert_eq!(jig.warp(240), 288);
+        assert_eq!(jig.warp(480), 482);
+        assert_eq!(jig.warp(720), 720);
+        assert_eq!(jig.warp(960), 1008);
+
+        let reel = lilt(tune_type::TuneType::Reel).unwrap();
+        assert_eq!(reel.warp(240), 259);
+        assert_eq!(reel.warp(480), 480);
+
+        // A semiquaver is halfway through the first quaver.
+        assert_eq!(reel.warp(120), 130);
+
+        assert_eq!(lilt(tune_type::TuneType::Strathspey), None);
+    }
+
+    #[test]
+    fn accent_test() {
+        let velocities = |metre| {
+            (0..8).map(|i| accent(i * 240, metre)).collect::<Vec<u8>>()
+        };
+
+        assert_eq!(
+            velocities(music::Metre(4, 4)),
+            vec![100, 70, 84, 70, 90, 70, 84, 70]
+        );
+        assert_eq!(
+            velocities(music::Metre(6, 8)),
+            vec![100, 70, 70, 84, 70, 70, 100, 70]
+        );
+    }
+
+    #[test]
+    fn realise_test() {
+        let g_major = music::KeySignature {
+            tonic: music::PitchClass {
+                diatonic_pitch_class: music::DiatonicPitchClass::G,
+                accidental: None,
+            },
+            mode: music::Mode::Major,
+        };
+        let a = pitch(music::DiatonicPitchClass::A, 0);
+        let b = pitch(music::DiatonicPitchClass::B, 0);
+        let g = pitch(music::DiatonicPitchClass::G, 0);
+
+        let plain = realise(a, 480, 80, &[], &[], false, g_major);
+        assert_eq!(
+            plain,
+            vec![
+                Sound {
+                    pitch: a,
+                    start: 0,
+                    duration: 480,
+                    velocity: 80,
+                },
+            ]
+        );
+
+        // A roll on a dotted crotchet: A, cut B, A, tap G, A.
+        let roll = realise(a, 720, 80, &[music::Decoration::Roll], &[], false, g_major);
+        assert_eq!(
+            roll.iter()
+                .map(|sound| (sound.pitch, sound.start, sound.duration))
+                .collect::<Vec<(music::Pitch, u32, u32)>>(),
+            vec![
+                (a, 0, 240),
+                (b, 240, 40),
+                (a, 280, 200),
+                (g, 480, 40),
+                (a, 520, 200),
+            ]
+        );
+
+        // A cut takes its time from the note.
+        let cut = realise(a, 240, 80, &[], &[b], true, g_major);
+        assert_eq!(cut[0].start, 0);
+        assert_eq!(cut[1].start, ORNAMENT_TICKS);
+        assert_eq!(cut[1].duration, 240 - ORNAMENT_TICKS);
+
+        // The upper note of a trill on E is F sharp in G major.
+        let e = pitch(music::DiatonicPitchClass::E, 0);
+        let trill = realise(e, 480, 80, &[music::Decoration::Trill], &[], false, g_major);
+        assert_eq!(trill.len(), 7);
+        assert_eq!(
+            trill[1].pitch.pitch_class.accidental,
+            Some(music::Accidental::Sharp)
+        );
+        assert_eq!(trill.last().unwrap().pitch, e);
+
+        let staccato = realise(a, 240, 80, &[music::Decoration::Staccato], &[], false, g_major);
+        assert_eq!(staccato[0].duration, 120);
+
+        let accent = realise(a, 240, 80, &[music::Decoration::Accent], &[], false, g_major);
+        assert_eq!(accent[0].velocity, 100);
+    }
+
+    #[test]
+    fn directive_test() {
+        assert_eq!(Directive::parse("MIDI program 73"), Some(Directive::Program(73)));
+        assert_eq!(Directive::parse("MIDI program 2 73"), Some(Directive::Program(73)));
+        assert_eq!(Directive::parse("MIDI transpose -12"), Some(Directive::Transpose(-12)));
+        assert_eq!(
+            Directive::parse("MIDI drone 70 45 33 80"),
+            Some(Directive::Drone {
+                program: 70,
+                notes: (45, 33),
+                velocity: 80,
+            })
+        );
+        assert_eq!(Directive::parse("MIDI droneon"), Some(Directive::DroneOn));
+        assert_eq!(Directive::parse("MIDI chordprog 24"), Some(Directive::ChordProgram(24)));
+        assert_eq!(Directive::parse("MIDI gchordoff"), Some(Directive::ChordsOff));
+
+        assert_eq!(Directive::parse("MIDI program 200"), None);
+        assert_eq!(Directive::parse("MIDI beat 100 90 80"), None);
+        assert_eq!(Directive::parse("pagewidth 21cm"), None);
+    }
+}
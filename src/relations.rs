@@ -1,155 +1,195 @@
 use std::usize;
-extern crate rand;
 
-use self::rand::Rng;
-use self::rand::RngCore;
-use self::rand::ThreadRng;
-
-use std::time::SystemTime;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::PathBuf;
 
 // Provide at least this much overhead when reallocating.
 pub const GROWTH_OVERHEAD: usize = 1024;
 
-// Represent groupings of tunes.
-// Tune ID usize::MAX isn't allowed.
-// If we get over 4 billion tunes, it may be time to consider an Option types.
-// Uses linear searches, but uses constant space and optimised for our corpus.
-// For 200,000 tunes, it takes about half a millisecond to add a connection.
+// Marks a tune that isn't in a group.
+const UNASSIGNED: usize = usize::MAX;
+
+/// Groupings of tunes, as a disjoint-set forest with path compression and union by rank, so
+/// adding a connection takes near-constant time. The members of each group are also linked in a
+/// ring, so they can be listed without scanning every tune.
+/// Tune ID usize::MAX isn't allowed, as it marks an unassigned tune. If we get over 4 billion
+/// tunes, it may be time to consider an Option type.
 pub struct Grouper {
-    // Dense mapping of tune id -> group ID.
-    // The ID of a group is the ID of its lowest member.
-    // For each tune, group ID can be:
+    // Dense mapping of tune id -> parent in its group's tree.
     // - MAX  : Unassigned.
-    // - Else : The tune belongs to this group ID.
-    groups: Vec<usize>,
+    // - Self : The root of its group's tree.
+    // - Else : Another member of the same group, closer to the root.
+    parents: Vec<usize>,
+
+    // Root -> upper bound on the height of its tree.
+    ranks: Vec<u8>,
+
+    // Root -> lowest member. The ID of a group is the ID of its lowest member.
+    lowest: Vec<usize>,
+
+    // Tune id -> next member of the same group, in a ring.
+    next: Vec<usize>,
+
+    num_groups: usize,
 }
 
 impl Grouper {
     pub fn new() -> Grouper {
         // Start non-empty, as we're always going to want to do something.
-        // Also means len can never be zero, so we can skip a check where it matters.
-        let mut groups = Vec::with_capacity(GROWTH_OVERHEAD);
-        groups.resize(GROWTH_OVERHEAD, usize::MAX);
+        Grouper {
+            parents: vec![UNASSIGNED; GROWTH_OVERHEAD],
+            ranks: vec![0; GROWTH_OVERHEAD],
+            lowest: vec![UNASSIGNED; GROWTH_OVERHEAD],
+            next: vec![UNASSIGNED; GROWTH_OVERHEAD],
+            num_groups: 0,
+        }
+    }
+
+    // Ensure that the ID is represented.
+    fn ensure(&mut self, a: usize) {
+        if a >= self.parents.len() {
+            let len = a + 1 + GROWTH_OVERHEAD;
+            self.parents.resize(len, UNASSIGNED);
+            self.ranks.resize(len, 0);
+            self.lowest.resize(len, UNASSIGNED);
+            self.next.resize(len, UNASSIGNED);
+        }
+    }
 
-        Grouper { groups }
+    // Root of the tree of a tune that's in a group.
+    fn root(&self, a: usize) -> usize {
+        let mut root = a;
+        while self.parents[root] != root {
+            root = self.parents[root];
+        }
+
+        root
+    }
+
+    // Root of the tree of a tune that's in a group, pointing everything on the way at it.
+    fn root_compress(&mut self, a: usize) -> usize {
+        let root = self.root(a);
+
+        let mut i = a;
+        while i != root {
+            let parent = self.parents[i];
+            self.parents[i] = root;
+            i = parent;
+        }
+
+        root
+    }
+
+    // Put a tune that isn't in a group into a group of its own.
+    fn make_group(&mut self, a: usize) {
+        if self.parents[a] == UNASSIGNED {
+            self.parents[a] = a;
+            self.ranks[a] = 0;
+            self.lowest[a] = a;
+            self.next[a] = a;
+            self.num_groups += 1;
+        }
     }
 
     // Put A and B into the same group.
     pub fn add(&mut self, a: usize, b: usize) {
-        if a == b || a == usize::MAX || b == usize::MAX {
+        if a == b || a == UNASSIGNED || b == UNASSIGNED {
             return;
         }
 
-        // len() - 1 is ok because the vector is initialized non-empty and never shrinks.
+        self.ensure(usize::max(a, b));
+        self.make_group(a);
+        self.make_group(b);
 
-        // Ensure that both IDs are represented.
-        if a > self.groups.len() - 1 {
-            self.groups.resize(a + 1 + GROWTH_OVERHEAD, usize::MAX);
-        }
+        let root_a = self.root_compress(a);
+        let root_b = self.root_compress(b);
 
-        if b > self.groups.len() - 1 {
-            self.groups.resize(b + 1 + GROWTH_OVERHEAD, usize::MAX);
+        if root_a == root_b {
+            return;
         }
 
-        if self.groups[a] == usize::MAX && self.groups[b] == usize::MAX {
-            // If neither is in a group yet, create a new one.
-            // Choose A's ID as being the group ID.
-
-            // Set marker on A to say that it's the canonical ID for this group.
-            self.groups[a] = a;
-            // B is the second member of the A group.
-            self.groups[b] = a;
-        } else if self.groups[a] == usize::MAX && self.groups[b] != usize::MAX {
-            // If A isn't in a group but B is, add A to B's group.
-            self.groups[a] = self.groups[b];
-        } else if self.groups[a] != usize::MAX && self.groups[b] == usize::MAX {
-            // And vice versa...
-            self.groups[b] = self.groups[a];
+        // Attach the shorter tree under the taller.
+        let (root, child) = if self.ranks[root_a] < self.ranks[root_b] {
+            (root_b, root_a)
         } else {
-            // Otherwise B and A are in different groups. Unite them.
-
-            let old_group_a: usize = self.groups[a];
-            let old_group_b: usize = self.groups[b];
-
-            // Update all members of A and B's previous groups to be A value.
-
-            // Choose a new ID for the group. This will be the lowest ID we find.
-            // Start with special value of MAX.
-            let mut new_id = usize::MAX;
-
-            for i in 0..self.groups.len() {
-                if self.groups[i] == old_group_a || self.groups[i] == old_group_b {
-                    // First time we see a member of either group, use that as the new group id.
-                    if new_id == usize::MAX {
-                        new_id = i;
-                    } else {
-                        self.groups[i] = new_id;
-                    }
-                }
-            }
+            (root_a, root_b)
+        };
+
+        self.parents[child] = root;
+        if self.ranks[root] == self.ranks[child] {
+            self.ranks[root] += 1;
         }
+
+        self.lowest[root] = usize::min(self.lowest[root_a], self.lowest[root_b]);
+
+        // Swapping successors joins the two rings into one.
+        self.next.swap(a, b);
+
+        self.num_groups -= 1;
     }
 
     // Get the group ID of a given id.
     pub fn get(&self, a: usize) -> Option<usize> {
-        if let Some(group_id) = self.groups.get(a) {
-            if *group_id == usize::MAX {
-                // Not in a group.
-                None
-            } else {
-                // Any other number is the actual group ID.
-                Some(*group_id)
-            }
-        } else {
-            None
+        match self.parents.get(a) {
+            // Not in a group.
+            None | Some(&UNASSIGNED) => None,
+            Some(_) => Some(self.lowest[self.root(a)]),
         }
     }
 
-    // Allocate and return a vector of tune IDs.
+    // Allocate and return a vector of group IDs, in order.
     pub fn group_ids(&self) -> Vec<usize> {
         let mut result = vec![];
 
-        // Scan for the first member of each group whose group is the same as the id.
-        for (i, value) in self.groups.iter().enumerate() {
-            if *value == i {
-                result.push(i);
+        for (i, parent) in self.parents.iter().enumerate() {
+            if *parent == i {
+                result.push(self.lowest[i]);
             }
         }
 
+        result.sort();
         result
     }
 
     pub fn num_groups(&self) -> usize {
-        let mut result = 0;
+        self.num_groups
+    }
 
-        for (i, value) in self.groups.iter().enumerate() {
-            if *value == i {
-                result += 1;
-            }
+    // Members of the group that a tune is in, in order, starting from that tune.
+    fn ring(&self, a: usize) -> Vec<usize> {
+        let mut result = vec![a];
+
+        let mut i = self.next[a];
+        while i != a {
+            result.push(i);
+            i = self.next[i];
         }
 
+        result.sort();
         result
     }
 
-    // Allocate and return list of members of group.
+    // Allocate and return list of members of group, in order.
+    // Empty if the ID isn't a group's ID.
     pub fn get_members(&self, a: usize) -> Vec<usize> {
-        let mut result = vec![];
-
-        // Scan for canonical group ids.
-        for (i, value) in self.groups.iter().enumerate() {
-            if *value == a {
-                result.push(i);
-            }
+        if self.get(a) == Some(a) {
+            self.ring(a)
+        } else {
+            vec![]
         }
+    }
 
-        result
+    // Allocate and return every group's members, in order of group ID.
+    // The first member of each is its ID.
+    pub fn groups(&self) -> Vec<Vec<usize>> {
+        self.group_ids().iter().map(|group_id| self.ring(*group_id)).collect()
     }
 
     pub fn print_debug(&self) {
-        let groups = self.group_ids();
-        for group_id in groups.iter() {
-            eprintln!("Group {}", group_id);
-            let members = self.get_members(*group_id);
+        for members in self.groups().iter() {
+            eprintln!("Group {}", members[0]);
             for member in members.iter() {
                 eprintln!(" - {}", member);
             }
@@ -157,33 +197,326 @@ impl Grouper {
     }
 }
 
-pub fn timing_test() {
-    let mut groups = Grouper::new();
-    let num = 200000;
-    let mut rng = rand::thread_rng();
-
-    for i in 0..num {
-        let mut start = SystemTime::now();
-        let b: usize = rng.next_u64() as usize % num;
-        groups.add(i, b);
-        let dur = SystemTime::now().duration_since(start);
-
-        if i % 1000 == 0 {
-            eprintln!(
-                "i: {},  groups: {}, duration: {:?}",
-                i,
-                groups.num_groups(),
-                dur
-            );
+// Name of the relations file in the tune store.
+pub const RELATIONS_FILENAME: &str = "relations";
+
+// Why two tunes are related.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Clone, Copy)]
+pub enum Kind {
+    // Their titles are the same. Supports a link, but doesn't make one.
+    SameTitle,
+
+    // Score is the proportion of ngrams shared. Also supports a link without making one.
+    NgramOverlap,
+
+    // Score is the distance between their curves, in semitones.
+    GeometricDistance,
+
+    // Their music is the same. Score is 1 if their ABC is identical, 0 if only the music is.
+    Duplicate,
+
+    // An editor says they're the same tune.
+    Link,
+
+    // An editor says they're different tunes, so they're never grouped together.
+    NeverLink,
+}
+
+impl Kind {
+    pub fn all() -> [Kind; 6] {
+        [
+            Kind::SameTitle,
+            Kind::NgramOverlap,
+            Kind::GeometricDistance,
+            Kind::Duplicate,
+            Kind::Link,
+            Kind::NeverLink,
+        ]
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            &Kind::SameTitle => "same_title",
+            &Kind::NgramOverlap => "ngram_overlap",
+            &Kind::GeometricDistance => "geometric_distance",
+            &Kind::Duplicate => "duplicate",
+            &Kind::Link => "link",
+            &Kind::NeverLink => "never_link",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Kind> {
+        Kind::all().iter().find(|kind| kind.name() == name).cloned()
+    }
+
+    // Manual relations are asserted by editors, and kept when tunes are re-clustered.
+    pub fn is_manual(&self) -> bool {
+        match self {
+            &Kind::Link | &Kind::NeverLink => true,
+            _ => false,
+        }
+    }
+}
+
+// A reason for two tunes to be grouped, or not.
+#[derive(Debug, PartialEq, PartialOrd, Clone)]
+pub struct Relation {
+    // The lower tune id.
+    pub a: u32,
+    pub b: u32,
+
+    pub kind: Kind,
+    pub score: f32,
+
+    // What made it, e.g. "cluster" or an editor's note.
+    pub provenance: String,
+}
+
+impl Relation {
+    pub fn new(a: u32, b: u32, kind: Kind, score: f32, provenance: &str) -> Relation {
+        // Tabs and newlines would break the file.
+        let provenance = provenance
+            .chars()
+            .map(|c| if c.is_control() { ' ' } else { c })
+            .collect();
+
+        Relation {
+            a: u32::min(a, b),
+            b: u32::max(a, b),
+            kind,
+            score,
+            provenance,
+        }
+    }
+
+    // One line of tab-separated fields: tune ids, kind, score and provenance.
+    fn to_line(&self) -> String {
+        format!(
+            "{}\t{}\t{}\t{}\t{}",
+            self.a,
+            self.b,
+            self.kind.name(),
+            self.score,
+            self.provenance
+        )
+    }
+
+    fn from_line(line: &str) -> Option<Relation> {
+        let mut fields = line.splitn(5, '\t');
+
+        let a = fields.next()?.parse::<u32>().ok()?;
+        let b = fields.next()?.parse::<u32>().ok()?;
+        let kind = Kind::from_name(fields.next()?)?;
+        let score = fields.next()?.parse::<f32>().ok()?;
+        let provenance = fields.next()?;
+
+        // Scores are sorted, so NaN can't be allowed.
+        if !score.is_finite() {
+            return None;
+        }
+
+        Some(Relation::new(a, b, kind, score, provenance))
+    }
+}
+
+// Root of the tree of a tune's group, or the tune itself if it isn't in one.
+fn representative(groups: &Grouper, a: usize) -> usize {
+    match groups.parents.get(a) {
+        None | Some(&UNASSIGNED) => a,
+        Some(_) => groups.root(a),
+    }
+}
+
+// A group of tunes, with the relations that explain it.
+pub struct Group {
+    pub id: u32,
+    pub members: Vec<u32>,
+
+    // Relations involving any member, including never-links to tunes outside the group.
+    pub relations: Vec<Relation>,
+}
+
+// Relations between tunes, persisted beside the tune cache.
+pub struct Relations {
+    filename: PathBuf,
+    relations: Vec<Relation>,
+}
+
+impl Relations {
+    // Construct at the given filename, loading it if it exists.
+    pub fn new(filename: PathBuf) -> Relations {
+        let mut result = Relations {
+            filename,
+            relations: vec![],
+        };
+
+        result.load();
+
+        result
+    }
+
+    fn load(&mut self) {
+        let mut content = String::new();
+
+        match File::open(&self.filename) {
+            Err(_) => return,
+            Ok(mut file) => {
+                file.read_to_string(&mut content).expect("Can't read relations file.");
+            }
+        }
+
+        for line in content.lines().filter(|line| !line.is_empty()) {
+            match Relation::from_line(line) {
+                Some(relation) => self.relations.push(relation),
+                None => eprintln!("Can't read relation: {}", line),
+            }
+        }
+    }
+
+    pub fn save(&self) {
+        eprintln!("Write relations to {:?}", &self.filename);
+
+        let mut file = File::create(&self.filename).expect("Can't create relations file.");
+
+        for relation in self.relations.iter() {
+            writeln!(file, "{}", relation.to_line()).expect("Can't write relations file.");
         }
     }
 
-    groups.print_debug();
+    #[cfg(test)]
+    pub fn all(&self) -> &[Relation] {
+        &self.relations
+    }
+
+    pub fn is_never_linked(&self, a: u32, b: u32) -> bool {
+        let (a, b) = (u32::min(a, b), u32::max(a, b));
+
+        self.relations
+            .iter()
+            .any(|relation| relation.a == a && relation.b == b && relation.kind == Kind::NeverLink)
+    }
+
+    // Record an editor's link or never-link, replacing any earlier one for the pair.
+    pub fn assert(&mut self, a: u32, b: u32, kind: Kind, provenance: &str) {
+        let relation = Relation::new(a, b, kind, 1.0, provenance);
+
+        self.relations.retain(|existing| {
+            !(existing.a == relation.a && existing.b == relation.b && existing.kind.is_manual())
+        });
+
+        self.relations.push(relation);
+    }
+
+    // Replace the automatic relations with the given provenance, keeping the manual ones and
+    // those found some other way.
+    pub fn replace_automatic(&mut self, provenance: &str, relations: Vec<Relation>) {
+        self.relations.retain(|relation| {
+            relation.kind.is_manual() || relation.provenance != provenance
+        });
+
+        self.relations.extend(relations.into_iter().filter(|relation| !relation.kind.is_manual()));
+    }
+
+    /// Group the related tunes. Manual links are made first, then duplicates, then the closest
+    /// automatic ones. A link that would put never-linked tunes in the same group isn't made.
+    /// Shared titles and ngram overlap don't group tunes on their own, as `Kind` says: different
+    /// tunes often go by the same name, and common phrases turn up everywhere, so either would
+    /// chain unrelated tunes into one group. They only count alongside a link.
+    pub fn grouper(&self) -> Grouper {
+        // Representative of each group -> tunes never linked with one of its members.
+        let mut never: HashMap<usize, Vec<usize>> = HashMap::new();
+        for relation in self.relations.iter().filter(|relation| relation.kind == Kind::NeverLink) {
+            let (a, b) = (relation.a as usize, relation.b as usize);
+            never.entry(a).or_default().push(b);
+            never.entry(b).or_default().push(a);
+        }
+
+        // Pair -> priority, lowest first.
+        let mut pairs: HashMap<(u32, u32), f32> = HashMap::new();
+        for relation in self.relations.iter() {
+            let priority = match relation.kind {
+                Kind::Link => f32::MIN,
+                // Before any distance.
+                Kind::Duplicate => -1.0,
+                Kind::GeometricDistance => relation.score,
+                Kind::SameTitle | Kind::NgramOverlap | Kind::NeverLink => continue,
+            };
+
+            let entry = pairs.entry((relation.a, relation.b)).or_insert(priority);
+            *entry = f32::min(*entry, priority);
+        }
+
+        let mut pairs = pairs.into_iter().collect::<Vec<((u32, u32), f32)>>();
+        pairs.sort_by(|x, y| x.1.partial_cmp(&y.1).unwrap().then(x.0.cmp(&y.0)));
+
+        let mut groups = Grouper::new();
+
+        for ((a, b), _) in pairs {
+            let (a, b) = (a as usize, b as usize);
+            let (root_a, root_b) = (representative(&groups, a), representative(&groups, b));
+
+            if root_a == root_b {
+                continue;
+            }
+
+            let forbidden = match never.get(&root_a) {
+                Some(others) => others.iter().any(|other| representative(&groups, *other) == root_b),
+                None => false,
+            };
+
+            if forbidden {
+                continue;
+            }
+
+            groups.add(a, b);
+
+            // The joined group keeps both groups' never-links.
+            let mut others = never.remove(&root_a).unwrap_or_default();
+            others.extend(never.remove(&root_b).unwrap_or_default());
+            if !others.is_empty() {
+                never.insert(representative(&groups, a), others);
+            }
+        }
+
+        groups
+    }
+
+    // The group that a tune is in, with the relations that explain it.
+    // A tune that isn't grouped is in a group of its own.
+    pub fn group(&self, groups: &Grouper, tune_id: u32) -> Group {
+        let (id, members) = match groups.get(tune_id as usize) {
+            Some(group_id) => (
+                group_id as u32,
+                groups.get_members(group_id).iter().map(|member| *member as u32).collect(),
+            ),
+            None => (tune_id, vec![tune_id]),
+        };
+
+        let member_set = members.iter().cloned().collect::<HashSet<u32>>();
+
+        let relations = self.relations
+            .iter()
+            .filter(|relation| member_set.contains(&relation.a) || member_set.contains(&relation.b))
+            .cloned()
+            .collect();
+
+        Group {
+            id,
+            members,
+            relations,
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    extern crate rand;
+
+    use self::rand::RngCore;
     use super::*;
+    use std::fs;
+    use std::time::Instant;
+    use test_support::temp_path;
 
     #[test]
     fn join_groups_test() {
@@ -301,4 +634,155 @@ mod tests {
             "When combined, one group remains and the lowest id of all members of the group is used."
         );
     }
+
+    #[test]
+    fn lowest_member_test() {
+        let mut groups = Grouper::new();
+
+        // 2 joins a group whose lowest member was 5.
+        groups.add(5, 6);
+        groups.add(2, 5);
+        assert_eq!(groups.get(6), Some(2));
+
+        // Joining groups through members that aren't their lowest.
+        groups.add(7, 8);
+        groups.add(0, 9);
+        groups.add(8, 9);
+        groups.add(6, 7);
+
+        assert_eq!(groups.group_ids(), vec![0]);
+        assert_eq!(groups.num_groups(), 1);
+        assert_eq!(groups.get_members(0), vec![0, 2, 5, 6, 7, 8, 9]);
+        assert_eq!(groups.get_members(5), vec![], "5 isn't a group id.");
+
+        // Beyond the initial allocation.
+        groups.add(10000, 3);
+        groups.add(4000, 10000);
+
+        assert_eq!(groups.get(4000), Some(3));
+        assert_eq!(groups.num_groups(), 2);
+        assert_eq!(
+            groups.groups(),
+            vec![vec![0, 2, 5, 6, 7, 8, 9], vec![3, 4000, 10000]]
+        );
+    }
+
+    #[test]
+    fn relations_test() {
+        let path = temp_path("relations");
+        let _ = fs::remove_file(&path);
+
+        let mut relations = Relations::new(path.clone());
+        assert!(relations.all().is_empty());
+
+        // 1 and 3 are close, 3 and 2 less so. 4 and 5 only share a title, so aren't grouped.
+        relations.replace_automatic("cluster", vec![
+            Relation::new(3, 1, Kind::GeometricDistance, 0.2, "cluster"),
+            Relation::new(1, 3, Kind::NgramOverlap, 0.9, "cluster"),
+            Relation::new(2, 3, Kind::GeometricDistance, 0.8, "cluster"),
+            Relation::new(4, 5, Kind::SameTitle, 1.0, "cluster"),
+        ]);
+
+        assert_eq!(relations.all()[0].a, 1, "Lower id first.");
+        assert_eq!(relations.grouper().groups(), vec![vec![1, 2, 3]]);
+
+        // An editor separates 1 and 2. They're still linked through 3, so the weaker link goes.
+        relations.assert(2, 1, Kind::NeverLink, "Different\ttunes");
+        assert!(relations.is_never_linked(1, 2));
+        assert_eq!(relations.grouper().groups(), vec![vec![1, 3]]);
+
+        // And links 2 with 6, replacing nothing.
+        relations.assert(2, 6, Kind::Link, "manual");
+
+        let grouper = relations.grouper();
+        assert_eq!(grouper.groups(), vec![vec![1, 3], vec![2, 6]]);
+
+        let group = relations.group(&grouper, 6);
+        assert_eq!(group.id, 2);
+        assert_eq!(group.members, vec![2, 6]);
+        assert_eq!(
+            group.relations.iter().map(|r| (r.a, r.b, r.kind)).collect::<Vec<_>>(),
+            vec![(2, 3, Kind::GeometricDistance), (1, 2, Kind::NeverLink), (2, 6, Kind::Link)]
+        );
+
+        // A tune that isn't grouped is on its own.
+        assert_eq!(relations.group(&grouper, 7).members, vec![7]);
+
+        // Saved and loaded.
+        relations.save();
+        let mut loaded = Relations::new(path.clone());
+        assert_eq!(loaded.all(), relations.all());
+        assert_eq!(loaded.all()[4].provenance, "Different tunes");
+
+        // Re-clustering keeps the manual relations.
+        loaded.replace_automatic(
+            "cluster",
+            vec![Relation::new(1, 2, Kind::GeometricDistance, 0.1, "cluster")],
+        );
+        assert_eq!(
+            loaded.all().iter().map(|r| r.kind).collect::<Vec<_>>(),
+            vec![Kind::NeverLink, Kind::Link, Kind::GeometricDistance]
+        );
+        assert_eq!(loaded.grouper().groups(), vec![vec![2, 6]]);
+
+        // As does finding relations another way.
+        loaded.replace_automatic("dupes", vec![Relation::new(6, 7, Kind::Duplicate, 1.0, "dupes")]);
+        assert_eq!(loaded.all().len(), 4);
+        assert_eq!(loaded.grouper().groups(), vec![vec![2, 6, 7]]);
+
+        // Scores that can't be sorted aren't read.
+        assert!(Relation::from_line("1\t2\tgeometric_distance\tNaN\tcluster").is_none());
+        assert!(Relation::from_line("1\t2\tgeometric_distance\tinf\tcluster").is_none());
+        assert!(Relation::from_line("1\t2\tgeometric_distance\t0.5\tcluster").is_some());
+
+        // A new assertion about a pair replaces the old one.
+        loaded.assert(1, 2, Kind::Link, "manual");
+        assert!(!loaded.is_never_linked(1, 2));
+        assert_eq!(loaded.grouper().groups(), vec![vec![1, 2, 6, 7]]);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    // Add random connections between tunes, and list the groups, reporting how long it takes.
+    // Run it with `cargo test --release -- --ignored benchmark`.
+    fn benchmark(num_tunes: usize, num_connections: usize) {
+        let mut groups = Grouper::new();
+        let mut rng = rand::thread_rng();
+
+        let start = Instant::now();
+
+        for i in 0..num_connections {
+            let a: usize = rng.next_u64() as usize % num_tunes;
+            let b: usize = rng.next_u64() as usize % num_tunes;
+            groups.add(a, b);
+
+            if (i + 1) % 1_000_000 == 0 {
+                eprintln!(
+                    "connections: {}, groups: {}, duration: {:?}",
+                    i + 1,
+                    groups.num_groups(),
+                    start.elapsed()
+                );
+            }
+        }
+
+        let duration = start.elapsed();
+        eprintln!(
+            "Added {} connections between {} tunes in {:?}, {:?} each",
+            num_connections,
+            num_tunes,
+            duration,
+            duration / usize::max(num_connections, 1) as u32
+        );
+
+        let start = Instant::now();
+        let num_groups = groups.groups().len();
+        eprintln!("Listed {} groups in {:?}", num_groups, start.elapsed());
+    }
+
+    #[test]
+    #[ignore]
+    fn benchmark_test() {
+        benchmark(200000, 5000000);
+    }
 }
@@ -0,0 +1,401 @@
+//! Search
+//! Find tunes that contain a melody, given as a short ABC fragment such as "GABc dedB".
+//! Candidates come from the ngram index. They are ranked by the query ngrams they contain,
+//! weighted so that rare ngrams count for more, and by how many of those are in the query's order.
+//! Results can be restricted to a tune type, e.g. reels.
+
+use std::collections::{HashMap, HashSet};
+
+use abc_lexer;
+use index;
+use ngram;
+use tune_ast_three;
+use tune_type;
+
+/// Number of results when none is given.
+pub const DEFAULT_LIMIT: usize = 20;
+
+/// How much more the ngrams of the best aligned occurrence count than ngrams found anywhere.
+const COHERENCE_WEIGHT: f32 = 2.0;
+
+/// An occurrence must have at least this proportion of the query's ngrams in order to be reported.
+const OCCURRENCE_THRESHOLD: f32 = 0.5;
+
+#[derive(Debug, PartialEq, PartialOrd, Clone)]
+pub struct SearchResult {
+    pub tune_id: u32,
+
+    /// From 0 to 1, where 1 is the whole query, in order.
+    pub score: f32,
+
+    /// Number of distinct query ngrams found in the tune.
+    pub matched: usize,
+
+    /// First and last bar of each occurrence of the query, numbered from 1 in reading order.
+    pub bars: Vec<(usize, usize)>,
+}
+
+/// Matches of the query in one tune.
+struct Candidate {
+    matched: HashSet<ngram::NgramId>,
+
+    /// Weight of matched query positions.
+    matched_weight: f32,
+
+    /// Tune position minus query position -> weight and tune positions of the ngrams aligned so.
+    alignments: HashMap<i64, (f32, Vec<u32>)>,
+}
+
+/// Read a query. A fragment without a "K:" field is read in the given key, or C if there isn't
+/// one, with a quaver default length.
+pub fn read_query(query: &str, key: Option<&str>) -> tune_ast_three::Tune {
+    let abc = if query.contains("K:") {
+        format!("{}\n", query)
+    } else {
+        format!("X:1\nL:1/8\nK:{}\n{}\n", key.unwrap_or("C"), query)
+    };
+
+    let chars = abc.chars().collect::<Vec<char>>();
+    tune_ast_three::read_from_lexer(abc_lexer::Lexer::new(&chars))
+}
+
+/// Is this a key that can be given for a query, as in a "K:" field, e.g. "D", "Em" or "Ador"?
+fn is_key(key: &str) -> bool {
+    let chars = format!("K:{}\n", key).chars().collect::<Vec<char>>();
+
+    !key.contains('\n') && abc_lexer::Lexer::new(&chars).collect_errors().is_empty() &&
+        matches!(
+            abc_lexer::Lexer::new(&chars).collect_tokens().as_slice(),
+            [abc_lexer::T::KeySignature(_, _)]
+        )
+}
+
+/// Inverse document frequency, from the postings of an ngram. Always positive.
+fn idf(postings: &[index::Posting], num_tunes: usize) -> f32 {
+    // Postings for a tune are added together, so count changes of tune.
+    let mut tunes = 0;
+    let mut previous = None;
+    for posting in postings.iter() {
+        if previous != Some(posting.tune_id) {
+            tunes += 1;
+            previous = Some(posting.tune_id);
+        }
+    }
+
+    (1.0 + num_tunes as f32 / usize::max(tunes, 1) as f32).ln()
+}
+
+/// Bar ranges of the query's occurrences in a tune, from the tune positions of each occurrence.
+fn bar_ranges(
+    tune: &tune_ast_three::Tune,
+    options: &ngram::Options,
+    occurrences: &[&Vec<u32>],
+) -> Vec<(usize, usize)> {
+    let bars = ngram::sequence_bars(tune, options);
+
+    let mut ranges = occurrences
+        .iter()
+        .filter_map(|positions| {
+            let first = positions.iter().filter_map(|p| bars.get(*p as usize)).map(|b| b.0).min();
+            let last = positions.iter().filter_map(|p| bars.get(*p as usize)).map(|b| b.1).max();
+
+            match (first, last) {
+                (Some(first), Some(last)) => Some((first + 1, last + 1)),
+                _ => None,
+            }
+        })
+        .collect::<Vec<(usize, usize)>>();
+
+    ranges.sort();
+
+    // Merge overlapping ranges.
+    let mut result: Vec<(usize, usize)> = vec![];
+    for (first, last) in ranges {
+        match result.last_mut() {
+            Some(previous) if first <= previous.1 => previous.1 = usize::max(previous.1, last),
+            _ => result.push((first, last)),
+        }
+    }
+
+    result
+}
+
+/// Search the index for tunes containing the query, best first.
+/// The query is encoded like the index, and may also be a Parsons code for a Parsons index.
+/// An ABC fragment is read in the key, if given. See `read_query`.
+/// Given a tune type's name, e.g. "reel", only tunes classified as that type are found.
+/// The tunes of the best results are retrieved with `get_tune`, to find where the query occurs.
+pub fn search<F>(
+    index: &index::NgramIndex,
+    query: &str,
+    key: Option<&str>,
+    tune_type: Option<&str>,
+    limit: usize,
+    get_tune: F,
+) -> Result<Vec<SearchResult>, String>
+where
+    F: Fn(u32) -> Option<tune_ast_three::Tune>,
+{
+    let options = index.options();
+
+    if let Some(key) = key {
+        if !is_key(key) {
+            return Err(format!("Didn't recognise the key {}. Try e.g. D, Em or Ador.", key));
+        }
+    }
+
+    let tune_type = match tune_type {
+        Some(name) => match tune_type::TuneType::from_name(name) {
+            Some(tune_type) => Some(tune_type),
+            None => {
+                return Err(format!(
+                    "Didn't recognise the tune type {}. Try e.g. reel, jig or slip-jig.",
+                    name
+                ))
+            }
+        },
+        None => None,
+    };
+
+    let parsons = match options.encoding {
+        ngram::Encoding::Parsons => ngram::parse_parsons(query),
+        _ => None,
+    };
+
+    let query_ngrams = match parsons {
+        Some(steps) => ngram::sequence_of_steps(&steps, options.n),
+        None => ngram::sequence(&read_query(query, key), &options),
+    };
+
+    if query_ngrams.is_empty() {
+        return Err(format!(
+            "The query needs at least {} notes for a {} search.",
+            options.n + 1,
+            options.encoding.name()
+        ));
+    }
+
+    let idfs = query_ngrams
+        .iter()
+        .map(|ngram| (*ngram, idf(index.get(*ngram), index.num_tunes())))
+        .collect::<HashMap<ngram::NgramId, f32>>();
+
+    let total_weight: f32 = query_ngrams.iter().map(|ngram| idfs[ngram]).sum();
+
+    let mut candidates: HashMap<u32, Candidate> = HashMap::new();
+
+    for (query_position, ngram) in query_ngrams.iter().enumerate() {
+        let weight = idfs[ngram];
+
+        // Each tune's matches for this query position.
+        let mut seen = HashSet::new();
+
+        for posting in index.get(*ngram).iter() {
+            let candidate = candidates.entry(posting.tune_id).or_insert_with(|| Candidate {
+                matched: HashSet::new(),
+                matched_weight: 0.0,
+                alignments: HashMap::new(),
+            });
+
+            if seen.insert(posting.tune_id) {
+                candidate.matched.insert(*ngram);
+                candidate.matched_weight += weight;
+            }
+
+            let alignment = candidate
+                .alignments
+                .entry(posting.position as i64 - query_position as i64)
+                .or_insert((0.0, vec![]));
+            alignment.0 += weight;
+            alignment.1.push(posting.position);
+        }
+    }
+
+    let mut results = candidates
+        .iter()
+        .map(|(tune_id, candidate)| {
+            let aligned_weight = candidate
+                .alignments
+                .values()
+                .map(|&(weight, _)| weight)
+                .fold(0.0, f32::max);
+
+            let score = (candidate.matched_weight + COHERENCE_WEIGHT * aligned_weight) /
+                ((1.0 + COHERENCE_WEIGHT) * total_weight);
+
+            (*tune_id, score)
+        })
+        .collect::<Vec<(u32, f32)>>();
+
+    results.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap().then(a.0.cmp(&b.0)));
+
+    let threshold = usize::max(
+        (query_ngrams.len() as f32 * OCCURRENCE_THRESHOLD).ceil() as usize,
+        1,
+    );
+
+    let mut found = vec![];
+
+    for &(tune_id, score) in results.iter() {
+        if found.len() >= limit {
+            break;
+        }
+
+        let tune = get_tune(tune_id);
+
+        // A tune that can't be retrieved or classified isn't of any type.
+        if let Some(tune_type) = tune_type {
+            match tune {
+                Some(ref tune) if tune_type::classify(tune) == Some(tune_type) => (),
+                _ => continue,
+            }
+        }
+
+        let candidate = &candidates[&tune_id];
+
+        let bars = match tune {
+            Some(tune) => {
+                let occurrences = candidate
+                    .alignments
+                    .values()
+                    .filter(|&&(_, ref positions)| positions.len() >= threshold)
+                    .map(|&(_, ref positions)| positions)
+                    .collect::<Vec<&Vec<u32>>>();
+
+                bar_ranges(&tune, &options, &occurrences)
+            }
+            None => vec![],
+        };
+
+        found.push(SearchResult {
+            tune_id,
+            score,
+            matched: candidate.matched.len(),
+            bars,
+        });
+    }
+
+    Ok(found)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_support;
+
+    const TUNES: &[(u32, &str)] = &[
+        // The query in the second bar, and its start across the fourth and fifth.
+        (1, "X:1\nR:reel\nL:1/8\nK:G\nD2 GF GABc|GABc dedB|d2 gf g2 d2|ec dB GABc|d6|]\n"),
+        // The start of the query transposed, across the first and second bars.
+        (2, "X:2\nR:hornpipe\nL:1/8\nK:D\nA2 FA defg|a2 ag fedc|]\n"),
+        // Unrelated.
+        (3, "X:3\nL:1/8\nK:D\nFAdA FAdA|GBdB GBdB|]\n"),
+    ];
+
+    fn get_tune(tune_id: u32) -> Option<tune_ast_three::Tune> {
+        test_support::get_tune(TUNES, tune_id)
+    }
+
+    fn test_index(encoding: ngram::Encoding) -> index::NgramIndex {
+        test_support::index(TUNES, encoding)
+    }
+
+    #[test]
+    fn search_test() {
+        let index = test_index(ngram::Encoding::Interval);
+        let results = search(&index, "GABc dedB", None, None, DEFAULT_LIMIT, get_tune).unwrap();
+
+        assert_eq!(results.len(), 2);
+
+        assert_eq!(results[0].tune_id, 1);
+        assert_eq!(results[0].matched, 4);
+        assert_eq!(results[0].bars, vec![(2, 2)]);
+        assert!((results[0].score - 1.0).abs() < 1e-6);
+
+        // Only G A B c d is in the second tune, a fifth higher.
+        assert_eq!(results[1].tune_id, 2);
+        assert_eq!(results[1].matched, 1);
+        assert_eq!(results[1].bars, vec![]);
+        assert!(results[1].score < 0.5);
+
+        // Limited.
+        assert_eq!(search(&index, "GABc dedB", None, None, 1, get_tune).unwrap().len(), 1);
+
+        // A key can be given, and changes the intervals.
+        let results = search(&index, "K:D\nAFAd e", None, None, DEFAULT_LIMIT, get_tune).unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].tune_id, 2);
+        assert_eq!(results[0].bars, vec![(1, 1)]);
+
+        // Or given separately. Without one, the fragment is read in C, so doesn't match.
+        assert_eq!(
+            search(&index, "AFAd e", Some("D"), None, DEFAULT_LIMIT, get_tune).unwrap(),
+            results
+        );
+        assert!(search(&index, "AFAd e", None, None, DEFAULT_LIMIT, get_tune).unwrap().is_empty());
+        assert!(search(&index, "AFAd e", Some("H"), None, DEFAULT_LIMIT, get_tune).is_err());
+        assert!(search(&index, "AFAd e", Some("D\nAFAd"), None, DEFAULT_LIMIT, get_tune).is_err());
+
+        assert!(search(&index, "GAB", None, None, DEFAULT_LIMIT, get_tune).is_err());
+    }
+
+    #[test]
+    fn tune_type_search_test() {
+        let index = test_index(ngram::Encoding::Interval);
+        let search_type = |tune_type| {
+            search(&index, "GABc dedB", None, Some(tune_type), DEFAULT_LIMIT, get_tune)
+                .map(|results| results.iter().map(|result| result.tune_id).collect::<Vec<u32>>())
+        };
+
+        assert_eq!(search_type("reel"), Ok(vec![1]));
+        assert_eq!(search_type("hornpipe"), Ok(vec![2]));
+        assert_eq!(search_type("jig"), Ok(vec![]));
+        assert!(search_type("Reel").is_err());
+        assert!(search_type("dance").is_err());
+
+        // The limit counts only tunes of the type.
+        assert_eq!(
+            search(&index, "GABc dedB", None, Some("hornpipe"), 1, get_tune).unwrap()[0].tune_id,
+            2
+        );
+    }
+
+    #[test]
+    fn bar_ranges_test() {
+        // Each occurrence is reported.
+        let index = test_index(ngram::Encoding::Interval);
+        let results = search(&index, "GABc d", None, None, DEFAULT_LIMIT, get_tune).unwrap();
+        assert_eq!(results[0].tune_id, 1);
+        assert_eq!(results[0].bars, vec![(2, 2), (4, 5)]);
+
+        // Overlapping occurrences are merged.
+        let tune = get_tune(1).unwrap();
+        assert_eq!(
+            bar_ranges(&tune, &ngram::Options::new(4), &[&vec![0, 1], &vec![2]]),
+            vec![(1, 1)]
+        );
+    }
+
+    #[test]
+    fn encoding_search_test() {
+        // Rising then falling, as in the second bar of the first tune, or the first of the third.
+        let index = test_index(ngram::Encoding::Parsons);
+        let results = search(&index, "*UUUUU DDD", None, None, DEFAULT_LIMIT, get_tune).unwrap();
+        assert_eq!(results[0].tune_id, 1);
+        assert_eq!(results[0].bars, vec![(2, 2)]);
+
+        // The same shape as ABC.
+        let from_abc = search(&index, "CDEFGA GFE", None, None, DEFAULT_LIMIT, get_tune).unwrap();
+        assert_eq!(from_abc, results);
+
+        assert!(search(&index, "*UUD", None, None, DEFAULT_LIMIT, get_tune).is_err());
+
+        // Only the rhythm matters: a crotchet, six quavers and a dotted minim. The first bar has
+        // the start of it, and the end of the third bar runs to the end.
+        let index = test_index(ngram::Encoding::Rhythm);
+        let results =
+            search(&index, "C2 DE FGAB | c6", None, None, DEFAULT_LIMIT, get_tune).unwrap();
+        assert_eq!(results[0].tune_id, 1);
+        assert_eq!(results[0].bars, vec![(1, 1), (3, 5)]);
+    }
+}
@@ -1,13 +1,61 @@
 use application;
+use json;
+use ngram;
 use regex;
+use search;
+use text;
 use std::env;
+use std::str;
 use std;
 
 use tiny_http::{Server, Response, StatusCode, Header};
 
+/// Decode a URL query string value, where "+" is a space and "%XX" is a byte.
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut result = vec![];
+    let mut i = 0;
+
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => result.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                let hex = str::from_utf8(&bytes[i + 1..i + 3]).ok();
+
+                match hex.and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+                    Some(byte) => {
+                        result.push(byte);
+                        i += 2;
+                    }
+                    None => result.push(b'%'),
+                }
+            }
+            byte => result.push(byte),
+        }
+        i += 1;
+    }
+
+    String::from_utf8_lossy(&result).into_owned()
+}
+
+/// Value of a parameter in a URL's query string.
+fn query_parameter(url: &str, name: &str) -> Option<String> {
+    let (_, query) = url.split_once('?')?;
+
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|&(key, _)| key == name)
+        .map(|(_, value)| percent_decode(value))
+}
+
 pub fn main(application: &application::Application) {
     let re_abc = regex::Regex::new(r"/abc/(\d+)").unwrap();
     let re_svg = regex::Regex::new(r"/svg/(\d+)").unwrap();
+    let re_metadata = regex::Regex::new(r"/metadata/(\d+)").unwrap();
+    let re_wav = regex::Regex::new(r"/wav/(\d+)").unwrap();
+    let re_search = regex::Regex::new(r"^/search(\?|$)").unwrap();
+    let re_group = regex::Regex::new(r"/group/(\d+)").unwrap();
 
     let key = "HTTP_BIND";
     let bind = match env::var(key) {
@@ -61,6 +109,120 @@ pub fn main(application: &application::Application) {
                 Response::from_string("Didn't recognise SVG tune id.")
                     .with_status_code(StatusCode(404))
             }
+        } else if let Some(groups) = re_metadata.captures(request.url()) {
+            if let Some(tune_id) = groups.get(1) {
+                if let Ok(tune_id) = tune_id.as_str().parse::<u32>() {
+                    if let Some(metadata) = application.get_metadata(tune_id) {
+                        Response::from_string(text::format_metadata(&metadata))
+                            .with_status_code(StatusCode(200))
+                    } else {
+                        Response::from_string("Didn't recognise metadata tune id.")
+                            .with_status_code(StatusCode(404))
+                    }
+                } else {
+                    Response::from_string("Didn't recognise metadata tune id.")
+                        .with_status_code(StatusCode(404))
+                }
+            } else {
+                Response::from_string("Didn't recognise metadata tune id.")
+                    .with_status_code(StatusCode(404))
+            }
+        } else if let Some(groups) = re_wav.captures(request.url()) {
+            if let Some(tune_id) = groups.get(1) {
+                if let Ok(tune_id) = tune_id.as_str().parse::<u32>() {
+                    if let Some(content) = application.get_wav(tune_id) {
+                        Response::from_data(content)
+                            .with_header(
+                                Header::from_bytes(&b"Content-Type"[..], &b"audio/wav"[..])
+                                    .unwrap(),
+                            )
+                            .with_status_code(StatusCode(200))
+                    } else {
+                        Response::from_string("Didn't recognise WAV tune id.")
+                            .with_status_code(StatusCode(404))
+                    }
+                } else {
+                    Response::from_string("Didn't recognise WAV tune id.")
+                        .with_status_code(StatusCode(404))
+                }
+            } else {
+                Response::from_string("Didn't recognise WAV tune id.")
+                    .with_status_code(StatusCode(404))
+            }
+        } else if let Some(groups) = re_group.captures(request.url()) {
+            let group = groups
+                .get(1)
+                .and_then(|tune_id| tune_id.as_str().parse::<u32>().ok())
+                .and_then(|tune_id| application.get_group(tune_id));
+
+            if let Some(group) = group {
+                let titles = group
+                    .members
+                    .iter()
+                    .map(|tune_id| {
+                        application
+                            .get_metadata(*tune_id)
+                            .and_then(|metadata| metadata.title().cloned())
+                    })
+                    .collect::<Vec<Option<String>>>();
+
+                Response::from_string(json::group_to_json(&group, &titles).render())
+                    .with_header(
+                        Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..])
+                            .unwrap(),
+                    )
+                    .with_status_code(StatusCode(200))
+            } else {
+                Response::from_string("Didn't recognise group tune id.")
+                    .with_status_code(StatusCode(404))
+            }
+        } else if re_search.is_match(request.url()) {
+            let limit = query_parameter(request.url(), "limit")
+                .and_then(|limit| limit.parse::<usize>().ok())
+                .unwrap_or(search::DEFAULT_LIMIT);
+
+            let mode = query_parameter(request.url(), "mode");
+            let encoding = match mode {
+                Some(ref mode) => ngram::Encoding::from_name(mode),
+                None => Some(ngram::Encoding::Interval),
+            };
+
+            // A fragment is read in C unless a key is given.
+            let key = query_parameter(request.url(), "key");
+
+            // Only tunes of this type, e.g. "reel", if given.
+            let tune_type = query_parameter(request.url(), "type");
+
+            match (query_parameter(request.url(), "q"), encoding) {
+                (_, None) => {
+                    Response::from_string(
+                        "Didn't recognise search mode. Try interval, parsons, contour or rhythm.",
+                    ).with_status_code(StatusCode(400))
+                }
+                (Some(query), Some(encoding)) => match application.search(
+                    &query,
+                    key.as_deref(),
+                    tune_type.as_deref(),
+                    encoding,
+                    limit,
+                ) {
+                    Ok(results) => {
+                        Response::from_string(json::search_results_to_json(&results).render())
+                            .with_header(
+                                Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..])
+                                    .unwrap(),
+                            )
+                            .with_status_code(StatusCode(200))
+                    }
+                    Err(error) => Response::from_string(error).with_status_code(StatusCode(400)),
+                },
+                (None, _) => {
+                    Response::from_string(
+                        "Search for what? Give a query, e.g. /search?q=GABc+dedB. It's read in C \
+                         unless a key is given, e.g. /search?q=FAdA&key=D",
+                    ).with_status_code(StatusCode(400))
+                }
+            }
         } else {
             Response::from_string("Didn't recognise that.").with_status_code(StatusCode(404))
         };
@@ -68,3 +230,19 @@ pub fn main(application: &application::Application) {
         request.respond(response);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn query_parameter_test() {
+        assert_eq!(
+            query_parameter("/search?q=GABc+d%5Ee%20f&limit=5", "q"),
+            Some("GABc d^e f".to_string())
+        );
+        assert_eq!(query_parameter("/search?q=GABc&limit=5", "limit"), Some("5".to_string()));
+        assert_eq!(query_parameter("/search?q=%", "q"), Some("%".to_string()));
+        assert_eq!(query_parameter("/search", "q"), None);
+    }
+}
@@ -16,6 +16,10 @@ use std::io::Read;
 use std::path::PathBuf;
 use std::env;
 
+use index;
+use ngram;
+use relations;
+
 
 /// Read a file from a path, return bytes.
 fn read_file(filename: &PathBuf) -> Vec<u8> {
@@ -182,9 +186,15 @@ impl TuneCache {
 }
 
 pub struct TuneStore {
-    // base: String,
+    base: PathBuf,
     glob_path: PathBuf,
     pub tune_cache: TuneCache,
+    /// An ngram index for each encoding, in the order of `ngram::Encoding::all`.
+    pub ngram_indexes: Vec<index::NgramIndex>,
+    /// Why tunes are grouped, and editors' links and never-links.
+    pub relations: relations::Relations,
+    /// Groups of tunes that are the same, from the relations.
+    pub groups: relations::Grouper,
 }
 
 impl TuneStore {
@@ -196,16 +206,37 @@ impl TuneStore {
                 tune_cache_path.push(&base);
                 tune_cache_path.push("tunecache");
 
+                let ngram_indexes = ngram::Encoding::all()
+                    .iter()
+                    .map(|encoding| {
+                        let mut path = PathBuf::new();
+                        path.push(&base);
+                        path.push(index::filename(*encoding));
+                        index::NgramIndex::new(path, *encoding)
+                    })
+                    .collect();
+
                 let mut glob_path = PathBuf::new();
                 glob_path.push(&base);
                 glob_path.push("**");
                 glob_path.push("*");
                 glob_path.set_extension("abc");
 
+                let mut relations_path = PathBuf::new();
+                relations_path.push(&base);
+                relations_path.push(relations::RELATIONS_FILENAME);
+
+                let relations = relations::Relations::new(relations_path);
+                let groups = relations.grouper();
+
                 let tune_cache = TuneCache::new(tune_cache_path);
                 return TuneStore {
+                    base: PathBuf::from(&base),
                     tune_cache,
                     glob_path,
+                    ngram_indexes,
+                    relations,
+                    groups,
                 };
             }
             Err(e) => panic!("Couldn't get config value {}: {}", key, e),
@@ -213,10 +244,32 @@ impl TuneStore {
     }
 
     /// Scan all files in the tune store, write to a consolidated cache file.
+    /// New tunes are also added to the ngram index.
     pub fn scan(&mut self) {
         let mut num_scanned = 0;
         let mut num_indexed = 0;
 
+        // Catch up with tunes cached before an ngram index existed, or before it was rebuilt.
+        let mut unindexed = self.tune_cache
+            .index
+            .keys()
+            .filter(|tune_id| {
+                self.ngram_indexes.iter().any(|index| !index.has_tune(**tune_id))
+            })
+            .cloned()
+            .collect::<Vec<u32>>();
+        unindexed.sort();
+
+        for tune_id in unindexed.iter() {
+            if let Some(data) = self.tune_cache.get_tune(tune_id) {
+                index::add_abc_to_all(&mut self.ngram_indexes, *tune_id, data);
+            }
+        }
+
+        if !unindexed.is_empty() {
+            eprintln!("Added {} cached tunes to ngram indexes", unindexed.len());
+        }
+
         // Iterate and load into cache.
         for entry in glob::glob(self.glob_path.to_str().expect("Can't create path"))
             .expect("Failed to read glob pattern")
@@ -229,6 +282,7 @@ impl TuneStore {
                         if !self.tune_cache.has_tune(tune_id) {
                             let data = read_file(&filepath);
                             self.tune_cache.ensure(tune_id, &data);
+                            index::add_abc_to_all(&mut self.ngram_indexes, tune_id, &data);
                             num_indexed += 1;
                         }
 
@@ -247,5 +301,27 @@ impl TuneStore {
         }
 
         self.tune_cache.save();
+        for index in self.ngram_indexes.iter() {
+            index.save();
+        }
+    }
+
+    /// Path of a file in the tune store, beside the tune cache.
+    pub fn path(&self, filename: &str) -> PathBuf {
+        self.base.join(filename)
+    }
+
+    /// Save the relations, and regroup the tunes to match.
+    pub fn update_relations(&mut self) {
+        self.relations.save();
+        self.groups = self.relations.grouper();
+    }
+
+    /// The ngram index for an encoding.
+    pub fn ngram_index(&self, encoding: ngram::Encoding) -> &index::NgramIndex {
+        self.ngram_indexes
+            .iter()
+            .find(|index| index.encoding() == encoding)
+            .expect("There's an index for every encoding.")
     }
 }
@@ -0,0 +1,357 @@
+//! Synth
+//! A simple synthesiser that renders a timeline to 16-bit PCM WAV, for listening without a MIDI
+//! synth. Each channel's MIDI program chooses a sine, triangle or plucked-string voice.
+
+use std::collections::HashMap;
+use std::f64::consts::PI;
+
+use music;
+use playback;
+use timeline;
+use tune_ast_three;
+
+pub const SAMPLE_RATE: u32 = 22_050;
+
+/// Loudness of a note at full velocity, leaving headroom for several at once.
+const NOTE_GAIN: f64 = 0.3;
+
+/// Peak level of the mix. Louder mixes are scaled down to this.
+const PEAK: f64 = 0.9;
+
+/// How much of a plucked string's energy remains after each period.
+const PLUCK_DAMPING: f64 = 0.996;
+
+/// Default MIDI program, as in General MIDI.
+const DEFAULT_PROGRAM: u8 = 0;
+
+#[derive(Debug, PartialEq, PartialOrd, Clone, Copy)]
+pub enum Waveform {
+    Sine,
+    Triangle,
+    /// Karplus-Strong plucked string.
+    Plucked,
+}
+
+/// Attack, decay, sustain, release envelope. Times are in seconds, sustain is a level.
+#[derive(Debug, PartialEq, PartialOrd, Clone, Copy)]
+pub struct Envelope {
+    pub attack: f64,
+    pub decay: f64,
+    pub sustain: f64,
+    pub release: f64,
+}
+
+impl Envelope {
+    /// Level at a time since the note started, for a note held for the given time.
+    pub fn level(&self, time: f64, held: f64) -> f64 {
+        if time < held {
+            self.held_level(time)
+        } else {
+            let released = time - held;
+            if released >= self.release {
+                0.0
+            } else {
+                self.held_level(held) * (1.0 - released / self.release)
+            }
+        }
+    }
+
+    fn held_level(&self, time: f64) -> f64 {
+        if time < self.attack {
+            time / self.attack
+        } else if time < self.attack + self.decay {
+            1.0 - (1.0 - self.sustain) * (time - self.attack) / self.decay
+        } else {
+            self.sustain
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, PartialOrd, Clone, Copy)]
+pub struct Instrument {
+    pub waveform: Waveform,
+    pub envelope: Envelope,
+}
+
+impl Instrument {
+    pub fn new(waveform: Waveform) -> Instrument {
+        let envelope = match waveform {
+            Waveform::Sine => Envelope {
+                attack: 0.02,
+                decay: 0.1,
+                sustain: 0.8,
+                release: 0.08,
+            },
+            Waveform::Triangle => Envelope {
+                attack: 0.03,
+                decay: 0.1,
+                sustain: 0.7,
+                release: 0.1,
+            },
+            // The string dies away by itself, the envelope only shapes the ends.
+            Waveform::Plucked => Envelope {
+                attack: 0.002,
+                decay: 0.0,
+                sustain: 1.0,
+                release: 0.05,
+            },
+        };
+
+        Instrument { waveform, envelope }
+    }
+
+    /// The nearest voice to a General MIDI program.
+    pub fn for_program(program: u8) -> Instrument {
+        let waveform = match program {
+            // Pianos, guitars, harp and pizzicato, banjo and the like.
+            0..=7 | 24..=31 | 45..=46 | 104..=108 => Waveform::Plucked,
+
+            // Organs, accordions, strings, brass and reeds, bagpipes and fiddle.
+            16..=23 | 40..=44 | 48..=71 | 109..=110 => Waveform::Triangle,
+
+            // Flutes, whistles and everything else.
+            _ => Waveform::Sine,
+        };
+
+        Instrument::new(waveform)
+    }
+}
+
+/// Converts ticks to seconds, following tempo changes.
+struct TempoMap {
+    /// Time in ticks and seconds of each change, and the new tempo.
+    changes: Vec<(u32, f64, u32)>,
+}
+
+impl TempoMap {
+    fn new(events: &[timeline::TimedEvent]) -> TempoMap {
+        let mut changes = vec![(0, 0.0, 60_000_000 / timeline::DEFAULT_BPM)];
+
+        for event in events.iter() {
+            if let timeline::Event::Tempo { microseconds_per_crotchet } = event.event {
+                let seconds = seconds_at(&changes, event.time);
+                changes.push((event.time, seconds, microseconds_per_crotchet));
+            }
+        }
+
+        TempoMap { changes }
+    }
+
+    fn seconds(&self, ticks: u32) -> f64 {
+        seconds_at(&self.changes, ticks)
+    }
+}
+
+fn seconds_at(changes: &[(u32, f64, u32)], ticks: u32) -> f64 {
+    let &(start, start_seconds, microseconds_per_crotchet) = changes
+        .iter()
+        .rev()
+        .find(|&&(time, _, _)| time <= ticks)
+        .unwrap_or(&changes[0]);
+
+    start_seconds +
+        (ticks - start) as f64 * microseconds_per_crotchet as f64 /
+            (timeline::TICKS_PER_CROTCHET as f64 * 1_000_000.0)
+}
+
+/// Deterministic noise to excite a plucked string, so renders are repeatable.
+struct Noise(u32);
+
+impl Noise {
+    fn next(&mut self) -> f64 {
+        self.0 = self.0.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+        (self.0 >> 8) as f64 / (1 << 23) as f64 - 1.0
+    }
+}
+
+/// Render one note into the mix.
+fn render_note(
+    mix: &mut Vec<f64>,
+    instrument: &Instrument,
+    start: f64,
+    held: f64,
+    frequency: f64,
+    gain: f64,
+) {
+    let rate = SAMPLE_RATE as f64;
+    let first = (start * rate).round() as usize;
+    let length = ((held + instrument.envelope.release) * rate).ceil() as usize;
+
+    if mix.len() < first + length {
+        mix.resize(first + length, 0.0);
+    }
+
+    // Karplus-Strong: a period of noise, repeatedly averaged as it goes round.
+    let period = usize::max((rate / frequency).round() as usize, 2);
+    let mut noise = Noise(frequency as u32);
+    let mut string = (0..period).map(|_| noise.next()).collect::<Vec<f64>>();
+
+    for i in 0..length {
+        let time = i as f64 / rate;
+        let phase = (frequency * time).fract();
+
+        let value = match instrument.waveform {
+            Waveform::Sine => (2.0 * PI * phase).sin(),
+            Waveform::Triangle => 1.0 - 4.0 * (phase - 0.5).abs(),
+            Waveform::Plucked => {
+                let position = i % period;
+                let value = string[position];
+                let next = string[(position + 1) % period];
+                string[position] = PLUCK_DAMPING * (value + next) / 2.0;
+                value
+            }
+        };
+
+        mix[first + i] += value * gain * instrument.envelope.level(time, held);
+    }
+}
+
+/// Render a timeline as mono samples.
+pub fn render(timeline: &timeline::Timeline) -> Vec<i16> {
+    let tempo = TempoMap::new(&timeline.events);
+    let tuning = music::Tuning::standard();
+
+    let mut programs: HashMap<u8, u8> = HashMap::new();
+    let mut mix: Vec<f64> = vec![];
+
+    for event in timeline.events.iter() {
+        match event.event {
+            timeline::Event::Program { channel, program, .. } => {
+                programs.insert(channel, program);
+            }
+
+            timeline::Event::Note {
+                channel,
+                midi_number,
+                velocity,
+                duration,
+                ..
+            } => {
+                let program = *programs.get(&channel).unwrap_or(&DEFAULT_PROGRAM);
+                let start = tempo.seconds(event.time);
+
+                render_note(
+                    &mut mix,
+                    &Instrument::for_program(program),
+                    start,
+                    tempo.seconds(event.time + duration) - start,
+                    tuning.frequency_of_midi_number(midi_number as f64),
+                    NOTE_GAIN * velocity as f64 / 127.0,
+                );
+            }
+
+            _ => (),
+        }
+    }
+
+    let loudest = mix.iter().fold(0.0, |loudest: f64, sample| loudest.max(sample.abs()));
+    let scale = if loudest > PEAK { PEAK / loudest } else { 1.0 };
+
+    mix.iter()
+        .map(|sample| (sample * scale * i16::MAX as f64).round() as i16)
+        .collect()
+}
+
+/// Wrap mono samples in a WAV file.
+pub fn to_wav(samples: &[i16]) -> Vec<u8> {
+    const CHANNELS: u16 = 1;
+    const BYTES_PER_SAMPLE: u16 = 2;
+
+    let data_length = samples.len() as u32 * BYTES_PER_SAMPLE as u32;
+
+    let mut bytes = vec![];
+    bytes.extend_from_slice(b"RIFF");
+    bytes.extend_from_slice(&(36 + data_length).to_le_bytes());
+    bytes.extend_from_slice(b"WAVE");
+
+    bytes.extend_from_slice(b"fmt ");
+    bytes.extend_from_slice(&16u32.to_le_bytes());
+    // PCM.
+    bytes.extend_from_slice(&1u16.to_le_bytes());
+    bytes.extend_from_slice(&CHANNELS.to_le_bytes());
+    bytes.extend_from_slice(&SAMPLE_RATE.to_le_bytes());
+    bytes.extend_from_slice(&(SAMPLE_RATE * (CHANNELS * BYTES_PER_SAMPLE) as u32).to_le_bytes());
+    bytes.extend_from_slice(&(CHANNELS * BYTES_PER_SAMPLE).to_le_bytes());
+    bytes.extend_from_slice(&(BYTES_PER_SAMPLE * 8).to_le_bytes());
+
+    bytes.extend_from_slice(b"data");
+    bytes.extend_from_slice(&data_length.to_le_bytes());
+    for sample in samples.iter() {
+        bytes.extend_from_slice(&sample.to_le_bytes());
+    }
+
+    bytes
+}
+
+/// Render a tune as a WAV file, with repeats expanded.
+pub fn tune_to_wav(tune: &tune_ast_three::Tune, options: &playback::Options) -> Vec<u8> {
+    to_wav(&render(&timeline::timeline(tune, options)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_support::read;
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-9
+    }
+
+    #[test]
+    fn envelope_test() {
+        let envelope = Envelope {
+            attack: 0.1,
+            decay: 0.1,
+            sustain: 0.5,
+            release: 0.2,
+        };
+
+        assert!(close(envelope.level(0.0, 1.0), 0.0));
+        assert!(close(envelope.level(0.05, 1.0), 0.5));
+        assert!(close(envelope.level(0.1, 1.0), 1.0));
+        assert!(close(envelope.level(0.5, 1.0), 0.5));
+        assert!(close(envelope.level(1.1, 1.0), 0.25));
+        assert!(close(envelope.level(1.2, 1.0), 0.0));
+
+        // Released during the attack.
+        assert!(close(envelope.level(0.05, 0.05), 0.5));
+    }
+
+    #[test]
+    fn render_test() {
+        // A crotchet at 60 to the crotchet lasts a second, plus the release.
+        let tune = read("X:1\nQ:1/4=60\nL:1/4\nK:C\n%%MIDI program 73\nA|\n");
+        let options = playback::Options {
+            directives: true,
+            ..playback::Options::straight()
+        };
+        let samples = render(&timeline::timeline(&tune, &options));
+
+        let release = Instrument::for_program(73).envelope.release;
+        let expected = ((1.0 + release) * SAMPLE_RATE as f64).ceil() as usize;
+        assert_eq!(samples.len(), expected);
+
+        // A flute plays a sine wave at 440Hz, so crosses zero upwards 440 times a second.
+        let upwards = samples[..SAMPLE_RATE as usize]
+            .windows(2)
+            .filter(|pair| pair[0] < 0 && pair[1] >= 0)
+            .count();
+        assert!((439..=441).contains(&upwards));
+
+        let loudest = samples.iter().map(|sample| (*sample as i32).abs()).max().unwrap();
+        assert!(loudest > 1000 && loudest as f64 <= PEAK * i16::MAX as f64 + 1.0);
+    }
+
+    #[test]
+    fn wav_test() {
+        let bytes = to_wav(&[0, 1, -1]);
+
+        assert_eq!(bytes.len(), 44 + 6);
+        assert_eq!(&bytes[0..4], b"RIFF");
+        assert_eq!(&bytes[4..8], &42u32.to_le_bytes());
+        assert_eq!(&bytes[8..16], b"WAVEfmt ");
+        assert_eq!(&bytes[24..28], &SAMPLE_RATE.to_le_bytes());
+        assert_eq!(&bytes[36..44], &[b'd', b'a', b't', b'a', 6, 0, 0, 0]);
+        assert_eq!(&bytes[44..], &[0, 0, 1, 0, 0xFF, 0xFF]);
+    }
+}
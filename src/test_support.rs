@@ -0,0 +1,39 @@
+//! Test Support
+//! Helpers shared by the tests of several modules.
+
+use std::env;
+use std::path::PathBuf;
+
+use abc_lexer as l;
+use index;
+use ngram;
+use tune_ast_three;
+
+/// Read a tune from ABC.
+pub fn read(abc: &str) -> tune_ast_three::Tune {
+    let chars = abc.chars().collect::<Vec<char>>();
+    tune_ast_three::read_from_lexer(l::Lexer::new(&chars))
+}
+
+/// A path in the temporary directory, for this name and this test run.
+pub fn temp_path(name: &str) -> PathBuf {
+    let mut path = env::temp_dir();
+    path.push(format!("abctool_test_{}_{}", name, ::std::process::id()));
+    path
+}
+
+/// A tune from a corpus of tune ids and their ABC.
+pub fn get_tune(tunes: &[(u32, &str)], tune_id: u32) -> Option<tune_ast_three::Tune> {
+    tunes.iter().find(|&&(id, _)| id == tune_id).map(|&(_, abc)| read(abc))
+}
+
+/// An index of a corpus of tune ids and their ABC. It isn't saved.
+pub fn index(tunes: &[(u32, &str)], encoding: ngram::Encoding) -> index::NgramIndex {
+    let mut index = index::NgramIndex::new(temp_path("unsaved_index"), encoding);
+
+    for &(tune_id, abc) in tunes.iter() {
+        index.add_abc(tune_id, abc.as_bytes());
+    }
+
+    index
+}
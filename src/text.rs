@@ -1,4 +1,296 @@
 //! Text
 //! Functions relating to the textual aspect of tunes (title, author etc).
 
-// Placeholder.
+use std::fmt::Write;
+
+use abc_lexer as l;
+use music;
+use tune_ast_three;
+
+/// Metadata about a tune, as declared in its header.
+/// Text fields that only make sense once take the first value found.
+#[derive(Debug, PartialEq, PartialOrd, Clone)]
+pub struct TuneMetadata {
+    /// All titles, in the order they were given. The first is the main title.
+    pub titles: Vec<String>,
+
+    /// Reference number from "X:", if it's a number.
+    pub x: Option<u32>,
+
+    pub composer: Option<String>,
+
+    /// Free-text rhythm, e.g. "double jig".
+    pub rhythm: Option<String>,
+
+    pub origin: Option<String>,
+    pub source: Option<String>,
+    pub book: Option<String>,
+    pub discography: Option<String>,
+
+    /// Notes can run over several lines, so keep them all.
+    pub notes: Vec<String>,
+
+    pub transcription: Option<String>,
+
+    pub key: Option<music::KeySignature>,
+    pub metre: Option<music::Metre>,
+    pub default_length: Option<music::FractionalDuration>,
+    pub tempo: Option<music::Tempo>,
+}
+
+/// Set an optional field only if it hasn't been set already.
+fn set_first<T>(field: &mut Option<T>, value: T) {
+    if field.is_none() {
+        *field = Some(value);
+    }
+}
+
+impl TuneMetadata {
+    pub fn new() -> TuneMetadata {
+        TuneMetadata {
+            titles: vec![],
+            x: None,
+            composer: None,
+            rhythm: None,
+            origin: None,
+            source: None,
+            book: None,
+            discography: None,
+            notes: vec![],
+            transcription: None,
+            key: None,
+            metre: None,
+            default_length: None,
+            tempo: None,
+        }
+    }
+
+    /// Build metadata from a sequence of header tokens.
+    pub fn from_tokens(tokens: &[l::T]) -> TuneMetadata {
+        let mut metadata = TuneMetadata::new();
+
+        for token in tokens.iter() {
+            match token {
+                &l::T::Title(ref value) => metadata.titles.push(value.clone()),
+                &l::T::X(ref value) => {
+                    if let Ok(x) = value.parse::<u32>() {
+                        set_first(&mut metadata.x, x);
+                    }
+                }
+                &l::T::Composer(ref value) => set_first(&mut metadata.composer, value.clone()),
+                &l::T::Origin(ref value) => set_first(&mut metadata.origin, value.clone()),
+                &l::T::Rhythm(ref value) => set_first(&mut metadata.rhythm, value.clone()),
+                &l::T::Source(ref value) => set_first(&mut metadata.source, value.clone()),
+                &l::T::Book(ref value) => set_first(&mut metadata.book, value.clone()),
+                &l::T::Discography(ref value) => {
+                    set_first(&mut metadata.discography, value.clone())
+                }
+                &l::T::Notes(ref value) => metadata.notes.push(value.clone()),
+                &l::T::Transcription(ref value) => {
+                    set_first(&mut metadata.transcription, value.clone())
+                }
+                &l::T::KeySignature(tonic, mode) => {
+                    set_first(&mut metadata.key, music::KeySignature { tonic, mode })
+                }
+                &l::T::Metre(metre) => set_first(&mut metadata.metre, metre),
+                &l::T::DefaultNoteLength(length) => {
+                    set_first(&mut metadata.default_length, length)
+                }
+                &l::T::Tempo(ref tempo) => set_first(&mut metadata.tempo, tempo.clone()),
+
+                // Everything else isn't metadata.
+                _ => (),
+            }
+        }
+
+        metadata
+    }
+
+    /// Build metadata from a tune's prelude.
+    pub fn from_tune(tune: &tune_ast_three::Tune) -> TuneMetadata {
+        TuneMetadata::from_tokens(&tune.prelude)
+    }
+
+    /// The main title, if there is one.
+    pub fn title(&self) -> Option<&String> {
+        self.titles.first()
+    }
+}
+
+/// Format a key for people to read, e.g. "F# Dorian".
+pub fn format_key(key: music::KeySignature) -> String {
+    let accidental = match key.tonic.accidental {
+        Some(music::Accidental::Sharp) => "#",
+        Some(music::Accidental::Flat) => "b",
+        Some(music::Accidental::DoubleSharp) => "##",
+        Some(music::Accidental::DoubleFlat) => "bb",
+        Some(music::Accidental::Natural) | None => "",
+    };
+
+    format!(
+        "{:?}{} {:?}",
+        key.tonic.diatonic_pitch_class,
+        accidental,
+        key.mode
+    )
+}
+
+/// Format metadata as human-readable lines of "Field: value".
+/// Missing fields are omitted.
+pub fn format_metadata(metadata: &TuneMetadata) -> String {
+    let mut buf = String::new();
+
+    if let Some(x) = metadata.x {
+        writeln!(&mut buf, "Reference: {}", x).unwrap();
+    }
+
+    for title in metadata.titles.iter() {
+        writeln!(&mut buf, "Title: {}", title).unwrap();
+    }
+
+    let text_fields = [
+        ("Composer", &metadata.composer),
+        ("Rhythm", &metadata.rhythm),
+        ("Origin", &metadata.origin),
+        ("Source", &metadata.source),
+        ("Book", &metadata.book),
+        ("Discography", &metadata.discography),
+        ("Transcription", &metadata.transcription),
+    ];
+
+    for &(name, value) in text_fields.iter() {
+        if let &Some(ref value) = value {
+            writeln!(&mut buf, "{}: {}", name, value).unwrap();
+        }
+    }
+
+    for note in metadata.notes.iter() {
+        writeln!(&mut buf, "Notes: {}", note).unwrap();
+    }
+
+    if let Some(music::Metre(numerator, denomenator)) = metadata.metre {
+        writeln!(&mut buf, "Metre: {}/{}", numerator, denomenator).unwrap();
+    }
+
+    if let Some(music::FractionalDuration(numerator, denomenator)) = metadata.default_length {
+        writeln!(&mut buf, "Default length: {}/{}", numerator, denomenator).unwrap();
+    }
+
+    if let Some(ref tempo) = metadata.tempo {
+        // The label and the speed on one line, e.g. "Allegro 1/4=120".
+        let mut parts = vec![];
+
+        if let Some(ref label) = tempo.label {
+            parts.push(label.clone());
+        }
+
+        match (tempo.beat, tempo.bpm) {
+            (Some(music::FractionalDuration(numerator, denomenator)), Some(bpm)) => {
+                parts.push(format!("{}/{}={}", numerator, denomenator, bpm))
+            }
+            (None, Some(bpm)) => parts.push(bpm.to_string()),
+            _ => (),
+        }
+
+        if !parts.is_empty() {
+            writeln!(&mut buf, "Tempo: {}", parts.join(" ")).unwrap();
+        }
+    }
+
+    if let Some(key) = metadata.key {
+        writeln!(&mut buf, "Key: {}", format_key(key)).unwrap();
+    }
+
+    buf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_support::read;
+
+    #[test]
+    fn metadata_from_tokens_test() {
+        let chars = "X:24
+T:Butterfly, The
+T:Dearg Doom
+C:John Potts
+O:Ireland
+R:slip jig
+N:First note.
+N:Second note.
+C:Someone else
+M:9/8
+L:1/8
+Q:3/8=120
+K:EDor
+B2EG2EF3|
+"
+            .chars()
+            .collect::<Vec<char>>();
+
+        let tune = tune_ast_three::read_from_lexer(l::Lexer::new(&chars));
+        let metadata = TuneMetadata::from_tune(&tune);
+
+        assert_eq!(metadata.x, Some(24));
+        assert_eq!(
+            metadata.titles,
+            vec!["Butterfly, The".to_string(), "Dearg Doom".to_string()],
+            "All titles should be kept in order."
+        );
+        assert_eq!(metadata.title(), Some(&"Butterfly, The".to_string()));
+        assert_eq!(
+            metadata.composer,
+            Some("John Potts".to_string()),
+            "First composer should be taken."
+        );
+        assert_eq!(metadata.origin, Some("Ireland".to_string()));
+        assert_eq!(metadata.rhythm, Some("slip jig".to_string()));
+        assert_eq!(
+            metadata.notes,
+            vec!["First note.".to_string(), "Second note.".to_string()]
+        );
+        assert_eq!(metadata.source, None);
+        assert_eq!(metadata.metre, Some(music::Metre(9, 8)));
+        assert_eq!(
+            metadata.default_length,
+            Some(music::FractionalDuration(1, 8))
+        );
+        assert_eq!(
+            metadata.tempo,
+            Some(music::Tempo {
+                beat: Some(music::FractionalDuration(3, 8)),
+                bpm: Some(120),
+                label: None,
+            })
+        );
+        assert_eq!(
+            metadata.key,
+            Some(music::KeySignature {
+                tonic: music::PitchClass {
+                    diatonic_pitch_class: music::DiatonicPitchClass::E,
+                    accidental: None,
+                },
+                mode: music::Mode::Dorian,
+            })
+        );
+    }
+
+    #[test]
+    fn format_metadata_tempo_test() {
+        let format = |abc| format_metadata(&TuneMetadata::from_tune(&read(abc)));
+
+        let formatted = format("X:1\nQ:\"Allegro\" 1/4=120\nK:G\n");
+        assert!(formatted.contains("Tempo: Allegro 1/4=120\n"));
+        assert_eq!(formatted.matches("Tempo:").count(), 1, "One line for the tempo.");
+
+        assert!(format("X:1\nQ:\"Slowly\"\nK:G\n").contains("Tempo: Slowly\n"));
+        assert!(format("X:1\nQ:3/8=100\nK:G\n").contains("Tempo: 3/8=100\n"));
+    }
+
+    #[test]
+    fn metadata_non_numerical_x_test() {
+        let metadata = TuneMetadata::from_tokens(&[l::T::X("one".to_string())]);
+        assert_eq!(metadata.x, None, "Non-numerical X should be ignored.");
+    }
+}
@@ -0,0 +1,668 @@
+//! Timeline
+//! A tune as a list of timed events, with repeats expanded, ready for playback or export.
+//! Expressive playback rules are applied here, according to the options.
+
+use abc_lexer as l;
+use music;
+use playback;
+use tune_ast_three;
+use tune_type;
+use visitor;
+
+/// Resolution of the timeline.
+pub const TICKS_PER_CROTCHET: u32 = 480;
+
+/// Tempo when there's no "Q:" field, in crotchets per minute.
+pub const DEFAULT_BPM: u32 = 120;
+
+/// Loudness of every note, as a MIDI velocity.
+pub const DEFAULT_VELOCITY: u8 = 80;
+
+const MICROSECONDS_PER_MINUTE: u64 = 60_000_000;
+
+/// Channel for guitar chords.
+pub const CHORD_CHANNEL: u8 = 14;
+
+/// Channel for the drone.
+pub const DRONE_CHANNEL: u8 = 15;
+
+/// Percussion channel in General MIDI, which voices skip.
+const PERCUSSION_CHANNEL: u8 = 9;
+
+/// Number of channels available to voices.
+const VOICE_CHANNELS: usize = 13;
+
+#[derive(Debug, PartialEq, PartialOrd, Clone, Copy)]
+pub enum Event {
+    Note {
+        track: usize,
+        channel: u8,
+        midi_number: u8,
+        velocity: u8,
+        duration: u32,
+    },
+    Program {
+        track: usize,
+        channel: u8,
+        program: u8,
+    },
+    Tempo { microseconds_per_crotchet: u32 },
+    Metre(music::Metre),
+    Key(music::KeySignature),
+}
+
+#[derive(Debug, PartialEq, PartialOrd, Clone, Copy)]
+pub struct TimedEvent {
+    /// Start time in ticks.
+    pub time: u32,
+    pub event: Event,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Timeline {
+    /// Events in time order.
+    pub events: Vec<TimedEvent>,
+
+    /// Number of tracks. Each voice has a track, followed by any accompaniment.
+    pub tracks: usize,
+
+    /// Time in ticks at which the last voice finishes.
+    pub length: u32,
+}
+
+/// Length of a duration in ticks. A malformed duration with no denominator, e.g. `A/0`, has none.
+pub fn ticks(duration: music::FractionalDuration) -> u32 {
+    if duration.1 == 0 {
+        return 0;
+    }
+
+    ((duration.0 as u64 * TICKS_PER_CROTCHET as u64 * 4) / duration.1 as u64) as u32
+}
+
+/// Channel for a voice, avoiding the percussion channel and those used for accompaniment.
+pub fn voice_channel(voice: usize) -> u8 {
+    let channel = (voice % VOICE_CHANNELS) as u8;
+
+    if channel >= PERCUSSION_CHANNEL {
+        channel + 1
+    } else {
+        channel
+    }
+}
+
+/// Transpose a MIDI note number, if it stays in range.
+fn transpose_midi_number(midi_number: u8, semitones: i16) -> Option<u8> {
+    let result = midi_number as i16 + semitones;
+
+    if result >= 0 && result <= 127 {
+        Some(result as u8)
+    } else {
+        None
+    }
+}
+
+/// Tempo as microseconds per crotchet, if it gives a speed.
+/// The beat is the default note length if it's not given.
+fn tempo_event(tempo: &music::Tempo, state: &visitor::RunningState) -> Option<Event> {
+    let bpm = match tempo.bpm {
+        Some(bpm) if bpm > 0 => bpm,
+        _ => return None,
+    };
+
+    let beat = tempo.beat.unwrap_or(state.default_length);
+    let crotchets_per_beat = beat.0 as u64 * 4;
+    if crotchets_per_beat == 0 || beat.1 == 0 {
+        return None;
+    }
+
+    let microseconds = MICROSECONDS_PER_MINUTE * beat.1 as u64 / (bpm as u64 * crotchets_per_beat);
+
+    Some(Event::Tempo { microseconds_per_crotchet: microseconds as u32 })
+}
+
+/// Notes sustained on a track of their own, i.e. guitar chords or a drone.
+/// The track is only allocated if something is played.
+struct Accompaniment {
+    channel: u8,
+    track: Option<usize>,
+    program: Option<u8>,
+
+    /// Start time, notes and velocity of what's sounding.
+    sounding: Option<(u32, Vec<u8>, u8)>,
+}
+
+impl Accompaniment {
+    fn new(channel: u8) -> Accompaniment {
+        Accompaniment {
+            channel,
+            track: None,
+            program: None,
+            sounding: None,
+        }
+    }
+
+    /// Start playing notes, stopping whatever was playing.
+    fn start(
+        &mut self,
+        events: &mut Vec<TimedEvent>,
+        tracks: &mut usize,
+        time: u32,
+        program: u8,
+        notes: Vec<u8>,
+        velocity: u8,
+    ) {
+        self.stop(events, time);
+
+        let track = match self.track {
+            Some(track) => track,
+            None => {
+                *tracks += 1;
+                self.track = Some(*tracks - 1);
+                *tracks - 1
+            }
+        };
+
+        if self.program != Some(program) {
+            self.program = Some(program);
+            events.push(TimedEvent {
+                time,
+                event: Event::Program {
+                    track,
+                    channel: self.channel,
+                    program,
+                },
+            });
+        }
+
+        self.sounding = Some((time, notes, velocity));
+    }
+
+    fn stop(&mut self, events: &mut Vec<TimedEvent>, time: u32) {
+        if let (Some(track), Some((start, notes, velocity))) = (self.track, self.sounding.take()) {
+            if time > start {
+                for midi_number in notes {
+                    events.push(TimedEvent {
+                        time: start,
+                        event: Event::Note {
+                            track,
+                            channel: self.channel,
+                            midi_number,
+                            velocity,
+                            duration: time - start,
+                        },
+                    });
+                }
+            }
+        }
+    }
+}
+
+/// Build the timeline of a tune, applying the given playback rules.
+/// Header events (tempo, metre, key) are taken from the prelude and the first voice only.
+/// Guitar chords and the drone follow the first voice.
+pub fn timeline(tune: &tune_ast_three::Tune, options: &playback::Options) -> Timeline {
+    let mut events = vec![];
+
+    let lilt = if options.lilt {
+        tune_type::classify(tune).and_then(playback::lilt)
+    } else {
+        None
+    };
+    let warp = |position: u32| lilt.map_or(position, |lilt| lilt.warp(position));
+
+    let mut state = visitor::RunningState::new();
+    let mut tempo = Event::Tempo {
+        microseconds_per_crotchet: (MICROSECONDS_PER_MINUTE / DEFAULT_BPM as u64) as u32,
+    };
+    let mut directives = vec![];
+
+    for token in tune.prelude.iter() {
+        state.update(token);
+
+        match token {
+            &l::T::Tempo(ref value) => {
+                if let Some(event) = tempo_event(value, &state) {
+                    tempo = event;
+                }
+            }
+            &l::T::Directive(ref value) if options.directives => {
+                directives.extend(playback::Directive::parse(value));
+            }
+            _ => (),
+        }
+    }
+
+    events.push(TimedEvent { time: 0, event: tempo });
+    events.push(TimedEvent {
+        time: 0,
+        event: Event::Metre(state.metre),
+    });
+    events.push(TimedEvent {
+        time: 0,
+        event: Event::Key(state.key),
+    });
+
+    let mut length = 0;
+    let mut tracks = tune.voices.len();
+    let mut chords = Accompaniment::new(CHORD_CHANNEL);
+    let mut drone = Accompaniment::new(DRONE_CHANNEL);
+
+    for (voice_number, voice) in tune.voices.iter().enumerate() {
+        let channel = voice_channel(voice_number);
+        let accompanied = voice_number == 0;
+
+        // Directives in the header apply to every voice.
+        let mut settings = playback::Settings::new();
+        for directive in directives.iter() {
+            if let &playback::Directive::Program(program) = directive {
+                events.push(TimedEvent {
+                    time: 0,
+                    event: Event::Program {
+                        track: voice_number,
+                        channel,
+                        program,
+                    },
+                });
+            }
+            settings.update(*directive);
+        }
+
+        if accompanied && settings.drone {
+            let (first, second) = settings.drone_notes;
+            drone.start(
+                &mut events,
+                &mut tracks,
+                0,
+                settings.drone_program,
+                vec![first, second],
+                settings.drone_velocity,
+            );
+        }
+
+        // State in force before each token, so that a bar can be played from anywhere.
+        let mut states = Vec::with_capacity(voice.len());
+        let mut running = state;
+        for token in voice.iter() {
+            states.push(running);
+            running.update(token);
+        }
+
+        let mut time = 0;
+
+        // Only the first voice emits header events, and only when they change.
+        let mut last_tempo = tempo;
+        let mut last_metre = state.metre;
+        let mut last_key = state.key;
+
+        // Decorations and grace notes waiting for their note.
+        let mut decorations = vec![];
+        let mut grace_notes: Option<music::GraceNotes> = None;
+
+        let bars = tune_ast_three::playing_order(&tune_ast_three::sections(voice));
+
+        for (bar_number, bar) in bars.iter().enumerate() {
+            let mut bar_state = states[bar.start];
+            let mut accidentals = music::Accidentals::new(bar_state.key);
+
+            let bar_length: u32 = voice[bar.start..bar.end]
+                .iter()
+                .map(|token| match token {
+                    &l::T::Note(music::Note(_, duration)) => ticks(duration),
+                    _ => 0,
+                })
+                .sum();
+
+            // A short first bar is an anacrusis, so its notes are at the end of the bar.
+            let full_bar = ticks(bar_state.metre.bar());
+            let offset = if bar_number == 0 && bar_length < full_bar {
+                full_bar - bar_length
+            } else {
+                0
+            };
+
+            // Played time of a written position in the bar.
+            let bar_start = time;
+            let at = |position: u32| bar_start + warp(position) - warp(offset);
+
+            let mut position = offset;
+
+            for token in voice[bar.start..bar.end].iter() {
+                bar_state.update(token);
+                let now = at(position);
+
+                match token {
+                    &l::T::KeySignature(_, _) => {
+                        accidentals.set_key(bar_state.key);
+
+                        if voice_number == 0 && bar_state.key != last_key {
+                            last_key = bar_state.key;
+                            events.push(TimedEvent {
+                                time: now,
+                                event: Event::Key(last_key),
+                            });
+                        }
+                    }
+
+                    &l::T::Metre(metre) => {
+                        if voice_number == 0 && metre != last_metre {
+                            last_metre = metre;
+                            events.push(TimedEvent {
+                                time: now,
+                                event: Event::Metre(metre),
+                            });
+                        }
+                    }
+
+                    &l::T::Tempo(ref value) => {
+                        if let Some(event) = tempo_event(value, &bar_state) {
+                            if voice_number == 0 && event != last_tempo {
+                                last_tempo = event;
+                                events.push(TimedEvent { time: now, event });
+                            }
+                        }
+                    }
+
+                    &l::T::Directive(ref value) if options.directives => {
+                        if let Some(directive) = playback::Directive::parse(value) {
+                            if let playback::Directive::Program(program) = directive {
+                                events.push(TimedEvent {
+                                    time: now,
+                                    event: Event::Program {
+                                        track: voice_number,
+                                        channel,
+                                        program,
+                                    },
+                                });
+                            }
+
+                            let before = settings;
+                            settings.update(directive);
+
+                            if accompanied && before.chords && !settings.chords {
+                                chords.stop(&mut events, now);
+                            }
+
+                            if accompanied && !before.drone && settings.drone {
+                                let (first, second) = settings.drone_notes;
+                                drone.start(
+                                    &mut events,
+                                    &mut tracks,
+                                    now,
+                                    settings.drone_program,
+                                    vec![first, second],
+                                    settings.drone_velocity,
+                                );
+                            } else if accompanied && before.drone && !settings.drone {
+                                drone.stop(&mut events, now);
+                            }
+                        }
+                    }
+
+                    &l::T::GuitarChord(ref value) if accompanied && settings.chords => {
+                        // Annotations aren't chords.
+                        if let Some(chord) = music::ChordSymbol::parse(value) {
+                            let notes = playback::chord_notes(&chord)
+                                .iter()
+                                .filter_map(|&n| transpose_midi_number(n, settings.transpose))
+                                .collect();
+
+                            chords.start(
+                                &mut events,
+                                &mut tracks,
+                                now,
+                                settings.chord_program,
+                                notes,
+                                playback::CHORD_VELOCITY,
+                            );
+                        }
+                    }
+
+                    &l::T::Decoration(decoration) => decorations.push(decoration),
+
+                    &l::T::GraceNotes(ref value) => grace_notes = Some(value.clone()),
+
+                    &l::T::Note(music::Note(pitch, duration)) => {
+                        let pitch = accidentals.resolve(pitch);
+                        let duration = ticks(duration);
+                        let played_duration = at(position + duration) - now;
+
+                        let velocity = if options.accents {
+                            playback::accent(position, bar_state.metre)
+                        } else {
+                            DEFAULT_VELOCITY
+                        };
+
+                        let sounds = if options.ornaments {
+                            // Accidentals on grace notes don't carry on to the rest of the bar.
+                            let (grace_pitches, acciaccatura) = match grace_notes {
+                                Some(ref value) => (
+                                    value
+                                        .notes
+                                        .iter()
+                                        .map(|note| accidentals.clone().resolve(note.0))
+                                        .collect::<Vec<music::Pitch>>(),
+                                    value.acciaccatura,
+                                ),
+                                None => (vec![], false),
+                            };
+
+                            playback::realise(
+                                pitch,
+                                played_duration,
+                                velocity,
+                                &decorations,
+                                &grace_pitches,
+                                acciaccatura,
+                                bar_state.key,
+                            )
+                        } else {
+                            vec![playback::Sound {
+                                pitch,
+                                start: 0,
+                                duration: played_duration,
+                                velocity,
+                            }]
+                        };
+
+                        for sound in sounds {
+                            let midi_number = sound.pitch.midi_number().and_then(|number| {
+                                transpose_midi_number(number, settings.transpose)
+                            });
+
+                            if let Some(midi_number) = midi_number {
+                                if sound.duration > 0 {
+                                    events.push(TimedEvent {
+                                        time: now + sound.start,
+                                        event: Event::Note {
+                                            track: voice_number,
+                                            channel,
+                                            midi_number,
+                                            velocity: sound.velocity,
+                                            duration: sound.duration,
+                                        },
+                                    });
+                                }
+                            }
+                        }
+
+                        decorations.clear();
+                        grace_notes = None;
+                        position += duration;
+                    }
+
+                    _ => (),
+                }
+            }
+
+            time = at(position);
+        }
+
+        if accompanied {
+            chords.stop(&mut events, time);
+            drone.stop(&mut events, time);
+        }
+
+        length = u32::max(length, time);
+    }
+
+    // Stable, so simultaneous events keep their order.
+    events.sort_by_key(|event| event.time);
+
+    Timeline {
+        events,
+        tracks,
+        length,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_support::read;
+
+    fn timeline_straight(tune: &tune_ast_three::Tune) -> Timeline {
+        timeline(tune, &playback::Options::straight())
+    }
+
+    fn notes(timeline: &Timeline) -> Vec<(u32, u8, u32)> {
+        timeline
+            .events
+            .iter()
+            .filter_map(|event| match event.event {
+                Event::Note { midi_number, duration, .. } => {
+                    Some((event.time, midi_number, duration))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn timeline_test() {
+        let timeline = timeline_straight(&read("L:1/8\nQ:3/8=60\nK:G\n|:F^F|F2:|\n"));
+
+        assert_eq!(
+            timeline.events[0].event,
+            Event::Tempo { microseconds_per_crotchet: 666_666 },
+            "Dotted crotchet beat at 60 is a crotchet at 90."
+        );
+
+        // F is sharp in the key, and the repeat is played twice.
+        assert_eq!(
+            notes(&timeline),
+            vec![
+                (0, 66, 240),
+                (240, 66, 240),
+                (480, 66, 480),
+                (960, 66, 240),
+                (1200, 66, 240),
+                (1440, 66, 480),
+            ]
+        );
+
+        assert_eq!(timeline.length, 1920);
+    }
+
+    #[test]
+    fn timeline_zero_denominator_test() {
+        // Malformed lengths take no time, rather than dividing by zero, so the note isn't played.
+        assert_eq!(ticks(music::FractionalDuration(1, 0)), 0);
+
+        let straight = timeline_straight(&read("M:3/0\nL:1/8\nQ:1/0=120\nK:G\nA/0B|c\n"));
+        assert_eq!(notes(&straight), vec![(0, 71, 240), (240, 72, 240)]);
+
+        let expressive = timeline(
+            &read("M:3/0\nL:1/8\n%%MIDI gchordon\nK:G\n\"G\"A/0B|c\n"),
+            &playback::Options::expressive(),
+        );
+        assert_eq!(expressive.tracks, 2);
+    }
+
+    #[test]
+    fn timeline_accidentals_reset_each_bar_test() {
+        let timeline = timeline_straight(&read("K:C\n^FF|F\n"));
+
+        assert_eq!(
+            notes(&timeline).iter().map(|n| n.1).collect::<Vec<u8>>(),
+            vec![66, 66, 65]
+        );
+    }
+
+    #[test]
+    fn timeline_expressive_test() {
+        let tune = read(
+            "R:jig\nM:6/8\nL:1/8\n%%MIDI program 73\n%%MIDI transpose 12\n%%MIDI gchordon\nK:G\n\
+             \"G\"GAB ~c3|\n",
+        );
+
+        let straight = timeline_straight(&tune);
+        assert_eq!(straight.tracks, 1);
+        assert_eq!(
+            notes(&straight),
+            vec![(0, 67, 240), (240, 69, 240), (480, 71, 240), (720, 72, 720)]
+        );
+
+        let expressive = timeline(&tune, &playback::Options::expressive());
+
+        // Guitar chords get a track of their own.
+        assert_eq!(expressive.tracks, 2);
+        assert_eq!(expressive.length, 1440);
+
+        assert!(expressive.events.contains(&TimedEvent {
+            time: 0,
+            event: Event::Program {
+                track: 0,
+                channel: 0,
+                program: 73,
+            },
+        }));
+
+        let melody = expressive
+            .events
+            .iter()
+            .filter_map(|event| match event.event {
+                Event::Note {
+                    track: 0,
+                    midi_number,
+                    velocity,
+                    duration,
+                    ..
+                } => Some((event.time, midi_number, duration, velocity)),
+                _ => None,
+            })
+            .collect::<Vec<(u32, u8, u32, u8)>>();
+
+        // Transposed up an octave, with a lilt, accents and the roll played.
+        assert_eq!(
+            melody,
+            vec![
+                (0, 79, 288, 100),
+                (288, 81, 194, 70),
+                (482, 83, 238, 70),
+                (720, 84, 240, 84),
+                (960, 86, 40, 84),
+                (1000, 84, 200, 84),
+                (1200, 83, 40, 84),
+                (1240, 84, 200, 84),
+            ]
+        );
+
+        // The chord lasts until the end.
+        let chord = expressive
+            .events
+            .iter()
+            .filter_map(|event| match event.event {
+                Event::Note {
+                    track: 1,
+                    channel,
+                    duration,
+                    ..
+                } => Some((event.time, channel, duration)),
+                _ => None,
+            })
+            .collect::<Vec<(u32, u8, u32)>>();
+
+        assert_eq!(chord.len(), 3);
+        assert!(chord.iter().all(|&note| note == (0, CHORD_CHANNEL, 1440)));
+    }
+}
@@ -0,0 +1,395 @@
+//! Transpose
+//! Move whole tunes by an interval or to a new key.
+//! Notes are transposed by their sounding pitch, taking into account the key signature and
+//! accidentals earlier in the bar, then spelled relative to the new key.
+
+use abc_lexer as l;
+use music;
+use tune_ast_three;
+
+/// Furthest a tune can be transposed, in semitones either way: the range of MIDI notes.
+pub const MAX_SEMITONES: i16 = 127;
+
+/// Where to transpose a tune to.
+#[derive(Debug, PartialEq, PartialOrd, Clone, Copy)]
+pub enum Target {
+    /// A number of semitones, positive for up.
+    Semitones(i16),
+
+    /// A named interval, e.g. a major third up.
+    Interval(music::Interval),
+
+    /// A new tonic. The tune moves by the smallest interval that gets there.
+    Tonic(music::PitchClass),
+}
+
+impl Target {
+    /// Parse a target from a command line argument, e.g. "-2", "+5", "M3", "-P5", "Bb" or "f#".
+    /// Semitones and intervals can go up to `MAX_SEMITONES` either way.
+    pub fn parse(input: &str) -> Result<Target, String> {
+        let out_of_range = || {
+            format!("Can't transpose by {}, as it's more than {} semitones.", input, MAX_SEMITONES)
+        };
+
+        if let Ok(semitones) = input.trim_start_matches('+').parse::<i64>() {
+            return if semitones.abs() <= MAX_SEMITONES as i64 {
+                Ok(Target::Semitones(semitones as i16))
+            } else {
+                Err(out_of_range())
+            };
+        }
+
+        if let Some(interval) = music::Interval::parse(input) {
+            return if interval.semitones().abs() <= MAX_SEMITONES {
+                Ok(Target::Interval(interval))
+            } else {
+                Err(out_of_range())
+            };
+        }
+
+        let unrecognised = || {
+            format!(
+                "Didn't recognise {}. Give a number of semitones (e.g. -2), an interval (e.g. -P5) \
+                 or a key (e.g. Bb).",
+                input
+            )
+        };
+
+        let mut chars = input.chars();
+
+        let diatonic_pitch_class = match chars.next().map(|c| c.to_ascii_uppercase()) {
+            Some('A') => music::DiatonicPitchClass::A,
+            Some('B') => music::DiatonicPitchClass::B,
+            Some('C') => music::DiatonicPitchClass::C,
+            Some('D') => music::DiatonicPitchClass::D,
+            Some('E') => music::DiatonicPitchClass::E,
+            Some('F') => music::DiatonicPitchClass::F,
+            Some('G') => music::DiatonicPitchClass::G,
+            _ => return Err(unrecognised()),
+        };
+
+        let accidental = match chars.as_str() {
+            "" => None,
+            "#" => Some(music::Accidental::Sharp),
+            "b" => Some(music::Accidental::Flat),
+            _ => return Err(unrecognised()),
+        };
+
+        Ok(Target::Tonic(music::PitchClass {
+            diatonic_pitch_class,
+            accidental,
+        }))
+    }
+
+    /// The interval to move a tune in this key to reach the target.
+    pub fn interval(&self, key: music::KeySignature) -> music::Interval {
+        match self {
+            &Target::Semitones(semitones) => interval_for_semitones(key, semitones),
+            &Target::Interval(interval) => interval,
+            &Target::Tonic(tonic) => {
+                // Go whichever way is nearest, preferring up for a tritone.
+                let semitones = (tonic.semitones() - key.tonic.semitones() + 5).rem_euclid(12) - 5;
+                music::Interval::between(key.tonic, tonic, semitones)
+            }
+        }
+    }
+}
+
+/// Spell a transposition by a number of semitones so that the new key has as few sharps or flats
+/// as possible, e.g. up 1 from G is to Ab, not G#.
+pub fn interval_for_semitones(key: music::KeySignature, semitones: i16) -> music::Interval {
+    let new_key = music::KeySignature::from_semitones(key.tonic.semitones() + semitones, key.mode);
+
+    music::Interval::between(key.tonic, new_key.tonic, semitones)
+}
+
+/// Transpose a guitar chord, e.g. "D/F#" up a tone to "E/G#".
+/// Text that isn't a recognisable chord (e.g. an annotation) is returned unchanged.
+pub fn transpose_chord(chord: &str, interval: music::Interval) -> String {
+    match music::ChordSymbol::parse(chord) {
+        Some(symbol) => symbol.transpose(interval).to_string(),
+        None => chord.to_string(),
+    }
+}
+
+/// Transposes a stream of tokens, tracking keys and accidentals.
+struct Transposer {
+    interval: music::Interval,
+
+    /// Accidentals in the original.
+    from: music::Accidentals,
+
+    /// Accidentals in the transposed version.
+    to: music::Accidentals,
+}
+
+impl Transposer {
+    fn new(key: music::KeySignature, interval: music::Interval) -> Transposer {
+        Transposer {
+            interval,
+            from: music::Accidentals::new(key),
+            to: music::Accidentals::new(key.transpose(interval)),
+        }
+    }
+
+    fn set_key(&mut self, key: music::KeySignature) {
+        self.from.set_key(key);
+        self.to.set_key(key.transpose(self.interval));
+    }
+
+    fn transpose_note(&mut self, note: &music::Note) -> music::Note {
+        let &music::Note(pitch, duration) = note;
+
+        let mut transposed = self.from.resolve(pitch).transpose(self.interval);
+
+        // Double sharps and flats only make sense if the key has them.
+        let key_accidental = self.to.key().accidental(
+            transposed.pitch_class.diatonic_pitch_class,
+        );
+        if transposed.pitch_class.accidental != key_accidental {
+            transposed = transposed.simplify();
+        }
+
+        music::Note(self.to.notate(transposed), duration)
+    }
+
+    fn transpose_token(&mut self, token: &l::T) -> l::T {
+        match token {
+            &l::T::KeySignature(tonic, mode) => {
+                self.set_key(music::KeySignature { tonic, mode });
+                l::T::KeySignature(self.to.key().tonic, self.to.key().mode)
+            }
+
+            &l::T::Note(ref note) => l::T::Note(self.transpose_note(note)),
+
+            &l::T::GraceNotes(ref grace_notes) => {
+                l::T::GraceNotes(music::GraceNotes {
+                    acciaccatura: grace_notes.acciaccatura,
+                    notes: grace_notes
+                        .notes
+                        .iter()
+                        .map(|note| self.transpose_note(note))
+                        .collect(),
+                })
+            }
+
+            &l::T::GuitarChord(ref chord) => {
+                l::T::GuitarChord(transpose_chord(chord, self.interval))
+            }
+
+            // Accidentals last until the end of the bar.
+            &l::T::SingleBar |
+            &l::T::DoubleBar |
+            &l::T::OpenRepeat |
+            &l::T::CloseRepeat |
+            &l::T::EndBar |
+            &l::T::NTimeBar(_) => {
+                self.from.end_bar();
+                self.to.end_bar();
+                token.clone()
+            }
+
+            _ => token.clone(),
+        }
+    }
+}
+
+/// The key declared in the tune's header, or C major if there isn't one.
+pub fn tune_key(tune: &tune_ast_three::Tune) -> music::KeySignature {
+    tune.prelude
+        .iter()
+        .filter_map(|token| match token {
+            &l::T::KeySignature(tonic, mode) => Some(music::KeySignature { tonic, mode }),
+            _ => None,
+        })
+        .next()
+        .unwrap_or(music::KeySignature {
+            tonic: music::PitchClass {
+                diatonic_pitch_class: music::DiatonicPitchClass::C,
+                accidental: None,
+            },
+            mode: music::Mode::Major,
+        })
+}
+
+/// Transpose a whole tune by an interval.
+pub fn transpose_tune(tune: &tune_ast_three::Tune, interval: music::Interval) -> tune_ast_three::Tune {
+    let key = tune_key(tune);
+
+    // If the new key had to be respelled, spell all the notes consistently with it.
+    let new_key = key.transpose(interval);
+    let interval = music::Interval::between(key.tonic, new_key.tonic, interval.semitones());
+
+    let mut transposer = Transposer::new(key, interval);
+
+    let prelude = tune.prelude
+        .iter()
+        .map(|token| transposer.transpose_token(token))
+        .collect();
+
+    // Each voice starts from the prelude's key.
+    let prelude_transposer = transposer;
+
+    let voices = tune.voices
+        .iter()
+        .map(|voice| {
+            let mut transposer = Transposer::new(prelude_transposer.from.key(), interval);
+            voice
+                .iter()
+                .map(|token| transposer.transpose_token(token))
+                .collect()
+        })
+        .collect();
+
+    tune_ast_three::Tune {
+        prelude,
+        voices,
+        prelude_spans: tune.prelude_spans.clone(),
+        voice_spans: tune.voice_spans.clone(),
+    }
+}
+
+/// Transpose a whole tune to a target.
+pub fn transpose_to(tune: &tune_ast_three::Tune, target: Target) -> tune_ast_three::Tune {
+    transpose_tune(tune, target.interval(tune_key(tune)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use abc_writer;
+    use test_support::read;
+
+    fn transpose_abc(abc: &str, target: Target) -> String {
+        abc_writer::write_tune(&transpose_to(&read(abc), target))
+    }
+
+    fn pitch_class(
+        diatonic_pitch_class: music::DiatonicPitchClass,
+        accidental: Option<music::Accidental>,
+    ) -> music::PitchClass {
+        music::PitchClass {
+            diatonic_pitch_class,
+            accidental,
+        }
+    }
+
+    #[test]
+    fn target_parse_test() {
+        assert_eq!(Target::parse("2"), Ok(Target::Semitones(2)));
+        assert_eq!(Target::parse("+5"), Ok(Target::Semitones(5)));
+        assert_eq!(Target::parse("-3"), Ok(Target::Semitones(-3)));
+        assert_eq!(
+            Target::parse("Bb"),
+            Ok(Target::Tonic(pitch_class(
+                music::DiatonicPitchClass::B,
+                Some(music::Accidental::Flat),
+            )))
+        );
+        assert_eq!(
+            Target::parse("f#"),
+            Ok(Target::Tonic(pitch_class(
+                music::DiatonicPitchClass::F,
+                Some(music::Accidental::Sharp),
+            )))
+        );
+        assert_eq!(
+            Target::parse("M3"),
+            Ok(Target::Interval(music::Interval::parse("M3").unwrap()))
+        );
+        assert_eq!(
+            Target::parse("-P5"),
+            Ok(Target::Interval(music::Interval::parse("-P5").unwrap()))
+        );
+        assert_eq!(
+            Target::parse("d"),
+            Ok(Target::Tonic(pitch_class(music::DiatonicPitchClass::D, None)))
+        );
+        assert!(Target::parse("H").is_err());
+        assert!(Target::parse("Cx").is_err());
+
+        // Nothing further than MIDI can go, and no overflow on the way.
+        assert_eq!(Target::parse("-127"), Ok(Target::Semitones(-127)));
+        assert!(Target::parse("128").is_err());
+        assert!(Target::parse("3000").is_err());
+        assert!(Target::parse("32767").is_err());
+        assert!(Target::parse("99999999999999999999").is_err());
+        assert!(Target::parse("A20000").is_err());
+        assert!(Target::parse("P71").is_ok());
+    }
+
+    #[test]
+    fn interval_test() {
+        let g_major = music::KeySignature {
+            tonic: pitch_class(music::DiatonicPitchClass::G, None),
+            mode: music::Mode::Major,
+        };
+
+        // G up a semitone is Ab, with fewer accidentals than G#.
+        assert_eq!(
+            g_major.transpose(interval_for_semitones(g_major, 1)).tonic,
+            pitch_class(music::DiatonicPitchClass::A, Some(music::Accidental::Flat))
+        );
+
+        // G to D is nearest by going down a fourth.
+        let interval = Target::Tonic(pitch_class(music::DiatonicPitchClass::D, None))
+            .interval(g_major);
+        assert_eq!(interval.semitones(), -5);
+        assert_eq!(interval.pitch_classes, -3);
+    }
+
+    #[test]
+    fn transpose_chord_test() {
+        let up_tone = music::Interval {
+            pitch_classes: 1,
+            accidental_semitones: 0,
+        };
+
+        assert_eq!(transpose_chord("D/F#", up_tone), "E/G#");
+        assert_eq!(transpose_chord("Am7", up_tone), "Bm7");
+        assert_eq!(transpose_chord("Bbmaj7", up_tone), "Cmaj7");
+        assert_eq!(transpose_chord("Gsus4", up_tone), "Asus4");
+        assert_eq!(transpose_chord("^Fine", up_tone), "^Fine", "Annotations unchanged.");
+
+        assert_eq!(transpose_chord("F#m", up_tone), "G#m");
+        assert_eq!(transpose_chord("E#", up_tone), "G", "Double sharps respelled.");
+    }
+
+    #[test]
+    fn transpose_tune_test() {
+        // F is sharp in G, so becomes G# in A, which is in the key signature.
+        // Accidentals are only written once per bar.
+        assert_eq!(
+            transpose_abc(
+                "M:6/8\nL:1/8\nK:G\n\"G\"GAB \"D\"dcA|F2G ^G=F=F|\n",
+                Target::Tonic(pitch_class(music::DiatonicPitchClass::A, None)),
+            ),
+            "M:6/8\nL:1/8\nK:A\n\"A\"ABc \"E\"edB|G2A ^A=GG|\n"
+        );
+
+        // Down a tone into a flat key. The natural E becomes a natural D, and F becomes Eb.
+        assert_eq!(
+            transpose_abc(
+                "L:1/8\nK:Gmin\nGABc d=ef2|\n",
+                Target::Semitones(-2),
+            ),
+            "L:1/8\nK:Fm\nFGAB c=de2|\n"
+        );
+
+        // Keys beyond six sharps are respelled.
+        assert_eq!(
+            transpose_abc("K:B\nBc^d|\n", Target::Semitones(2)),
+            "K:Db\ndef|\n"
+        );
+
+        // A named interval fixes the spelling of the new key.
+        assert_eq!(
+            transpose_abc("K:D\nDEF|\n", Target::Interval(music::Interval::parse("M3").unwrap())),
+            "K:F#\nFGA|\n"
+        );
+        assert_eq!(
+            transpose_abc("K:D\nDEF|\n", Target::Interval(music::Interval::parse("d4").unwrap())),
+            "K:Gb\nGAB|\n"
+        );
+    }
+}
@@ -3,12 +3,22 @@
 use abc_lexer as l;
 use music;
 
-#[derive(Debug)]
+/// Default note length, when there's no "L:" field.
+pub const DEFAULT_NOTE_LENGTH: music::FractionalDuration = music::FractionalDuration(1, 4);
+
+#[derive(Debug, Clone)]
 pub struct Tune {
     /// All the entities that fall outside of the tune structure, i.e. occur in the tune header.
     pub prelude: Vec<l::T>,
 
     pub voices: Vec<Vec<l::T>>,
+
+    /// Span of input for each prelude token, in parallel with `prelude`.
+    /// May be empty if the tune wasn't read from input.
+    pub prelude_spans: Vec<l::Span>,
+
+    /// Span of input for each voice token, in parallel with `voices`.
+    pub voice_spans: Vec<Vec<l::Span>>,
 }
 
 // TODO SHOULD BE ENTITY?
@@ -19,6 +29,8 @@ impl Tune {
         Tune {
             prelude: vec![],
             voices: vec![],
+            prelude_spans: vec![],
+            voice_spans: vec![],
         }
     }
 }
@@ -32,11 +44,14 @@ pub fn read_from_lexer(lexer: l::Lexer) -> Tune {
 
     let mut finished_prelude = false;
     let mut current_sequence = vec![];
+    let mut current_spans = vec![];
 
     // The base note length. This can change during the tune.
-    let mut note_length = music::FractionalDuration(1, 4);
+    let mut note_length = DEFAULT_NOTE_LENGTH;
+
+    for (token, span) in lexer.collect_tokens_with_spans() {
+        current_spans.push(span);
 
-    for token in lexer.collect_tokens() {
         match token {
             l::T::KeySignature(pitch_class, mode) => {
                 current_sequence.push(l::T::KeySignature(pitch_class, mode));
@@ -44,18 +59,43 @@ pub fn read_from_lexer(lexer: l::Lexer) -> Tune {
                 // K marks the end of the prelude.
                 if !finished_prelude {
                     tune.prelude = current_sequence;
+                    tune.prelude_spans = current_spans;
                     finished_prelude = true;
                     current_sequence = vec![];
+                    current_spans = vec![];
                 }
             }
 
-            // The "L:" token doesn't produce an entity, it just updates the running status.
-            l::T::DefaultNoteLength(new_note_length) => note_length = new_note_length,
+            // A clef read from the same "K:" field as the one that ends the prelude belongs with it.
+            l::T::Clef(clef) if finished_prelude && current_sequence.is_empty() &&
+                                  tune.prelude_spans.last() == Some(&span) => {
+                current_spans.pop();
+                tune.prelude.push(l::T::Clef(clef));
+                tune.prelude_spans.push(span);
+            }
+
+            // The "L:" token updates the running status. It's kept in the sequence so that
+            // consumers (e.g. metadata) know what was declared.
+            l::T::DefaultNoteLength(new_note_length) => {
+                note_length = new_note_length;
+                current_sequence.push(l::T::DefaultNoteLength(new_note_length));
+            }
 
             l::T::Note(note) => {
                 current_sequence.push(l::T::Note(note.resolve_duration(note_length)))
             }
 
+            l::T::GraceNotes(grace_notes) => {
+                current_sequence.push(l::T::GraceNotes(music::GraceNotes {
+                    notes: grace_notes
+                        .notes
+                        .iter()
+                        .map(|note| note.resolve_duration(note_length))
+                        .collect(),
+                    ..grace_notes
+                }))
+            }
+
             token => current_sequence.push(token),
         }
 
@@ -63,12 +103,290 @@ pub fn read_from_lexer(lexer: l::Lexer) -> Tune {
     }
 
     tune.voices.push(current_sequence);
+    tune.voice_spans.push(current_spans);
 
 
     tune
 }
 
+/// A bar, as a range of token indices within a voice.
+#[derive(Debug, PartialEq, PartialOrd, Clone, Copy)]
+pub struct Bar {
+    /// Index of the first token.
+    pub start: usize,
+    /// Index after the last token, excluding trailing beam breaks and newlines.
+    pub end: usize,
+}
+
+impl Bar {
+    /// Span of input covered by the bar, if the spans are available.
+    pub fn span(&self, spans: &[l::Span]) -> Option<l::Span> {
+        let last = self.end.checked_sub(1).and_then(|last| spans.get(last));
+
+        match (spans.get(self.start), last) {
+            (Some(first), Some(last)) => Some(l::Span {
+                start: first.start,
+                end: last.end,
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// A section of a tune, bounded by double bars, end bars or repeats.
+#[derive(Debug, PartialEq, PartialOrd, Clone)]
+pub struct Section {
+    /// Is the section repeated?
+    pub repeat: bool,
+
+    /// Bars played every time.
+    pub main: Vec<Bar>,
+
+    /// Numbered endings, e.g. first and second time bars, in order.
+    pub endings: Vec<(u32, Vec<Bar>)>,
+}
+
+impl Section {
+    pub fn new() -> Section {
+        Section {
+            repeat: false,
+            main: vec![],
+            endings: vec![],
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.main.is_empty() && self.endings.is_empty()
+    }
+}
+
+/// Build the bar and section structure of a voice.
+/// Bars without any notes (e.g. between two adjacent barlines) are skipped.
+pub fn sections(voice: &[l::T]) -> Vec<Section> {
+    let mut sections = vec![];
+    let mut section = Section::new();
+
+    // Extent of the bar in progress and whether it has any notes yet.
+    let mut bar_start = 0;
+    let mut bar_end = 0;
+    let mut bar_has_notes = false;
+
+    // Which ending are we in, if any?
+    let mut ending: Option<u32> = None;
+
+    // We closed a repeat during an ending, so the section ends unless another ending follows.
+    let mut pending_close = false;
+
+    for (i, token) in voice.iter().enumerate() {
+        let is_barline = match token {
+            &l::T::SingleBar |
+            &l::T::DoubleBar |
+            &l::T::EndBar |
+            &l::T::OpenRepeat |
+            &l::T::CloseRepeat |
+            &l::T::NTimeBar(_) => true,
+            _ => false,
+        };
+
+        if !is_barline {
+            match token {
+                &l::T::BeamBreak | &l::T::Newline => (),
+                _ => bar_end = i + 1,
+            }
+
+            if let &l::T::Note(_) = token {
+                if !bar_has_notes && pending_close {
+                    sections.push(section);
+                    section = Section::new();
+                    ending = None;
+                    pending_close = false;
+                }
+
+                bar_has_notes = true;
+            }
+
+            continue;
+        }
+
+        // Any barline finishes the bar in progress.
+        if bar_has_notes {
+            let bar = Bar {
+                start: bar_start,
+                end: bar_end,
+            };
+
+            match section.endings.last_mut() {
+                Some(&mut (_, ref mut bars)) if ending.is_some() => bars.push(bar),
+                _ => section.main.push(bar),
+            }
+        }
+        bar_start = i + 1;
+        bar_has_notes = false;
+
+        match token {
+            &l::T::NTimeBar(n) => {
+                ending = Some(n);
+                pending_close = false;
+                section.endings.push((n, vec![]));
+            }
+
+            &l::T::CloseRepeat => {
+                section.repeat = true;
+
+                if ending.is_some() {
+                    pending_close = true;
+                } else {
+                    sections.push(section);
+                    section = Section::new();
+                }
+            }
+
+            &l::T::OpenRepeat | &l::T::DoubleBar | &l::T::EndBar => {
+                if !section.is_empty() {
+                    sections.push(section);
+                    section = Section::new();
+                }
+                ending = None;
+                pending_close = false;
+            }
+
+            _ => (),
+        }
+    }
+
+    // Anything left over after the last barline.
+    if bar_has_notes {
+        let bar = Bar {
+            start: bar_start,
+            end: bar_end,
+        };
+
+        match section.endings.last_mut() {
+            Some(&mut (_, ref mut bars)) if ending.is_some() => bars.push(bar),
+            _ => section.main.push(bar),
+        }
+    }
+
+    if !section.is_empty() {
+        sections.push(section);
+    }
+
+    sections
+}
+
+/// The bars of a voice in the order they are played, with repeats and endings expanded.
+pub fn playing_order(sections: &[Section]) -> Vec<Bar> {
+    let mut result = vec![];
+
+    for section in sections.iter() {
+        if !section.endings.is_empty() {
+            // The main part is played before each ending.
+            for &(_, ref ending) in section.endings.iter() {
+                result.extend(section.main.iter().cloned());
+                result.extend(ending.iter().cloned());
+            }
+        } else if section.repeat {
+            result.extend(section.main.iter().cloned());
+            result.extend(section.main.iter().cloned());
+        } else {
+            result.extend(section.main.iter().cloned());
+        }
+    }
+
+    result
+}
 
 // Heuristics:
 // 1 - Remove consecutive beam breaks.
 // 2 - Remove unused beam breaks, e.g. first thing in a sequence.
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_support::read;
+
+    #[test]
+    fn spans_parallel_test() {
+        let tune = read("T:Title\nK:G\nAB|c|\n");
+
+        assert_eq!(tune.prelude.len(), tune.prelude_spans.len());
+        assert_eq!(tune.voices[0].len(), tune.voice_spans[0].len());
+        assert_eq!(tune.voice_spans[0][0], l::Span { start: 12, end: 13 });
+    }
+
+    #[test]
+    fn sections_simple_test() {
+        let tune = read("K:G\nAB|cd||ef|]\n");
+        let found = sections(&tune.voices[0]);
+
+        assert_eq!(found.len(), 2, "Double bar splits sections.");
+        assert_eq!(found[0].main.len(), 2);
+        assert!(!found[0].repeat);
+        assert_eq!(found[1].main.len(), 1);
+
+        // The bar spans the notes but not the barline.
+        assert_eq!(
+            found[0].main[0].span(&tune.voice_spans[0]),
+            Some(l::Span { start: 4, end: 6 })
+        );
+
+        // An empty bar at the start has no span.
+        assert_eq!(Bar { start: 0, end: 0 }.span(&tune.voice_spans[0]), None);
+    }
+
+    #[test]
+    fn sections_repeats_test() {
+        let tune = read("K:G\n|:AB|cd:|\n|:ef|1ga:|2bc|]\n");
+        let found = sections(&tune.voices[0]);
+
+        assert_eq!(found.len(), 2);
+
+        assert!(found[0].repeat);
+        assert_eq!(found[0].main.len(), 2);
+        assert_eq!(found[0].endings.len(), 0);
+
+        assert!(found[1].repeat);
+        assert_eq!(found[1].main.len(), 1);
+        assert_eq!(found[1].endings.len(), 2);
+        assert_eq!(found[1].endings[0].0, 1);
+        assert_eq!(found[1].endings[0].1.len(), 1);
+        assert_eq!(found[1].endings[1].0, 2);
+        assert_eq!(found[1].endings[1].1.len(), 1);
+    }
+
+    #[test]
+    fn sections_close_repeat_after_ending_test() {
+        // The second section has no explicit start.
+        let tune = read("K:G\nAB|1cd:|2ef|gg|\n");
+        let found = sections(&tune.voices[0]);
+
+        assert_eq!(found.len(), 1, "A plain bar after an ending continues it.");
+        assert_eq!(found[0].endings[1].1.len(), 2);
+
+        let tune = read("K:G\nAB|1cd:|ef|\n");
+        let found = sections(&tune.voices[0]);
+
+        assert_eq!(found.len(), 2, "A bar after a closed ending starts a new section.");
+        assert_eq!(found[1].main.len(), 1);
+    }
+
+    #[test]
+    fn playing_order_test() {
+        let tune = read("K:G\nAB|:cd|ef|1ga:|2bc|]\n");
+        let voice = &tune.voices[0];
+
+        let first_notes = playing_order(&sections(voice))
+            .iter()
+            .map(|bar| match voice[bar.start] {
+                l::T::Note(music::Note(pitch, _)) => format!("{:?}", pitch.pitch_class.diatonic_pitch_class),
+                _ => "?".to_string(),
+            })
+            .collect::<Vec<String>>()
+            .join(" ");
+
+        assert_eq!(first_notes, "A C E G C E B");
+
+        let tune = read("K:G\n|:AB:|cd|]\n");
+        assert_eq!(playing_order(&sections(&tune.voices[0])).len(), 3);
+    }
+}
@@ -0,0 +1,330 @@
+//! Tune Type
+//! Classify tunes into dance types (reel, jig etc). Uses the "R:" field when there is one,
+//! otherwise infers the type from the metre and note lengths.
+
+use music;
+use text;
+use tune_ast_three;
+use visitor;
+
+/// Normalised type of a tune.
+#[derive(Debug, PartialEq, PartialOrd, Clone, Copy, Eq, Hash)]
+pub enum TuneType {
+    Reel,
+    /// Double jig, the common or garden jig.
+    Jig,
+    SingleJig,
+    SlipJig,
+    Slide,
+    Hornpipe,
+    Strathspey,
+    Polka,
+    Polska,
+    Waltz,
+    Mazurka,
+    Barndance,
+    Schottische,
+    March,
+    Air,
+}
+
+// All tune types, for lookup by name.
+const TUNE_TYPES: &[TuneType] = &[
+    TuneType::Reel,
+    TuneType::Jig,
+    TuneType::SingleJig,
+    TuneType::SlipJig,
+    TuneType::Slide,
+    TuneType::Hornpipe,
+    TuneType::Strathspey,
+    TuneType::Polka,
+    TuneType::Polska,
+    TuneType::Waltz,
+    TuneType::Mazurka,
+    TuneType::Barndance,
+    TuneType::Schottische,
+    TuneType::March,
+    TuneType::Air,
+];
+
+impl TuneType {
+    /// Short canonical name, e.g. for use in URLs.
+    pub fn name(&self) -> &'static str {
+        match self {
+            &TuneType::Reel => "reel",
+            &TuneType::Jig => "jig",
+            &TuneType::SingleJig => "single-jig",
+            &TuneType::SlipJig => "slip-jig",
+            &TuneType::Slide => "slide",
+            &TuneType::Hornpipe => "hornpipe",
+            &TuneType::Strathspey => "strathspey",
+            &TuneType::Polka => "polka",
+            &TuneType::Polska => "polska",
+            &TuneType::Waltz => "waltz",
+            &TuneType::Mazurka => "mazurka",
+            &TuneType::Barndance => "barndance",
+            &TuneType::Schottische => "schottische",
+            &TuneType::March => "march",
+            &TuneType::Air => "air",
+        }
+    }
+
+    /// Look up a tune type by its canonical name.
+    pub fn from_name(name: &str) -> Option<TuneType> {
+        TUNE_TYPES.iter().cloned().find(|t| t.name() == name)
+    }
+
+    /// Classify a free-text rhythm, e.g. "Double Jig" or "polska".
+    /// None if it isn't recognised.
+    pub fn from_rhythm(rhythm: &str) -> Option<TuneType> {
+        let rhythm = rhythm.to_lowercase();
+        let words = rhythm
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|word| !word.is_empty())
+            .collect::<Vec<&str>>();
+
+        let has = |word: &str| words.iter().any(|w| *w == word);
+
+        // Qualified jigs first, as they also contain "jig".
+        if has("slip") && has("jig") || has("slipjig") {
+            Some(TuneType::SlipJig)
+        } else if has("single") && has("jig") {
+            Some(TuneType::SingleJig)
+        } else if has("jig") || has("jigs") {
+            Some(TuneType::Jig)
+        } else if has("reel") || has("reels") {
+            Some(TuneType::Reel)
+        } else if has("hornpipe") || has("hornpipes") {
+            Some(TuneType::Hornpipe)
+        } else if has("strathspey") || has("strathspeys") {
+            Some(TuneType::Strathspey)
+        } else if has("slide") || has("slides") {
+            Some(TuneType::Slide)
+        } else if has("polska") || has("polskas") || has("polsk") {
+            Some(TuneType::Polska)
+        } else if has("polka") || has("polkas") {
+            Some(TuneType::Polka)
+        } else if has("waltz") || has("waltzes") || has("vals") || has("valse") {
+            Some(TuneType::Waltz)
+        } else if has("mazurka") || has("mazurkas") {
+            Some(TuneType::Mazurka)
+        } else if has("barndance") || has("fling") || has("barn") && has("dance") {
+            Some(TuneType::Barndance)
+        } else if has("schottische") || has("schottis") {
+            Some(TuneType::Schottische)
+        } else if has("march") || has("marches") {
+            Some(TuneType::March)
+        } else if has("air") || has("slow") {
+            Some(TuneType::Air)
+        } else {
+            None
+        }
+    }
+}
+
+/// Proportion of notes that are at least this common to count as a pattern.
+const PATTERN_THRESHOLD: f32 = 0.2;
+
+/// Infer a tune type from the metre and its notes' resolved durations.
+/// This is a heuristic and can only give a plausible guess.
+pub fn infer_tune_type(metre: music::Metre, notes: &[music::Note]) -> Option<TuneType> {
+    if notes.is_empty() {
+        return None;
+    }
+
+    let num_notes = notes.len() as f32;
+
+    let count = |duration: music::FractionalDuration| {
+        notes.iter().filter(|note| note.1 == duration).count() as f32
+    };
+
+    // Onset of each note, in whole notes.
+    let mut onsets = Vec::with_capacity(notes.len());
+    let mut onset = 0.0;
+    for note in notes.iter() {
+        onsets.push(onset);
+        onset += (note.1).0 as f32 / (note.1).1 as f32;
+    }
+
+    // Count pairs of notes where one is three times the length of the other, i.e. a dotted
+    // rhythm. Only pairs that start on a multiple of their combined length count, so that the
+    // "short-long" between two dotted pairs isn't mistaken for a snap.
+    let count_pairs = |long_first: bool| {
+        let mut result = 0;
+        for i in 0..notes.len().saturating_sub(1) {
+            let a = notes[i].1;
+            let b = notes[i + 1].1;
+
+            let (long, short) = if long_first { (a, b) } else { (b, a) };
+            let is_dotted = long.reduce() == short.multiply(music::FractionalDuration(3, 1));
+
+            // Saturate rather than overflow on absurd durations, which only skews the guess.
+            let pair_length = a.0.saturating_mul(b.1).saturating_add(b.0.saturating_mul(a.1)) as f32 /
+                a.1.saturating_mul(b.1) as f32;
+            let phase = onsets[i] / pair_length;
+            let on_boundary = (phase - phase.round()).abs() < 0.001;
+
+            if is_dotted && on_boundary {
+                result += 1;
+            }
+        }
+        result as f32
+    };
+
+    match metre {
+        music::Metre(9, 8) => Some(TuneType::SlipJig),
+        music::Metre(12, 8) => Some(TuneType::Slide),
+        music::Metre(6, 8) => {
+            // Single jigs are dominated by crotchet-quaver.
+            if count(music::FractionalDuration(1, 4)) / num_notes > PATTERN_THRESHOLD {
+                Some(TuneType::SingleJig)
+            } else {
+                Some(TuneType::Jig)
+            }
+        }
+        music::Metre(3, 4) => {
+            // Polskas move in semiquavers, waltzes mostly don't.
+            if count(music::FractionalDuration(1, 16)) / num_notes > PATTERN_THRESHOLD {
+                Some(TuneType::Polska)
+            } else {
+                Some(TuneType::Waltz)
+            }
+        }
+        music::Metre(2, 4) => Some(TuneType::Polka),
+        music::Metre(4, 4) | music::Metre(2, 2) => {
+            let dotted = count_pairs(true);
+            let snapped = count_pairs(false);
+
+            // Every pair accounts for two notes.
+            if snapped * 2.0 / num_notes > PATTERN_THRESHOLD {
+                Some(TuneType::Strathspey)
+            } else if (dotted + snapped) * 2.0 / num_notes > PATTERN_THRESHOLD {
+                Some(TuneType::Hornpipe)
+            } else {
+                Some(TuneType::Reel)
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Collects all notes in a tune.
+struct NoteCollector {
+    notes: Vec<music::Note>,
+}
+
+impl visitor::Visitor for NoteCollector {
+    fn visit_note(&mut self, note: &music::Note, _state: &visitor::RunningState) {
+        self.notes.push(note.clone());
+    }
+}
+
+/// Classify a tune, using its "R:" field if recognised, otherwise inferring from its content.
+pub fn classify(tune: &tune_ast_three::Tune) -> Option<TuneType> {
+    let metadata = text::TuneMetadata::from_tune(tune);
+
+    if let Some(tune_type) = metadata.rhythm.as_ref().and_then(|r| TuneType::from_rhythm(r)) {
+        return Some(tune_type);
+    }
+
+    // ABC's default metre is free, so there's nothing to go on without one.
+    if let Some(metre) = metadata.metre {
+        let mut collector = NoteCollector { notes: vec![] };
+        visitor::walk(tune, &mut collector);
+
+        infer_tune_type(metre, &collector.notes)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_support::read;
+
+    fn classify_abc(abc: &str) -> Option<TuneType> {
+        classify(&read(abc))
+    }
+
+    #[test]
+    fn from_rhythm_test() {
+        assert_eq!(TuneType::from_rhythm("double jig"), Some(TuneType::Jig));
+        assert_eq!(TuneType::from_rhythm("Jig"), Some(TuneType::Jig));
+        assert_eq!(TuneType::from_rhythm("slip jig"), Some(TuneType::SlipJig));
+        assert_eq!(TuneType::from_rhythm("Slip-Jig"), Some(TuneType::SlipJig));
+        assert_eq!(
+            TuneType::from_rhythm("single jig"),
+            Some(TuneType::SingleJig)
+        );
+        assert_eq!(TuneType::from_rhythm("reel"), Some(TuneType::Reel));
+        assert_eq!(TuneType::from_rhythm("Polska"), Some(TuneType::Polska));
+        assert_eq!(TuneType::from_rhythm("polka"), Some(TuneType::Polka));
+        assert_eq!(TuneType::from_rhythm("hornpipe"), Some(TuneType::Hornpipe));
+        assert_eq!(TuneType::from_rhythm("Barn Dance"), Some(TuneType::Barndance));
+        assert_eq!(TuneType::from_rhythm("Slow air"), Some(TuneType::Air));
+        assert_eq!(TuneType::from_rhythm("quickstep"), None);
+        assert_eq!(TuneType::from_rhythm(""), None);
+    }
+
+    #[test]
+    fn name_round_trip_test() {
+        for tune_type in TUNE_TYPES.iter() {
+            assert_eq!(TuneType::from_name(tune_type.name()), Some(*tune_type));
+        }
+
+        assert_eq!(TuneType::from_name("nonsense"), None);
+    }
+
+    #[test]
+    fn classify_from_rhythm_field_test() {
+        assert_eq!(
+            classify_abc("R:hornpipe\nM:6/8\nK:G\nABc|\n"),
+            Some(TuneType::Hornpipe),
+            "Rhythm field takes priority over the metre."
+        );
+
+        assert_eq!(
+            classify_abc("R:unheard of\nM:9/8\nK:G\nABc|\n"),
+            Some(TuneType::SlipJig),
+            "Unrecognised rhythm field falls back to inference."
+        );
+    }
+
+    #[test]
+    fn classify_inferred_test() {
+        assert_eq!(
+            classify_abc("M:9/8\nL:1/8\nK:Emin\nB2E G2E F3|\n"),
+            Some(TuneType::SlipJig)
+        );
+
+        assert_eq!(
+            classify_abc("M:6/8\nL:1/8\nK:G\nGAB cBA|GAB d2B|\n"),
+            Some(TuneType::Jig)
+        );
+
+        assert_eq!(
+            classify_abc("M:6/8\nL:1/8\nK:G\nG2A B2c|d2B A2F|\n"),
+            Some(TuneType::SingleJig)
+        );
+
+        assert_eq!(
+            classify_abc("M:4/4\nL:1/8\nK:D\nDFAF DFAF|GBdB GBdB|\n"),
+            Some(TuneType::Reel)
+        );
+
+        assert_eq!(
+            classify_abc("M:4/4\nL:1/16\nK:D\nD3F A3F d3F A3F|\n"),
+            Some(TuneType::Hornpipe)
+        );
+
+        assert_eq!(
+            classify_abc("M:4/4\nL:1/16\nK:D\nDF3 AF3 dF3 AF3|\n"),
+            Some(TuneType::Strathspey)
+        );
+
+        assert_eq!(classify_abc("M:3/4\nL:1/4\nK:G\nGAB|c2B|\n"), Some(TuneType::Waltz));
+
+        assert_eq!(classify_abc("K:G\nGAB|c2B|\n"), None, "No metre, no guess.");
+    }
+}
@@ -2,6 +2,7 @@ use svg;
 use tune_ast_three;
 use abc_lexer as l;
 use music;
+use visitor;
 use std::iter::FromIterator;
 
 /// Desired stave width.
@@ -89,9 +90,8 @@ enum Glyph {
     EndBar,
     OpenRepeat,
     CloseRepeat,
-    /// Note head of (position-on-stave)
-    /// If we're unable to determine the glyph, can be none.
-    NoteHead(i32, Option<music::DurationGlyph>),
+    /// Note head of (position-on-stave, duration, tied-to-next-note-head).
+    NoteHead(i32, music::DurationGlyph, bool),
     Clef(music::Clef),
     BeamBreak,
 }
@@ -102,6 +102,59 @@ fn draw_tail(svg: &mut svg::Drawing, x: f32, y: f32) {
     svg.line_path(x, y, "M0 0 l2 1 l5 3 l2 14 l-2 5".to_string());
 }
 
+/// Draw a clef whose reference line is at y.
+fn draw_clef(svg: &mut svg::Drawing, clef: music::Clef, x: f32, y: f32, stave_y: f32) {
+    let top_line = stave_y + HEAD_HEIGHT;
+    let stave_height = (LINES_IN_STAVE - 1) as f32 * HEAD_HEIGHT;
+
+    match clef.shape {
+        music::ClefShape::Treble => {
+            // A spiral around the G line, rising to a loop above the stave and a tail below it.
+            svg.line_path(
+                x,
+                y,
+                "M10 0 c-5 0 -5 -7 0 -7 c7 0 9 11 0 13 c-12 2 -15 -14 -4 -24 c9 -8 14 -22 8 -36 \
+                 c-4 -8 -10 0 -9 10 l5 62 c1 8 -8 10 -10 4"
+                    .to_string(),
+            );
+            svg.circle(x + 2.0, y + 20.0, 3.0, true);
+
+            if clef.octave_shift() < 0 {
+                svg.text(x + 6.0, y + 4.0 * HEAD_HEIGHT, "8".to_string());
+            }
+        }
+
+        music::ClefShape::Bass => {
+            // A curl starting on the F line, with a dot either side of the line.
+            svg.circle(x + 4.0, y, 3.0, true);
+            svg.line_path(
+                x,
+                y,
+                "M1 0 c0 -10 20 -14 20 0 c0 14 -10 26 -20 30".to_string(),
+            );
+            svg.circle(x + 27.0, y - HALF_HEAD_HEIGHT, 2.0, true);
+            svg.circle(x + 27.0, y + HALF_HEAD_HEIGHT, 2.0, true);
+        }
+
+        music::ClefShape::C => {
+            // Two bars the height of the stave, with two curves meeting at the C line.
+            svg.rect_fill(x, top_line, 3.0, stave_height);
+            svg.rect(x + 7.0, top_line, 1.0, stave_height);
+            svg.line_path(
+                x + 10.0,
+                y,
+                "M0 0 l4 -4 c14 -2 14 -22 0 -16 M0 0 l4 4 c14 2 14 22 0 16".to_string(),
+            );
+        }
+
+        music::ClefShape::Percussion => {
+            // Two thick bars across the middle of the stave.
+            svg.rect_fill(x, top_line + 2.0 * HEAD_HEIGHT, 3.0, 4.0 * HEAD_HEIGHT);
+            svg.rect_fill(x + 8.0, top_line + 2.0 * HEAD_HEIGHT, 3.0, 4.0 * HEAD_HEIGHT);
+        }
+    }
+}
+
 /// Entity
 #[derive(Debug, PartialEq, PartialOrd, Clone, Copy)]
 struct Entity {
@@ -131,7 +184,7 @@ impl Entity {
 
             // Notehead and friends are definitely out.
             // TODO no catch-all until all glyph types initially settled.
-            Glyph::NoteHead(_, _) => false,
+            Glyph::NoteHead(_, _, _) => false,
 
             Glyph::BeamBreak => false,
         }
@@ -148,15 +201,11 @@ impl Entity {
 
     fn width(&self) -> f32 {
         match self.glyph {
-            // TODO number of dots will make a difference.
-            Glyph::NoteHead(_, glyph) => {
+            Glyph::NoteHead(_, glyph, _) => {
                 // Space for the head.
                 HEAD_WIDTH * 2.0 +
                     // Space for the dots.
-                    match glyph {
-                        Some(music::DurationGlyph { shape, dots }) => HEAD_WIDTH * dots as f32,
-                        _ => 0.0,
-                    }
+                    HEAD_WIDTH * glyph.dots as f32
             }
 
             // TODO add padding, but in a way that is flush with the end of the line.
@@ -178,18 +227,11 @@ impl Entity {
     /// TODO currently assumes only up.
     fn tail_anchor(&self) -> Option<(f32, f32)> {
         match self.glyph {
-            Glyph::NoteHead(position, duration) => {
-                match duration {
+            Glyph::NoteHead(position, _, _) => {
+                let y = (LINES_IN_STAVE - position) as f32 * HEAD_HEIGHT;
 
-                    Some(music::DurationGlyph { shape, dots }) => {
-                        let y = (LINES_IN_STAVE - position) as f32 * HEAD_HEIGHT;
-
-                        // TODO switch on direction.
-                        Some((self.x + HEAD_WIDTH, y - STEM_HEIGHT))
-                    }
-
-                    None => None,
-                }
+                // TODO switch on direction.
+                Some((self.x + HEAD_WIDTH, y - STEM_HEIGHT))
             }
 
             _ => None,
@@ -206,8 +248,7 @@ impl Entity {
             Glyph::Clef(clef) => {
                 let yy = y + (LINES_IN_STAVE - clef.centre) as f32 * HEAD_HEIGHT;
 
-                svg.rect(x, yy - HEAD_HEIGHT / 2.0, 10.0, HEAD_HEIGHT);
-                svg.text(x, yy - HEAD_HEIGHT / 2.0, "clef".to_string());
+                draw_clef(svg, clef, x, yy, y);
             }
             Glyph::SingleBar => {
                 svg.rect(
@@ -295,115 +336,82 @@ impl Entity {
                 );
             }
 
-            Glyph::NoteHead(position, glyph) => {
-                let yy = (y + (LINES_IN_STAVE - position) as f32 * HEAD_HEIGHT);
+            Glyph::NoteHead(position, music::DurationGlyph { shape, dots }, _) => {
+                let yy = y + (LINES_IN_STAVE - position) as f32 * HEAD_HEIGHT;
+
+                // Note head
+                match shape {
+                    music::DurationClass::Breve => {
+                        svg.circle(
+                            x + HEAD_WIDTH / 2.0,
+                            yy + HEAD_WIDTH / 2.0,
+                            HEAD_WIDTH / 2.0,
+                            false,
+                        );
+
+                        // Bars either side.
+                        for bar_x in [x - 2.0, x + HEAD_WIDTH + 2.0].iter() {
+                            svg.line(*bar_x, yy, *bar_x, yy + HEAD_WIDTH);
+                        }
+                    }
 
-                match glyph {
-                    None => {
-                        svg.text(x, yy, "?".to_string());
+                    music::DurationClass::Semibreve |
+                    music::DurationClass::Minim => {
+                        svg.circle(
+                            x + HEAD_WIDTH / 2.0,
+                            yy + HEAD_WIDTH / 2.0,
+                            HEAD_WIDTH / 2.0,
+                            false,
+                        );
                     }
-                    Some(music::DurationGlyph { shape, dots }) => {
-
-                        // Note head
-                        match shape {
-                            music::DurationClass::Semibreve |
-                            music::DurationClass::Minim => {
-                                svg.circle(
-                                    x + HEAD_WIDTH / 2.0,
-                                    yy + HEAD_WIDTH / 2.0,
-                                    HEAD_WIDTH / 2.0,
-                                    false,
-                                );
-                            }
-
-                            music::DurationClass::Crotchet |
-                            music::DurationClass::Quaver |
-                            music::DurationClass::Semiquaver |
-                            music::DurationClass::Demisemiquaver => {
-                                svg.circle(
-                                    x + HEAD_WIDTH / 2.0,
-                                    yy + HEAD_WIDTH / 2.0,
-                                    HEAD_WIDTH / 2.0,
-                                    true,
-                                );
-                            }
-                        }
 
-                        if let Some((stem_x, stem_y)) = self.tail_anchor() {
-
-                            // Stem
-                            match shape {
-                                music::DurationClass::Minim |
-                                music::DurationClass::Crotchet |
-                                music::DurationClass::Quaver |
-                                music::DurationClass::Semiquaver |
-                                music::DurationClass::Demisemiquaver => {
-                                    svg.line(stem_x, stem_y + y, stem_x, yy);
-                                }
-
-                                _ => (),
-                            }
-
-
-
-
-
-                            // Tail 1
-                            match shape {
-                                music::DurationClass::Quaver |
-                                music::DurationClass::Semiquaver |
-                                music::DurationClass::Demisemiquaver => {
-                                    draw_tail(svg, x + HEAD_WIDTH, stem_y + y + HALF_HEAD_HEIGHT);
-                                }
-
-                                _ => (),
-                            }
-
-                            // Tail 2
-                            match shape {
-                                music::DurationClass::Semiquaver |
-                                music::DurationClass::Demisemiquaver => {
-                                    draw_tail(
-                                        svg,
-                                        x + HEAD_WIDTH,
-                                        stem_y + y + HALF_HEAD_HEIGHT + 8.0,
-                                    );
-                                }
-
-                                _ => (),
-                            }
-
-                            // Tail 3
-                            match shape {
-                                music::DurationClass::Demisemiquaver => {
-                                    draw_tail(
-                                        svg,
-                                        x + HEAD_WIDTH,
-                                        stem_y + y + HALF_HEAD_HEIGHT + 16.0,
-                                    );
-                                }
-
-                                _ => (),
-                            }
-                        }
+                    music::DurationClass::Crotchet |
+                    music::DurationClass::Quaver |
+                    music::DurationClass::Semiquaver |
+                    music::DurationClass::Demisemiquaver |
+                    music::DurationClass::Hemidemisemiquaver => {
+                        svg.circle(
+                            x + HEAD_WIDTH / 2.0,
+                            yy + HEAD_WIDTH / 2.0,
+                            HEAD_WIDTH / 2.0,
+                            true,
+                        );
+                    }
+                }
 
-                        for dot in 0..dots {
-                            svg.circle(
-                                x + HEAD_WIDTH + (dot + 2) as f32 * HEAD_HEIGHT * 0.5,
-                                yy - HEAD_HEIGHT / 2.0,
-                                2.0,
-                                true,
-                            );
+                if let Some((stem_x, stem_y)) = self.tail_anchor() {
 
-                        }
+                    // Stem
+                    match shape {
+                        music::DurationClass::Breve |
+                        music::DurationClass::Semibreve => (),
+
+                        _ => svg.line(stem_x, stem_y + y, stem_x, yy),
                     }
 
-                    // svg.rect_fill(x, yy - HEAD_HEIGHT / 2.0, HEAD_HEIGHT * 1.5, HEAD_HEIGHT);
+                    // One tail per beam, each below the last.
+                    for tail in 0..shape.beams() {
+                        draw_tail(
+                            svg,
+                            x + HEAD_WIDTH,
+                            stem_y + y + HALF_HEAD_HEIGHT + tail as f32 * 8.0,
+                        );
+                    }
+                }
+
+                for dot in 0..dots {
+                    svg.circle(
+                        x + HEAD_WIDTH + (dot + 2) as f32 * HEAD_HEIGHT * 0.5,
+                        yy - HEAD_HEIGHT / 2.0,
+                        2.0,
+                        true,
+                    );
+
                 }
             }
 
             // As a glyph this doesn't render.
-            BeamBreak => (),
+            Glyph::BeamBreak => (),
         }
     }
 }
@@ -519,6 +527,27 @@ impl Stave {
             }
         }
 
+        // Draw ties as a curve under each tied note head to the next.
+        for i in 0..entities.len() {
+            if let Glyph::NoteHead(position, _, true) = entities[i].glyph {
+                let next = entities[i + 1..].iter().find(|entity| match entity.glyph {
+                    Glyph::NoteHead(_, _, _) => true,
+                    _ => false,
+                });
+
+                if let Some(next) = next {
+                    let yy = y + (LINES_IN_STAVE - position) as f32 * HEAD_HEIGHT + HEAD_WIDTH;
+                    let width = next.x - entities[i].x;
+
+                    svg.line_path(
+                        entities[i].x + HEAD_WIDTH / 2.0,
+                        yy + 2.0,
+                        format!("M0 0 q{} {} {} 0", width / 2.0, HEAD_HEIGHT, width),
+                    );
+                }
+            }
+        }
+
         // Now draw beams.
 
         // Start (most recent qualifying glyph entity) of this beam group.
@@ -530,19 +559,13 @@ impl Stave {
             let entity = &entities[i];
 
             match entity.glyph {
-                Glyph::NoteHead(_, duration) => {
-                    match duration {
-                        Some(duration) => {
-                            if duration.shape.beams() > 0 {
-                                if beam_start_i == None {
-                                    beam_start_i = Some(i);
-                                } else {
-                                    beam_end_i = Some(i);
-                                }
-                            }
+                Glyph::NoteHead(_, duration, _) => {
+                    if duration.shape.beams() > 0 {
+                        if beam_start_i == None {
+                            beam_start_i = Some(i);
+                        } else {
+                            beam_end_i = Some(i);
                         }
-
-                        None => (),
                     }
                 }
 
@@ -578,93 +601,118 @@ impl Stave {
     }
 }
 
-pub fn typeset_from_ast(ast: tune_ast_three::Tune) -> Page {
-    let mut page = Page::new();
-
-    let mut current_stave = Stave::new();
-
-    // Always have a key and time signature on the go.
-    let mut key_signature = l::T::KeySignature(
-        music::PitchClass {
-            diatonic_pitch_class: music::DiatonicPitchClass::C,
-            accidental: None,
-        },
-        music::Mode::Major,
-    );
-    let mut metre = music::Metre(4, 4);
-
-    // TODO We only ever use treble clef at the moment.
-    let mut current_clef = music::Clef::treble();
-
-    for token in ast.prelude {
-        match token {
-            l::T::KeySignature(pitch_class, mode) => {
-                key_signature = l::T::KeySignature(pitch_class, mode)
-            }
-            l::T::Metre(new_metre) => metre = new_metre,
-            _ => (),
-        }
-    }
-
-    current_stave.entities.push(
-        Entity::new(Glyph::Clef(current_clef)),
-    );
-    // TODO add key signature with params
-    // TODO add time signature with params.
+/// Builds a Page by visiting a tune.
+struct PageBuilder {
+    page: Page,
+    current_stave: Stave,
 
-    for voice in ast.voices {
-        for token in voice {
-            match token {
-                l::T::Newline => {
-                    page.boxes.push(HorizontalBox::System(current_stave));
-                    current_stave = Stave::new();
+    /// Time since the last barline, for splitting notes at beats.
+    bar_position: music::FractionalDuration,
 
+    /// Has the prelude finished, so that staves have started?
+    in_body: bool,
+}
 
-                    current_stave.entities.push(
-                        Entity::new(Glyph::Clef(current_clef)),
-                    );
-                    // TODO add key signature with params
-                    // TODO add time signature with params.
-                }
+impl PageBuilder {
+    fn new() -> PageBuilder {
+        PageBuilder {
+            page: Page::new(),
+            current_stave: Stave::new(),
+            bar_position: music::FractionalDuration(0, 1),
+            in_body: false,
+        }
+    }
 
-                // TODO can collapse some sequential things down into single glyphs.
-                l::T::SingleBar => current_stave.entities.push(Entity::new(Glyph::SingleBar)),
+    /// Start a new stave with front matter.
+    fn start_stave(&mut self, state: &visitor::RunningState) {
+        self.current_stave.entities.push(
+            Entity::new(Glyph::Clef(state.clef)),
+        );
+        // TODO add key signature with params
+        // TODO add time signature with params.
+    }
 
-                l::T::DoubleBar => current_stave.entities.push(Entity::new(Glyph::DoubleBar)),
+    /// Finish the page, including the stave in progress.
+    fn finish(mut self) -> Page {
+        self.page.boxes.push(HorizontalBox::System(self.current_stave));
+        self.page
+    }
+}
 
-                l::T::OpenRepeat => current_stave.entities.push(Entity::new(Glyph::OpenRepeat)),
+impl visitor::Visitor for PageBuilder {
+    fn end_prelude(&mut self, state: &visitor::RunningState) {
+        self.in_body = true;
+        self.start_stave(state);
+    }
 
-                l::T::CloseRepeat => current_stave.entities.push(Entity::new(Glyph::CloseRepeat)),
+    // A clef in the prelude is drawn at the start of the first stave. Later ones are changes.
+    fn visit_clef(&mut self, clef: music::Clef, _state: &visitor::RunningState) {
+        if self.in_body {
+            self.current_stave.entities.push(Entity::new(Glyph::Clef(clef)));
+        }
+    }
 
-                l::T::EndBar => current_stave.entities.push(Entity::new(Glyph::EndBar)),
+    fn visit_newline(&mut self, state: &visitor::RunningState) {
+        let stave = ::std::mem::replace(&mut self.current_stave, Stave::new());
+        self.page.boxes.push(HorizontalBox::System(stave));
 
-                l::T::Note(note) => {
-                    // TODO extras like accidentals etc.
-                    let music::Note(pitch, duration) = note;
-                    let clef_interval = current_clef.pitch.interval_to(pitch);
+        self.start_stave(state);
+    }
 
-                    let position = (clef_interval.pitch_classes + current_clef.centre) as i32;
-                    let glyph = duration.to_glyph();
+    // TODO can collapse some sequential things down into single glyphs.
+    fn visit_barline(&mut self, barline: &l::T, _state: &visitor::RunningState) {
+        let glyph = match barline {
+            &l::T::SingleBar => Glyph::SingleBar,
+            &l::T::DoubleBar => Glyph::DoubleBar,
+            &l::T::OpenRepeat => Glyph::OpenRepeat,
+            &l::T::CloseRepeat => Glyph::CloseRepeat,
+            &l::T::EndBar => Glyph::EndBar,
+
+            // Only barlines are visited here.
+            _ => return,
+        };
+
+        self.current_stave.entities.push(Entity::new(glyph));
+        self.bar_position = music::FractionalDuration(0, 1);
+    }
 
-                    current_stave.entities.push(Entity::new(
-                        Glyph::NoteHead(position, glyph),
-                    ));
-                }
+    fn visit_note(&mut self, note: &music::Note, state: &visitor::RunningState) {
+        // TODO extras like accidentals etc.
+        let &music::Note(pitch, duration) = note;
+        let position = state.clef.position(pitch);
+        let glyphs = duration.to_glyphs(self.bar_position, state.metre);
 
-                // Beam break manifests as a zero-width entity. Just like in ABC.
-                l::T::BeamBreak => current_stave.entities.push(Entity::new(Glyph::BeamBreak)),
+        for (i, glyph) in glyphs.iter().enumerate() {
+            let tied = i + 1 < glyphs.len();
 
-                _ => {
-                    // Ignore
-                    // TODO don't ignore!
-                }
-            }
+            self.current_stave.entities.push(Entity::new(
+                Glyph::NoteHead(position, *glyph, tied),
+            ));
         }
+
+        // Without barlines, carry on into the next bar, so the position stays within one.
+        let bar = state.metre.bar();
+        let position = self.bar_position.add(duration);
+        self.bar_position = if bar.0 == 0 || bar.1 == 0 || position.1 == 0 {
+            music::FractionalDuration(0, 1)
+        } else {
+            let numerator = (position.0 * bar.1) % (bar.0 * position.1);
+            music::FractionalDuration(numerator, position.1 * bar.1).reduce()
+        };
     }
 
-    page.boxes.push(HorizontalBox::System(current_stave));
+    // Beam break manifests as a zero-width entity. Just like in ABC.
+    fn visit_beam_break(&mut self, _state: &visitor::RunningState) {
+        self.current_stave.entities.push(Entity::new(Glyph::BeamBreak));
+    }
+}
 
-    page
+pub fn typeset_from_ast(ast: tune_ast_three::Tune) -> Page {
+    let mut builder = PageBuilder::new();
+
+    visitor::walk(&ast, &mut builder);
+
+    builder.finish()
 }
 
 pub fn render_page(page: Page) -> String {
@@ -674,3 +722,75 @@ pub fn render_page(page: Page) -> String {
 
     svg.render()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_support::read;
+
+    fn note_heads(abc: &str) -> Vec<(i32, music::DurationGlyph, bool)> {
+        let page = typeset_from_ast(read(abc));
+        let mut result = vec![];
+
+        for horizontal_box in page.boxes.iter() {
+            let HorizontalBox::System(ref stave) = *horizontal_box;
+
+            for entity in stave.entities.iter() {
+                if let Glyph::NoteHead(position, glyph, tied) = entity.glyph {
+                    result.push((position, glyph, tied));
+                }
+            }
+        }
+
+        result
+    }
+
+    fn positions(abc: &str) -> Vec<i32> {
+        note_heads(abc).iter().map(|&(position, _, _)| position).collect()
+    }
+
+    fn glyph(shape: music::DurationClass, dots: u32) -> music::DurationGlyph {
+        music::DurationGlyph { shape, dots }
+    }
+
+    #[test]
+    fn clef_position_test() {
+        // The bottom, middle and top lines of each stave.
+        assert_eq!(positions("L:1/4\nK:G\nEBf|\n"), vec![0, 4, 8]);
+        assert_eq!(positions("L:1/4\nK:G clef=bass\nG,,D,A,|\n"), vec![0, 4, 8]);
+    }
+
+    #[test]
+    fn tie_test() {
+        // The dotted crotchet crosses the first beat, so is a quaver tied to a crotchet.
+        assert_eq!(
+            note_heads("M:4/4\nL:1/8\nK:C\nA C3 C4|\n"),
+            vec![
+                (3, glyph(music::DurationClass::Quaver, 0), false),
+                (-2, glyph(music::DurationClass::Quaver, 0), true),
+                (-2, glyph(music::DurationClass::Crotchet, 0), false),
+                (-2, glyph(music::DurationClass::Minim, 0), false),
+            ]
+        );
+    }
+
+    #[test]
+    fn unusual_duration_test() {
+        // Durations that have no single glyph, or no denominator, still get note heads.
+        let abc = "L:1/8\nK:C\nA/3 A5 A/0 B11|\n";
+
+        assert!(note_heads(abc).len() >= 4);
+        assert!(!render_page(typeset_from_ast(read(abc))).contains('?'));
+    }
+
+    #[test]
+    fn bar_position_test() {
+        // Without barlines, the position is still within the bar.
+        let abc = format!("M:3/4\nL:1/4\nK:C\n{}\n", "A".repeat(3001));
+
+        let mut builder = PageBuilder::new();
+        visitor::walk(&read(&abc), &mut builder);
+
+        assert_eq!(builder.bar_position, music::FractionalDuration(1, 4));
+    }
+}
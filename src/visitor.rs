@@ -0,0 +1,252 @@
+//! Visitor
+//! Walk a tune AST, calling a method per kind of entity.
+//! Running header state (key, metre etc) is tracked so that consumers don't have to.
+
+use abc_lexer as l;
+use music;
+use tune_ast_three;
+
+/// The header state in force at a given point in the tune.
+#[derive(Debug, PartialEq, PartialOrd, Clone, Copy)]
+pub struct RunningState {
+    pub key: music::KeySignature,
+    pub metre: music::Metre,
+    pub default_length: music::FractionalDuration,
+    pub clef: music::Clef,
+}
+
+impl RunningState {
+    /// State at the start of a tune, before any headers.
+    pub fn new() -> RunningState {
+        RunningState {
+            key: music::KeySignature {
+                tonic: music::PitchClass {
+                    diatonic_pitch_class: music::DiatonicPitchClass::C,
+                    accidental: None,
+                },
+                mode: music::Mode::Major,
+            },
+            metre: music::Metre(4, 4),
+            default_length: tune_ast_three::DEFAULT_NOTE_LENGTH,
+            clef: music::Clef::treble(),
+        }
+    }
+
+    /// Update the state from a token. Tokens that don't affect the state are ignored.
+    pub fn update(&mut self, token: &l::T) {
+        match token {
+            &l::T::KeySignature(tonic, mode) => self.key = music::KeySignature { tonic, mode },
+            &l::T::Metre(metre) => self.metre = metre,
+            &l::T::DefaultNoteLength(length) => self.default_length = length,
+            &l::T::Clef(clef) => self.clef = clef,
+            _ => (),
+        }
+    }
+}
+
+/// Visit the entities of a tune. Every method has a default no-op implementation, so implement
+/// only those of interest.
+/// Each method receives the running state, which has already been updated by the entity.
+pub trait Visitor {
+    /// Text header fields, e.g. title, composer.
+    fn visit_text_field(&mut self, _field: &l::T, _state: &RunningState) {}
+
+    fn visit_key_signature(&mut self, _key: music::KeySignature, _state: &RunningState) {}
+
+    fn visit_metre(&mut self, _metre: music::Metre, _state: &RunningState) {}
+
+    fn visit_default_note_length(
+        &mut self,
+        _length: music::FractionalDuration,
+        _state: &RunningState,
+    ) {
+    }
+
+    fn visit_tempo(&mut self, _tempo: &music::Tempo, _state: &RunningState) {}
+
+    fn visit_clef(&mut self, _clef: music::Clef, _state: &RunningState) {}
+
+    /// Notes, with durations already resolved against the default note length.
+    fn visit_note(&mut self, _note: &music::Note, _state: &RunningState) {}
+
+    /// Barlines, i.e. single, double, end, open and close repeat.
+    fn visit_barline(&mut self, _barline: &l::T, _state: &RunningState) {}
+
+    fn visit_n_time_bar(&mut self, _n: u32, _state: &RunningState) {}
+
+    /// Guitar chords and annotations, uninterpreted.
+    fn visit_guitar_chord(&mut self, _chord: &str, _state: &RunningState) {}
+
+    fn visit_decoration(&mut self, _decoration: music::Decoration, _state: &RunningState) {}
+
+    /// Grace notes, with durations resolved like other notes.
+    fn visit_grace_notes(&mut self, _grace_notes: &music::GraceNotes, _state: &RunningState) {}
+
+    /// Directives, e.g. "MIDI program 73", uninterpreted.
+    fn visit_directive(&mut self, _directive: &str, _state: &RunningState) {}
+
+    fn visit_beam_break(&mut self, _state: &RunningState) {}
+
+    fn visit_newline(&mut self, _state: &RunningState) {}
+
+    /// Called once the prelude (header) has been visited, before any voices.
+    fn end_prelude(&mut self, _state: &RunningState) {}
+
+    /// Called at the start of each voice. The state is that at the end of the prelude.
+    fn start_voice(&mut self, _voice: usize, _state: &RunningState) {}
+
+    fn end_voice(&mut self, _voice: usize, _state: &RunningState) {}
+}
+
+/// Update the running state with a token and dispatch it to the visitor.
+pub fn visit_token<V: Visitor>(token: &l::T, state: &mut RunningState, visitor: &mut V) {
+    state.update(token);
+
+    // No catch-all, so that new tokens have to be considered here.
+    match token {
+        &l::T::Newline => visitor.visit_newline(state),
+        &l::T::BeamBreak => visitor.visit_beam_break(state),
+
+        &l::T::Area(_) |
+        &l::T::Book(_) |
+        &l::T::Composer(_) |
+        &l::T::Discography(_) |
+        &l::T::Filename(_) |
+        &l::T::Group(_) |
+        &l::T::History(_) |
+        &l::T::Information(_) |
+        &l::T::Notes(_) |
+        &l::T::Origin(_) |
+        &l::T::Rhythm(_) |
+        &l::T::Source(_) |
+        &l::T::Title(_) |
+        &l::T::Words(_) |
+        &l::T::X(_) |
+        &l::T::Transcription(_) => visitor.visit_text_field(token, state),
+
+        &l::T::Metre(metre) => visitor.visit_metre(metre, state),
+        &l::T::KeySignature(_, _) => visitor.visit_key_signature(state.key, state),
+        &l::T::DefaultNoteLength(length) => visitor.visit_default_note_length(length, state),
+        &l::T::Tempo(ref tempo) => visitor.visit_tempo(tempo, state),
+        &l::T::Clef(clef) => visitor.visit_clef(clef, state),
+
+        &l::T::SingleBar |
+        &l::T::DoubleBar |
+        &l::T::OpenRepeat |
+        &l::T::CloseRepeat |
+        &l::T::EndBar => visitor.visit_barline(token, state),
+        &l::T::NTimeBar(n) => visitor.visit_n_time_bar(n, state),
+
+        &l::T::GuitarChord(ref chord) => visitor.visit_guitar_chord(chord, state),
+        &l::T::Decoration(decoration) => visitor.visit_decoration(decoration, state),
+        &l::T::GraceNotes(ref grace_notes) => visitor.visit_grace_notes(grace_notes, state),
+
+        &l::T::Note(ref note) => visitor.visit_note(note, state),
+
+        &l::T::Directive(ref directive) => visitor.visit_directive(directive, state),
+    }
+}
+
+/// Walk the whole tune: prelude, then each voice in turn.
+pub fn walk<V: Visitor>(tune: &tune_ast_three::Tune, visitor: &mut V) {
+    let mut state = RunningState::new();
+
+    for token in tune.prelude.iter() {
+        visit_token(token, &mut state, visitor);
+    }
+
+    visitor.end_prelude(&state);
+
+    // Each voice starts from the state established in the prelude.
+    let prelude_state = state;
+
+    for (i, voice) in tune.voices.iter().enumerate() {
+        let mut state = prelude_state;
+
+        visitor.start_voice(i, &state);
+
+        for token in voice.iter() {
+            visit_token(token, &mut state, visitor);
+        }
+
+        visitor.end_voice(i, &state);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Records the metre in force at every note.
+    struct MetreRecorder {
+        metres: Vec<music::Metre>,
+        num_barlines: usize,
+    }
+
+    impl Visitor for MetreRecorder {
+        fn visit_note(&mut self, _note: &music::Note, state: &RunningState) {
+            self.metres.push(state.metre);
+        }
+
+        fn visit_barline(&mut self, _barline: &l::T, _state: &RunningState) {
+            self.num_barlines += 1;
+        }
+    }
+
+    #[test]
+    fn walk_tracks_state_test() {
+        let note = music::Note(
+            music::Pitch {
+                pitch_class: music::PitchClass {
+                    diatonic_pitch_class: music::DiatonicPitchClass::C,
+                    accidental: None,
+                },
+                octave: 0,
+            },
+            music::FractionalDuration(1, 8),
+        );
+
+        // Mid-tune metre change.
+        let mut tune = tune_ast_three::Tune::new();
+        tune.prelude = vec![l::T::Metre(music::Metre(6, 8))];
+        tune.voices = vec![
+            vec![
+                l::T::Note(note.clone()),
+                l::T::Note(note.clone()),
+                l::T::SingleBar,
+                l::T::Metre(music::Metre(3, 4)),
+                l::T::Note(note.clone()),
+                l::T::EndBar,
+            ],
+        ];
+
+        let mut recorder = MetreRecorder {
+            metres: vec![],
+            num_barlines: 0,
+        };
+        walk(&tune, &mut recorder);
+
+        assert_eq!(
+            recorder.metres,
+            vec![
+                music::Metre(6, 8),
+                music::Metre(6, 8),
+                music::Metre(3, 4),
+            ],
+            "Each note should see the metre in force at the time."
+        );
+
+        assert_eq!(recorder.num_barlines, 2);
+    }
+
+    #[test]
+    fn running_state_update_test() {
+        let mut state = RunningState::new();
+
+        state.update(&l::T::DefaultNoteLength(music::FractionalDuration(1, 16)));
+        state.update(&l::T::Title("Ignored".to_string()));
+
+        assert_eq!(state.default_length, music::FractionalDuration(1, 16));
+        assert_eq!(state.metre, music::Metre(4, 4), "Other fields unchanged.");
+    }
+}